@@ -1,16 +1,20 @@
 use std::net::SocketAddr;
 
+use bytes::Bytes;
+
+use crate::extensions::Extensions;
+
 #[derive(Clone, Debug)]
 pub enum Action {
     SendNoOp(SocketAddr),
     SendRoot(Option<[u8; 32]>, SocketAddr),
     SendError(Vec<u8>, SocketAddr),
-    SendHello(Option<[u8; 4]>, Vec<u8>, SocketAddr),
+    SendHello(Extensions, Vec<u8>, SocketAddr),
     SendPublicKey(Option<[u8; 64]>, SocketAddr),
     SendGetDatumWithHash([u8; 32], SocketAddr),
     SendNatTraversalRequest(Vec<u8>, SocketAddr),
 
-    SendHelloReply([u8; 4], Option<[u8; 4]>, Vec<u8>, SocketAddr),
+    SendHelloReply([u8; 4], Extensions, Vec<u8>, SocketAddr),
     SendRootReply([u8; 4], Option<[u8; 32]>, SocketAddr),
     SendPublicKeyReply([u8; 4], Option<[u8; 64]>, SocketAddr),
     SendErrorReply([u8; 4], Option<Vec<u8>>, SocketAddr),
@@ -18,17 +22,41 @@ pub enum Action {
     SendNoDatum([u8; 4], SocketAddr),
 
     ProcessNoOp(SocketAddr),
-    ProcessHello([u8; 4], Option<[u8; 4]>, Vec<u8>, SocketAddr),
+    ProcessHello([u8; 4], Extensions, Vec<u8>, SocketAddr),
     ProcessError([u8; 4], Vec<u8>, SocketAddr),
     ProcessPublicKey([u8; 4], Option<[u8; 64]>, SocketAddr),
     ProcessRoot([u8; 4], Option<[u8; 32]>, SocketAddr),
     ProcessGetDatum([u8; 4], [u8; 32], SocketAddr),
     ProcessNatTraversal(Vec<u8>, SocketAddr),
 
-    ProcessHelloReply(Option<[u8; 4]>, Vec<u8>, SocketAddr),
+    ProcessHelloReply(Extensions, Vec<u8>, SocketAddr),
     ProcessErrorReply(Vec<u8>, SocketAddr),
     ProcessRootReply(Option<[u8; 32]>, SocketAddr),
     ProcessPublicKeyReply(Option<[u8; 64]>, SocketAddr),
-    ProcessDatum(Vec<u8>, SocketAddr),
+    /*Hash then mkfs node, sharing the buffer of the received packet */
+    ProcessDatum([u8; 32], Bytes, SocketAddr),
     ProcessNoDatum(SocketAddr),
 }
+
+impl Action {
+    /*Actions built from a received packet, as opposed to the ones
+    asking to send a packet */
+    pub fn is_received(&self) -> bool {
+        matches!(
+            self,
+            Action::ProcessNoOp(..)
+                | Action::ProcessHello(..)
+                | Action::ProcessError(..)
+                | Action::ProcessPublicKey(..)
+                | Action::ProcessRoot(..)
+                | Action::ProcessGetDatum(..)
+                | Action::ProcessNatTraversal(..)
+                | Action::ProcessHelloReply(..)
+                | Action::ProcessErrorReply(..)
+                | Action::ProcessRootReply(..)
+                | Action::ProcessPublicKeyReply(..)
+                | Action::ProcessDatum(..)
+                | Action::ProcessNoDatum(..)
+        )
+    }
+}
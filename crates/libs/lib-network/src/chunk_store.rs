@@ -0,0 +1,280 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use bytes::Bytes;
+use log::{debug, error, info, warn};
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+
+/*
+    Datums kept on disk under their hash : chunks, big files and
+    directories alike, as the mkfs node a Datum carries. Whatever is
+    stored hashes to its name, so it can stand for a GetDatum to any
+    peer and be served to others in turn.
+    Each datum is dir/<first byte>/<hash>, written aside then renamed
+    so that a datum is never seen half written.
+    Past max_bytes, the datums stored first are evicted. Every datum
+    is read from the disk, the async methods do it off the runtime.
+*/
+#[derive(Debug, Clone)]
+pub struct ChunkStore {
+    dir: PathBuf,
+    max_bytes: u64,
+    /*Bytes of the datums on disk, shared by the clones */
+    used: Arc<Mutex<u64>>,
+}
+
+/*Eviction goes a tenth below the cap so that it doesn't run on every put */
+fn evict_target(max_bytes: u64) -> u64 {
+    max_bytes - max_bytes / 10
+}
+
+fn is_valid(hash: &[u8; 32], node: &[u8]) -> bool {
+    <[u8; 32]>::from(Sha256::digest(node)) == *hash
+}
+
+impl ChunkStore {
+    pub fn open(dir: &Path, max_bytes: u64) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let store = Self {
+            dir: dir.to_path_buf(),
+            max_bytes,
+            used: Arc::new(Mutex::new(0)),
+        };
+        let mut used = store.lock_used();
+        *used = store.list()?.iter().map(|(_, len, _)| len).sum();
+        if *used > max_bytes {
+            store.evict(&mut used)?;
+        }
+        drop(used);
+        Ok(store)
+    }
+
+    pub fn get_dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn get_max_bytes(&self) -> u64 {
+        self.max_bytes
+    }
+
+    pub fn get_used_bytes(&self) -> u64 {
+        *self.lock_used()
+    }
+
+    fn lock_used(&self) -> std::sync::MutexGuard<'_, u64> {
+        match self.used.lock() {
+            Ok(used) => used,
+            Err(e) => {
+                error!("[lock_used] ChunkStore mutex is poisoned {e}");
+                panic!("ChunkStore mutex is poisoned {e}")
+            }
+        }
+    }
+
+    /*Modification time, length and path of every datum stored */
+    fn list(&self) -> io::Result<Vec<(SystemTime, u64, PathBuf)>> {
+        let mut datums = vec![];
+        for prefix in fs::read_dir(&self.dir)? {
+            let prefix = prefix?.path();
+            if !prefix.is_dir() {
+                continue;
+            }
+            for datum in fs::read_dir(&prefix)? {
+                let datum = datum?;
+                /*A datum being written by another put */
+                if datum.path().extension().is_some() {
+                    continue;
+                }
+                let metadata = datum.metadata()?;
+                datums.push((metadata.modified()?, metadata.len(), datum.path()));
+            }
+        }
+        Ok(datums)
+    }
+
+    /*Removes the oldest datums until used is below the eviction target */
+    fn evict(&self, used: &mut u64) -> io::Result<()> {
+        let mut datums = self.list()?;
+        datums.sort();
+        *used = datums.iter().map(|(_, len, _)| len).sum();
+        let mut evicted = 0;
+        for (_, len, path) in datums {
+            if *used <= evict_target(self.max_bytes) {
+                break;
+            }
+            match fs::remove_file(&path) {
+                Ok(()) => *used -= len,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => *used -= len,
+                Err(e) => return Err(e),
+            }
+            evicted += 1;
+        }
+        info!("Evicted {evicted} datums from the store, {used} bytes left");
+        Ok(())
+    }
+
+    fn datum_path(&self, hash: &[u8; 32]) -> PathBuf {
+        self.dir
+            .join(hex::encode(&hash[..1]))
+            .join(hex::encode(hash))
+    }
+
+    pub fn contains(&self, hash: &[u8; 32]) -> bool {
+        self.datum_path(hash).is_file()
+    }
+
+    /*The node with the given hash, a datum altered on disk is dropped */
+    pub fn get(&self, hash: &[u8; 32]) -> Option<Bytes> {
+        let path = self.datum_path(hash);
+        let node = fs::read(&path).ok()?;
+        if !is_valid(hash, &node) {
+            warn!("Dropping corrupted datum {}", hex::encode(hash));
+            if fs::remove_file(&path).is_ok() {
+                let mut used = self.lock_used();
+                *used = used.saturating_sub(node.len() as u64);
+            }
+            return None;
+        }
+        debug!("Datum {} found in the store", hex::encode(hash));
+        Some(node.into())
+    }
+
+    /*Keeps node if it hashes to hash, returns whether it did */
+    pub fn put(&self, hash: &[u8; 32], node: &[u8]) -> io::Result<bool> {
+        if !is_valid(hash, node) {
+            return Ok(false);
+        }
+        let path = self.datum_path(hash);
+        if path.is_file() {
+            return Ok(true);
+        }
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        /*Several downloads may put the same datum at once */
+        let partial = path.with_extension(format!("{}.part", OsRng.next_u32()));
+        fs::write(&partial, node)?;
+        /*Only the put that creates the datum counts it */
+        let mut used = self.lock_used();
+        if path.is_file() {
+            let _ = fs::remove_file(&partial);
+            return Ok(true);
+        }
+        if let Err(e) = fs::rename(&partial, &path) {
+            let _ = fs::remove_file(&partial);
+            return Err(e);
+        }
+        *used += node.len() as u64;
+        if *used > self.max_bytes {
+            self.evict(&mut used)?;
+        }
+        Ok(true)
+    }
+
+    /*get on a blocking thread */
+    pub async fn spawn_get(&self, hash: [u8; 32]) -> Option<Bytes> {
+        let store = self.clone();
+        tokio::task::spawn_blocking(move || store.get(&hash))
+            .await
+            .ok()
+            .flatten()
+    }
+
+    /*put on a blocking thread */
+    pub async fn spawn_put(&self, hash: [u8; 32], node: Bytes) -> io::Result<bool> {
+        let store = self.clone();
+        match tokio::task::spawn_blocking(move || store.put(&hash, &node)).await {
+            Ok(stored) => stored,
+            Err(e) => Err(io::Error::other(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lib_network_chunk_store_get_and_put() {
+        let dir = std::env::temp_dir().join(format!("udp2p-store-{}", std::process::id()));
+        let store = ChunkStore::open(&dir, 1 << 20).unwrap();
+        let node = b"\x00some chunk".to_vec();
+        let hash: [u8; 32] = Sha256::digest(&node).into();
+
+        assert_eq!(store.get(&hash), None);
+        /*Only what hashes to its name is kept */
+        assert!(!store.put(&[0u8; 32], &node).unwrap());
+        assert!(!store.contains(&[0u8; 32]));
+        assert!(store.put(&hash, &node).unwrap());
+        assert!(store.put(&hash, &node).unwrap());
+        assert_eq!(store.get(&hash), Some(Bytes::from(node.clone())));
+
+        /*Shared with another store on the same directory */
+        assert!(ChunkStore::open(&dir, 1 << 20).unwrap().contains(&hash));
+
+        fs::write(store.datum_path(&hash), b"\x00tampered").unwrap();
+        assert_eq!(store.get(&hash), None);
+        assert!(!store.contains(&hash));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn lib_network_chunk_store_counts_a_datum_once() {
+        let dir = std::env::temp_dir().join(format!("udp2p-concurrent-{}", std::process::id()));
+        let store = ChunkStore::open(&dir, 1 << 30).unwrap();
+        /*Long to write, so that the puts overlap */
+        let node = vec![7u8; 1 << 22];
+        let hash: [u8; 32] = Sha256::digest(&node).into();
+
+        let barrier = std::sync::Barrier::new(8);
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    barrier.wait();
+                    assert!(store.put(&hash, &node).unwrap());
+                });
+            }
+        });
+        assert_eq!(store.get_used_bytes(), 1 << 22);
+        assert_eq!(store.list().unwrap().len(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn lib_network_chunk_store_evicts_the_oldest() {
+        let dir = std::env::temp_dir().join(format!("udp2p-evict-{}", std::process::id()));
+        let store = ChunkStore::open(&dir, 100).unwrap();
+        let nodes: Vec<(Vec<u8>, [u8; 32])> = (0..4u8)
+            .map(|i| {
+                let node = vec![i; 40];
+                let hash = Sha256::digest(&node).into();
+                (node, hash)
+            })
+            .collect();
+        for (node, hash) in &nodes[..2] {
+            assert!(store.put(hash, node).unwrap());
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(store.get_used_bytes(), 80);
+
+        /*Over the cap, the first one makes room */
+        assert!(store.put(&nodes[2].1, &nodes[2].0).unwrap());
+        assert!(!store.contains(&nodes[0].1));
+        assert!(store.contains(&nodes[1].1) && store.contains(&nodes[2].1));
+        assert_eq!(store.get_used_bytes(), 80);
+
+        /*A smaller cap is enforced on open */
+        let store = ChunkStore::open(&dir, 50).unwrap();
+        assert_eq!(store.get_used_bytes(), 40);
+        assert!(store.contains(&nodes[2].1));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
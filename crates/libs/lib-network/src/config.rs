@@ -0,0 +1,176 @@
+use std::net::SocketAddr;
+
+use log::info;
+use reqwest::Url;
+use thiserror::Error;
+use tokio::net::lookup_host;
+
+use crate::{
+    chunk_store::ChunkStore, congestion_handler::QueueLimits, rate_limit::RateLimits,
+    rest_server::RestServer,
+};
+
+/*The rendezvous server of jch, "host:port" to resolve */
+pub const DEFAULT_RENDEZVOUS: &str = "jch.irif.fr:8443";
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Failed to resolve {0} : {1}")]
+    Resolve(String, std::io::Error),
+    #[error("{0} doesn't resolve to any address")]
+    NoAddress(String),
+    #[error("Url {0} has no host")]
+    NoHost(String),
+}
+
+/*
+    Settings shared by the tasks of a node. The rendezvous servers
+    are the ones we say Hello to, keep alive, and ask for NAT traversals.
+    The REST server, if any, is the one we publish on and cross-check
+    peers with. The queue limits bound the queues of the node, see
+    congestion_handler::QueueLimits. The rate limits bound the
+    bandwidth of the datums we serve. The chunk store, if any, keeps
+    the datums we download. They are served along our own tree only
+    when resharing, and only while we export one.
+*/
+#[derive(Debug, Clone, Default)]
+pub struct NodeConfig {
+    rendezvous_servers: Vec<SocketAddr>,
+    rest_server: Option<RestServer>,
+    queue_limits: QueueLimits,
+    rate_limits: RateLimits,
+    chunk_store: Option<ChunkStore>,
+    reshare: bool,
+}
+
+impl NodeConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_rendezvous_server(&mut self, server: SocketAddr) -> &mut Self {
+        if !self.rendezvous_servers.contains(&server) {
+            self.rendezvous_servers.push(server);
+        }
+        self
+    }
+
+    pub fn set_rest_server(&mut self, rest_server: Option<RestServer>) -> &mut Self {
+        self.rest_server = rest_server;
+        self
+    }
+
+    pub fn set_queue_limits(&mut self, queue_limits: QueueLimits) -> &mut Self {
+        self.queue_limits = queue_limits;
+        self
+    }
+
+    pub fn set_rate_limits(&mut self, rate_limits: RateLimits) -> &mut Self {
+        self.rate_limits = rate_limits;
+        self
+    }
+
+    pub fn set_chunk_store(&mut self, chunk_store: Option<ChunkStore>) -> &mut Self {
+        self.chunk_store = chunk_store;
+        self
+    }
+
+    pub fn set_reshare(&mut self, reshare: bool) -> &mut Self {
+        self.reshare = reshare;
+        self
+    }
+
+    pub fn get_rendezvous_servers(&self) -> &Vec<SocketAddr> {
+        &self.rendezvous_servers
+    }
+
+    pub fn get_rest_server(&self) -> Option<&RestServer> {
+        self.rest_server.as_ref()
+    }
+
+    pub fn get_queue_limits(&self) -> &QueueLimits {
+        &self.queue_limits
+    }
+
+    pub fn get_rate_limits(&self) -> RateLimits {
+        self.rate_limits
+    }
+
+    pub fn get_chunk_store(&self) -> Option<&ChunkStore> {
+        self.chunk_store.as_ref()
+    }
+
+    /*The chunk store if its datums may be served to others */
+    pub fn get_reshared_store(&self) -> Option<&ChunkStore> {
+        self.chunk_store.as_ref().filter(|_| self.reshare)
+    }
+
+    pub fn is_rendezvous_server(&self, sock_addr: SocketAddr) -> bool {
+        self.rendezvous_servers.contains(&sock_addr)
+    }
+
+    /*Adds every address of host, given as "host:port" */
+    pub async fn resolve_rendezvous_server(
+        &mut self,
+        host: &str,
+    ) -> Result<&mut Self, ConfigError> {
+        let addresses: Vec<SocketAddr> = match lookup_host(host).await {
+            Ok(addresses) => addresses.collect(),
+            Err(e) => return Err(ConfigError::Resolve(host.to_string(), e)),
+        };
+        if addresses.is_empty() {
+            return Err(ConfigError::NoAddress(host.to_string()));
+        }
+
+        for address in addresses {
+            info!("Rendezvous server {host} at {address}");
+            self.add_rendezvous_server(address);
+        }
+        Ok(self)
+    }
+
+    /*The rendezvous server listens for UDP on the host and port of its REST API */
+    pub async fn resolve_rendezvous_server_from_url(
+        &mut self,
+        url: &Url,
+    ) -> Result<&mut Self, ConfigError> {
+        let host = match (url.host_str(), url.port_or_known_default()) {
+            (Some(host), Some(port)) => format!("{host}:{port}"),
+            _ => return Err(ConfigError::NoHost(url.to_string())),
+        };
+        self.resolve_rendezvous_server(&host).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn lib_network_config_resolve_rendezvous_servers() {
+        let mut config = NodeConfig::new();
+        config
+            .resolve_rendezvous_server("127.0.0.1:8443")
+            .await
+            .unwrap();
+        config
+            .resolve_rendezvous_server_from_url(&Url::parse("https://127.0.0.1:8443/").unwrap())
+            .await
+            .unwrap();
+        config
+            .resolve_rendezvous_server_from_url(&Url::parse("http://[::1]/").unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            config.get_rendezvous_servers(),
+            &vec![
+                "127.0.0.1:8443".parse::<SocketAddr>().unwrap(),
+                "[::1]:80".parse().unwrap()
+            ]
+        );
+        assert!(config.is_rendezvous_server("[::1]:80".parse().unwrap()));
+        assert!(!config.is_rendezvous_server("127.0.0.1:80".parse().unwrap()));
+        assert!(config.resolve_rendezvous_server("127.0.0.1").await.is_err());
+    }
+}
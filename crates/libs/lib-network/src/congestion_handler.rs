@@ -2,7 +2,10 @@ use std::collections::HashMap;
 use std::net::SocketAddr;
 
 use std::collections::VecDeque;
-use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::pin::pin;
+use std::sync::{Arc, Mutex, RwLock};
+
+use tokio::sync::{broadcast, oneshot, Notify};
 
 use log::{info, error};
 use std::time::{Duration, Instant};
@@ -33,63 +36,51 @@ use crate::packet::*;
 //     }
 // }
 
+/*
+    Whether a queue has something to pop. Tasks await wait() instead of
+    blocking their worker thread so that the whole pipeline can run on
+    a single thread.
+*/
 pub struct QueueState {
-    is_not_empty: (Mutex<bool>, Condvar),
+    is_not_empty: (Mutex<bool>, Notify),
 }
 impl QueueState {
     pub fn build_arc() -> Arc<Self> {
         Arc::new(Self {
-            is_not_empty: (Mutex::new(false), Condvar::new()),
+            is_not_empty: (Mutex::new(false), Notify::new()),
         })
     }
-    pub fn wait(&self) {
-        /*
-            Get the lock once and give it to a Condvar.
-            The wait method atomically unlocks the mutex and waits
-            for a notification.
-        */
-        let (state_lock, notif_var) = &self.is_not_empty;
-        let mut start_or_wait = state_lock.lock().unwrap();
-
-        /*
-            Due to some obscure reasons the Condvar is
-            susceptible to spurious wakeups so that we
-            need to pair it with a variable change on the Mutex
-        */
-        while !*start_or_wait {
-            start_or_wait = notif_var.wait(start_or_wait).unwrap();
+    fn is_not_empty(&self) -> bool {
+        let (state_lock, _) = &self.is_not_empty;
+        match state_lock.lock() {
+            Ok(state_guard) => *state_guard,
+            Err(poison_error) => {
+                error!("{poison_error}");
+                panic!("QueueState poisoned, sender panicked ?")
+            }
         }
     }
-    pub fn wait_timeout_ms(&self, timeout: u64) -> Result<(), CongestionHandlerError> {
-        /*
-            Get the lock once and give it to a Condvar.
-            The wait method atomically unlocks the mutex and waits
-            for a notification.
-        */
-        let (state_lock, notif_var) = &self.is_not_empty;
-        let mut start_or_wait = state_lock.lock().unwrap();
-
-        /*
-            Due to some obscure reasons the Condvar is
-            susceptible to spurious wakeups so that we
-            need to pair it with a variable change on the Mutex
-        */
-        while !*start_or_wait {
-            start_or_wait =
-                match notif_var.wait_timeout(start_or_wait, Duration::from_millis(timeout)) {
-                    Ok(result) => {
-                        if result.1.timed_out() {
-                            return Err(CongestionHandlerError::TimeOutError);
-                        }
-                        result.0
-                    }
-                    Err(_) => {
-                        error!("poison in QueueState");
-                        panic!("Poison")
-                    }
-                }
+    pub async fn wait(&self) {
+        let (_, notifyer) = &self.is_not_empty;
+        loop {
+            /*
+                Register for the notification before reading the
+                state, a set_non_empty_queue in between would
+                otherwise be missed
+            */
+            let mut notified = pin!(notifyer.notified());
+            notified.as_mut().enable();
+            if self.is_not_empty() {
+                return;
+            }
+            notified.await;
+        }
+    }
+    pub async fn wait_timeout_ms(&self, timeout: u64) -> Result<(), CongestionHandlerError> {
+        match tokio::time::timeout(Duration::from_millis(timeout), self.wait()).await {
+            Ok(()) => Ok(()),
+            Err(_) => Err(CongestionHandlerError::TimeOutError),
         }
-        Ok(())
     }
 
     pub fn set_empty_queue(queue_state: Arc<QueueState>) {
@@ -108,7 +99,7 @@ impl QueueState {
     pub fn set_non_empty_queue(queue_state: Arc<QueueState>) {
         /*
             Put the lock state to true and get the notifyer to
-            tell the other threads to wake up
+            wake up the tasks waiting on it
         */
         let (state_lock, notifyer) = &queue_state.is_not_empty;
         let mut state_guard = match state_lock.lock() {
@@ -120,27 +111,85 @@ impl QueueState {
         };
 
         *state_guard = true;
-        notifyer.notify_all();
+        notifyer.notify_waiters();
+    }
+}
+
+/*What a full queue does with what is pushed to it */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    DropNewest,
+    DropOldest,
+    /*
+        Producers that can wait (the receivers, the handle packet and
+        handle action tasks, the requests) wait for a place. The others
+        drop the newest.
+    */
+    Block,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueLimit {
+    pub capacity: usize,
+    pub policy: OverflowPolicy,
+}
+
+impl QueueLimit {
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> Self {
+        Self { capacity, policy }
     }
 }
 
+/*
+    Limits of the queues of a node. By default the receivers stop
+    reading the sockets when the receive queue is full, so that a flood
+    is dropped by the kernel instead of growing our queues.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueLimits {
+    pub receive: QueueLimit,
+    pub send: QueueLimit,
+    pub action: QueueLimit,
+    pub process: QueueLimit,
+}
+
+impl Default for QueueLimits {
+    fn default() -> Self {
+        Self {
+            receive: QueueLimit::new(DEFAULT_QUEUE_CAPACITY, OverflowPolicy::Block),
+            send: QueueLimit::new(DEFAULT_QUEUE_CAPACITY, OverflowPolicy::Block),
+            action: QueueLimit::new(DEFAULT_QUEUE_CAPACITY, OverflowPolicy::DropNewest),
+            process: QueueLimit::new(DEFAULT_QUEUE_CAPACITY, OverflowPolicy::DropOldest),
+        }
+    }
+}
+
+pub const DEFAULT_QUEUE_CAPACITY: usize = 1024;
+
 pub struct Queue<T> {
     data: VecDeque<T>,
     // can_pop: bool,
+    limit: QueueLimit,
+    /*Pushes dropped because the queue was full */
+    dropped: usize,
+    /*Notified on pop, for the producers waiting for a place */
+    space: Arc<Notify>,
 }
 
 impl<T: Clone> Queue<T> {
-    fn build_mutex() -> Arc<Mutex<Self>> {
-        Arc::new(Mutex::new(Self {
+    pub fn new(limit: QueueLimit) -> Self {
+        Self {
             data: VecDeque::new(),
-            // can_pop: true,
-        }))
+            limit,
+            dropped: 0,
+            space: Arc::new(Notify::new()),
+        }
     }
-    fn build_rwlock() -> Arc<RwLock<Self>> {
-        Arc::new(RwLock::new(Self {
-            data: VecDeque::new(),
-            // can_pop: false,
-        }))
+    fn build_mutex(limit: QueueLimit) -> Arc<Mutex<Self>> {
+        Arc::new(Mutex::new(Self::new(limit)))
+    }
+    fn build_rwlock(limit: QueueLimit) -> Arc<RwLock<Self>> {
+        Arc::new(RwLock::new(Self::new(limit)))
     }
 
     pub fn read_lock_and_peek(queue: Arc<RwLock<Queue<T>>>) -> Option<T> {
@@ -196,6 +245,80 @@ impl<T: Clone> Queue<T> {
 
         queue_guard.push_back(data);
     }
+    /*Pushes data, waiting for a place if the queue is full and blocks */
+    pub async fn lock_and_push_or_wait(queue: Arc<Mutex<Queue<T>>>, data: T) {
+        let space = match queue.lock() {
+            Ok(queue_guard) => Arc::clone(&queue_guard.space),
+            Err(poison_error) => {
+                error!("{poison_error}");
+                panic!("Mutex is poisoned, some thread panicked")
+            }
+        };
+        loop {
+            /*Registered before checking so that a pop in between isn't missed */
+            let mut popped = pin!(space.notified());
+            popped.as_mut().enable();
+            {
+                let mut queue_guard = match queue.lock() {
+                    Ok(queue_gard) => queue_gard,
+                    Err(poison_error) => {
+                        error!("{poison_error}");
+                        panic!("Mutex is poisoned, some thread panicked")
+                    }
+                };
+                if !queue_guard.must_wait() {
+                    queue_guard.push_back(data);
+                    return;
+                }
+            }
+            popped.await;
+        }
+    }
+    pub async fn write_lock_and_push_or_wait(queue: Arc<RwLock<Queue<T>>>, data: T) {
+        let space = match queue.read() {
+            Ok(queue_guard) => Arc::clone(&queue_guard.space),
+            Err(poison_error) => {
+                error!("{poison_error}");
+                panic!("Mutex is poisoned, some thread panicked")
+            }
+        };
+        loop {
+            let mut popped = pin!(space.notified());
+            popped.as_mut().enable();
+            {
+                let mut queue_guard = match queue.write() {
+                    Ok(queue_gard) => queue_gard,
+                    Err(poison_error) => {
+                        error!("{poison_error}");
+                        panic!("Mutex is poisoned, some thread panicked")
+                    }
+                };
+                if !queue_guard.must_wait() {
+                    queue_guard.push_back(data);
+                    return;
+                }
+            }
+            popped.await;
+        }
+    }
+    pub fn lock_and_get_dropped(queue: Arc<Mutex<Queue<T>>>) -> usize {
+        match queue.lock() {
+            Ok(queue_guard) => queue_guard.dropped,
+            Err(poison_error) => {
+                error!("{poison_error}");
+                panic!("Mutex is poisoned, some thread panicked")
+            }
+        }
+    }
+    pub fn read_lock_and_get_dropped(queue: Arc<RwLock<Queue<T>>>) -> usize {
+        match queue.read() {
+            Ok(queue_guard) => queue_guard.dropped,
+            Err(poison_error) => {
+                error!("{poison_error}");
+                panic!("Mutex is poisoned, some thread panicked")
+            }
+        }
+    }
     pub fn lock_and_push_mul(queue: Arc<Mutex<Queue<T>>>, data_vec: Vec<T>) {
         let mut queue_guard = match queue.lock() {
             Ok(queue_gard) => queue_gard,
@@ -224,6 +347,17 @@ impl<T: Clone> Queue<T> {
         //     queue_guard.peek_front()
         // }
     }
+    pub fn lock_and_is_empty(queue: Arc<Mutex<Queue<T>>>) -> bool {
+        let queue_guard = match queue.lock() {
+            Ok(queue_gard) => queue_gard,
+            Err(poison_error) => {
+                error!("{poison_error}");
+                panic!("Mutex is poisoned, some thread panicked")
+            }
+        };
+
+        queue_guard.is_empty()
+    }
     pub fn lock_and_pop(queue: Arc<Mutex<Queue<T>>>) -> Option<T> {
         let mut queue_guard = match queue.lock() {
             Ok(queue_gard) => queue_gard,
@@ -235,15 +369,46 @@ impl<T: Clone> Queue<T> {
 
         queue_guard.pop_front()
     }
-    pub fn push_back(&mut self, data: T) {
-        self.data.push_back(data);
+    /*Applies the overflow policy when full, returns false if data was dropped */
+    pub fn push_back(&mut self, data: T) -> bool {
+        if self.data.len() < self.limit.capacity {
+            self.data.push_back(data);
+            return true;
+        }
+        self.dropped += 1;
+        match self.limit.policy {
+            OverflowPolicy::DropOldest => {
+                self.data.pop_front();
+                self.data.push_back(data);
+                true
+            }
+            OverflowPolicy::DropNewest | OverflowPolicy::Block => false,
+        }
     }
     pub fn append_back(&mut self, data_vec: impl Into<VecDeque<T>>) {
-        self.data.extend(data_vec.into());
+        for data in data_vec.into() {
+            self.push_back(data);
+        }
     }
 
     pub fn pop_front(&mut self) -> Option<T> {
-        self.data.pop_front()
+        let front = self.data.pop_front();
+        if front.is_some() {
+            self.space.notify_waiters();
+        }
+        front
+    }
+    pub fn is_full(&self) -> bool {
+        self.data.len() >= self.limit.capacity
+    }
+    fn must_wait(&self) -> bool {
+        self.limit.policy == OverflowPolicy::Block && self.is_full()
+    }
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+    pub fn get_dropped(&self) -> usize {
+        self.dropped
     }
     pub fn flush_and_front(&mut self) -> Option<T> {
         let front = self.data.front();
@@ -252,6 +417,7 @@ impl<T: Clone> Queue<T> {
             None => None,
         };
         self.data.clear();
+        self.space.notify_waiters();
         front
     }
 
@@ -305,6 +471,202 @@ impl std::fmt::Display for CongestionHandlerError {
     }
 }
 
+/*Bounds of the retransmission timeout, in ms */
+pub const MIN_RTO: u64 = 100;
+pub const MAX_RTO: u64 = 10_000;
+/*Until a peer replied once, RFC 6298 */
+pub const INITIAL_RTO: u64 = 1_000;
+
+/*
+    Smoothed round trip time and variance of a peer, as in RFC 6298.
+    The retransmission timeout is srtt + 4 * rttvar, kept between
+    MIN_RTO and MAX_RTO.
+*/
+#[derive(Debug, Clone, Copy)]
+pub struct RttEstimator {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+}
+
+impl Default for RttEstimator {
+    fn default() -> Self {
+        Self {
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: Duration::from_millis(INITIAL_RTO),
+        }
+    }
+}
+
+impl RttEstimator {
+    pub fn add_sample(&mut self, rtt: Duration) {
+        let srtt = match self.srtt {
+            None => {
+                self.rttvar = rtt / 2;
+                rtt
+            }
+            Some(srtt) => {
+                self.rttvar = (self.rttvar * 3 + srtt.abs_diff(rtt)) / 4;
+                (srtt * 7 + rtt) / 8
+            }
+        };
+        self.srtt = Some(srtt);
+        self.rto = (srtt + self.rttvar * 4).clamp(
+            Duration::from_millis(MIN_RTO),
+            Duration::from_millis(MAX_RTO),
+        );
+    }
+
+    pub fn get_srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+    pub fn get_rto(&self) -> Duration {
+        self.rto
+    }
+
+    /*Timeout of a packet already resent attempts times, doubled at each resend */
+    pub fn get_backed_off_rto(&self, attempts: usize) -> Duration {
+        self.rto
+            .saturating_mul(1 << attempts.min(16))
+            .min(Duration::from_millis(MAX_RTO))
+    }
+}
+
+/*Bounds of the congestion window, in GetDatums in flight */
+pub const MIN_WINDOW: usize = 1;
+pub const MAX_WINDOW: usize = 128;
+pub const INITIAL_WINDOW: usize = 4;
+/*Above this many peers the windows with nothing in flight are forgotten */
+const MAX_WINDOWS: usize = 1024;
+
+/*
+    How many GetDatums may wait for their reply from a peer. Grows by
+    one per reply until ssthresh (slow start), then by one per window
+    of replies, and is halved when a request times out (AIMD).
+    Requests are numbered as they take a place : the ones sent before
+    the window was last halved were lost in the same event, like the
+    recovery point of TCP, and don't halve it again.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CongestionWindow {
+    cwnd: usize,
+    ssthresh: usize,
+    in_flight: usize,
+    /*replies since cwnd last grew, in congestion avoidance */
+    acked: usize,
+    /*Number of the last request that took a place */
+    sent: u64,
+    /*Number of the last request sent before cwnd was last halved */
+    recover: u64,
+}
+
+impl Default for CongestionWindow {
+    fn default() -> Self {
+        Self {
+            cwnd: INITIAL_WINDOW,
+            ssthresh: MAX_WINDOW,
+            in_flight: 0,
+            acked: 0,
+            sent: 0,
+            recover: 0,
+        }
+    }
+}
+
+impl CongestionWindow {
+    /*The number of the request if it has a place */
+    pub fn try_acquire(&mut self) -> Option<u64> {
+        if self.in_flight >= self.cwnd {
+            return None;
+        }
+        self.in_flight += 1;
+        self.sent += 1;
+        Some(self.sent)
+    }
+
+    /*A request got its reply in time */
+    pub fn on_reply(&mut self) {
+        self.in_flight = self.in_flight.saturating_sub(1);
+        if self.cwnd < self.ssthresh {
+            self.cwnd += 1;
+        } else {
+            self.acked += 1;
+            if self.acked >= self.cwnd {
+                self.acked = 0;
+                self.cwnd += 1;
+            }
+        }
+        self.cwnd = self.cwnd.min(MAX_WINDOW);
+    }
+
+    /*The request numbered seq timed out */
+    pub fn on_timeout(&mut self, seq: u64) {
+        self.in_flight = self.in_flight.saturating_sub(1);
+        if seq <= self.recover {
+            return;
+        }
+        self.recover = self.sent;
+        self.ssthresh = (self.cwnd / 2).max(MIN_WINDOW);
+        self.cwnd = self.ssthresh;
+        self.acked = 0;
+    }
+
+    /*A request was given up without an outcome */
+    pub fn on_cancel(&mut self) {
+        self.in_flight = self.in_flight.saturating_sub(1);
+    }
+
+    pub fn get_cwnd(&self) -> usize {
+        self.cwnd
+    }
+    pub fn get_ssthresh(&self) -> usize {
+        self.ssthresh
+    }
+    pub fn get_in_flight(&self) -> usize {
+        self.in_flight
+    }
+    pub fn is_idle(&self) -> bool {
+        self.in_flight == 0
+    }
+}
+
+/*
+    A request counted in the window of its peer. Dropping it frees
+    its place and wakes the requests waiting for one.
+*/
+pub struct WindowSlot {
+    pending_ids: Arc<Mutex<PendingIds>>,
+    window_state: Arc<Notify>,
+    sock_addr: SocketAddr,
+    /*See CongestionWindow::try_acquire */
+    seq: u64,
+    /*None if the request was dropped before it ended */
+    replied: Option<bool>,
+}
+
+impl WindowSlot {
+    pub fn set_replied(&mut self, replied: bool) {
+        self.replied = Some(replied);
+    }
+}
+
+impl Drop for WindowSlot {
+    fn drop(&mut self) {
+        /*Don't panic again while unwinding */
+        if let Ok(mut pending_ids_guard) = self.pending_ids.lock() {
+            if let Some(window) = pending_ids_guard.addr_to_window.get_mut(&self.sock_addr) {
+                match self.replied {
+                    Some(true) => window.on_reply(),
+                    Some(false) => window.on_timeout(self.seq),
+                    None => window.on_cancel(),
+                }
+            }
+        }
+        self.window_state.notify_waiters();
+    }
+}
+
 /*
     Tracks the ids of the packet sent. The protocol
     asserts that a response to a packet must have the
@@ -319,7 +681,7 @@ pub struct PendingIds {
             SocketAddr,
             /*for convenience */
             PacketType,
-            /*timeout timer */
+            /*last time it was sent */
             Instant,
             /*resending attempts */
             usize,
@@ -328,6 +690,13 @@ pub struct PendingIds {
         ),
     >,
     id_to_packet: HashMap<[u8; 4], (Packet, SocketAddr)>, //attempted_nat_trav: HashMap<SocketAddr, bool>?
+    /*Requests waiting for their reply */
+    id_to_waiter: HashMap<[u8; 4], oneshot::Sender<Action>>,
+    /*Measured from the replies, see id_exists */
+    addr_to_rtt: HashMap<SocketAddr, RttEstimator>,
+    /*GetDatums in flight to each peer, see acquire_window. Bounded by
+    MAX_WINDOWS but for the peers with requests in flight */
+    addr_to_window: HashMap<SocketAddr, CongestionWindow>,
 }
 
 impl PendingIds {
@@ -392,19 +761,17 @@ impl PendingIds {
         };
 
         /*
-            Check if id exists before adding, doesn't check timer.
-            If a packet is in queue to resend. It means the timer
-            (RTO) has been checked.
+            Check if id exists before adding. If it does the packet
+            is being resent, packets_to_resend already counted the
+            attempt so only restart its timer.
         */
         match pending_ids_guard.search_id_mut(&packet) {
             /*id exists */
-            Ok((sock_addr, _packet_type, _, attempts)) => {
-                if *sock_addr != *peer_addr {
-                    return;
-                } else {
-                    *attempts += 1;
-                    return;
+            Ok((sock_addr, _packet_type, sent, _attempts)) => {
+                if *sock_addr == *peer_addr {
+                    *sent = Instant::now();
                 }
+                return;
             }
             Err(CongestionHandlerError::NoPacketWithIdError) => {
                 pending_ids_guard.id_to_addr.insert(
@@ -453,10 +820,25 @@ impl PendingIds {
 
         /*Check if id exists */
         match pending_ids_guard.search_id(&packet) {
-            Ok((sock_addr, ..)) => {
+            Ok((sock_addr, attempts)) => {
+                /*
+                    Karn's rule : the reply to a resent packet may answer
+                    any of its copies, only measure packets sent once.
+                */
+                if attempts == 0 && sock_addr == socket_addr {
+                    if let Some((.., sent, _, _)) =
+                        pending_ids_guard.id_to_addr.get(packet.get_id())
+                    {
+                        let rtt = sent.elapsed();
+                        pending_ids_guard
+                            .addr_to_rtt
+                            .entry(sock_addr)
+                            .or_default()
+                            .add_sample(rtt);
+                    }
+                }
                 /*if id exists, pop the packet before handling it. */
                 /*We choose to not handle the collisions. */
-                /*compute rto ? */
                 pending_ids_guard.pop_packet_id(packet.get_id());
 
                 /*Now check if the address it was sent to is
@@ -484,6 +866,44 @@ impl PendingIds {
         /*Mutex is dropped here */
     }
 
+    /*
+        Registers a request waiting for the reply with the given id.
+        Must be called before the request is sent so that the reply
+        can't come first.
+    */
+    pub fn lock_and_add_waiter(
+        pending_ids: Arc<Mutex<PendingIds>>,
+        id: [u8; 4],
+    ) -> oneshot::Receiver<Action> {
+        let mut pending_ids_guard = match pending_ids.lock() {
+            Ok(guard) => guard,
+            /*If Mutex is poisoned stop every thread, something is wrong */
+            Err(poison_error) => {
+                error!("{poison_error}");
+                panic!("Poisoned IDs mutex")
+            }
+        };
+        let (waiter, reply) = oneshot::channel();
+        pending_ids_guard.id_to_waiter.insert(id, waiter);
+        reply
+    }
+
+    /*Removes the request waiting for the reply with the given id, if any */
+    pub fn lock_and_take_waiter(
+        pending_ids: Arc<Mutex<PendingIds>>,
+        id: &[u8; 4],
+    ) -> Option<oneshot::Sender<Action>> {
+        let mut pending_ids_guard = match pending_ids.lock() {
+            Ok(guard) => guard,
+            /*If Mutex is poisoned stop every thread, something is wrong */
+            Err(poison_error) => {
+                error!("{poison_error}");
+                panic!("Poisoned IDs mutex")
+            }
+        };
+        pending_ids_guard.id_to_waiter.remove(id)
+    }
+
     /*Because hashmaps are not made to be enumerated, this may be heavy.*/
     /*Should ignore nat traversal requests */
     pub fn packets_to_resend(
@@ -502,9 +922,22 @@ impl PendingIds {
         let mut id_to_send_nat_trav = vec![];
         let mut id_to_pop = vec![];
 
-        for (id, (_addr, packet_type, _, attempts, _nat_trav)) in
-            pending_ids_guard.id_to_addr.iter_mut()
-        {
+        let now = Instant::now();
+        let PendingIds {
+            id_to_addr,
+            addr_to_rtt,
+            ..
+        } = &mut *pending_ids_guard;
+        for (id, (addr, packet_type, sent, attempts, _nat_trav)) in id_to_addr.iter_mut() {
+            /*Each packet is resent once its own timeout expires */
+            let rto = match addr_to_rtt.get(addr) {
+                Some(rtt) => rtt.get_backed_off_rto(*attempts),
+                None => RttEstimator::default().get_backed_off_rto(*attempts),
+            };
+            if now.duration_since(*sent) < rto {
+                continue;
+            }
+            *sent = now;
             match *attempts {
                 3 | 5 | 7 | 9=>{
                     *attempts+=1;
@@ -534,6 +967,8 @@ impl PendingIds {
             info!("Packet with id {id:?} timed out");
             pending_ids_guard.id_to_packet.remove(&id);
             pending_ids_guard.id_to_addr.remove(&id).unwrap();
+            /*The waiter sees its request fail instead of waiting for its timeout */
+            pending_ids_guard.id_to_waiter.remove(&id);
         }
 
         let mut packet_to_resend = vec![];
@@ -547,6 +982,112 @@ impl PendingIds {
 
         (addr_to_send_nat_trav, packet_to_resend)
     }
+    /*Time until the next pending packet has to be resent, None if
+    there is no pending packet */
+    pub fn lock_and_next_resend_in(pending_ids: Arc<Mutex<PendingIds>>) -> Option<Duration> {
+        let pending_ids_guard = match pending_ids.lock() {
+            Ok(guard) => guard,
+            /*If Mutex is poisoned stop every thread, something is wrong */
+            Err(poison_error) => {
+                error!("{poison_error}");
+                panic!("Poisoned IDs mutex")
+            }
+        };
+
+        let now = Instant::now();
+        pending_ids_guard
+            .id_to_addr
+            .values()
+            .map(|(addr, _, sent, attempts, _)| {
+                let rto = pending_ids_guard
+                    .addr_to_rtt
+                    .get(addr)
+                    .copied()
+                    .unwrap_or_default()
+                    .get_backed_off_rto(*attempts);
+                (*sent + rto).saturating_duration_since(now)
+            })
+            .min()
+    }
+
+    pub fn lock_and_get_rtt(
+        pending_ids: Arc<Mutex<PendingIds>>,
+        sock_addr: SocketAddr,
+    ) -> RttEstimator {
+        let pending_ids_guard = match pending_ids.lock() {
+            Ok(guard) => guard,
+            /*If Mutex is poisoned stop every thread, something is wrong */
+            Err(poison_error) => {
+                error!("{poison_error}");
+                panic!("Poisoned IDs mutex")
+            }
+        };
+        pending_ids_guard
+            .addr_to_rtt
+            .get(&sock_addr)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn lock_and_get_window(
+        pending_ids: Arc<Mutex<PendingIds>>,
+        sock_addr: SocketAddr,
+    ) -> CongestionWindow {
+        let pending_ids_guard = match pending_ids.lock() {
+            Ok(guard) => guard,
+            /*If Mutex is poisoned stop every thread, something is wrong */
+            Err(poison_error) => {
+                error!("{poison_error}");
+                panic!("Poisoned IDs mutex")
+            }
+        };
+        pending_ids_guard
+            .addr_to_window
+            .get(&sock_addr)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /*Waits until the window of sock_addr has room for one more request */
+    pub async fn acquire_window(
+        pending_ids: Arc<Mutex<PendingIds>>,
+        window_state: Arc<Notify>,
+        sock_addr: SocketAddr,
+    ) -> WindowSlot {
+        let seq = loop {
+            /*Registered before checking so that a release in between
+            isn't missed */
+            let mut released = pin!(window_state.notified());
+            released.as_mut().enable();
+            {
+                let mut pending_ids_guard = match pending_ids.lock() {
+                    Ok(guard) => guard,
+                    /*If Mutex is poisoned stop every thread, something is wrong */
+                    Err(poison_error) => {
+                        error!("{poison_error}");
+                        panic!("Poisoned IDs mutex")
+                    }
+                };
+                let windows = &mut pending_ids_guard.addr_to_window;
+                /*A forgotten peer starts over from INITIAL_WINDOW */
+                if windows.len() >= MAX_WINDOWS && !windows.contains_key(&sock_addr) {
+                    windows.retain(|_, window| !window.is_idle());
+                }
+                if let Some(seq) = windows.entry(sock_addr).or_default().try_acquire() {
+                    break seq;
+                }
+            }
+            released.await;
+        };
+        WindowSlot {
+            pending_ids,
+            window_state,
+            sock_addr,
+            seq,
+            replied: None,
+        }
+    }
+
     pub fn pop_packet_id(&mut self, packet_id: &[u8; 4]) {
         self.id_to_addr.remove(packet_id);
     }
@@ -597,28 +1138,294 @@ impl PendingIds {
     }
 }
 
-pub fn build_queues() -> (
-    Arc<Mutex<Queue<(Packet, SocketAddr)>>>,
-    Arc<Mutex<Queue<(Packet, SocketAddr)>>>,
-    Arc<Mutex<Queue<Action>>>,
-    Arc<RwLock<Queue<Action>>>,
-    Arc<Mutex<PendingIds>>,
-    Arc<QueueState>,
-    Arc<QueueState>,
-    Arc<QueueState>,
-    Arc<QueueState>,
-    Arc<QueueState>,
-) {
-    (
-        Queue::build_mutex(),
-        Queue::build_mutex(),
-        Queue::build_mutex(),
-        Queue::build_rwlock(),
-        PendingIds::build_mutex(),
-        QueueState::build_arc(),
-        QueueState::build_arc(),
-        QueueState::build_arc(),
-        QueueState::build_arc(),
-        QueueState::build_arc(),
-    )
+/*Events kept for slow subscribers before they start missing some */
+pub const EVENTS_CAPACITY: usize = 256;
+
+/*
+    The queues shared by the tasks of a node and the states used to
+    wake up their readers.
+*/
+#[derive(Clone)]
+pub struct Queues {
+    pub receive_queue: Arc<Mutex<Queue<(Packet, SocketAddr)>>>,
+    pub send_queue: Arc<Mutex<Queue<(Packet, SocketAddr)>>>,
+    pub action_queue: Arc<Mutex<Queue<Action>>>,
+    pub process_queue: Arc<RwLock<Queue<Action>>>,
+    pub pending_ids: Arc<Mutex<PendingIds>>,
+    pub receive_queue_state: Arc<QueueState>,
+    pub action_queue_state: Arc<QueueState>,
+    pub send_queue_state: Arc<QueueState>,
+    pub process_queue_state: Arc<QueueState>,
+    /*Set when an id is added, the resend task may have to wake up sooner */
+    pub pending_ids_state: Arc<QueueState>,
+    /*Notified when a place frees up in a congestion window */
+    pub window_state: Arc<Notify>,
+    /*What was received and no request waits for */
+    pub events: broadcast::Sender<Action>,
+}
+
+/*Number of pushes each queue dropped since it was built */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DroppedCounts {
+    pub receive: usize,
+    pub send: usize,
+    pub action: usize,
+    pub process: usize,
+}
+
+impl Queues {
+    pub fn get_dropped(&self) -> DroppedCounts {
+        DroppedCounts {
+            receive: Queue::lock_and_get_dropped(Arc::clone(&self.receive_queue)),
+            send: Queue::lock_and_get_dropped(Arc::clone(&self.send_queue)),
+            action: Queue::lock_and_get_dropped(Arc::clone(&self.action_queue)),
+            process: Queue::read_lock_and_get_dropped(Arc::clone(&self.process_queue)),
+        }
+    }
+}
+
+pub fn build_queues() -> Queues {
+    build_queues_with_limits(&QueueLimits::default())
+}
+
+pub fn build_queues_with_limits(limits: &QueueLimits) -> Queues {
+    Queues {
+        receive_queue: Queue::build_mutex(limits.receive),
+        send_queue: Queue::build_mutex(limits.send),
+        action_queue: Queue::build_mutex(limits.action),
+        process_queue: Queue::build_rwlock(limits.process),
+        pending_ids: PendingIds::build_mutex(),
+        receive_queue_state: QueueState::build_arc(),
+        action_queue_state: QueueState::build_arc(),
+        send_queue_state: QueueState::build_arc(),
+        process_queue_state: QueueState::build_arc(),
+        pending_ids_state: QueueState::build_arc(),
+        window_state: Arc::new(Notify::new()),
+        events: broadcast::channel(EVENTS_CAPACITY).0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extensions::Extensions;
+
+    #[tokio::test]
+    async fn lib_network_congestion_handler_queue_state_wakes_waiters() {
+        let queue_state = QueueState::build_arc();
+        assert!(queue_state.wait_timeout_ms(10).await.is_err());
+
+        /*The waiter runs on the same thread as the notifier */
+        let waiter = tokio::spawn({
+            let queue_state = Arc::clone(&queue_state);
+            async move { queue_state.wait_timeout_ms(1000).await }
+        });
+        tokio::task::yield_now().await;
+        QueueState::set_non_empty_queue(Arc::clone(&queue_state));
+        assert!(waiter.await.unwrap().is_ok());
+
+        /*A non empty queue doesn't wait at all */
+        queue_state.wait().await;
+        QueueState::set_empty_queue(Arc::clone(&queue_state));
+        assert!(queue_state.wait_timeout_ms(10).await.is_err());
+    }
+
+    #[test]
+    fn lib_network_congestion_handler_rtt_estimator() {
+        let mut rtt = RttEstimator::default();
+        assert_eq!(rtt.get_srtt(), None);
+        assert_eq!(rtt.get_rto(), Duration::from_millis(INITIAL_RTO));
+
+        /*srtt = 200, rttvar = 100 */
+        rtt.add_sample(Duration::from_millis(200));
+        assert_eq!(rtt.get_srtt(), Some(Duration::from_millis(200)));
+        assert_eq!(rtt.get_rto(), Duration::from_millis(600));
+        /*srtt = 200, rttvar = 75 */
+        rtt.add_sample(Duration::from_millis(200));
+        assert_eq!(rtt.get_rto(), Duration::from_millis(500));
+
+        /*Each resend doubles the timeout, up to MAX_RTO */
+        assert_eq!(rtt.get_backed_off_rto(1), Duration::from_millis(1000));
+        assert_eq!(rtt.get_backed_off_rto(10), Duration::from_millis(MAX_RTO));
+
+        for _ in 0..100 {
+            rtt.add_sample(Duration::from_millis(1));
+        }
+        assert_eq!(rtt.get_rto(), Duration::from_millis(MIN_RTO));
+    }
+
+    #[test]
+    fn lib_network_congestion_handler_karn_rule() {
+        let pending_ids = PendingIds::build_mutex();
+        let peer: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+
+        /*A reply to a packet sent once is measured */
+        let hello = PacketBuilder::hello_packet(Extensions::empty(), b"nist".to_vec()).unwrap();
+        PendingIds::lock_and_add_id(Arc::clone(&pending_ids), &hello, &peer);
+        std::thread::sleep(Duration::from_millis(20));
+        let reply = PacketBuilder::hello_reply_packet(
+            hello.get_id(),
+            Extensions::empty(),
+            b"peer".to_vec(),
+        )
+        .unwrap();
+        assert!(PendingIds::id_exists(Arc::clone(&pending_ids), &reply, peer).is_ok());
+        let srtt = PendingIds::lock_and_get_rtt(Arc::clone(&pending_ids), peer).get_srtt();
+        assert!(srtt.unwrap() >= Duration::from_millis(20));
+
+        /*A reply to a resent packet is not */
+        let hello = PacketBuilder::hello_packet(Extensions::empty(), b"nist".to_vec()).unwrap();
+        PendingIds::lock_and_add_id(Arc::clone(&pending_ids), &hello, &peer);
+        pending_ids
+            .lock()
+            .unwrap()
+            .id_to_addr
+            .get_mut(hello.get_id())
+            .unwrap()
+            .3 = 1;
+        std::thread::sleep(Duration::from_millis(100));
+        let reply = PacketBuilder::hello_reply_packet(
+            hello.get_id(),
+            Extensions::empty(),
+            b"peer".to_vec(),
+        )
+        .unwrap();
+        assert!(PendingIds::id_exists(Arc::clone(&pending_ids), &reply, peer).is_ok());
+        assert_eq!(
+            PendingIds::lock_and_get_rtt(Arc::clone(&pending_ids), peer).get_srtt(),
+            srtt
+        );
+        assert_eq!(
+            PendingIds::lock_and_next_resend_in(Arc::clone(&pending_ids)),
+            None
+        );
+    }
+
+    #[test]
+    fn lib_network_congestion_handler_window_aimd() {
+        let mut window = CongestionWindow::default();
+        let mut seqs = vec![];
+        for _ in 0..INITIAL_WINDOW {
+            seqs.push(window.try_acquire().unwrap());
+        }
+        assert_eq!(window.try_acquire(), None);
+
+        /*Slow start : one more per reply */
+        window.on_reply();
+        assert_eq!(window.get_cwnd(), INITIAL_WINDOW + 1);
+        assert_eq!(window.get_in_flight(), INITIAL_WINDOW - 1);
+
+        /*Halved once for the requests lost together */
+        let cwnd = INITIAL_WINDOW.div_ceil(2);
+        for seq in &seqs[1..] {
+            window.on_timeout(*seq);
+        }
+        assert_eq!(window.get_cwnd(), cwnd);
+        assert_eq!(window.get_ssthresh(), cwnd);
+        assert_eq!(window.get_in_flight(), 0);
+
+        /*Then one more per window of replies */
+        for _ in 0..cwnd {
+            window.try_acquire().unwrap();
+            window.on_reply();
+        }
+        assert_eq!(window.get_cwnd(), cwnd + 1);
+        assert_eq!(window.get_in_flight(), 0);
+
+        /*A request sent after the loss halves it again */
+        for _ in 0..10 {
+            let seq = window.try_acquire().unwrap();
+            window.on_timeout(seq);
+        }
+        assert_eq!(window.get_cwnd(), MIN_WINDOW);
+        window.try_acquire().unwrap();
+        window.on_cancel();
+        assert!(window.is_idle());
+    }
+
+    #[tokio::test]
+    async fn lib_network_congestion_handler_window_waits_for_a_slot() {
+        let pending_ids = PendingIds::build_mutex();
+        let window_state = Arc::new(Notify::new());
+        let peer: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+
+        let mut slots = vec![];
+        for _ in 0..INITIAL_WINDOW {
+            slots.push(
+                PendingIds::acquire_window(
+                    Arc::clone(&pending_ids),
+                    Arc::clone(&window_state),
+                    peer,
+                )
+                .await,
+            );
+        }
+        let waiting =
+            PendingIds::acquire_window(Arc::clone(&pending_ids), Arc::clone(&window_state), peer);
+        let waiting = tokio::spawn(waiting);
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+
+        /*A reply frees a place and grows the window */
+        slots.pop().unwrap().set_replied(true);
+        let _slot = tokio::time::timeout(Duration::from_secs(1), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+        let window = PendingIds::lock_and_get_window(Arc::clone(&pending_ids), peer);
+        assert_eq!(window.get_cwnd(), INITIAL_WINDOW + 1);
+        assert_eq!(window.get_in_flight(), INITIAL_WINDOW);
+    }
+
+    #[tokio::test]
+    async fn lib_network_congestion_handler_forgets_idle_windows() {
+        let pending_ids = PendingIds::build_mutex();
+        let window_state = Arc::new(Notify::new());
+        let acquire = |port: u16| {
+            PendingIds::acquire_window(
+                Arc::clone(&pending_ids),
+                Arc::clone(&window_state),
+                SocketAddr::from(([127, 0, 0, 1], port)),
+            )
+        };
+
+        let busy = acquire(0).await;
+        for port in 1..MAX_WINDOWS as u16 {
+            acquire(port).await;
+        }
+        assert_eq!(
+            pending_ids.lock().unwrap().addr_to_window.len(),
+            MAX_WINDOWS
+        );
+        /*Only the peer with a request in flight is kept */
+        let _slot = acquire(MAX_WINDOWS as u16).await;
+        assert_eq!(pending_ids.lock().unwrap().addr_to_window.len(), 2);
+        drop(busy);
+    }
+
+    #[tokio::test]
+    async fn lib_network_congestion_handler_bounded_queues() {
+        let newest = Queue::build_mutex(QueueLimit::new(2, OverflowPolicy::DropNewest));
+        let oldest = Queue::build_mutex(QueueLimit::new(2, OverflowPolicy::DropOldest));
+        Queue::lock_and_push_mul(Arc::clone(&newest), vec![1, 2, 3]);
+        Queue::lock_and_push_mul(Arc::clone(&oldest), vec![1, 2, 3]);
+        assert_eq!(Queue::lock_and_pop(Arc::clone(&newest)), Some(1));
+        assert_eq!(Queue::lock_and_pop(Arc::clone(&oldest)), Some(2));
+        assert_eq!(Queue::lock_and_get_dropped(Arc::clone(&newest)), 1);
+        assert_eq!(Queue::lock_and_get_dropped(Arc::clone(&oldest)), 1);
+
+        /*The producer waits until the consumer pops */
+        let block = Queue::build_mutex(QueueLimit::new(1, OverflowPolicy::Block));
+        Queue::lock_and_push_or_wait(Arc::clone(&block), 1).await;
+        let producer = tokio::spawn(Queue::lock_and_push_or_wait(Arc::clone(&block), 2));
+        tokio::task::yield_now().await;
+        assert!(!producer.is_finished());
+        assert_eq!(Queue::lock_and_pop(Arc::clone(&block)), Some(1));
+        tokio::time::timeout(Duration::from_secs(1), producer)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(Queue::lock_and_pop(Arc::clone(&block)), Some(2));
+        assert_eq!(Queue::lock_and_get_dropped(Arc::clone(&block)), 0);
+    }
 }
@@ -0,0 +1,81 @@
+use std::fmt::Display;
+
+use bitflags::bitflags;
+
+bitflags! {
+    /*
+        Optional features advertised in the 4 bytes of Hello and
+        HelloReply, one bit each. New features take the next free bit,
+        a peer ignores the bits it doesn't know. Bits 1 to 3 were
+        advertised by earlier versions without gating anything, they
+        stay unused.
+    */
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+    pub struct Extensions: u32 {
+        /*Signs its packets and checks ours */
+        const SIGNATURES = 1 << 0;
+    }
+}
+
+impl Extensions {
+    pub fn from_bytes(raw_extensions: [u8; 4]) -> Self {
+        /*Unknown bits are kept so that they show in the logs */
+        Extensions::from_bits_retain(u32::from_be_bytes(raw_extensions))
+    }
+
+    pub fn to_bytes(self) -> [u8; 4] {
+        self.bits().to_be_bytes()
+    }
+
+    /*What we can use with a peer that advertised theirs, the features
+    both of us advertise */
+    pub fn negotiate(self, theirs: Extensions) -> Extensions {
+        self & theirs
+    }
+
+    /*
+        A peer that advertises no extension at all predates them. It
+        still gets our signatures as every peer did before extensions,
+        one that doesn't check them ignores them, but its own packets
+        aren't checked since it never said it signs them.
+    */
+    pub fn predates_extensions(self) -> bool {
+        self.is_empty()
+    }
+}
+
+impl Display for Extensions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_empty() {
+            return write!(f, "none");
+        }
+        bitflags::parser::to_writer(self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lib_network_extensions_negotiate() {
+        let mine = Extensions::SIGNATURES;
+        /*Bits we don't know are kept */
+        let theirs = Extensions::from_bytes([0x80, 0, 0, 0b1010]);
+        assert_eq!(theirs.bits(), 0x8000_000a);
+        assert_eq!(Extensions::from_bytes(theirs.to_bytes()), theirs);
+
+        /*They don't sign, we don't either */
+        assert_eq!(mine.negotiate(theirs), Extensions::empty());
+        assert_eq!(
+            mine.negotiate(theirs | Extensions::SIGNATURES),
+            Extensions::SIGNATURES
+        );
+        /*An old peer advertises nothing */
+        assert_eq!(mine.negotiate(Extensions::empty()), Extensions::empty());
+        assert!(Extensions::empty().predates_extensions());
+        assert!(!theirs.predates_extensions());
+        assert_eq!(Extensions::SIGNATURES.to_string(), "SIGNATURES");
+        assert_eq!(Extensions::empty().to_string(), "none");
+    }
+}
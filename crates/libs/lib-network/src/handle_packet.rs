@@ -1,31 +1,54 @@
 use std::net::SocketAddr;
 
-use std::sync::{Arc, Mutex, RwLock};
+use std::sync::{Arc, Mutex};
 
 use log::{debug, error};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
 
 use crate::action::*;
+use crate::config::NodeConfig;
 use crate::congestion_handler::*;
+use crate::extensions::Extensions;
 use crate::packet::*;
+use crate::peer::ActivePeers;
+use crate::signature::{verify_packet, SignatureError};
 // use crate::peer_data::PeerData;
 
 #[derive(Debug)]
 pub enum HandlingError {
     InvalidPacketError,
     InvalidHashError,
+    UnsignedPacketError,
+    InvalidSignatureError,
+    /*The sender is told what is wrong, see DecodeError */
+    MalformedPacketError(DecodeError),
+}
+
+impl From<DecodeError> for HandlingError {
+    fn from(e: DecodeError) -> Self {
+        HandlingError::MalformedPacketError(e)
+    }
 }
 
 pub fn handle_packet_task(
-    pending_ids: Arc<Mutex<PendingIds>>,
-    receive_queue: Arc<Mutex<Queue<(Packet, SocketAddr)>>>,
-    receive_queue_state: Arc<QueueState>,
-    process_queue: Arc<RwLock<Queue<Action>>>,
-    process_queue_state: Arc<QueueState>,
-    process_queue_readers_state: Arc<QueueState>,
-) {
+    queues: &Queues,
+    active_peers: Arc<Mutex<ActivePeers>>,
+    config: Arc<NodeConfig>,
+    cancel: CancellationToken,
+) -> JoinHandle<()> {
+    let pending_ids = Arc::clone(&queues.pending_ids);
+    let receive_queue = Arc::clone(&queues.receive_queue);
+    let receive_queue_state = Arc::clone(&queues.receive_queue_state);
+    let process_queue = Arc::clone(&queues.process_queue);
+    let process_queue_state = Arc::clone(&queues.process_queue_state);
+    let events = queues.events.clone();
     tokio::spawn(async move {
         loop {
-            let action_or_error = match Queue::lock_and_pop(Arc::clone(&receive_queue)) {
+            if cancel.is_cancelled() {
+                break;
+            }
+            let (header, action_or_error) = match Queue::lock_and_pop(Arc::clone(&receive_queue)) {
                 Some((packet, sock_addr)) =>
                 /*receive queue is not empty get a packet and handle it*/
                 /*verif packet? */
@@ -35,10 +58,17 @@ pub fn handle_packet_task(
                     //     packet.get_packet_type(),
                     //     sock_addr,
                     // );
-                    handle_packet(
-                        packet,
-                        sock_addr,
-                        Arc::clone(&pending_ids), /*return the action required */
+                    /*Kept to answer the packet once it is consumed */
+                    let header = (*packet.get_id(), packet.is_response(), sock_addr);
+                    (
+                        header,
+                        handle_packet(
+                            packet,
+                            sock_addr,
+                            Arc::clone(&pending_ids), /*return the action required */
+                            Arc::clone(&active_peers),
+                            &config,
+                        ),
                     )
                 }
                 None => {
@@ -49,38 +79,97 @@ pub fn handle_packet_task(
                     // println!("handle packet waits");
                     QueueState::set_empty_queue(Arc::clone(&receive_queue_state));
                     debug!("handle wait");
-                    receive_queue_state.wait();
-                    continue;
+                    tokio::select! {
+                        _ = cancel.cancelled() => break,
+                        _ = receive_queue_state.wait() => continue,
+                    }
                 }
             };
 
             debug!("{:?}", action_or_error);
+            let (id, is_response, sock_addr) = header;
+            /*Only a reply can answer one of our requests */
+            let reply_id = is_response.then_some(id);
+            let action_or_error = match action_or_error {
+                /*Tell the sender what is wrong with its packet */
+                Err(HandlingError::MalformedPacketError(e)) => {
+                    debug!("[handle packet task] Malformed packet from {sock_addr} : {e}");
+                    let message = e.to_string().into_bytes();
+                    match is_response {
+                        /*The request waiting for it fails now rather than timing out */
+                        true => {
+                            if let Some(waiter) =
+                                PendingIds::lock_and_take_waiter(Arc::clone(&pending_ids), &id)
+                            {
+                                let _ = waiter
+                                    .send(Action::ProcessErrorReply(message.clone(), sock_addr));
+                            }
+                            Ok(Action::SendError(message, sock_addr))
+                        }
+                        false => Ok(Action::SendErrorReply(id, Some(message), sock_addr)),
+                    }
+                }
+                action_or_error => action_or_error,
+            };
             match action_or_error {
                 Ok(action) => {
+                    /*Replies go straight to the request waiting for them,
+                    what nobody waits for is broadcast */
+                    match reply_id.and_then(|id| {
+                        PendingIds::lock_and_take_waiter(Arc::clone(&pending_ids), &id)
+                    }) {
+                        Some(waiter) => {
+                            let _ = waiter.send(action.clone());
+                        }
+                        None if action.is_received() => {
+                            let _ = events.send(action.clone());
+                        }
+                        None => (),
+                    }
                     /* we have an action, push it to the queue*/
-                    Queue::write_lock_and_push(Arc::clone(&process_queue), action.clone());
-                    QueueState::set_non_empty_queue(Arc::clone(&process_queue_readers_state));
+                    let pushed =
+                        Queue::write_lock_and_push_or_wait(Arc::clone(&process_queue), action);
+                    tokio::select! {
+                        _ = cancel.cancelled() => break,
+                        _ = pushed => (),
+                    }
                     QueueState::set_non_empty_queue(Arc::clone(&process_queue_state));
                     continue;
                 }
+                /*A packet must never stop the task */
                 Err(HandlingError::InvalidPacketError) => {
-                    debug!("[handle packet task] Invalid packet error");
-                    return;
+                    debug!("[handle packet task] Dropped invalid packet");
+                    continue;
+                }
+                /*Drop the packet but keep handling the next ones */
+                Err(HandlingError::UnsignedPacketError) => {
+                    debug!("[handle packet task] Dropped unsigned packet");
+                    continue;
+                }
+                Err(HandlingError::InvalidSignatureError) => {
+                    debug!("[handle packet task] Dropped packet with invalid signature");
+                    continue;
                 }
                 Err(e) => {
                     error!("{:?}", e);
-                    return;
+                    continue;
                 }
             };
         }
-    });
+    })
 }
 
 pub fn handle_packet(
     packet: Packet,
     socket_addr: SocketAddr,
     pending_ids: Arc<Mutex<PendingIds>>,
+    active_peers: Arc<Mutex<ActivePeers>>,
+    config: &NodeConfig,
 ) -> Result<Action, HandlingError> {
+    /*Check the signature before touching the pending ids so that
+    a forged reply can't cancel one of our requests */
+    verify_signature(&packet, socket_addr, active_peers)?;
+
     let id_exists = PendingIds::id_exists(Arc::clone(&pending_ids), &packet, socket_addr);
 
     match id_exists {
@@ -90,7 +179,7 @@ pub fn handle_packet(
                 handle_response_packet(packet, socket_addr, pending_ids)
             } else {
                 if packet.is(PacketType::NatTraversal) {
-                    handle_request_packet(packet, socket_addr, pending_ids)
+                    handle_request_packet(packet, socket_addr, pending_ids, config)
                 } else {
                     Err(HandlingError::InvalidPacketError)
                 }
@@ -105,7 +194,7 @@ pub fn handle_packet(
                     socket_addr,
                 ))
             } else {
-                handle_request_packet(packet, socket_addr, pending_ids)
+                handle_request_packet(packet, socket_addr, pending_ids, config)
             }
         }
 
@@ -117,7 +206,39 @@ pub fn handle_packet(
         }
         Err(e) => {
             error!("{e}");
-            panic!("Shouldn't happen, handle packet ")
+            Err(HandlingError::InvalidPacketError)
+        }
+    }
+}
+
+/*Peers we didn't negotiate signatures with, or that never sent a
+public key, don't sign : their packets are accepted as is. */
+fn verify_signature(
+    packet: &Packet,
+    socket_addr: SocketAddr,
+    active_peers: Arc<Mutex<ActivePeers>>,
+) -> Result<(), HandlingError> {
+    if !packet.get_packet_type().is_signed() {
+        return Ok(());
+    }
+    let negotiated = ActivePeers::lock_and_get_extensions(Arc::clone(&active_peers), socket_addr);
+    if !negotiated.is_some_and(|negotiated| negotiated.contains(Extensions::SIGNATURES)) {
+        return Ok(());
+    }
+    let public_key = match ActivePeers::lock_and_get_public_key(active_peers, socket_addr) {
+        Some(public_key) => public_key,
+        None => return Ok(()),
+    };
+
+    match verify_packet(&public_key, packet) {
+        Ok(()) => Ok(()),
+        Err(SignatureError::Unsigned) => {
+            error!("Unsigned {} from {}", packet.get_packet_type(), socket_addr);
+            Err(HandlingError::UnsignedPacketError)
+        }
+        Err(e) => {
+            error!("{e} on {} from {}", packet.get_packet_type(), socket_addr);
+            Err(HandlingError::InvalidSignatureError)
         }
     }
 }
@@ -127,84 +248,34 @@ fn handle_request_packet(
     packet: Packet,
     socket_addr: SocketAddr,
     _pending_ids: Arc<Mutex<PendingIds>>, //should add self_info with public key root, etc..
+    config: &NodeConfig,
 ) -> Result<Action, HandlingError> {
-    let id = packet.get_id();
-    let body = packet.get_body();
-    match packet.get_packet_type() {
-        PacketType::NoOp => Ok(Action::ProcessNoOp(socket_addr)),
-        PacketType::Error => Ok(Action::ProcessError(
-            *id,
-            packet.get_body().to_owned(),
+    let id = *packet.get_id();
+    match packet.message()? {
+        Message::NoOp => Ok(Action::ProcessNoOp(socket_addr)),
+        Message::Error(message) => Ok(Action::ProcessError(id, message.to_vec(), socket_addr)),
+        /*id is transmitted to be reused in a send hello reply */
+        Message::Hello { extensions, name } => Ok(Action::ProcessHello(
+            id,
+            extensions,
+            name.to_vec(),
             socket_addr,
         )),
-        PacketType::Hello => {
-            /*change to process hello reply */
-            {
-                let mut extensions: [u8; 4] = [0; 4];
-                extensions.copy_from_slice(&packet.get_body().as_slice()[0..4]);
-                let extensions = match extensions[3] {
-                    /*No extensions */
-                    0 => None,
-                    _ => Some(extensions),
-                };
-                let name = packet.get_body().as_slice()[4..packet.get_body_length()].to_vec();
-                /*id is transmitted to be reused in a send hello reply */
-                Ok(Action::ProcessHello(*id, extensions, name, socket_addr))
-            }
-        }
-        PacketType::PublicKey => {
-            Ok(Action::ProcessPublicKey(
-                *id,
-                match packet.get_body_length() {
-                    /*Peer doesn't implement signatures */
-                    0 => None,
-                    /*Peer implements signatures */
-                    _ => Some({
-                        let mut public_key: [u8; 64] = [0; 64];
-                        public_key.copy_from_slice(&packet.get_body().as_slice()[0..64]);
-                        public_key
-                    }),
-                },
-                socket_addr,
-            ))
-        }
-        PacketType::Root => {
-            if body.len() < 32 {
-                error!("{packet:?}");
-                return Ok(Action::SendError(
-                    b"root is too short".to_vec(),
-                    socket_addr,
-                ));
-            } else {
-                let mut root = [0u8; 32];
-                root.copy_from_slice(&body.as_slice()[0..32]);
-
-                if root == hex::decode(HASH_OF_EMPTY_STRING).unwrap().as_slice() {
-                    return Ok(Action::ProcessRoot(*id, None, socket_addr));
-                }
-                Ok(Action::ProcessRoot(*id, Some(root), socket_addr))
-            }
-        }
+        Message::PublicKey(public_key) => Ok(Action::ProcessPublicKey(id, public_key, socket_addr)),
+        Message::Root(root) => Ok(Action::ProcessRoot(id, root, socket_addr)),
         /*Exports should have its own send/receive queue?*/
-        PacketType::GetDatum => Ok(Action::ProcessGetDatum(
-            *id,
-            {
-                let mut hash: [u8; 32] = [0; 32];
-                hash.copy_from_slice(&packet.get_body().as_slice()[0..32]);
-                hash
-            },
-            socket_addr,
-        )),
-        PacketType::NatTraversal => {
-            if socket_addr == "81.194.27.155:8443".parse().unwrap() {
+        Message::GetDatum(hash) => Ok(Action::ProcessGetDatum(id, hash, socket_addr)),
+        Message::NatTraversal(requester_addr) => {
+            /*Only rendezvous servers relay NatTraversals */
+            if config.is_rendezvous_server(socket_addr) {
                 debug!("Received NatTraversal from server\n");
-                return Ok(Action::ProcessNatTraversal(
-                    packet.get_body().to_owned(),
+                Ok(Action::ProcessNatTraversal(
+                    requester_addr.to_vec(),
                     socket_addr,
-                ));
+                ))
             } else {
-                return Err(HandlingError::InvalidPacketError);
-            };
+                Err(HandlingError::InvalidPacketError)
+            }
         }
         /*Invalid packet, should send error*/
         _ => Err(HandlingError::InvalidPacketError),
@@ -217,70 +288,202 @@ fn handle_response_packet(
     socket_addr: SocketAddr,
     _pending: Arc<Mutex<PendingIds>>,
 ) -> Result<Action, HandlingError> {
-    let body = packet.get_body();
-    match packet.get_packet_type() {
-        PacketType::ErrorReply => {
-            let error_message = packet.get_body().to_owned();
-            Ok(Action::ProcessErrorReply(error_message, socket_addr))
+    match packet.message()? {
+        Message::ErrorReply(message) => {
+            Ok(Action::ProcessErrorReply(message.to_vec(), socket_addr))
         }
-        PacketType::HelloReply => {
-            let mut extensions: [u8; 4] = [0; 4];
-            extensions.copy_from_slice(&packet.get_body().as_slice()[0..4]);
-            let extensions = match extensions[3] {
-                /*No extensions */
-                0 => None,
-                _ => Some(extensions),
-            };
-            let name = packet.get_body().as_slice()[4..packet.get_body_length()].to_vec();
-            Ok(Action::ProcessHelloReply(extensions, name, socket_addr))
-        }
-        PacketType::PublicKeyReply => {
-            Ok(Action::ProcessPublicKeyReply(
-                match packet.get_body_length() {
-                    /*Peer doesn't implement signatures */
-                    0 => None,
-                    /*Peer implements signatures */
-                    _ => Some({
-                        let mut public_key: [u8; 64] = [0; 64];
-                        public_key.copy_from_slice(&packet.get_body().as_slice()[0..64]);
-                        public_key
-                    }),
-                },
-                socket_addr,
-            ))
-        }
-        PacketType::RootReply => {
-            if body.len() < 32 {
-                return Ok(Action::SendError(
-                    b"root is too short".to_vec(),
-                    socket_addr,
-                ));
-            } else {
-                let mut root = [0u8; 32];
-                root.copy_from_slice(&body.as_slice()[0..32]);
-
-                if root == hex::decode(HASH_OF_EMPTY_STRING).unwrap().as_slice() {
-                    return Ok(Action::ProcessRootReply(None, socket_addr));
-                }
-                Ok(Action::ProcessRootReply(Some(root), socket_addr))
-            }
+        Message::HelloReply { extensions, name } => Ok(Action::ProcessHelloReply(
+            extensions,
+            name.to_vec(),
+            socket_addr,
+        )),
+        Message::PublicKeyReply(public_key) => {
+            Ok(Action::ProcessPublicKeyReply(public_key, socket_addr))
         }
-        PacketType::Datum => match packet.valid_hash() {
-            true => Ok(Action::ProcessDatum(
-                packet.get_body().to_owned(),
-                socket_addr,
-            )),
-            false =>{
+        Message::RootReply(root) => Ok(Action::ProcessRootReply(root, socket_addr)),
+        Message::Datum { hash, node } => match packet.valid_hash() {
+            true => Ok(Action::ProcessDatum(hash, node, socket_addr)),
+            false => {
                 error!("Invalid hash");
-                Ok(Action::SendError(
-                            b"Connection timedout,
-                                             proceed to handshake again.\n"
-                                .to_vec(),
-                            socket_addr,
-                        ))
+                Err(DecodeError::InvalidHash.into())
             }
         },
-        PacketType::NoDatum => Ok(Action::ProcessNoDatum(socket_addr)),
-        _ => return Err(HandlingError::InvalidPacketError),
+        Message::NoDatum => Ok(Action::ProcessNoDatum(socket_addr)),
+        _ => Err(HandlingError::InvalidPacketError),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{peer::PeerError, signature::KeyPair};
+
+    fn decode(packet_type: PacketType, body: Vec<u8>) -> Result<Action, HandlingError> {
+        let packet = PacketBuilder::new()
+            .gen_id()
+            .packet_type(packet_type)
+            .body(body)
+            .build()
+            .unwrap();
+        handle_packet(
+            packet,
+            "127.0.0.1:4000".parse().unwrap(),
+            PendingIds::build_mutex(),
+            ActivePeers::build_mutex(),
+            &NodeConfig::new(),
+        )
+    }
+
+    #[test]
+    fn lib_network_handle_packet_malformed_bodies() {
+        let malformed = [
+            (
+                PacketType::Hello,
+                vec![0; 2],
+                DecodeError::TooShort(PacketType::Hello),
+            ),
+            (
+                PacketType::PublicKey,
+                vec![0; 10],
+                DecodeError::InvalidPublicKeyLength(10),
+            ),
+            (
+                PacketType::Root,
+                vec![0; 31],
+                DecodeError::TooShort(PacketType::Root),
+            ),
+            (
+                PacketType::GetDatum,
+                vec![],
+                DecodeError::TooShort(PacketType::GetDatum),
+            ),
+        ];
+        for (packet_type, body, expected) in malformed {
+            match decode(packet_type, body) {
+                Err(HandlingError::MalformedPacketError(e)) => assert_eq!(e, expected),
+                other => panic!("{packet_type} decoded to {other:?}"),
+            }
+        }
+
+        assert!(matches!(
+            decode(PacketType::GetDatum, vec![0; 32]),
+            Ok(Action::ProcessGetDatum(..))
+        ));
+    }
+
+    #[test]
+    fn lib_network_handle_packet_keeps_the_first_public_key() {
+        let sock_addr: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        let active_peers = ActivePeers::build_mutex();
+        ActivePeers::set_peer_extensions_and_name(
+            Arc::clone(&active_peers),
+            sock_addr,
+            Extensions::SIGNATURES,
+            Extensions::SIGNATURES,
+            b"nist".to_vec(),
+        )
+        .unwrap();
+        let (key_pair, spoofer) = (KeyPair::generate(), KeyPair::generate());
+        for _ in 0..2 {
+            ActivePeers::set_peer_public_key(
+                Arc::clone(&active_peers),
+                sock_addr,
+                Some(key_pair.public_key()),
+            )
+            .unwrap();
+        }
+
+        /*Neither a spoofed key nor no key replaces it */
+        for spoofed in [None, Some(spoofer.public_key())] {
+            assert_eq!(
+                ActivePeers::set_peer_public_key(Arc::clone(&active_peers), sock_addr, spoofed),
+                Err(PeerError::PublicKeyChanged)
+            );
+        }
+        let receive = |packet: Packet| {
+            handle_packet(
+                packet,
+                sock_addr,
+                PendingIds::build_mutex(),
+                Arc::clone(&active_peers),
+                &NodeConfig::new(),
+            )
+        };
+        let root = || PacketBuilder::root_packet(Some([7u8; 32])).unwrap();
+        assert!(matches!(
+            receive(root()),
+            Err(HandlingError::UnsignedPacketError)
+        ));
+        let mut forged = root();
+        spoofer.sign_packet(&mut forged);
+        assert!(matches!(
+            receive(forged),
+            Err(HandlingError::InvalidSignatureError)
+        ));
+        let mut signed = root();
+        key_pair.sign_packet(&mut signed);
+        assert!(matches!(receive(signed), Ok(Action::ProcessRoot(..))));
+    }
+
+    #[test]
+    fn lib_network_handle_packet_checks_signatures_when_negotiated() {
+        let active_peers = ActivePeers::build_mutex();
+        let key_pair = KeyPair::generate();
+        let signs: SocketAddr = "127.0.0.1:4001".parse().unwrap();
+        let unsigned: SocketAddr = "127.0.0.1:4002".parse().unwrap();
+        /*Both sent a key, only one said it signs */
+        let peers = [
+            (signs, Extensions::SIGNATURES, b"nist"),
+            (unsigned, Extensions::from_bytes([0, 0, 0, 0b1000]), b"jch_"),
+        ];
+        for (sock_addr, theirs, name) in peers {
+            ActivePeers::set_peer_extensions_and_name(
+                Arc::clone(&active_peers),
+                sock_addr,
+                Extensions::SIGNATURES,
+                theirs,
+                name.to_vec(),
+            )
+            .unwrap();
+            ActivePeers::set_peer_public_key(
+                Arc::clone(&active_peers),
+                sock_addr,
+                Some(key_pair.public_key()),
+            )
+            .unwrap();
+        }
+        let receive = |sock_addr: SocketAddr| {
+            handle_packet(
+                PacketBuilder::root_packet(Some([7u8; 32])).unwrap(),
+                sock_addr,
+                PendingIds::build_mutex(),
+                Arc::clone(&active_peers),
+                &NodeConfig::new(),
+            )
+        };
+        assert!(matches!(
+            receive(signs),
+            Err(HandlingError::UnsignedPacketError)
+        ));
+        assert!(matches!(receive(unsigned), Ok(Action::ProcessRoot(..))));
+        assert_eq!(
+            ActivePeers::lock_and_gets_signatures(Arc::clone(&active_peers), unsigned),
+            Some(false)
+        );
+
+        /*Nor does a peer that predates extensions, it still gets ours */
+        ActivePeers::set_peer_extensions_and_name(
+            Arc::clone(&active_peers),
+            unsigned,
+            Extensions::SIGNATURES,
+            Extensions::empty(),
+            b"jch_".to_vec(),
+        )
+        .unwrap();
+        assert!(matches!(receive(unsigned), Ok(Action::ProcessRoot(..))));
+        assert_eq!(
+            ActivePeers::lock_and_gets_signatures(Arc::clone(&active_peers), unsigned),
+            Some(true)
+        );
     }
 }
@@ -0,0 +1,526 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    os::unix::{
+        ffi::{OsStrExt, OsStringExt},
+        fs::FileExt,
+    },
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use log::{error, info, warn};
+use sha2::{Digest, Sha256};
+
+use crate::{protocol::HASH_LENGTH, writer::FileWriter};
+
+/*
+    Progress of a download, appended next to its output so that a
+    restarted download of the same root only asks for what is missing.
+    One record per line, after a header naming the version of the
+    format, 1, and the root :
+        big <hash> <hashes of its parts>
+        file <index> <file>
+        chunk <hash> <offset> <length> <index of its file>
+        done <index of the file>
+    file is relative to the output, empty when the output is the file,
+    and hex encoded since a name may hold any byte but '/'. Files are
+    numbered in the order they are first recorded.
+    A chunk is recorded after it is written and a line cut by a crash
+    is ignored. Since the chunk may not have reached the disk, it is
+    read back and hashed before being trusted.
+    The journal is only created with its first record, a download
+    that fails before writing anything leaves none behind. Downloads
+    go through the spawn_ methods, which write and read back on a
+    blocking thread.
+*/
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    root: [u8; 32],
+    resumed: bool,
+    file: Mutex<Option<File>>,
+    state: Mutex<JournalState>,
+}
+
+#[derive(Debug, Default)]
+struct JournalState {
+    bigs: HashMap<[u8; 32], Vec<[u8; 32]>>,
+    /*Index of each file in the records */
+    files: HashMap<PathBuf, u32>,
+    /*(index of the file, offset) to hash and length */
+    chunks: HashMap<(u32, u64), ([u8; 32], u64)>,
+    done: HashSet<u32>,
+}
+
+/*Where the journal of output goes : dump.journal for dump */
+pub fn journal_path(output: &Path) -> PathBuf {
+    let mut name = output
+        .file_name()
+        .unwrap_or(output.as_os_str())
+        .to_os_string();
+    name.push(".journal");
+    output.with_file_name(name)
+}
+
+/*Hash of a mkfs node : its type then its content */
+fn hash_node(node_type: u8, content: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([node_type]);
+    hasher.update(content);
+    hasher.finalize().into()
+}
+
+fn header(root: &[u8; 32]) -> String {
+    format!("udp2p-journal 1 {}\n", hex::encode(root))
+}
+
+impl Journal {
+    /*Resumes the journal of output if it is for root, starts over otherwise */
+    pub fn open(output: &Path, root: [u8; 32]) -> io::Result<Self> {
+        let path = journal_path(output);
+        let content = match fs::read(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        let content = String::from_utf8_lossy(&content);
+        let state = match content.starts_with(&header(&root)) {
+            true => JournalState::parse(&content),
+            false => JournalState::default(),
+        };
+        let resumed = !state.is_empty();
+        let file = match resumed {
+            true => {
+                info!("Resuming the download of {}", output.display());
+                Some(OpenOptions::new().append(true).open(&path)?)
+            }
+            /*Nothing worth keeping, the first record starts over */
+            false => {
+                remove_journal(&path)?;
+                None
+            }
+        };
+        Ok(Self {
+            path,
+            root,
+            resumed,
+            file: Mutex::new(file),
+            state: Mutex::new(state),
+        })
+    }
+
+    /*Whether something of this root was downloaded before */
+    pub fn is_resumed(&self) -> bool {
+        self.resumed
+    }
+
+    pub fn get_path(&self) -> &Path {
+        &self.path
+    }
+
+    fn lock_state(&self) -> std::sync::MutexGuard<'_, JournalState> {
+        match self.state.lock() {
+            Ok(state) => state,
+            Err(e) => {
+                error!("[lock_state] Journal mutex is poisoned {e}");
+                panic!("Journal mutex is poisoned {e}")
+            }
+        }
+    }
+
+    fn append(&self, record: String) -> io::Result<()> {
+        let mut file = match self.file.lock() {
+            Ok(file) => file,
+            Err(e) => {
+                error!("[append] Journal mutex is poisoned {e}");
+                panic!("Journal mutex is poisoned {e}")
+            }
+        };
+        let file = match &mut *file {
+            Some(file) => file,
+            None => {
+                let mut created = File::create(&self.path)?;
+                created.write_all(header(&self.root).as_bytes())?;
+                file.insert(created)
+            }
+        };
+        /*One write per record, a crash cuts at most the last line */
+        file.write_all(record.as_bytes())
+    }
+
+    /*Index of file in the records, the first record of a file numbers it */
+    fn file_index(&self, file: &Path) -> io::Result<u32> {
+        let mut state = self.lock_state();
+        if let Some(index) = state.files.get(file) {
+            return Ok(*index);
+        }
+        let index = state.files.len() as u32;
+        /*Recorded before any record using it */
+        self.append(format!(
+            "file {index} {}\n",
+            hex::encode(file.as_os_str().as_bytes())
+        ))?;
+        state.files.insert(file.to_path_buf(), index);
+        Ok(index)
+    }
+
+    pub fn get_big(&self, hash: &[u8; 32]) -> Option<Vec<[u8; 32]>> {
+        self.lock_state().bigs.get(hash).cloned()
+    }
+
+    pub fn record_big(&self, hash: [u8; 32], children: &[[u8; 32]]) -> io::Result<()> {
+        if self.lock_state().bigs.contains_key(&hash) {
+            return Ok(());
+        }
+        self.append(format!(
+            "big {} {}\n",
+            hex::encode(hash),
+            hex::encode(children.concat())
+        ))?;
+        self.lock_state().bigs.insert(hash, children.to_vec());
+        Ok(())
+    }
+
+    /*Length of the chunk with the given hash if it was recorded at offset of file */
+    fn get_recorded_chunk(&self, file: &Path, offset: u64, hash: &[u8; 32]) -> Option<u64> {
+        let state = self.lock_state();
+        let index = state.files.get(file)?;
+        match state.chunks.get(&(*index, offset))? {
+            (recorded_hash, len) if recorded_hash == hash => Some(*len),
+            _ => None,
+        }
+    }
+
+    /*
+        Length of the chunk with the given hash if it was written at
+        offset of file before, and still reads back as that chunk.
+    */
+    pub fn get_written_chunk(
+        &self,
+        file: &Path,
+        offset: u64,
+        hash: &[u8; 32],
+        writer: &FileWriter,
+    ) -> Option<u64> {
+        let len = self.get_recorded_chunk(file, offset, hash)?;
+        let mut chunk = vec![0u8; len as usize];
+        writer.read_chunk(offset, &mut chunk).ok()?;
+        match hash_node(0, &chunk) == *hash {
+            true => Some(len),
+            false => {
+                warn!(
+                    "Chunk at {offset} of {} changed, fetching it again",
+                    file.display()
+                );
+                None
+            }
+        }
+    }
+
+    pub fn record_chunk(
+        &self,
+        file: &Path,
+        offset: u64,
+        hash: [u8; 32],
+        len: u64,
+    ) -> io::Result<()> {
+        let index = self.file_index(file)?;
+        self.append(format!(
+            "chunk {} {offset} {len} {index}\n",
+            hex::encode(hash)
+        ))?;
+        self.lock_state()
+            .chunks
+            .insert((index, offset), (hash, len));
+        Ok(())
+    }
+
+    pub fn is_done(&self, file: &Path) -> bool {
+        let state = self.lock_state();
+        state
+            .files
+            .get(file)
+            .is_some_and(|index| state.done.contains(index))
+    }
+
+    pub fn record_done(&self, file: &Path) -> io::Result<()> {
+        let index = self.file_index(file)?;
+        self.append(format!("done {index}\n"))?;
+        self.lock_state().done.insert(index);
+        Ok(())
+    }
+
+    /*
+        Reads on_disk back and checks it against hash, following the big
+        nodes and chunks recorded for file : every chunk in order, with
+        nothing missing or left over.
+    */
+    pub fn verify(&self, file: &Path, hash: [u8; 32], on_disk: &Path) -> io::Result<bool> {
+        let on_disk = File::open(on_disk)?;
+        let state = self.lock_state();
+        let index = match state.files.get(file) {
+            Some(index) => *index,
+            None => return Ok(false),
+        };
+        let mut offset = 0;
+        let mut stack = vec![hash];
+        while let Some(hash) = stack.pop() {
+            if let Some(children) = state.bigs.get(&hash) {
+                stack.extend(children.iter().rev());
+                continue;
+            }
+            let len = match state.chunks.get(&(index, offset)) {
+                Some((chunk_hash, len)) if *chunk_hash == hash => *len,
+                _ => return Ok(false),
+            };
+            let mut chunk = vec![0u8; len as usize];
+            on_disk.read_exact_at(&mut chunk, offset)?;
+            if hash_node(0, &chunk) != hash {
+                return Ok(false);
+            }
+            offset += len;
+        }
+        Ok(offset == on_disk.metadata()?.len())
+    }
+
+    /*The download is over, nothing to resume */
+    pub fn remove(&self) -> io::Result<()> {
+        remove_journal(&self.path)
+    }
+
+    /*Runs f on a blocking thread */
+    async fn spawn<T, F>(self: &Arc<Self>, f: F) -> io::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Journal) -> io::Result<T> + Send + 'static,
+    {
+        let journal = Arc::clone(self);
+        match tokio::task::spawn_blocking(move || f(&journal)).await {
+            Ok(result) => result,
+            Err(e) => Err(io::Error::other(e)),
+        }
+    }
+
+    /*get_written_chunk on a blocking thread, only for a recorded chunk */
+    pub async fn spawn_get_written_chunk(
+        self: &Arc<Self>,
+        file: &Path,
+        offset: u64,
+        hash: [u8; 32],
+        writer: &FileWriter,
+    ) -> Option<u64> {
+        self.get_recorded_chunk(file, offset, &hash)?;
+        let (file, writer) = (file.to_path_buf(), writer.try_clone().ok()?);
+        self.spawn(move |journal| Ok(journal.get_written_chunk(&file, offset, &hash, &writer)))
+            .await
+            .ok()
+            .flatten()
+    }
+
+    /*record_big on a blocking thread */
+    pub async fn spawn_record_big(
+        self: &Arc<Self>,
+        hash: [u8; 32],
+        children: &[[u8; 32]],
+    ) -> io::Result<()> {
+        if self.lock_state().bigs.contains_key(&hash) {
+            return Ok(());
+        }
+        let children = children.to_vec();
+        self.spawn(move |journal| journal.record_big(hash, &children))
+            .await
+    }
+
+    /*record_chunk on a blocking thread */
+    pub async fn spawn_record_chunk(
+        self: &Arc<Self>,
+        file: &Path,
+        offset: u64,
+        hash: [u8; 32],
+        len: u64,
+    ) -> io::Result<()> {
+        let file = file.to_path_buf();
+        self.spawn(move |journal| journal.record_chunk(&file, offset, hash, len))
+            .await
+    }
+
+    /*record_done on a blocking thread */
+    pub async fn spawn_record_done(self: &Arc<Self>, file: &Path) -> io::Result<()> {
+        let file = file.to_path_buf();
+        self.spawn(move |journal| journal.record_done(&file)).await
+    }
+
+    /*verify on a blocking thread */
+    pub async fn spawn_verify(
+        self: &Arc<Self>,
+        file: &Path,
+        hash: [u8; 32],
+        on_disk: &Path,
+    ) -> io::Result<bool> {
+        let (file, on_disk) = (file.to_path_buf(), on_disk.to_path_buf());
+        self.spawn(move |journal| journal.verify(&file, hash, &on_disk))
+            .await
+    }
+}
+
+/*A journal never created is as good as removed */
+fn remove_journal(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+impl JournalState {
+    fn is_empty(&self) -> bool {
+        self.bigs.is_empty() && self.chunks.is_empty() && self.done.is_empty()
+    }
+
+    /*Skips the header, the last line if a crash cut it and any record that doesn't parse */
+    fn parse(content: &str) -> Self {
+        let mut state = JournalState::default();
+        let mut lines: Vec<&str> = content.split('\n').skip(1).collect();
+        /*Empty when the journal ends with a full line */
+        lines.pop();
+        for line in lines {
+            if state.parse_record(line).is_none() {
+                warn!("Skipping journal record {line}");
+            }
+        }
+        state
+    }
+
+    fn parse_record(&mut self, line: &str) -> Option<()> {
+        let (kind, record) = line.split_once(' ')?;
+        match kind {
+            "big" => {
+                let (hash, children) = record.split_once(' ')?;
+                let hash: [u8; 32] = hex::decode(hash).ok()?.try_into().ok()?;
+                let children = hex::decode(children).ok()?;
+                /*Only a verified node can stand for the datum */
+                if children.len() % HASH_LENGTH != 0 || hash_node(1, &children) != hash {
+                    return None;
+                }
+                let children = children
+                    .chunks_exact(HASH_LENGTH)
+                    .map(|child| child.try_into().unwrap())
+                    .collect();
+                self.bigs.insert(hash, children);
+            }
+            "file" => {
+                let (index, file) = record.split_once(' ')?;
+                let index: u32 = index.parse().ok()?;
+                let file = PathBuf::from(OsString::from_vec(hex::decode(file).ok()?));
+                /*Files are numbered in order, once */
+                if index as usize != self.files.len() || self.files.contains_key(&file) {
+                    return None;
+                }
+                self.files.insert(file, index);
+            }
+            "chunk" => {
+                let mut fields = record.splitn(4, ' ');
+                let hash: [u8; 32] = hex::decode(fields.next()?).ok()?.try_into().ok()?;
+                let offset: u64 = fields.next()?.parse().ok()?;
+                let len: u64 = fields.next()?.parse().ok()?;
+                let index = self.parse_index(fields.next()?)?;
+                self.chunks.insert((index, offset), (hash, len));
+            }
+            "done" => {
+                let index = self.parse_index(record)?;
+                self.done.insert(index);
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+
+    /*Only the files recorded before are known */
+    fn parse_index(&self, index: &str) -> Option<u32> {
+        let index: u32 = index.parse().ok()?;
+        match (index as usize) < self.files.len() {
+            true => Some(index),
+            false => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lib_network_journal_resume() {
+        let dir = std::env::temp_dir().join(format!("udp2p-journal-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let output = dir.join("dump");
+        assert_eq!(journal_path(&output), dir.join("dump.journal"));
+
+        let (first, second) = (b"first chunk".to_vec(), b"second".to_vec());
+        let (first_hash, second_hash) = (hash_node(0, &first), hash_node(0, &second));
+        let root = hash_node(1, &[first_hash, second_hash].concat());
+        fs::write(&output, [first.clone(), second.clone()].concat()).unwrap();
+
+        let journal = Journal::open(&output, root).unwrap();
+        assert!(!journal.is_resumed());
+        /*Created with its first record */
+        assert!(!journal_path(&output).exists());
+        let file = Path::new("");
+        journal
+            .record_big(root, &[first_hash, second_hash])
+            .unwrap();
+        journal.record_chunk(file, 0, first_hash, 11).unwrap();
+        journal.record_chunk(file, 11, second_hash, 6).unwrap();
+        assert!(journal.verify(file, root, &output).unwrap());
+        /*A name that would cut a record */
+        let odd = Path::new("a dir/odd\nname");
+        journal.record_chunk(odd, 0, first_hash, 11).unwrap();
+        journal.record_done(odd).unwrap();
+        drop(journal);
+
+        /*A crash in the middle of a record */
+        let mut raw = OpenOptions::new()
+            .append(true)
+            .open(journal_path(&output))
+            .unwrap();
+        raw.write_all(b"chunk 00").unwrap();
+
+        let journal = Journal::open(&output, root).unwrap();
+        assert!(journal.is_resumed());
+        assert_eq!(journal.get_big(&root), Some(vec![first_hash, second_hash]));
+        let writer = FileWriter::open(&output).unwrap();
+        assert_eq!(
+            journal.get_written_chunk(file, 11, &second_hash, &writer),
+            Some(6)
+        );
+        assert_eq!(
+            journal.get_written_chunk(file, 0, &second_hash, &writer),
+            None
+        );
+        assert_eq!(
+            journal.get_written_chunk(odd, 0, &first_hash, &writer),
+            Some(11)
+        );
+        assert!(journal.is_done(odd));
+        assert!(!journal.is_done(file));
+        /*A chunk that didn't reach the disk */
+        writer.write_chunk(11, b"S").unwrap();
+        assert_eq!(
+            journal.get_written_chunk(file, 11, &second_hash, &writer),
+            None
+        );
+        assert!(!journal.verify(file, root, &output).unwrap());
+        drop(journal);
+
+        /*Another root starts over */
+        let journal = Journal::open(&output, first_hash).unwrap();
+        assert!(!journal.is_resumed());
+        assert_eq!(journal.get_big(&root), None);
+        journal.remove().unwrap();
+        assert!(!journal_path(&output).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,253 @@
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+
+use log::info;
+use nanorand::{wyrand::WyRand, BufferedRng, Rng};
+use thiserror::Error;
+
+use crate::peer::Peer;
+use crate::signature::{KeyPair, SignatureError};
+
+const IDENTITY_FILE: &str = "identity";
+const NAME_PREFIX: &str = "nist";
+
+#[derive(Error, Debug)]
+pub enum KeystoreError {
+    #[error("Keystore IO error : {0}")]
+    Io(#[from] std::io::Error),
+    #[error("No identity in keystore")]
+    NoIdentity,
+    #[error("Invalid identity file : {0}")]
+    InvalidFormat(&'static str),
+    #[error("Invalid key in identity file : {0}")]
+    InvalidKey(#[from] SignatureError),
+}
+
+/*Name and key pair this node presents to other peers. */
+#[derive(Debug, Clone)]
+pub struct Identity {
+    name: String,
+    key_pair: KeyPair,
+}
+
+impl Identity {
+    /*The name gets a random suffix so that two nodes of the
+    team don't collide on the REST server */
+    pub fn generate() -> Self {
+        let mut rng = BufferedRng::new(WyRand::new());
+        let mut suffix = [0u8; 4];
+        rng.fill(&mut suffix);
+
+        Self {
+            name: format!("{NAME_PREFIX}-{}", hex::encode(suffix)),
+            key_pair: KeyPair::generate(),
+        }
+    }
+
+    pub fn get_name(&self) -> &String {
+        &self.name
+    }
+    pub fn get_key_pair(&self) -> &KeyPair {
+        &self.key_pair
+    }
+
+    /*Builds the Peer describing ourselves */
+    pub fn to_peer(&self) -> Peer {
+        let mut my_data = Peer::new();
+        my_data
+            .set_name(self.name.clone())
+            .set_key_pair(Some(self.key_pair.clone()));
+        my_data
+    }
+
+    fn to_file_content(&self) -> String {
+        format!(
+            "name={}\nprivate_key={}\n",
+            self.name,
+            hex::encode(self.key_pair.private_key())
+        )
+    }
+
+    fn from_file_content(content: &str) -> Result<Self, KeystoreError> {
+        let mut name = None;
+        let mut private_key = None;
+        for line in content.lines() {
+            match line.split_once('=') {
+                Some(("name", value)) => name = Some(value.to_string()),
+                Some(("private_key", value)) => private_key = Some(value.to_string()),
+                _ => continue,
+            }
+        }
+
+        let name = match name {
+            Some(name) if !name.is_empty() => name,
+            _ => return Err(KeystoreError::InvalidFormat("missing name")),
+        };
+        let private_key = match private_key.map(hex::decode) {
+            Some(Ok(private_key)) => private_key,
+            _ => return Err(KeystoreError::InvalidFormat("missing private key")),
+        };
+        let private_key = match <[u8; 32]>::try_from(private_key) {
+            Ok(private_key) => private_key,
+            Err(_) => return Err(KeystoreError::InvalidFormat("private key isn't 32 bytes")),
+        };
+
+        Ok(Self {
+            name,
+            key_pair: KeyPair::from_private_key(&private_key)?,
+        })
+    }
+}
+
+/*
+    Directory holding the identity of the node, so that
+    it keeps the same name and key across restarts.
+*/
+#[derive(Debug, Clone)]
+pub struct Keystore {
+    dir: PathBuf,
+}
+
+impl Keystore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /*$UDP2P_HOME, else $XDG_CONFIG_HOME/udp2p, else ~/.config/udp2p */
+    pub fn default_dir() -> PathBuf {
+        if let Some(dir) = env::var_os("UDP2P_HOME") {
+            return PathBuf::from(dir);
+        }
+        if let Some(dir) = env::var_os("XDG_CONFIG_HOME") {
+            return Path::new(&dir).join("udp2p");
+        }
+        match env::var_os("HOME") {
+            Some(home) => Path::new(&home).join(".config").join("udp2p"),
+            None => PathBuf::from(".udp2p"),
+        }
+    }
+
+    pub fn get_dir(&self) -> &Path {
+        &self.dir
+    }
+    pub fn identity_path(&self) -> PathBuf {
+        self.dir.join(IDENTITY_FILE)
+    }
+
+    pub fn load(&self) -> Result<Identity, KeystoreError> {
+        let content = match fs::read_to_string(self.identity_path()) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(KeystoreError::NoIdentity)
+            }
+            Err(e) => return Err(KeystoreError::Io(e)),
+        };
+        Identity::from_file_content(&content)
+    }
+
+    /*Writes to a temporary file then renames it so that a crash
+    never leaves a half written identity. The file is only readable
+    by its owner since it holds the private key. */
+    pub fn save(&self, identity: &Identity) -> Result<(), KeystoreError> {
+        fs::create_dir_all(&self.dir)?;
+        let tmp_path = self.dir.join(format!("{IDENTITY_FILE}.tmp"));
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp_path)?;
+        file.write_all(identity.to_file_content().as_bytes())?;
+        file.sync_all()?;
+
+        fs::rename(tmp_path, self.identity_path())?;
+        Ok(())
+    }
+
+    pub fn load_or_create(&self) -> Result<Identity, KeystoreError> {
+        match self.load() {
+            Ok(identity) => Ok(identity),
+            Err(KeystoreError::NoIdentity) => {
+                let identity = Identity::generate();
+                self.save(&identity)?;
+                info!(
+                    "Created identity {} in {}",
+                    identity.get_name(),
+                    self.identity_path().to_string_lossy()
+                );
+                Ok(identity)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /*Replaces the key pair and keeps the name */
+    pub fn rotate(&self) -> Result<Identity, KeystoreError> {
+        let mut identity = self.load_or_create()?;
+        identity.key_pair = KeyPair::generate();
+        self.save(&identity)?;
+        Ok(identity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_keystore() -> Keystore {
+        let mut rng = BufferedRng::new(WyRand::new());
+        let mut suffix = [0u8; 8];
+        rng.fill(&mut suffix);
+        Keystore::new(env::temp_dir().join(format!("udp2p-keystore-{}", hex::encode(suffix))))
+    }
+
+    #[test]
+    fn lib_network_keystore_persists_identity() {
+        let keystore = temp_keystore();
+        assert!(matches!(keystore.load(), Err(KeystoreError::NoIdentity)));
+
+        let created = keystore.load_or_create().unwrap();
+        let loaded = keystore.load_or_create().unwrap();
+        assert_eq!(created.get_name(), loaded.get_name());
+        assert_eq!(
+            created.get_key_pair().public_key(),
+            loaded.get_key_pair().public_key()
+        );
+
+        let rotated = keystore.rotate().unwrap();
+        assert_eq!(rotated.get_name(), created.get_name());
+        assert_ne!(
+            rotated.get_key_pair().public_key(),
+            created.get_key_pair().public_key()
+        );
+        assert_eq!(
+            keystore.load().unwrap().get_key_pair().public_key(),
+            rotated.get_key_pair().public_key()
+        );
+
+        let _ = fs::remove_dir_all(keystore.get_dir());
+    }
+
+    #[test]
+    fn lib_network_keystore_rejects_invalid_file() {
+        assert!(matches!(
+            Identity::from_file_content("name=nist\n"),
+            Err(KeystoreError::InvalidFormat(_))
+        ));
+        assert!(matches!(
+            Identity::from_file_content("name=nist\nprivate_key=00\n"),
+            Err(KeystoreError::InvalidFormat(_))
+        ));
+        /*0 isn't a valid P-256 scalar */
+        assert!(matches!(
+            Identity::from_file_content(&format!("name=nist\nprivate_key={}\n", "00".repeat(32))),
+            Err(KeystoreError::InvalidKey(_))
+        ));
+    }
+}
@@ -1,80 +1,286 @@
 pub mod action;
+pub mod chunk_store;
+pub mod config;
 pub mod congestion_handler;
+pub mod extensions;
 pub mod handle_action;
 pub mod handle_packet;
+pub mod journal;
+pub mod keystore;
+pub mod node;
 pub mod packet;
 pub mod peer;
 pub mod process;
+pub mod protocol;
+pub mod rate_limit;
 pub mod resend;
+pub mod rest_server;
 pub mod sender_receiver;
+pub mod signature;
 pub mod store;
+pub mod swarm;
 pub mod task_launcher_canceller;
+pub mod writer;
 
 pub mod import_export {
-    use std::pin::Pin;
+    use std::path::Path;
 
     use {
-        crate::{action::Action, congestion_handler::*, peer::*, store::*},
-        futures::{future::join_all, Future},
+        crate::{
+            action::Action, chunk_store::ChunkStore, config::NodeConfig, congestion_handler::*,
+            journal::*, packet::*, peer::*, process::THROTTLED, sender_receiver::HANDSHAKE_FIRST,
+            store::*, swarm::Swarm, writer::FileWriter,
+        },
+        futures::{future::join_all, stream, StreamExt, TryStreamExt},
         log::{debug, error, info, warn},
         prelude::*,
         std::{
             net::SocketAddr,
             /*Multi task*/
-            sync::{Arc, Mutex, RwLock},
+            sync::{Arc, Mutex},
+            time::Instant,
         },
+        tokio::{sync::Semaphore, task::JoinHandle},
+        tokio_util::sync::CancellationToken,
     };
 
-    /*Sends Hello then peeks the process queue to check for a helloreply, if no
-    helloreply in 3 seconds, sends hello again, repeats 3 times.
-
+    /*Files of a directory downloaded at once, each keeps its file open */
+    const PARALLEL_FILES: usize = 8;
+    /*Times a GetDatum the peer can't answer yet is sent again, waiting
+    twice longer each time */
+    const RETRIES: u32 = 5;
+    const RETRY_BACKOFF: u64 = 200;
+
+    /*Says Hello to each rendezvous server and waits for the replies.
+    Registering succeeds if any server replies, the resend task keeps
+    us alive with all of them.
+    Once the servers know us, publishes our key, root and addresses on
+    the REST server if one is given.
     */
     pub async fn register(
-        peek_process_queue: Arc<RwLock<Queue<Action>>>,
-        process_queue_state: Arc<QueueState>,
-        process_queue_readers_state: Arc<QueueState>,
-        action_queue: Arc<Mutex<Queue<Action>>>,
-        action_queue_state: Arc<QueueState>,
+        queues: &Queues,
         my_data: Arc<Peer>,
+        config: &NodeConfig,
+        addresses: &[SocketAddr],
     ) -> Result<(), PeerError> {
-        let server_sock_addr: SocketAddr = "81.194.27.155:8443".parse().unwrap();
-
-        handshake(
-            peek_process_queue.clone(),
-            process_queue_readers_state.clone(),
-            action_queue.clone(),
-            action_queue_state.clone(),
-            server_sock_addr,
-            my_data.clone(),
-        );
+        if config.get_rendezvous_servers().is_empty() {
+            debug!("No rendezvous server to register with");
+            return Err(PeerError::NoAddr);
+        }
 
-        match peek_until_hello_reply_from(
-            Arc::clone(&peek_process_queue),
-            Arc::clone(&process_queue_state),
-            Arc::clone(&process_queue_readers_state),
-            Arc::clone(&action_queue),
-            Arc::clone(&action_queue_state),
-            server_sock_addr,
-            1000,
+        let replies = join_all(
+            config
+                .get_rendezvous_servers()
+                .iter()
+                .map(|server_sock_addr| hello(queues, *server_sock_addr, my_data.clone(), 3000)),
         )
-        .await
-        {
-            Ok(_) => {
-                keep_alive_to_peer(
-                    Arc::clone(&action_queue),
-                    Arc::clone(&action_queue_state),
-                    *&server_sock_addr,
-                    my_data.clone(),
-                    30_000_000_000,
+        .await;
+
+        let mut registered = false;
+        for (server_sock_addr, reply) in config.get_rendezvous_servers().iter().zip(replies) {
+            match reply {
+                Ok(_) => {
+                    debug!("Register OK with {server_sock_addr}");
+                    registered = true;
+                }
+                Err(e) => debug!("Register failed with {server_sock_addr} : {e}"),
+            }
+        }
+
+        if !registered {
+            return Err(PeerError::ResponseTimeout);
+        }
+        match config.get_rest_server() {
+            Some(rest_server) => rest_server.publish(&my_data, addresses).await,
+            None => Ok(()),
+        }
+    }
+
+    /*
+        Sends packet to sock_addr and waits for the reply with the same
+        id, at most timeout ms. The resend task resends the packet until
+        the reply comes or it gives up on the id.
+    */
+    pub async fn request(
+        queues: &Queues,
+        packet: Packet,
+        sock_addr: SocketAddr,
+        timeout: u64,
+    ) -> Result<Action, PeerError> {
+        let id = *packet.get_id();
+        let reply = PendingIds::lock_and_add_waiter(Arc::clone(&queues.pending_ids), id);
+        Queue::lock_and_push_or_wait(Arc::clone(&queues.send_queue), (packet, sock_addr)).await;
+        QueueState::set_non_empty_queue(Arc::clone(&queues.send_queue_state));
+
+        match tokio::time::timeout(Duration::from_millis(timeout), reply).await {
+            Ok(Ok(Action::ProcessErrorReply(error_message, _))) if error_message == THROTTLED => {
+                Err(PeerError::Throttled)
+            }
+            Ok(Ok(Action::ProcessErrorReply(error_message, _)))
+                if error_message == HANDSHAKE_FIRST =>
+            {
+                Err(PeerError::HandshakeFirst)
+            }
+            Ok(Ok(Action::ProcessErrorReply(error_message, _))) => {
+                error!(
+                    "{sock_addr} replied with an error : {}",
+                    String::from_utf8_lossy(&error_message)
                 );
-                debug!("Register OK");
-                return Ok(());
+                Err(PeerError::ErrorReply)
             }
+            Ok(Ok(action)) => Ok(action),
+            /*The resend task gave up */
+            Ok(Err(_)) => Err(PeerError::ResponseTimeout),
             Err(_) => {
-                debug!("Register failed");
-                return Err(PeerError::ResponseTimeout);
+                PendingIds::lock_and_take_waiter(Arc::clone(&queues.pending_ids), &id);
+                Err(PeerError::ResponseTimeout)
+            }
+        }
+    }
+
+    /*
+        Handshakes with sock_addr : sends Hello and waits for the reply,
+        then sends our root and public key now that the peer knows us.
+        Returns the root the peer replied with.
+    */
+    pub async fn hello(
+        queues: &Queues,
+        sock_addr: SocketAddr,
+        my_data: Arc<Peer>,
+        timeout: u64,
+    ) -> Result<Option<[u8; 32]>, PeerError> {
+        let hello_packet = PacketBuilder::hello_packet(
+            my_data.get_extensions(),
+            my_data.get_name().unwrap().as_bytes().to_vec(),
+        )?;
+        match request(queues, hello_packet, sock_addr, timeout).await? {
+            Action::ProcessHelloReply(..) => (),
+            _ => return Err(PeerError::InvalidPacket),
+        }
+
+        Queue::lock_and_push(
+            Arc::clone(&queues.action_queue),
+            Action::SendPublicKey(my_data.get_public_key(), sock_addr),
+        );
+        QueueState::set_non_empty_queue(Arc::clone(&queues.action_queue_state));
+
+        let root_packet = PacketBuilder::root_packet(my_data.get_root_hash())?;
+        match request(queues, root_packet, sock_addr, timeout).await? {
+            Action::ProcessRootReply(root, _) => Ok(root),
+            _ => Err(PeerError::InvalidPacket),
+        }
+    }
+
+    /*Asks sock_addr for the datum with the given hash, once its
+    congestion window has room. Asks again later while sock_addr
+    says it is over its rate limits, or that it didn't validate our
+    address yet : its Hello answering our handshake may still be on
+    its way */
+    pub async fn get_datum(
+        queues: &Queues,
+        hash: [u8; 32],
+        sock_addr: SocketAddr,
+        timeout: u64,
+    ) -> Result<Action, PeerError> {
+        let mut backoff = Duration::from_millis(RETRY_BACKOFF);
+        for _ in 0..RETRIES {
+            match request_datum(queues, hash, sock_addr, timeout).await {
+                Err(e @ (PeerError::Throttled | PeerError::HandshakeFirst)) => {
+                    debug!("{sock_addr} : {e}, asking again in {backoff:?}");
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                reply => return reply,
+            }
+        }
+        request_datum(queues, hash, sock_addr, timeout).await
+    }
+
+    async fn request_datum(
+        queues: &Queues,
+        hash: [u8; 32],
+        sock_addr: SocketAddr,
+        timeout: u64,
+    ) -> Result<Action, PeerError> {
+        let mut slot = PendingIds::acquire_window(
+            Arc::clone(&queues.pending_ids),
+            Arc::clone(&queues.window_state),
+            sock_addr,
+        )
+        .await;
+        let get_datum_packet = PacketBuilder::get_datum_packet(hash)?;
+        let reply = request(queues, get_datum_packet, sock_addr, timeout).await;
+        /*Any reply, even an error, means the peer keeps up */
+        slot.set_replied(!matches!(reply, Err(PeerError::ResponseTimeout)));
+        match reply? {
+            Action::ProcessDatum(datum_hash, node, addr) => {
+                /*The reply to our id must still carry the hash we asked for */
+                if datum_hash != hash {
+                    return Err(PeerError::InvalidPacket);
+                }
+                Ok(Action::ProcessDatum(datum_hash, node, addr))
             }
+            Action::ProcessNoDatum(_) => Err(PeerError::NoDatum),
+            _ => Err(PeerError::InvalidPacket),
+        }
+    }
+
+    /*The datum with the given hash from the chunk store if it has it,
+    otherwise asked to the peers of the swarm until one sends it, and
+    kept in the store. Fails with the error of the last peer tried, or
+    NoPeers if the swarm had none left to ask. */
+    pub async fn fetch_datum(
+        queues: &Queues,
+        chunk_store: Option<&ChunkStore>,
+        hash: [u8; 32],
+        swarm: &Swarm,
+        timeout: u64,
+    ) -> Result<Action, PeerError> {
+        let stored = match chunk_store {
+            Some(store) => store.spawn_get(hash).await,
+            None => None,
         };
+        if let Some(node) = stored {
+            /*Not from any peer */
+            let unspecified = SocketAddr::from(([0, 0, 0, 0], 0));
+            return Ok(Action::ProcessDatum(hash, node, unspecified));
+        }
+        let mut tried = vec![];
+        let mut failure = PeerError::NoPeers;
+        while let Some(request) = swarm.pick(&tried) {
+            let sock_addr = request.get_sock_addr();
+            let sent = Instant::now();
+            match get_datum(queues, hash, sock_addr, timeout).await {
+                Ok(datum_action) => {
+                    if let Action::ProcessDatum(_, node, _) = &datum_action {
+                        request.record_reply(node.len(), sent.elapsed());
+                        /*The download goes on without the store */
+                        if let Some(store) = chunk_store {
+                            if let Err(e) = store.spawn_put(hash, node.clone()).await {
+                                warn!("Failed to store {} : {e}", hex::encode(hash));
+                            }
+                        }
+                    }
+                    return Ok(datum_action);
+                }
+                Err(
+                    e @ (PeerError::NoDatum
+                    | PeerError::ResponseTimeout
+                    | PeerError::PeerTimedOut
+                    | PeerError::ErrorReply
+                    | PeerError::Throttled
+                    | PeerError::HandshakeFirst
+                    | PeerError::InvalidPacket),
+                ) => {
+                    debug!("{sock_addr} didn't send {} : {e}", hex::encode(hash));
+                    request.record_failure();
+                    tried.push(sock_addr);
+                    failure = e;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Err(failure)
     }
 
     pub fn keep_alive_to_peer(
@@ -83,148 +289,72 @@ pub mod import_export {
         sock_addr: SocketAddr,
         my_data: Arc<Peer>,
         timing: u64,
-    ) {
+        cancel: CancellationToken,
+    ) -> JoinHandle<()> {
         tokio::spawn(async move {
             loop {
                 Queue::lock_and_push(
                     Arc::clone(&action_queue),
                     Action::SendHello(
-                        None,
+                        my_data.get_extensions(),
                         my_data.get_name().unwrap().as_bytes().to_vec(),
                         sock_addr,
                     ),
                 );
                 QueueState::set_non_empty_queue(Arc::clone(&action_queue_state));
 
-                std::thread::sleep(Duration::from_millis(timing));
+                tokio::select! {
+                    _ = cancel.cancelled() => break,
+                    _ = tokio::time::sleep(Duration::from_millis(timing)) => (),
+                }
             }
-        });
+        })
     }
 
+    /*Asks every rendezvous server to relay a NatTraversal to sock_addr */
     pub fn try_nat_traversal_with(
         action_queue: Arc<Mutex<Queue<Action>>>,
         action_queue_state: Arc<QueueState>,
         sock_addr: Vec<u8>,
         timing: u64,
-    ) {
+        config: Arc<NodeConfig>,
+        cancel: CancellationToken,
+    ) -> JoinHandle<()> {
         tokio::spawn(async move {
-            let server_sock_addr: SocketAddr = "81.194.27.155:8443".parse().unwrap();
             loop {
-                std::thread::sleep(Duration::from_nanos(timing));
-                Queue::lock_and_push(
+                tokio::select! {
+                    _ = cancel.cancelled() => break,
+                    _ = tokio::time::sleep(Duration::from_nanos(timing)) => (),
+                }
+                Queue::lock_and_push_mul(
                     Arc::clone(&action_queue),
-                    Action::SendNatTraversalRequest(sock_addr.clone(), server_sock_addr),
+                    config
+                        .get_rendezvous_servers()
+                        .iter()
+                        .map(|server_sock_addr| {
+                            Action::SendNatTraversalRequest(sock_addr.clone(), *server_sock_addr)
+                        })
+                        .collect(),
                 );
                 QueueState::set_non_empty_queue(Arc::clone(&action_queue_state));
             }
-        });
-    }
-
-    pub async fn peek_until_root_reply_from(
-        peek_process_queue: Arc<RwLock<Queue<Action>>>,
-        _process_queue_state: Arc<QueueState>,
-        process_queue_readers_state: Arc<QueueState>,
-        _action_queue: Arc<Mutex<Queue<Action>>>,
-        _action_queue_state: Arc<QueueState>,
-        sock_addr: SocketAddr,
-        timeout: u64,
-    ) -> Result<Action, PeerError> {
-        loop {
-            /*Wait notify all from receive task */
-            let front = match Queue::read_lock_and_peek(Arc::clone(&peek_process_queue)) {
-                Some(front) => front,
-                None => {
-                    match process_queue_readers_state.wait_timeout_ms(timeout) {
-                        Ok(_) => (),
-                        Err(_) => return Err(PeerError::PeerTimedOut),
-                    };
-                    continue;
-                }
-            };
-            match front {
-                Action::ProcessRootReply(_hash, addr) => {
-                    if addr == sock_addr {
-                        break Ok::<Action, PeerError>(front);
-                    } else {
-                        continue;
-                    }
-                }
-                _ => continue,
-            }
-        }
-    }
-    pub async fn peek_until_hello_reply_from(
-        peek_process_queue: Arc<RwLock<Queue<Action>>>,
-        _process_queue_state: Arc<QueueState>,
-        process_queue_readers_state: Arc<QueueState>,
-        _action_queue: Arc<Mutex<Queue<Action>>>,
-        _action_queue_state: Arc<QueueState>,
-        sock_addr: SocketAddr,
-        timeout: u64,
-    ) -> Result<Action, PeerError> {
-        loop {
-            /*Wait notify all from receive task */
-            let front = match Queue::read_lock_and_peek(Arc::clone(&peek_process_queue)) {
-                Some(front) => front,
-                None => {
-                    match process_queue_readers_state.wait_timeout_ms(timeout) {
-                        Ok(_) => (),
-                        Err(_) => return Err(PeerError::PeerTimedOut),
-                    };
-                    continue;
-                }
-            };
-            match front {
-                Action::ProcessHelloReply(.., addr) => {
-                    if addr == sock_addr {
-                        break Ok::<Action, PeerError>(front);
-                    } else {
-                        continue;
-                    }
-                }
-                _ => continue,
-            }
-        }
+        })
     }
 
     #[async_recursion::async_recursion]
     pub async fn fetch_subtree_from(
-        peek_process_queue: Arc<RwLock<Queue<Action>>>,
-        process_queue_readers_state: Arc<QueueState>,
-        action_queue: Arc<Mutex<Queue<Action>>>,
-        action_queue_state: Arc<QueueState>,
-        maps: Arc<
-            Mutex<(
-                HashMap<[u8; 32], [u8; 32]>,
-                HashMap<[u8; 32], Vec<[u8; 32]>>,
-                HashMap<[u8; 32], String>,
-            )>,
-        >,
+        queues: &Queues,
+        chunk_store: Option<&ChunkStore>,
+        maps: TreeMaps,
         hash: [u8; 32],
-        sock_addr: SocketAddr,
+        swarm: &Swarm,
         timeout: u64,
     ) -> Result<(), PeerError> {
         let mut subtasks = vec![];
         let children: Option<Vec<[u8; 32]>>;
 
         // Send a get datum with the first target hash
-        Queue::lock_and_push(
-            Arc::clone(&action_queue),
-            Action::SendGetDatumWithHash(*&hash, *&sock_addr),
-        );
-        QueueState::set_non_empty_queue(Arc::clone(&action_queue_state));
-
-        match peek_until_datum_with_hash_from(
-            Arc::clone(&peek_process_queue),
-            Arc::clone(&process_queue_readers_state),
-            Arc::clone(&action_queue),
-            Arc::clone(&action_queue_state),
-            *&hash,
-            *&sock_addr,
-            timeout,
-        )
-        .await
-        {
+        match fetch_datum(queues, chunk_store, hash, swarm, timeout).await {
             Ok(datum_action) => {
                 // build the maps :
                 // - child -> parent
@@ -252,6 +382,10 @@ pub mod import_export {
                 // break Err::<Action, PeerError>(PeerError::ResponseTimeout)
             }
             Err(PeerError::PeerTimedOut)=> return Err(PeerError::PeerTimedOut),
+            Err(PeerError::ErrorReply) => return Err(PeerError::ErrorReply),
+            Err(PeerError::Throttled) => return Err(PeerError::Throttled),
+            Err(PeerError::HandshakeFirst) => return Err(PeerError::HandshakeFirst),
+            Err(PeerError::NoPeers) => return Err(PeerError::NoPeers),
             _=> return Err(PeerError::Unknown),
         };
         match children {
@@ -260,13 +394,11 @@ pub mod import_export {
                 for child_hash in childs {
                     debug!("Asking for child {:?}", &child_hash);
                     subtasks.push(fetch_subtree_from(
-                        Arc::clone(&peek_process_queue),
-                        Arc::clone(&process_queue_readers_state),
-                        Arc::clone(&action_queue),
-                        Arc::clone(&action_queue_state),
+                        queues,
+                        chunk_store,
                         Arc::clone(&maps),
                         child_hash,
-                        sock_addr,
+                        swarm,
                         timeout,
                     ));
                     // hash_vec.push(Action::SendGetDatumWithHash(child_hash, sock_addr));
@@ -290,167 +422,381 @@ pub mod import_export {
         Ok(())
     }
 
-    #[async_recursion::async_recursion]
+    /*
+        Downloads the file with the given hash into output, each chunk
+        written at its offset as soon as it arrives, and returns its
+        length. A directory only has its tree fetched into maps, output
+        isn't created and the result is FileIsDirectory.
+        The journal next to output lets a download of the same hash pick
+        up where an interrupted one stopped.
+    */
     pub async fn download_from(
-        peek_process_queue: Arc<RwLock<Queue<Action>>>,
-        process_queue_readers_state: Arc<QueueState>,
-        action_queue: Arc<Mutex<Queue<Action>>>,
-        action_queue_state: Arc<QueueState>,
-        maps: Arc<
-            Mutex<(
-                HashMap<[u8; 32], [u8; 32]>,
-                HashMap<[u8; 32], Vec<[u8; 32]>>,
-                HashMap<[u8; 32], String>,
-            )>,
-        >,
+        queues: &Queues,
+        chunk_store: Option<&ChunkStore>,
+        maps: TreeMaps,
         hash: [u8; 32],
-        sock_addr: SocketAddr,
+        swarm: &Swarm,
         timeout: u64,
-    ) -> Result<SimpleNode, PeerError> {
-        let _subtasks: Vec<Pin<Box<dyn Future<Output = Result<SimpleNode, PeerError>> + Send>>> =
-            vec![];
-        let _children: Option<Vec<[u8; 32]>>;
-
-        // Send a get datum with the first target hash
-        Queue::lock_and_push(
-            Arc::clone(&action_queue),
-            Action::SendGetDatumWithHash(*&hash, *&sock_addr),
-        );
-        QueueState::set_non_empty_queue(Arc::clone(&action_queue_state));
-
-        match peek_until_datum_with_hash_from(
-            Arc::clone(&peek_process_queue),
-            Arc::clone(&process_queue_readers_state),
-            Arc::clone(&action_queue),
-            Arc::clone(&action_queue_state),
-            *&hash,
-            *&sock_addr,
-            timeout,
-        )
-        .await
-        {
-            Ok(datum_action) => {
-                // build the maps :
-                // - child -> parent
-                // - parent -> child
-                // - hash -> name
-                // and return the children if there are some
-                // - chunk -> no children
-                // - tree -> no children (fetching only the filesystem)
-                // - directory -> children
-                let data_type = get_type(&datum_action)?;
-                match data_type {
-                    0 | 1 => {
-                        debug!("Selected hash is a file, downloading it");
-                        let mut node = match get_children(&datum_action, Arc::clone(&maps)) {
-                            Ok(n) => n,
-                            Err(_e) => {
-                                error!("Failed to download datum");
-                                return Err(PeerError::InvalidPacket);
-                            }
-                        };
-                        match node.children {
-                            Some(c) => {
-                                let mut subtasks = vec![];
-                                for n in c.into_iter() {
-                                    subtasks.push(download_from(
-                                        Arc::clone(&peek_process_queue),
-                                        Arc::clone(&process_queue_readers_state),
-                                        Arc::clone(&action_queue),
-                                        Arc::clone(&action_queue_state),
-                                        Arc::clone(&maps),
-                                        n.hash,
-                                        sock_addr,
-                                        timeout,
-                                    ));
-                                }
-                                let completed = join_all(subtasks).await;
-                                node.children = Some(
-                                    completed
-                                        .into_iter()
-                                        .filter_map(|n| match n {
-                                            Ok(r) => Some(r),
-                                            Err(e) => {
-                                                warn!(
-                                                    "Failed to download child, file may be corrupted. {e}"
-                                                );
-                                                None
-                                            }
-                                        })
-                                        .collect::<Vec<SimpleNode>>(),
-                                );
-                            }
-                            None => (),
-                        }
-                        return Ok(node);
-                    }
-                    2 => {
+        output: &Path,
+    ) -> Result<u64, PeerError> {
+        let journal = open_journal(output, hash)?;
+        let node = match journal.get_big(&hash) {
+            Some(children) => FileNode::Big(children),
+            None => {
+                let datum_action = fetch_datum(queues, chunk_store, hash, swarm, timeout).await?;
+                match get_file_node(&datum_action) {
+                    Ok(node) => node,
+                    Err(PeerError::FileIsDirectory) => {
                         info!("Selected hash is a directory. Fetching the file tree.");
-                        let _ = fetch_subtree_from(
-                            Arc::clone(&peek_process_queue),
-                            Arc::clone(&process_queue_readers_state),
-                            Arc::clone(&action_queue),
-                            Arc::clone(&action_queue_state),
-                            Arc::clone(&maps),
-                            hash,
-                            sock_addr,
-                            timeout,
-                        )
-                        .await?;
+                        let maps = Arc::clone(&maps);
+                        fetch_subtree_from(queues, chunk_store, maps, hash, swarm, timeout).await?;
                         return Err(PeerError::FileIsDirectory);
                     }
-                    _ => return Err(PeerError::InvalidPacket),
+                    Err(e) => return Err(e),
                 }
             }
-            Err(PeerError::NoDatum) => return Err(PeerError::NoDatum),
-            Err(PeerError::ResponseTimeout) => return Err(PeerError::ResponseTimeout),
-            Err(_e) => return Err(PeerError::Unknown),
         };
+
+        debug!("Selected hash is a file, downloading it");
+        let target = FileTarget::open(
+            queues,
+            chunk_store,
+            &journal,
+            Path::new(""),
+            output,
+            swarm,
+            timeout,
+        )?;
+        let len = download_file(target, hash, Some(node)).await?;
+        journal
+            .remove()
+            .map_err(|e| write_failed(journal.get_path(), e))?;
+        Ok(len)
     }
 
-    pub async fn peek_until_datum_with_hash_from(
-        peek_process_queue: Arc<RwLock<Queue<Action>>>,
-        process_queue_readers_state: Arc<QueueState>,
-        _action_queue: Arc<Mutex<Queue<Action>>>,
-        _action_queue_state: Arc<QueueState>,
+    /*
+        Recreates under output the directory with the given hash, once
+        download_from fetched its tree into maps. Directories are created
+        first, then PARALLEL_FILES files at a time are streamed to their
+        place. Files the journal marks done are left as they are. Returns
+        the entries of the directory.
+    */
+    pub async fn download_directory(
+        queues: &Queues,
+        chunk_store: Option<&ChunkStore>,
+        maps: TreeMaps,
         hash: [u8; 32],
-        sock_addr: SocketAddr,
+        swarm: &Swarm,
         timeout: u64,
-    ) -> Result<Action, PeerError> {
-        /*Spawns a task that peeks the process queue waiting for a packet
-        from the given address with the given peer. */
-
-        loop {
-            /*Wait notify all from receive task */
-            let front = match Queue::read_lock_and_peek(Arc::clone(&peek_process_queue)) {
-                Some(front) => front,
-                None => {
-                    match process_queue_readers_state.wait_timeout_ms(timeout) {
-                        Ok(_) => (),
-                        Err(_) => return Err(PeerError::PeerTimedOut),
-                    };
-                    continue;
+        output: &Path,
+    ) -> Result<Vec<LocalEntry>, PeerError> {
+        let entries = match maps.lock() {
+            Ok(maps) => {
+                for (name, child_hash) in get_name_to_hash_hashmap(&maps.0, &maps.2) {
+                    debug!("Remote {name} : {}", hex::encode(child_hash));
                 }
+                get_local_entries(&hash, &maps.1)
+            }
+            Err(e) => {
+                error!("[download_directory] Maps mutex is poisoned {e}");
+                panic!("Maps mutex is poisoned {e}")
+            }
+        };
+
+        let journal = open_journal(output, hash)?;
+        std::fs::create_dir_all(output).map_err(|e| write_failed(output, e))?;
+        for entry in entries.iter().filter(|entry| entry.is_directory) {
+            let path = output.join(&entry.path);
+            std::fs::create_dir_all(&path).map_err(|e| write_failed(&path, e))?;
+        }
+
+        /*Files a previous run finished are still there */
+        let done =
+            |entry: &LocalEntry| journal.is_done(&entry.path) && output.join(&entry.path).is_file();
+        let files = entries
+            .iter()
+            .filter(|entry| !entry.is_directory && !done(entry));
+        let journal_ref = &journal;
+        stream::iter(files)
+            .map(|entry| async move {
+                let path = output.join(&entry.path);
+                info!("Downloading {}", path.display());
+                let target = FileTarget::open(
+                    queues,
+                    chunk_store,
+                    journal_ref,
+                    &entry.path,
+                    &path,
+                    swarm,
+                    timeout,
+                )?;
+                download_file(target, entry.hash, None).await
+            })
+            .buffer_unordered(PARALLEL_FILES)
+            .try_collect::<Vec<u64>>()
+            .await?;
+        journal
+            .remove()
+            .map_err(|e| write_failed(journal.get_path(), e))?;
+        Ok(entries)
+    }
+
+    fn write_failed(path: &Path, e: std::io::Error) -> PeerError {
+        error!("Failed to write {} : {e}", path.display());
+        PeerError::WriteFailed
+    }
+
+    fn open_journal(output: &Path, hash: [u8; 32]) -> Result<Arc<Journal>, PeerError> {
+        match Journal::open(output, hash) {
+            Ok(journal) => Ok(Arc::new(journal)),
+            Err(e) => Err(write_failed(&journal_path(output), e)),
+        }
+    }
+
+    /*One file being downloaded, file is its name in the journal. Its
+    GetDatums in flight are bounded by window, as many as the congestion
+    windows of the swarm can hold at most */
+    struct FileTarget<'a> {
+        queues: &'a Queues,
+        chunk_store: Option<&'a ChunkStore>,
+        writer: FileWriter,
+        journal: &'a Arc<Journal>,
+        file: &'a Path,
+        swarm: &'a Swarm,
+        timeout: u64,
+        window: Semaphore,
+    }
+
+    impl<'a> FileTarget<'a> {
+        /*Keeps what was written before if the journal knows of it */
+        fn open(
+            queues: &'a Queues,
+            chunk_store: Option<&'a ChunkStore>,
+            journal: &'a Arc<Journal>,
+            file: &'a Path,
+            path: &Path,
+            swarm: &'a Swarm,
+            timeout: u64,
+        ) -> Result<Self, PeerError> {
+            let writer = match journal.is_resumed() {
+                true => FileWriter::open(path),
+                false => FileWriter::create(path),
             };
-            match front {
-                Action::ProcessDatum(datum, addr) => {
-                    let mut datum_hash = [0u8; 32];
-                    datum_hash.copy_from_slice(&datum.as_slice()[0..32]);
-                    if (addr == sock_addr) && (datum_hash == hash) {
-                        break Ok::<Action, PeerError>(Action::ProcessDatum(datum, addr));
-                    } else {
-                        continue;
-                    }
+            Ok(Self {
+                queues,
+                chunk_store,
+                writer: writer.map_err(|e| write_failed(path, e))?,
+                journal,
+                file,
+                swarm,
+                timeout,
+                window: Semaphore::new(MAX_WINDOW * swarm.get_peers().len().max(1)),
+            })
+        }
+
+        fn write_failed(&self, e: std::io::Error) -> PeerError {
+            write_failed(self.writer.get_path(), e)
+        }
+    }
+
+    /*
+        Places the file with the given hash, node being its datum if it
+        was already fetched, then reads it back against the hash before
+        the journal marks it done.
+    */
+    async fn download_file(
+        target: FileTarget<'_>,
+        hash: [u8; 32],
+        node: Option<FileNode>,
+    ) -> Result<u64, PeerError> {
+        let len = match node {
+            Some(node) => place_file_node(&target, hash, node, 0, None).await?,
+            None => fetch_and_place(&target, hash, 0, None).await?,
+        };
+        let path = target.writer.get_path().to_path_buf();
+        target
+            .writer
+            .finish(len)
+            .map_err(|e| write_failed(&path, e))?;
+
+        let verified = target
+            .journal
+            .spawn_verify(target.file, hash, &path)
+            .await
+            .map_err(|e| write_failed(&path, e))?;
+        if !verified {
+            error!("{} doesn't match {}", path.display(), hex::encode(hash));
+            return Err(PeerError::CorruptedDownload);
+        }
+        target
+            .journal
+            .spawn_record_done(target.file)
+            .await
+            .map_err(|e| write_failed(target.journal.get_path(), e))?;
+        Ok(len)
+    }
+
+    async fn fetch_and_place(
+        target: &FileTarget<'_>,
+        hash: [u8; 32],
+        offset: u64,
+        span: Option<u64>,
+    ) -> Result<u64, PeerError> {
+        let journal = target.journal;
+        if let Some(len) = journal
+            .spawn_get_written_chunk(target.file, offset, hash, &target.writer)
+            .await
+        {
+            if span.is_some_and(|span| span != len) {
+                return Err(PeerError::InvalidLayout);
+            }
+            return Ok(len);
+        }
+        let node = match journal.get_big(&hash) {
+            Some(children) => FileNode::Big(children),
+            None => {
+                let _permit = target
+                    .window
+                    .acquire()
+                    .await
+                    .map_err(|_| PeerError::Unknown)?;
+                let datum_action = fetch_datum(
+                    target.queues,
+                    target.chunk_store,
+                    hash,
+                    target.swarm,
+                    target.timeout,
+                )
+                .await?;
+                match get_file_node(&datum_action) {
+                    Ok(node) => node,
+                    /*A directory can't be part of a file */
+                    Err(PeerError::FileIsDirectory) => return Err(PeerError::InvalidLayout),
+                    Err(e) => return Err(e),
+                }
+            }
+        };
+        place_file_node(target, hash, node, offset, span).await
+    }
+
+    /*
+        Writes the part of a file held by node at offset and returns its
+        length. The parts of a big file usually all span as many bytes
+        as the first one but the last, span is that length when the
+        parent knows it, so that the parts are fetched side by side,
+        a window of them at a time. A part that doesn't span it means
+        the tree was cut otherwise : the parts are then placed one after
+        the other, each at the end of the previous one.
+        Each part is journaled once written.
+    */
+    #[async_recursion::async_recursion]
+    async fn place_file_node(
+        target: &FileTarget<'_>,
+        hash: [u8; 32],
+        node: FileNode,
+        offset: u64,
+        span: Option<u64>,
+    ) -> Result<u64, PeerError> {
+        let journal = target.journal;
+        let children = match node {
+            FileNode::Chunk(chunk) => {
+                let len = chunk.len() as u64;
+                if span.is_some_and(|span| span != len) {
+                    return Err(PeerError::InvalidLayout);
                 }
-                Action::ProcessNoDatum(_addr) => break Err(PeerError::NoDatum),
-                _ => continue,
+                target
+                    .writer
+                    .write_chunk(offset, &chunk)
+                    .map_err(|e| target.write_failed(e))?;
+                journal
+                    .spawn_record_chunk(target.file, offset, hash, len)
+                    .await
+                    .map_err(|e| write_failed(journal.get_path(), e))?;
+                return Ok(len);
+            }
+            FileNode::Big(children) if children.is_empty() => return Err(PeerError::InvalidLayout),
+            FileNode::Big(children) => children,
+        };
+        journal
+            .spawn_record_big(hash, &children)
+            .await
+            .map_err(|e| write_failed(journal.get_path(), e))?;
+
+        let len = match place_side_by_side(target, &children, offset, span).await {
+            Err(PeerError::InvalidLayout) => {
+                warn!(
+                    "Parts of {} aren't cut evenly, placing them one by one",
+                    hex::encode(hash)
+                );
+                place_one_by_one(target, &children, offset).await?
+            }
+            len => len?,
+        };
+        match span {
+            Some(span) if span != len => Err(PeerError::InvalidLayout),
+            _ => Ok(len),
+        }
+    }
+
+    /*Places the parts of a big file assuming they are cut evenly */
+    async fn place_side_by_side(
+        target: &FileTarget<'_>,
+        children: &[[u8; 32]],
+        offset: u64,
+        span: Option<u64>,
+    ) -> Result<u64, PeerError> {
+        let count = children.len() as u64;
+        let last = children.len() - 1;
+
+        /*Without a span the first part has to come back before its
+        siblings can be placed */
+        let (child_span, first) = match span {
+            Some(span) if span % count != 0 => return Err(PeerError::InvalidLayout),
+            Some(span) => (span / count, 0),
+            None => {
+                let child_span = fetch_and_place(target, children[0], offset, None).await?;
+                target
+                    .writer
+                    .preallocate(offset + child_span * count)
+                    .map_err(|e| target.write_failed(e))?;
+                (child_span, 1)
             }
+        };
+
+        let lens: Vec<u64> = stream::iter(children.iter().copied().enumerate().skip(first))
+            .map(|(i, child_hash)| {
+                /*Only the last part of the file may be shorter */
+                let part_span = match (span, i == last) {
+                    (None, true) => None,
+                    _ => Some(child_span),
+                };
+                Ok(fetch_and_place(
+                    target,
+                    child_hash,
+                    offset + i as u64 * child_span,
+                    part_span,
+                ))
+            })
+            .try_buffered(MAX_WINDOW)
+            .try_collect()
+            .await?;
+        Ok(last as u64 * child_span + lens.last().copied().unwrap_or(child_span))
+    }
+
+    /*Places the parts of a big file at the end of one another */
+    async fn place_one_by_one(
+        target: &FileTarget<'_>,
+        children: &[[u8; 32]],
+        offset: u64,
+    ) -> Result<u64, PeerError> {
+        let mut len = 0;
+        for child_hash in children {
+            len += fetch_and_place(target, *child_hash, offset + len, None).await?;
         }
+        Ok(len)
     }
 
     pub fn handshake(
-        _peek_process_queue: Arc<RwLock<Queue<Action>>>,
-        _process_queue_readers_state: Arc<QueueState>,
         action_queue: Arc<Mutex<Queue<Action>>>,
         action_queue_state: Arc<QueueState>,
         sock_addr: SocketAddr,
@@ -460,24 +806,24 @@ pub mod import_export {
             action_queue.clone(),
             vec![
                 Action::SendHello(
-                    None,
+                    my_data.get_extensions(),
                     my_data.get_name().unwrap().as_bytes().to_vec(),
                     sock_addr,
                 ),
-                Action::SendRoot(None, sock_addr),
-                Action::SendPublicKey(None, sock_addr),
+                Action::SendRoot(my_data.get_root_hash(), sock_addr),
+                Action::SendPublicKey(my_data.get_public_key(), sock_addr),
             ],
         );
         Queue::lock_and_push_mul(
             action_queue,
             vec![
                 Action::SendHello(
-                    None,
+                    my_data.get_extensions(),
                     my_data.get_name().unwrap().as_bytes().to_vec(),
                     sock_addr,
                 ),
-                Action::SendRoot(None, sock_addr),
-                Action::SendPublicKey(None, sock_addr),
+                Action::SendRoot(my_data.get_root_hash(), sock_addr),
+                Action::SendPublicKey(my_data.get_public_key(), sock_addr),
             ],
         );
         QueueState::set_non_empty_queue(action_queue_state);
@@ -489,21 +835,84 @@ mod tests {
     use {
         super::*,
         crate::{
-            congestion_handler::*, handle_action::handle_action_task,
-            handle_packet::handle_packet_task, packet::*, peer::*, process::process_task,
-            sender_receiver::*, store::*, task_launcher_canceller::task_launcher,
+            config::{NodeConfig, DEFAULT_RENDEZVOUS},
+            congestion_handler::*,
+            handle_action::handle_action_task,
+            handle_packet::handle_packet_task,
+            keystore::Identity,
+            packet::*,
+            peer::*,
+            process::process_task,
+            rest_server::{MismatchPolicy, RestServer},
+            sender_receiver::*,
+            store::*,
+            swarm::Swarm,
+            task_launcher_canceller::task_launcher,
         },
         import_export::*,
         lib_file::mk_fs::MktFsNode,
+        lib_web::discovery,
         nanorand::{wyrand::WyRand, BufferedRng, Rng},
+        rendezvous::RendezvousServer,
         std::sync::Arc,
-        std::{net::SocketAddr, path::PathBuf},
+        std::{fs, net::SocketAddr, path::PathBuf},
         tokio::{
             self,
             net::UdpSocket,
+            sync::watch,
             time::{sleep, Duration},
         },
+        tokio_util::sync::CancellationToken,
     };
+
+    /*jch, and its first ipv4 address */
+    async fn jch_config() -> (Arc<NodeConfig>, SocketAddr) {
+        let mut config = NodeConfig::new();
+        config
+            .resolve_rendezvous_server(DEFAULT_RENDEZVOUS)
+            .await
+            .unwrap();
+        let server_sock_addr4 = *config
+            .get_rendezvous_servers()
+            .iter()
+            .find(|sock_addr| sock_addr.is_ipv4())
+            .unwrap();
+        (Arc::new(config), server_sock_addr4)
+    }
+
+    /*A rendezvous server on the loopback standing for jch, with its REST API */
+    async fn local_config() -> Arc<NodeConfig> {
+        let server = RendezvousServer::bind(
+            "127.0.0.1:0".parse().unwrap(),
+            "rendezvous",
+            Duration::from_secs(180),
+        )
+        .await
+        .unwrap();
+        let mut config = NodeConfig::new();
+        config
+            .add_rendezvous_server(server.local_addr())
+            .set_rest_server(Some(
+                RestServer::new(
+                    discovery::parse_url(server.url()).unwrap(),
+                    5,
+                    MismatchPolicy::Flag,
+                )
+                .unwrap(),
+            ));
+        server.spawn();
+        Arc::new(config)
+    }
+
+    /*A directory to export with a file of a few chunks */
+    fn to_export(name: &str) -> (PathBuf, MktFsNode) {
+        let dir = std::env::temp_dir().join(format!("udp2p-{name}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("notes"), "some notes\n".repeat(400)).unwrap();
+        let tree = MktFsNode::try_from_path(&dir, 1024, 32).expect("unexisting path");
+        (dir, tree)
+    }
+
     #[tokio::test]
     async fn packet_bytes_conversion() {
         let mut rng = BufferedRng::new(WyRand::new());
@@ -518,7 +927,7 @@ mod tests {
             .build()
             .unwrap();
 
-        let hello_packet = PacketBuilder::noop_packet();
+        let hello_packet = PacketBuilder::noop_packet().unwrap();
         let raw_hello_packet = hello_packet.as_bytes();
         let raw_packet = packet.as_bytes();
 
@@ -528,7 +937,7 @@ mod tests {
 
     #[tokio::test]
     async fn handshake_with_pi() {
-        let packet = PacketBuilder::noop_packet();
+        let packet = PacketBuilder::noop_packet().unwrap();
         let packet2 = PacketBuilder::new()
             .body(b"j'ai rotey :)".to_vec())
             .gen_id()
@@ -545,99 +954,80 @@ mod tests {
         let _ = sleep(Duration::from_millis(500));
     }
 
-    #[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+    #[tokio::test]
     async fn register_and_export() {
-        let sock4 = Arc::new(UdpSocket::bind("192.168.1.90:40000").await.unwrap());
-        // let sock = Arc::new(UdpSocket::bind("0.0.0.0:0").await.unwrap());
+        let config = local_config().await;
+        let sock4 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
 
-        let tree = MktFsNode::try_from_path(
-            &PathBuf::from("/home/splash/files/notes_m2/protocoles_reseaux/tp/Projet/to_export/"),
-            1024,
-            100,
-        )
-        .expect("unexisting path");
-
-        let _map = Arc::new(tree.to_hashmap());
+        let (dir, tree) = to_export("register-and-export");
+        let root = tree.hash;
 
-        let (
-            receive_queue,
-            send_queue,
-            action_queue,
-            process_queue,
-            pending_ids,
-            receive_queue_state,
-            action_queue_state,
-            send_queue_state,
-            process_queue_state,
-            process_queue_readers_state,
-        ) = build_queues();
+        let queues = build_queues();
+        let cancel = CancellationToken::new();
 
         let active_peers = ActivePeers::build_mutex();
 
-        let mut my_data = Peer::new();
-        my_data.set_name("nist".to_string());
+        let mut my_data = Identity::generate().to_peer();
+        my_data.set_name("nist".to_string()).set_hash(Some(root));
         let my_data = Arc::new(my_data.clone());
 
         let _receiving = receiver4(
             Arc::clone(&sock4),
-            Arc::clone(&receive_queue),
-            Arc::clone(&receive_queue_state),
+            &queues,
+            Arc::clone(&active_peers),
+            cancel.clone(),
         );
 
         let _handling = handle_packet_task(
-            Arc::clone(&pending_ids),
-            Arc::clone(&receive_queue),
-            Arc::clone(&receive_queue_state),
-            Arc::clone(&process_queue),
-            Arc::clone(&process_queue_state),
-            Arc::clone(&process_queue_readers_state),
-        );
-        let _processing_two = handle_action_task(
-            Arc::clone(&send_queue),
-            Arc::clone(&send_queue_state),
-            Arc::clone(&action_queue),
-            Arc::clone(&action_queue_state),
-            Arc::clone(&process_queue),
-            Arc::clone(&process_queue_state),
+            &queues,
+            Arc::clone(&active_peers),
+            Arc::clone(&config),
+            cancel.clone(),
         );
+        let _processing_two = handle_action_task(&queues, cancel.clone());
+        let (_export, exported) = watch::channel(Some(Arc::new(tree)));
         let _processing_one = process_task(
-            Arc::clone(&action_queue),
-            Arc::clone(&action_queue_state),
-            Arc::clone(&process_queue),
-            Arc::clone(&process_queue_state),
+            &queues,
             Arc::clone(&active_peers),
-            Peer::new(),
-            false,
-            PathBuf::from("/home/splash/"),
-            // Arc::clone(&map)
+            (*my_data).clone(),
+            exported,
+            Arc::clone(&config),
+            cancel.clone(),
         );
 
         let _sending = sender(
             Arc::clone(&sock4),
             Arc::clone(&sock4),
-            Arc::clone(&send_queue),
-            Arc::clone(&send_queue_state),
-            Arc::clone(&pending_ids),
+            &queues,
+            Arc::clone(&active_peers),
+            Arc::clone(&my_data),
+            cancel.clone(),
         );
 
-        // let metrics = Handle::current().metrics();
-        // let n = metrics.active_tasks_count();
-        // println!("Runtime has {} active tasks", n);
-        let _registering = register(
-            Arc::clone(&process_queue),
-            Arc::clone(&process_queue_state),
-            Arc::clone(&process_queue_readers_state),
-            Arc::clone(&action_queue),
-            Arc::clone(&action_queue_state),
-            Arc::clone(&my_data),
+        register(&queues, Arc::clone(&my_data), &config, &[])
+            .await
+            .unwrap();
+
+        /*The server serves our root and address to the other peers */
+        let rest_server = config.get_rest_server().unwrap();
+        let (client, url) = (rest_server.get_client(), rest_server.get_url());
+        let recorded = discovery::get_peer_root(client, url, "nist").await.unwrap();
+        assert_eq!(recorded.as_ref(), root.as_slice());
+        let recorded = discovery::get_peer_addresses(client, url, "nist")
+            .await
+            .unwrap();
+        assert_eq!(
+            recorded.addresses,
+            vec![sock4.local_addr().unwrap().to_string()]
         );
 
-        let _ = sleep(Duration::from_secs(1_000));
+        cancel.cancel();
+        fs::remove_dir_all(&dir).unwrap();
     }
 
     /*Currently seems to sometime not be able to register peer.
     Sometimes when receiving helloreply, doesn't even attempt to create peer.  */
-    #[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+    #[tokio::test]
     async fn register_and_fetch_tree() {
         env_logger::init();
         let sock4 = Arc::new(UdpSocket::bind("0.0.0.0:0").await.unwrap());
@@ -663,31 +1053,27 @@ mod tests {
         let queues = build_queues();
         let active_peers = ActivePeers::build_mutex();
 
-        let _receive_queue_state = Arc::clone(&queues.5);
-        let action_queue = Arc::clone(&queues.2);
-        let action_queue_state = Arc::clone(&queues.6);
-        let process_queue = Arc::clone(&queues.3);
-        let process_queue_state = Arc::clone(&queues.8);
-        let process_queue_readers_state = Arc::clone(&queues.9);
+        let _receive_queue_state = Arc::clone(&queues.receive_queue_state);
+        let action_queue = Arc::clone(&queues.action_queue);
+        let action_queue_state = Arc::clone(&queues.action_queue_state);
+        let _process_queue = Arc::clone(&queues.process_queue);
+        let process_queue_state = Arc::clone(&queues.process_queue_state);
 
+        let (config, _server_sock_addr4) = jch_config().await;
         let mut my_data = Peer::new();
         my_data.set_name("nist".to_string());
-        let my_data_own = my_data.clone();
-        let my_data = Arc::new(my_data);
-
-        task_launcher(
-            queues,
+        let _tasks = task_launcher(
+            queues.clone(),
             active_peers.clone(),
             my_data.clone(),
-            my_data_own,
             sock4.clone(),
             sock6.clone(),
-            false,
-            PathBuf::from("/home/splash/files/dls"),
+            watch::channel(None).1,
+            config,
         );
+        let my_data = Arc::new(my_data);
 
         /*jch */
-        let _server_sock_addr4: SocketAddr = "81.194.27.155:8443".parse().unwrap();
         // let server_sock_addr6: SocketAddr = "[2001:660:3301:9200::51c2:1b9b]:8443".parse().unwrap();
         /*yoan */
         // let sock_addr: SocketAddr ="86.246.24.173:63801".parse().unwrap();
@@ -698,16 +1084,12 @@ mod tests {
         /*derriere un nat */
         // let sock_addr: SocketAddr = "81.65.148.210:40214".parse().unwrap();
         handshake(
-            process_queue.clone(),
-            process_queue_readers_state.clone(),
             action_queue.clone(),
             action_queue_state.clone(),
             _server_sock_addr4,
             my_data.clone(),
         );
         handshake(
-            process_queue.clone(),
-            process_queue_readers_state.clone(),
             action_queue.clone(),
             action_queue_state.clone(),
             sock_addr,
@@ -730,7 +1112,7 @@ mod tests {
                     action::Action::SendRoot(None, _server_sock_addr4),
                 );
                 QueueState::set_non_empty_queue(Arc::clone(&action_queue_state));
-                process_queue_state.wait();
+                process_queue_state.wait().await;
                 sleep(Duration::from_millis(100)).await;
                 let guard = active_peers.lock().unwrap();
                 /*If panics here, means the packet received had invalid hash (body length<32) */
@@ -745,21 +1127,21 @@ mod tests {
         };
 
         // keep_alive_to_peer(Arc::clone(&action_queue), Arc::clone(&action_queue_state), *&sock_addr);
+        let output = std::env::temp_dir().join("udp2p-dump");
+        let swarm = Swarm::new(&[_server_sock_addr4]);
         let fetch1 = download_from(
-            Arc::clone(&process_queue),
-            Arc::clone(&process_queue_readers_state),
-            Arc::clone(&action_queue),
-            Arc::clone(&action_queue_state),
+            &queues,
+            None,
             Arc::clone(&maps),
             // yoan_hash,
             peer_hash,
-            _server_sock_addr4,
+            &swarm,
             10000,
+            &output,
         );
         let _ = fetch1.await;
         // let fetch2 = fetch_subtree_from(
         //     Arc::clone(&process_queue),
-        //     Arc::clone(&process_queue_readers_state),
         //     Arc::clone(&action_queue),
         //     Arc::clone(&action_queue_state),
         //     Arc::clone(&maps),
@@ -785,117 +1167,92 @@ mod tests {
         };
     }
 
-    #[tokio::test(flavor = "multi_thread", worker_threads = 10)]
+    #[tokio::test]
     async fn register_and_fetch_file() {
-        // env_logger::init();
-        let sock4 = Arc::new(UdpSocket::bind("0.0.0.0:0").await.unwrap());
-        let sock6 = Arc::new(
-            UdpSocket::bind(SocketAddr::new("::1".parse().unwrap(), 0))
-                .await
-                .unwrap(),
+        let config = local_config().await;
+        let (dir, tree) = to_export("register-and-fetch");
+        let notes = fs::read(dir.join("notes")).unwrap();
+        let (root, file_hash) = (tree.hash, tree.children.as_ref().unwrap()[0].hash);
+
+        /*The peer exporting the file */
+        let exporter_sock4 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let mut exporter = Identity::generate().to_peer();
+        exporter
+            .set_name("nist-exporter".to_string())
+            .set_hash(Some(root));
+        let (_export, exported) = watch::channel(Some(Arc::new(tree)));
+        let exporter_queues = build_queues();
+        let exporter_tasks = task_launcher(
+            exporter_queues.clone(),
+            ActivePeers::build_mutex(),
+            exporter.clone(),
+            exporter_sock4.clone(),
+            exporter_sock4,
+            exported,
+            Arc::clone(&config),
         );
-        // let sock6 = Arc::new(
-        //     UdpSocket::bind(SocketAddr::new(
-        //         "fdb0:ccfe:b9b5:b600:47a1:849c:2d22:9ce9".parse().unwrap(),
-        //         // "2001:861:36c2:cdf0:4572:dd2e:473a:4081".parse().unwrap(),
-        //         0,
-        //     ))
-        //     .await
-        //     .unwrap(),
-        // );
+        register(&exporter_queues, Arc::new(exporter), &config, &[])
+            .await
+            .unwrap();
 
+        let sock4 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
         let maps = build_tree_mutex();
         let queues = build_queues();
         let active_peers = ActivePeers::build_mutex();
 
-        let _receive_queue_state = Arc::clone(&queues.5);
-        let action_queue = Arc::clone(&queues.2);
-        let action_queue_state = Arc::clone(&queues.6);
-        let process_queue = Arc::clone(&queues.3);
-        let _process_queue_state = Arc::clone(&queues.8);
-        let process_queue_readers_state = Arc::clone(&queues.9);
-
-        let mut my_data= Peer::new();
+        let mut my_data = Identity::generate().to_peer();
         my_data.set_name("nist".to_string());
-        let my_data_own = my_data.clone();
-        let my_data = Arc::new(my_data);
-
-        task_launcher(
-            queues,
+        let tasks = task_launcher(
+            queues.clone(),
             active_peers.clone(),
             my_data.clone(),
-            my_data_own,
             sock4.clone(),
-            sock6.clone(),
-            false,
-            PathBuf::from("/home/splash/files/dls"),
-        );
-
-        /*jch */
-        let server_sock_addr4: SocketAddr = "81.194.27.155:8443".parse().unwrap();
-        /*droso-srv */
-        let sock_addr: SocketAddr = "82.66.83.225:8000".parse().unwrap();
-        handshake(
-            process_queue.clone(),
-            process_queue_readers_state.clone(),
-            action_queue.clone(),
-            action_queue_state.clone(),
-            server_sock_addr4.clone(),
-            my_data.clone(),
-        );
-        handshake(
-            process_queue.clone(),
-            process_queue_readers_state.clone(),
-            action_queue.clone(),
-            action_queue_state.clone(),
-            sock_addr.clone(),
-            my_data.clone(),
+            sock4.clone(),
+            watch::channel(None).1,
+            Arc::clone(&config),
         );
+        let my_data = Arc::new(my_data);
+        register(&queues, Arc::clone(&my_data), &config, &[])
+            .await
+            .unwrap();
 
-        let root_hash = <[u8; 32]>::try_from(
-            hex::decode("19629b381026ff80f9b72ff6ea1a06ce6942081fdfa251611a950c081cd99629")
-                .unwrap(),
-        )
-        .unwrap();
-
-        let _file_hash = <[u8; 32]>::try_from(
-            hex::decode("4080d430508e6f1c68a19ee5f94466455a6ad430b24a864a4e4344135a88f5d2")
-                .unwrap(),
+        /*Found on the server, like any other peer */
+        let rest_server = config.get_rest_server().unwrap();
+        let recorded = discovery::get_peer_addresses(
+            rest_server.get_client(),
+            rest_server.get_url(),
+            "nist-exporter",
         )
+        .await
         .unwrap();
+        let sock_addr: SocketAddr = recorded.addresses[0].parse().unwrap();
+        let root_hash = hello(&queues, sock_addr, Arc::clone(&my_data), 3000)
+            .await
+            .unwrap();
+        assert_eq!(root_hash, Some(root));
 
-        let file = download_from(
-            Arc::clone(&process_queue),
-            Arc::clone(&process_queue_readers_state),
-            Arc::clone(&action_queue),
-            Arc::clone(&action_queue_state),
+        let output = std::env::temp_dir().join(format!("udp2p-dump-{}", std::process::id()));
+        let len = download_from(
+            &queues,
+            None,
             Arc::clone(&maps),
-            // yoan_hash,
-            root_hash,
-            server_sock_addr4,
-            10000,
+            file_hash,
+            &Swarm::new(&[sock_addr]),
+            3000,
+            &output,
         )
-        .await;
+        .await
+        .unwrap();
+        assert_eq!(len, notes.len() as u64);
+        assert_eq!(fs::read(&output).unwrap(), notes);
 
-        // let file = download_file(
-        //     Arc::clone(&process_queue),
-        //     Arc::clone(&process_queue_readers_state),
-        //     Arc::clone(&action_queue),
-        //     Arc::clone(&action_queue_state),
-        //     Arc::clone(&maps),
-        //     file_hash,
-        //     server_sock_addr4,
-        // )
-        // .await;
-
-        match file {
-            Ok(f) => println!("Got file {f:?}"),
-            Err(_e) => println!("Failed to get file"),
-        }
-        println!("FINISHED");
+        tasks.cancel();
+        exporter_tasks.cancel();
+        fs::remove_file(&output).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
     }
 
-    #[tokio::test(flavor = "multi_thread", worker_threads = 100)]
+    #[tokio::test]
     async fn keep_alive() {
         let sock6 = Arc::new(
             UdpSocket::bind(SocketAddr::new("::1".parse().unwrap(), 40000))
@@ -908,32 +1265,28 @@ mod tests {
         let queues = build_queues();
         let active_peers = ActivePeers::build_mutex();
 
-        let action_queue = Arc::clone(&queues.2);
-        let action_queue_state = Arc::clone(&queues.6);
-        let _send_queue = Arc::clone(&queues.1);
-        let _send_queue_state = Arc::clone(&queues.7);
-        let process_queue = Arc::clone(&queues.3);
-        let _process_queue_state = Arc::clone(&queues.8);
-        let process_queue_readers_state = Arc::clone(&queues.9);
+        let action_queue = Arc::clone(&queues.action_queue);
+        let action_queue_state = Arc::clone(&queues.action_queue_state);
+        let _send_queue = Arc::clone(&queues.send_queue);
+        let _send_queue_state = Arc::clone(&queues.send_queue_state);
+        let _process_queue = Arc::clone(&queues.process_queue);
+        let _process_queue_state = Arc::clone(&queues.process_queue_state);
 
+        let (config, server_sock_addr4) = jch_config().await;
         let mut my_data= Peer::new();
         my_data.set_name("nist".to_string());
-        let my_data_own = my_data.clone();
-        let my_data = Arc::new(my_data);
-
-        task_launcher(
+        let tasks = task_launcher(
             queues,
             active_peers.clone(),
             my_data.clone(),
-            my_data_own,
             sock4.clone(),
             sock6.clone(),
-            false,
-            PathBuf::from("/home/splash/files/dls"),
+            watch::channel(None).1,
+            config,
         );
+        let my_data = Arc::new(my_data);
 
         /*jch */
-        let server_sock_addr4: SocketAddr = "81.194.27.155:8443".parse().unwrap();
         // let sock_addr: SocketAddr = "[2001:660:3301:9200::51c2:1b9b]:8443".parse().unwrap();
         /*yoan */
         // let sock_addr: SocketAddr ="86.246.24.173:63801".parse().unwrap();
@@ -955,18 +1308,15 @@ mod tests {
             my_data.clone(),
             /*en nanosecs */
             5000000000,
+            tasks.get_cancel_token(),
         );
         handshake(
-            process_queue.clone(),
-            process_queue_readers_state.clone(),
             action_queue.clone(),
             action_queue_state.clone(),
             server_sock_addr4.clone(),
             my_data.clone(),
         );
         handshake(
-            process_queue.clone(),
-            process_queue_readers_state.clone(),
             action_queue.clone(),
             action_queue_state.clone(),
             sock_addr.clone(),
@@ -983,87 +1333,73 @@ mod tests {
         println!("main ends");
     }
 
-    #[tokio::test(flavor = "multi_thread", worker_threads = 100)]
+    #[tokio::test]
     async fn attempt_nat_traversal() {
-        let sock6 = Arc::new(
-            UdpSocket::bind(SocketAddr::new("::1".parse().unwrap(), 40000))
-                .await
-                .unwrap(),
+        let config = local_config().await;
+
+        /*The peer we can't reach first, it registered with the server */
+        let behind_nat_sock4 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let behind_nat_addr = behind_nat_sock4.local_addr().unwrap();
+        let behind_nat_queues = build_queues();
+        let behind_nat_peers = ActivePeers::build_mutex();
+        let mut behind_nat = Identity::generate().to_peer();
+        behind_nat.set_name("nist-behind-nat".to_string());
+        let behind_nat_tasks = task_launcher(
+            behind_nat_queues.clone(),
+            behind_nat_peers.clone(),
+            behind_nat.clone(),
+            behind_nat_sock4.clone(),
+            behind_nat_sock4,
+            watch::channel(None).1,
+            Arc::clone(&config),
         );
-        let sock4 = Arc::new(UdpSocket::bind("0.0.0.0:0").await.unwrap());
-        // let sock = Arc::new(UdpSocket::bind("192.168.1.90:40000").await.unwrap());
-        let _maps = build_tree_mutex();
+        register(&behind_nat_queues, Arc::new(behind_nat), &config, &[])
+            .await
+            .unwrap();
+
+        let sock4 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
         let queues = build_queues();
         let active_peers = ActivePeers::build_mutex();
 
-        let action_queue = Arc::clone(&queues.2);
-        let action_queue_state = Arc::clone(&queues.6);
+        let action_queue = Arc::clone(&queues.action_queue);
+        let action_queue_state = Arc::clone(&queues.action_queue_state);
 
-        let mut my_data= Peer::new();
+        let mut my_data = Identity::generate().to_peer();
         my_data.set_name("nist".to_string());
-        let my_data_own = my_data.clone();
-        let my_data = Arc::new(my_data);
-
-        task_launcher(
-            queues,
+        let tasks = task_launcher(
+            queues.clone(),
             active_peers.clone(),
             my_data.clone(),
-            my_data_own,
             sock4.clone(),
-            sock6.clone(),
-            false,
-            PathBuf::from("/home/splash/files/dls"),
+            sock4.clone(),
+            watch::channel(None).1,
+            Arc::clone(&config),
         );
+        let my_data = Arc::new(my_data);
+        register(&queues, Arc::clone(&my_data), &config, &[])
+            .await
+            .unwrap();
 
-        /*jch */
-        let _server_sock_addr: SocketAddr = "81.194.27.155:8443".parse().unwrap();
-        // let sock_addr: SocketAddr = "[2001:660:3301:9200::51c2:1b9b]:8443".parse().unwrap();
-        /*yoan */
-        // let sock_addr: SocketAddr ="86.246.24.173:63801".parse().unwrap();
-        /*Pas derriere un nat */
-        let sock_addr: SocketAddr = "82.66.83.225:8000".parse().unwrap();
-        let sock_addr_vec = vec![82, 66, 83, 225, 31, 64];
-        /*derriere un nat */
-        // let sock_addr: SocketAddr ="178.132.106.168:33313".parse().unwrap();
-        /*derriere un nat */
-        // let sock_addr: SocketAddr = "81.65.148.210:40214".parse().unwrap();
-        // let sock_addr: SocketAddr = "[2a02:842a:853e:b501:7211:24ff:fe8c:d728]:28469".parse().unwrap();
-        /*derriere un nat */
-        // let sock_addr: SocketAddr = "81.220.68.200:23834".parse().unwrap();
-        // let sock_addr: SocketAddr = "81.220.68.200:23834".parse().unwrap();
-
-        /* derriere un nat*/
-        // let sock_addr : SocketAddr = "81.65.148.210:40214".parse().unwrap();
-        // let sock_addr_vec = vec![81, 65, 148, 210, 157, 22];
-        /*derriere un nat */
-        // let sock_addr_vec = b"46.193.66.81:56766".to_vec();
-        // let sock_addr_vec = vec![46, 193, 66, 81, 221, 190];
-        /*Peer disappears when attempt nat trav */
-        // let sock_addr : SocketAddr = "80.215.153.182:2023".parse().unwrap();
-        // let sock_addr_vec = vec![80, 215, 153, 182, 7, 231];
-
-        // /*Disappears */
-        // let sock_addr : SocketAddr = "90.3.112.55:36546".parse().unwrap();
-        // let sock_addr_vec = vec![90, 3, 112, 55, 142, 194];
-
-        sleep(Duration::from_secs(2)).await;
-
-        try_nat_traversal_with(
-            action_queue.clone(),
-            action_queue_state.clone(),
-            sock_addr_vec,
-            /*en nanosecs */
-            1_000_000,
-        );
-        keep_alive_to_peer(
+        let _traversal = try_nat_traversal_with(
             action_queue,
             action_queue_state,
-            sock_addr,
-            my_data.clone(),
+            socket_addr_to_bytes(behind_nat_addr),
             /*en nanosecs */
-            9_000_000_000,
+            100_000_000,
+            config,
+            tasks.get_cancel_token(),
         );
-        sleep(Duration::from_secs(1_000)).await;
-        println!("main ends");
+
+        /*The server relays it, the peer behind the nat says Hello to us */
+        let my_addr = sock4.local_addr().unwrap();
+        let mut attempt = 0;
+        while ActivePeers::lock_and_get(Arc::clone(&behind_nat_peers), my_addr).is_none() {
+            attempt += 1;
+            assert!(attempt < 50, "Nat traversal wasn't relayed");
+            sleep(Duration::from_millis(100)).await;
+        }
+
+        tasks.cancel();
+        behind_nat_tasks.cancel();
     }
 }
@@ -0,0 +1,1084 @@
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use lib_file::mk_fs::MktFsNode;
+use log::info;
+use thiserror::Error;
+use tokio::{
+    net::UdpSocket,
+    sync::{broadcast, watch},
+};
+
+use crate::{
+    action::Action,
+    config::NodeConfig,
+    congestion_handler::{
+        build_queues_with_limits, CongestionWindow, DroppedCounts, PendingIds, Queues, RttEstimator,
+    },
+    import_export::{download_directory, download_from, handshake, hello, register},
+    peer::{ActivePeers, Peer, PeerError},
+    protocol::{CHUNK_SIZE, MAX_CHILDREN},
+    store::{build_tree_mutex, LocalEntry},
+    swarm::Swarm,
+    task_launcher_canceller::{task_launcher, Tasks, DRAIN_TIMEOUT},
+};
+
+/*Milliseconds to wait for a reply */
+const DEFAULT_TIMEOUT: u64 = 10_000;
+
+#[derive(Error, Debug)]
+pub enum NodeError {
+    #[error("Failed to bind {0} : {1}")]
+    Bind(SocketAddr, std::io::Error),
+    #[error("Failed to read {0} : {1}")]
+    Export(PathBuf, anyhow::Error),
+}
+
+/*What a download gave back : the length of the file written, or
+the entries of the directory recreated */
+#[derive(Debug)]
+pub enum Download {
+    File(u64),
+    Directory(Vec<LocalEntry>),
+}
+
+/*
+    Handle on a running node. Owns the sockets, the queues, the pending
+    ids, the active peers and our identity, and launches the tasks when
+    built. Every method only pushes actions and waits for the replies.
+*/
+pub struct Node {
+    queues: Queues,
+    active_peers: Arc<Mutex<ActivePeers>>,
+    /*Name and key pair, the root comes from the exported tree */
+    my_data: Peer,
+    exported: watch::Sender<Option<Arc<MktFsNode>>>,
+    config: Arc<NodeConfig>,
+    sock4: Arc<UdpSocket>,
+    sock6: Arc<UdpSocket>,
+    /*Dropping the node cancels its tasks */
+    tasks: Tasks,
+    timeout: u64,
+}
+
+impl Node {
+    pub async fn bind(
+        addr4: SocketAddr,
+        addr6: SocketAddr,
+        my_data: Peer,
+        config: Arc<NodeConfig>,
+    ) -> Result<Self, NodeError> {
+        let sock4 = match UdpSocket::bind(addr4).await {
+            Ok(sock4) => sock4,
+            Err(e) => return Err(NodeError::Bind(addr4, e)),
+        };
+        let sock6 = match UdpSocket::bind(addr6).await {
+            Ok(sock6) => sock6,
+            Err(e) => return Err(NodeError::Bind(addr6, e)),
+        };
+        Ok(Self::from_sockets(
+            Arc::new(sock4),
+            Arc::new(sock6),
+            my_data,
+            config,
+        ))
+    }
+
+    pub fn from_sockets(
+        sock4: Arc<UdpSocket>,
+        sock6: Arc<UdpSocket>,
+        mut my_data: Peer,
+        config: Arc<NodeConfig>,
+    ) -> Self {
+        let queues = build_queues_with_limits(config.get_queue_limits());
+        let active_peers = ActivePeers::build_mutex();
+        let (exported, exported_receiver) = watch::channel(None);
+        my_data.set_hash(None);
+
+        let tasks = task_launcher(
+            queues.clone(),
+            Arc::clone(&active_peers),
+            my_data.clone(),
+            Arc::clone(&sock4),
+            Arc::clone(&sock6),
+            exported_receiver,
+            Arc::clone(&config),
+        );
+
+        Self {
+            queues,
+            active_peers,
+            my_data,
+            exported,
+            config,
+            sock4,
+            sock6,
+            tasks,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    pub fn set_timeout(&mut self, timeout: u64) -> &mut Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn get_queues(&self) -> &Queues {
+        &self.queues
+    }
+    pub fn get_active_peers(&self) -> Arc<Mutex<ActivePeers>> {
+        Arc::clone(&self.active_peers)
+    }
+    pub fn get_config(&self) -> Arc<NodeConfig> {
+        Arc::clone(&self.config)
+    }
+    pub fn get_root(&self) -> Option<[u8; 32]> {
+        self.exported.borrow().as_ref().map(|tree| tree.hash)
+    }
+    /*Congestion window and rtt estimate of a peer, for diagnostics */
+    pub fn get_window(&self, sock_addr: SocketAddr) -> CongestionWindow {
+        PendingIds::lock_and_get_window(Arc::clone(&self.queues.pending_ids), sock_addr)
+    }
+    pub fn get_rtt(&self, sock_addr: SocketAddr) -> RttEstimator {
+        PendingIds::lock_and_get_rtt(Arc::clone(&self.queues.pending_ids), sock_addr)
+    }
+    /*What the full queues dropped, see NodeConfig::set_queue_limits */
+    pub fn get_dropped(&self) -> DroppedCounts {
+        self.queues.get_dropped()
+    }
+    pub fn local_addr4(&self) -> std::io::Result<SocketAddr> {
+        self.sock4.local_addr()
+    }
+    pub fn local_addr6(&self) -> std::io::Result<SocketAddr> {
+        self.sock6.local_addr()
+    }
+
+    /*Our peer as we announce it, with the root of the exported tree */
+    pub fn get_my_data(&self) -> Arc<Peer> {
+        let mut my_data = self.my_data.clone();
+        my_data.set_hash(self.get_root());
+        Arc::new(my_data)
+    }
+
+    /*Sends Hello, Root and PublicKey to sock_addr without waiting */
+    pub fn handshake(&self, sock_addr: SocketAddr) {
+        handshake(
+            Arc::clone(&self.queues.action_queue),
+            Arc::clone(&self.queues.action_queue_state),
+            sock_addr,
+            self.get_my_data(),
+        );
+    }
+
+    /*Handshakes with sock_addr and waits for it to reply */
+    pub async fn hello(&self, sock_addr: SocketAddr) -> Result<(), PeerError> {
+        hello(&self.queues, sock_addr, self.get_my_data(), self.timeout).await?;
+        Ok(())
+    }
+
+    /*Addresses our sockets are bound to, the unspecified ones tell
+    nothing to other peers */
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        let bound = [self.local_addr4(), self.local_addr6()];
+        let mut addresses = vec![];
+        for sock_addr in bound.into_iter().flatten() {
+            if !sock_addr.ip().is_unspecified() && !addresses.contains(&sock_addr) {
+                addresses.push(sock_addr);
+            }
+        }
+        addresses
+    }
+
+    /*Handshakes with every rendezvous server then publishes us on the
+    REST server with our bound addresses, see import_export::register */
+    pub async fn register(&self) -> Result<(), PeerError> {
+        register(
+            &self.queues,
+            self.get_my_data(),
+            &self.config,
+            &self.local_addrs(),
+        )
+        .await
+    }
+
+    /*Root exported by the peer at sock_addr, None if it exports nothing.
+    Fails with the flag of a peer the REST server disagrees with. */
+    pub async fn root_of(&self, sock_addr: SocketAddr) -> Result<Option<[u8; 32]>, PeerError> {
+        let root = hello(&self.queues, sock_addr, self.get_my_data(), self.timeout).await?;
+        match ActivePeers::lock_and_get_flag(Arc::clone(&self.active_peers), sock_addr) {
+            Some(flag) => Err(flag),
+            None => Ok(root),
+        }
+    }
+
+    /*Downloads the file with the given hash into output, or recreates
+    the directory with the given hash under output */
+    pub async fn download(
+        &self,
+        sock_addr: SocketAddr,
+        hash: [u8; 32],
+        output: &Path,
+    ) -> Result<Download, PeerError> {
+        self.swarm_download(&Swarm::new(&[sock_addr]), hash, output)
+            .await
+    }
+
+    /*Peers we can download root from, the ones that said it is theirs */
+    pub fn peers_with_root(&self, root: [u8; 32]) -> Vec<SocketAddr> {
+        ActivePeers::lock_and_get_with_root(Arc::clone(&self.active_peers), root)
+    }
+
+    /*Same as download, each datum asked to the peer of the swarm
+    expected to send it first. Every peer must have had a handshake.
+    The flagged ones are left out, whatever they send is checked
+    against hash anyway once the download started. */
+    pub async fn swarm_download(
+        &self,
+        swarm: &Swarm,
+        hash: [u8; 32],
+        output: &Path,
+    ) -> Result<Download, PeerError> {
+        for peer in swarm.get_peers() {
+            let sock_addr = peer.get_sock_addr();
+            if let Some(flag) =
+                ActivePeers::lock_and_get_flag(Arc::clone(&self.active_peers), sock_addr)
+            {
+                info!("Leaving {sock_addr} out of the download : {flag}");
+                swarm.exclude(sock_addr);
+            }
+        }
+        let maps = build_tree_mutex();
+        let chunk_store = self.config.get_chunk_store();
+        match download_from(
+            &self.queues,
+            chunk_store,
+            Arc::clone(&maps),
+            hash,
+            swarm,
+            self.timeout,
+            output,
+        )
+        .await
+        {
+            Ok(len) => Ok(Download::File(len)),
+            Err(PeerError::FileIsDirectory) => Ok(Download::Directory(
+                download_directory(
+                    &self.queues,
+                    chunk_store,
+                    maps,
+                    hash,
+                    swarm,
+                    self.timeout,
+                    output,
+                )
+                .await?,
+            )),
+            Err(e) => Err(e),
+        }
+    }
+
+    /*Everything received that no request of ours waits for : hellos,
+    roots and getdatums from other peers, nat traversals... */
+    pub fn subscribe(&self) -> broadcast::Receiver<Action> {
+        self.queues.events.subscribe()
+    }
+
+    pub fn is_shut_down(&self) -> bool {
+        self.tasks.is_cancelled()
+    }
+
+    /*Lets the queued packets go out, stops the keep-alives and
+    every other task, and waits for them to end */
+    pub async fn shutdown(mut self) {
+        info!("Shutting down");
+        self.tasks.shutdown(DRAIN_TIMEOUT).await;
+    }
+
+    /*Builds the tree of path and serves it in place of the previous
+    one. Returns its root, call register to publish it. */
+    pub async fn export(&self, path: &Path) -> Result<[u8; 32], NodeError> {
+        let tree_path = path.to_path_buf();
+        let tree = tokio::task::spawn_blocking(move || {
+            MktFsNode::try_from_path(&tree_path, CHUNK_SIZE, MAX_CHILDREN)
+        })
+        .await
+        .map_err(|e| NodeError::Export(path.to_path_buf(), e.into()))?
+        .map_err(|e| NodeError::Export(path.to_path_buf(), e))?;
+
+        let root = tree.hash;
+        info!(
+            "Exporting {} with root {}",
+            path.display(),
+            hex::encode(root)
+        );
+        self.exported.send_replace(Some(Arc::new(tree)));
+        Ok(root)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, fs, time::Duration};
+
+    use lib_web::discovery;
+    use rendezvous::RendezvousServer;
+    use sha2::{Digest, Sha256};
+    use tokio::time::timeout;
+
+    use super::*;
+    use crate::{
+        chunk_store::ChunkStore,
+        congestion_handler::INITIAL_WINDOW,
+        extensions::Extensions,
+        journal::{journal_path, Journal},
+        keystore::Identity,
+        packet::{Message, Packet, PacketBuilder, PacketType},
+        peer::Announced,
+        rate_limit::RateLimits,
+        rest_server::{MismatchPolicy, RestServer},
+        sender_receiver::HANDSHAKE_FIRST,
+    };
+
+    /*Journals the big files of node and its first written chunks, as
+    an interrupted download would have. Returns the hashes journaled */
+    fn journal_tree(
+        journal: &Journal,
+        node: &MktFsNode,
+        len: u64,
+        offset: &mut u64,
+        written: &mut usize,
+    ) -> Vec<[u8; 32]> {
+        match &node.children {
+            Some(children) => {
+                let hashes: Vec<[u8; 32]> = children.iter().map(|child| child.hash).collect();
+                journal.record_big(node.hash, &hashes).unwrap();
+                let mut journaled = vec![node.hash];
+                for child in children {
+                    journaled.extend(journal_tree(journal, child, len, offset, written));
+                }
+                journaled
+            }
+            None => {
+                let chunk_len = (len - *offset).min(CHUNK_SIZE as u64);
+                let chunk_offset = *offset;
+                *offset += chunk_len;
+                if *written == 0 {
+                    return vec![];
+                }
+                *written -= 1;
+                journal
+                    .record_chunk(Path::new(""), chunk_offset, node.hash, chunk_len)
+                    .unwrap();
+                vec![node.hash]
+            }
+        }
+    }
+
+    async fn local_node() -> Node {
+        local_node_with(NodeConfig::new()).await
+    }
+
+    async fn local_node_with(config: NodeConfig) -> Node {
+        let sock4 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        /*Nothing is sent over ipv6 */
+        let sock6 = Arc::clone(&sock4);
+        let mut node = Node::from_sockets(
+            sock4,
+            sock6,
+            Identity::generate().to_peer(),
+            Arc::new(config),
+        );
+        node.set_timeout(5000);
+        node
+    }
+
+    #[tokio::test]
+    async fn lib_network_node_export_and_hello() {
+        let dir = std::env::temp_dir().join(format!("udp2p-node-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("hello.txt"), b"hello from the exporter").unwrap();
+
+        let exporter = local_node().await;
+        let downloader = local_node().await;
+        let exporter_addr = exporter.local_addr4().unwrap();
+        assert_eq!(exporter.get_root(), None);
+
+        let root = exporter.export(&dir).await.unwrap();
+        assert_eq!(exporter.get_root(), Some(root));
+        assert_eq!(exporter.get_my_data().get_root_hash(), Some(root));
+
+        downloader.hello(exporter_addr).await.unwrap();
+        /*The RootReply to the handshake may come after the HelloReply */
+        let mut exported_root = None;
+        for _ in 0..50 {
+            exported_root = downloader
+                .get_active_peers()
+                .lock()
+                .unwrap()
+                .get(exporter_addr)
+                .and_then(|peer| peer.get_root_hash());
+            if exported_root.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(exported_root, Some(root));
+
+        fs::remove_dir_all(&dir).unwrap();
+
+        /*Both nodes stop without blocking the runtime */
+        timeout(Duration::from_secs(5), async {
+            exporter.shutdown().await;
+            downloader.shutdown().await;
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn lib_network_node_root_of_and_download() {
+        let dir = std::env::temp_dir().join(format!("udp2p-download-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let content = b"downloaded through the reply waiters".repeat(100);
+        fs::write(dir.join("file.txt"), &content).unwrap();
+        /*Two levels of big files, the last part is shorter */
+        let big_content: Vec<u8> = (0..CHUNK_SIZE * 33 + 100)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        fs::write(dir.join("big.bin"), &big_content).unwrap();
+        let output = dir.with_extension("out");
+
+        let exporter = local_node().await;
+        let downloader = local_node().await;
+        let exporter_addr = exporter.local_addr4().unwrap();
+        let mut exporter_events = exporter.subscribe();
+        let root = exporter.export(&dir).await.unwrap();
+
+        assert_eq!(downloader.root_of(exporter_addr).await.unwrap(), Some(root));
+        /*The exporter answered the handshake and still saw it */
+        match exporter_events.recv().await.unwrap() {
+            Action::ProcessHello(.., sock_addr) => {
+                assert_eq!(sock_addr, downloader.local_addr4().unwrap())
+            }
+            action => panic!("Unexpected {action:?}"),
+        }
+
+        /*The tree is recreated under tree, whatever the exporter named its files */
+        let tree = dir.with_extension("tree");
+        let entries = match downloader
+            .download(exporter_addr, root, &tree)
+            .await
+            .unwrap()
+        {
+            Download::Directory(entries) => entries,
+            Download::File(_) => panic!("The root is a directory"),
+        };
+        let mut downloaded: Vec<Vec<u8>> = entries
+            .iter()
+            .filter(|entry| !entry.is_directory)
+            .map(|entry| fs::read(tree.join(&entry.path)).unwrap())
+            .collect();
+        downloaded.sort();
+        let mut exported = vec![content.clone(), big_content.clone()];
+        exported.sort();
+        assert_eq!(downloaded, exported);
+        for (name, content) in [("file.txt", content), ("big.bin", big_content)] {
+            let file_hash = MktFsNode::try_from_path(&dir.join(name), CHUNK_SIZE, MAX_CHILDREN)
+                .unwrap()
+                .hash;
+            match downloader
+                .download(exporter_addr, file_hash, &output)
+                .await
+                .unwrap()
+            {
+                Download::File(len) => assert_eq!(len, content.len() as u64),
+                Download::Directory(_) => panic!("{name} is a file"),
+            }
+            assert_eq!(fs::read(&output).unwrap(), content);
+            assert!(!journal_path(&output).exists());
+        }
+
+        /*Output holds big.bin, as if its download stopped after 20 chunks */
+        let big = MktFsNode::try_from_path(&dir.join("big.bin"), CHUNK_SIZE, MAX_CHILDREN).unwrap();
+        let big_len = fs::metadata(&output).unwrap().len();
+        let journal = Journal::open(&output, big.hash).unwrap();
+        let journaled = journal_tree(&journal, &big, big_len, &mut 0, &mut 20);
+        drop(journal);
+        while exporter_events.try_recv().is_ok() {}
+        match downloader
+            .download(exporter_addr, big.hash, &output)
+            .await
+            .unwrap()
+        {
+            Download::File(len) => assert_eq!(len, big_len),
+            Download::Directory(_) => panic!("big.bin is a file"),
+        }
+        let mut requested = vec![];
+        while let Ok(action) = exporter_events.try_recv() {
+            if let Action::ProcessGetDatum(_, hash, _) = action {
+                requested.push(hash);
+            }
+        }
+        assert!(!requested.is_empty());
+        assert!(requested.iter().all(|hash| !journaled.contains(hash)));
+        assert!(!journal_path(&output).exists());
+        /*Every GetDatum got its reply */
+        let window = downloader.get_window(exporter_addr);
+        assert_eq!(window.get_in_flight(), 0);
+        assert!(window.get_cwnd() > INITIAL_WINDOW);
+        assert!(downloader.get_rtt(exporter_addr).get_srtt().is_some());
+        assert_eq!(
+            downloader
+                .download(exporter_addr, [0u8; 32], &output)
+                .await
+                .unwrap_err(),
+            PeerError::NoDatum
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_file(&output).unwrap();
+        fs::remove_dir_all(&tree).unwrap();
+        exporter.shutdown().await;
+        downloader.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn lib_network_node_reshares_from_chunk_store() {
+        let dir = std::env::temp_dir().join(format!("udp2p-reshare-{}", std::process::id()));
+        let other_dir = dir.with_extension("other");
+        fs::create_dir_all(&dir).unwrap();
+        fs::create_dir_all(&other_dir).unwrap();
+        let content = b"kept in the chunk store".repeat(500);
+        fs::write(dir.join("file.txt"), &content).unwrap();
+        fs::write(other_dir.join("other.txt"), b"exported by the resharer").unwrap();
+        let store_dir = dir.with_extension("store");
+        let output = dir.with_extension("out");
+
+        let exporter = local_node().await;
+        let mut config = NodeConfig::new();
+        config
+            .set_chunk_store(Some(ChunkStore::open(&store_dir, 1 << 20).unwrap()))
+            .set_reshare(true);
+        let resharer = local_node_with(config).await;
+        let downloader = local_node().await;
+        let root = exporter.export(&dir).await.unwrap();
+        let exporter_addr = exporter.local_addr4().unwrap();
+        assert_eq!(resharer.root_of(exporter_addr).await.unwrap(), Some(root));
+        resharer
+            .download(exporter_addr, root, &output)
+            .await
+            .unwrap();
+        fs::remove_dir_all(&output).unwrap();
+        exporter.shutdown().await;
+
+        /*Everything comes from the store once the exporter is gone */
+        let resharer_addr = resharer.local_addr4().unwrap();
+        resharer
+            .download(exporter_addr, root, &output)
+            .await
+            .unwrap();
+        fs::remove_dir_all(&output).unwrap();
+        /*The store is only served along an exported tree */
+        assert_eq!(downloader.root_of(resharer_addr).await.unwrap(), None);
+        assert_eq!(
+            downloader
+                .download(resharer_addr, root, &output)
+                .await
+                .unwrap_err(),
+            PeerError::NoDatum
+        );
+        resharer.export(&other_dir).await.unwrap();
+        let entries = match downloader
+            .download(resharer_addr, root, &output)
+            .await
+            .unwrap()
+        {
+            Download::Directory(entries) => entries,
+            Download::File(_) => panic!("The root is a directory"),
+        };
+        assert_eq!(entries.len(), 1);
+        assert_eq!(fs::read(output.join(&entries[0].path)).unwrap(), content);
+
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(&other_dir).unwrap();
+        fs::remove_dir_all(&output).unwrap();
+        fs::remove_dir_all(&store_dir).unwrap();
+        resharer.shutdown().await;
+        downloader.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn lib_network_node_swarm_download() {
+        let dir = std::env::temp_dir().join(format!("udp2p-swarm-{}", std::process::id()));
+        let other_dir = dir.with_extension("other");
+        fs::create_dir_all(&dir).unwrap();
+        fs::create_dir_all(&other_dir).unwrap();
+        let content: Vec<u8> = (0..CHUNK_SIZE * 33 + 100)
+            .map(|i| (i % 253) as u8)
+            .collect();
+        fs::write(dir.join("big.bin"), &content).unwrap();
+        fs::write(other_dir.join("other.txt"), b"something else").unwrap();
+        let output = dir.with_extension("out");
+
+        let (first, second, other) = (local_node().await, local_node().await, local_node().await);
+        let downloader = local_node().await;
+        let root = first.export(&dir).await.unwrap();
+        assert_eq!(second.export(&dir).await.unwrap(), root);
+        let other_root = other.export(&other_dir).await.unwrap();
+        let mut addresses = vec![];
+        for node in [&first, &second, &other] {
+            let sock_addr = node.local_addr4().unwrap();
+            addresses.push(sock_addr);
+            downloader.root_of(sock_addr).await.unwrap();
+        }
+        let mut sharing = addresses[..2].to_vec();
+        sharing.sort();
+        assert_eq!(downloader.peers_with_root(root), sharing);
+        assert_eq!(downloader.peers_with_root(other_root), vec![addresses[2]]);
+
+        /*other has none of it, the datums go to the two others */
+        let swarm = Swarm::new(&[addresses[2], addresses[0], addresses[1]]);
+        let hash = MktFsNode::try_from_path(&dir.join("big.bin"), CHUNK_SIZE, MAX_CHILDREN)
+            .unwrap()
+            .hash;
+        match downloader
+            .swarm_download(&swarm, hash, &output)
+            .await
+            .unwrap()
+        {
+            Download::File(len) => assert_eq!(len, content.len() as u64),
+            Download::Directory(_) => panic!("big.bin is a file"),
+        }
+        assert_eq!(fs::read(&output).unwrap(), content);
+        for peer in swarm.get_peers() {
+            match peer.get_sock_addr() == addresses[2] {
+                true => assert!(peer.get_failures() > 0 && peer.get_received() == 0),
+                false => assert!(peer.get_received() > 0 && peer.get_throughput().is_some()),
+            }
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(&other_dir).unwrap();
+        fs::remove_file(&output).unwrap();
+        for node in [first, second, other, downloader] {
+            node.shutdown().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn lib_network_node_download_without_peers() {
+        let downloader = local_node().await;
+        let output =
+            std::env::temp_dir().join(format!("udp2p-no-peers-{}.out", std::process::id()));
+        assert_eq!(
+            downloader
+                .swarm_download(&Swarm::new(&[]), [1u8; 32], &output)
+                .await
+                .unwrap_err(),
+            PeerError::NoPeers
+        );
+        assert!(!output.exists());
+        assert!(!journal_path(&output).exists());
+        downloader.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn lib_network_node_throttles_downloads() {
+        let dir = std::env::temp_dir().join(format!("udp2p-throttle-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let content = b"four chunks ".repeat(CHUNK_SIZE / 3);
+        fs::write(dir.join("file.txt"), &content).unwrap();
+        let file_hash = MktFsNode::try_from_path(&dir.join("file.txt"), CHUNK_SIZE, MAX_CHILDREN)
+            .unwrap()
+            .hash;
+
+        /*The first chunk fits in the bucket, the others are throttled */
+        let mut config = NodeConfig::new();
+        config.set_rate_limits(RateLimits::new(Some(2 * CHUNK_SIZE as u64), None));
+        let exporter = local_node_with(config).await;
+        let downloader = local_node().await;
+        let exporter_addr = exporter.local_addr4().unwrap();
+        exporter.export(&dir).await.unwrap();
+        downloader.root_of(exporter_addr).await.unwrap();
+
+        let output = dir.with_extension("out");
+        let start = std::time::Instant::now();
+        match downloader
+            .download(exporter_addr, file_hash, &output)
+            .await
+            .unwrap()
+        {
+            Download::File(len) => assert_eq!(len, content.len() as u64),
+            Download::Directory(_) => panic!("Downloaded a file"),
+        }
+        /*and asked for again until the bucket refills */
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        assert_eq!(fs::read(&output).unwrap(), content);
+
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_file(&output).unwrap();
+        exporter.shutdown().await;
+        downloader.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn lib_network_node_downloads_uneven_parts() {
+        /*A big file over chunks of 10, 30 and 5 bytes, as no exporter of
+        ours would cut it */
+        let datum = |data_type: u8, data: &[u8]| {
+            let datum = [&[data_type][..], data].concat();
+            let hash: [u8; 32] = Sha256::digest(&datum).into();
+            (hash, datum)
+        };
+        let parts = [vec![b'a'; 10], vec![b'b'; 30], vec![b'c'; 5]];
+        let mut datums: HashMap<[u8; 32], Vec<u8>> = HashMap::new();
+        let mut children = vec![];
+        for part in &parts {
+            let (hash, chunk) = datum(0, part);
+            children.extend_from_slice(&hash);
+            datums.insert(hash, chunk);
+        }
+        let (hash, big) = datum(1, &children);
+        datums.insert(hash, big);
+
+        /*The exporter only answers what the downloader asks */
+        let sock = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let exporter_addr = sock.local_addr().unwrap();
+        let exporter = tokio::spawn(async move {
+            loop {
+                let (sock_addr, packet) = Packet::recv_from(&sock).await.unwrap();
+                let id = packet.get_id();
+                let reply = match packet.message().unwrap() {
+                    Message::Hello { .. } => {
+                        PacketBuilder::hello_reply_packet(id, Extensions::empty(), b"raw".to_vec())
+                            .unwrap()
+                    }
+                    Message::PublicKey(_) => {
+                        PacketBuilder::public_key_reply_packet(None, *id).unwrap()
+                    }
+                    Message::Root(_) => PacketBuilder::root_reply_packet(id, None).unwrap(),
+                    Message::GetDatum(hash) => match datums.get(&hash) {
+                        Some(datum) => {
+                            PacketBuilder::datum_packet(id, hash, datum.clone()).unwrap()
+                        }
+                        None => PacketBuilder::nodatum_packet(id).unwrap(),
+                    },
+                    _ => continue,
+                };
+                reply.send_to_addr(&sock, &sock_addr).await.unwrap();
+            }
+        });
+
+        let downloader = local_node().await;
+        let output = std::env::temp_dir().join(format!("udp2p-uneven-{}.out", std::process::id()));
+        downloader.hello(exporter_addr).await.unwrap();
+        match downloader
+            .download(exporter_addr, hash, &output)
+            .await
+            .unwrap()
+        {
+            Download::File(len) => assert_eq!(len, 45),
+            Download::Directory(_) => panic!("The hash is a file"),
+        }
+        assert_eq!(fs::read(&output).unwrap(), parts.concat());
+        assert!(!journal_path(&output).exists());
+
+        fs::remove_file(&output).unwrap();
+        exporter.abort();
+        downloader.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn lib_network_node_negotiates_extensions() {
+        /*A peer advertising a feature we don't know, and no signatures */
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let peer_addr = sock.local_addr().unwrap();
+        let downloader = local_node().await;
+        let downloader_addr = downloader.local_addr4().unwrap();
+        let recv = |packet_type: PacketType| {
+            let sock = &sock;
+            async move {
+                loop {
+                    let (_, packet) = timeout(Duration::from_secs(5), Packet::recv_from(sock))
+                        .await
+                        .unwrap()
+                        .unwrap();
+                    if packet.is(packet_type) {
+                        return packet;
+                    }
+                }
+            }
+        };
+
+        let hello = tokio::spawn({
+            let (queues, my_data) = (downloader.queues.clone(), downloader.get_my_data());
+            async move { hello(&queues, peer_addr, my_data, 5000).await }
+        });
+        /*It may sign, as long as it didn't say otherwise */
+        let our_hello = recv(PacketType::Hello).await;
+        assert!(our_hello.get_signature().is_ok());
+        PacketBuilder::hello_reply_packet(
+            our_hello.get_id(),
+            Extensions::from_bytes([0, 0, 0, 0b1000]),
+            b"raw".to_vec(),
+        )
+        .unwrap()
+        .send_to_addr(&sock, &downloader_addr)
+        .await
+        .unwrap();
+
+        /*Then it doesn't */
+        let root = recv(PacketType::Root).await;
+        assert!(root.get_signature().is_err());
+        PacketBuilder::root_reply_packet(root.get_id(), None)
+            .unwrap()
+            .send_to_addr(&sock, &downloader_addr)
+            .await
+            .unwrap();
+        assert_eq!(hello.await.unwrap(), Ok(None));
+        assert_eq!(
+            ActivePeers::lock_and_get_extensions(downloader.get_active_peers(), peer_addr),
+            Some(Extensions::empty())
+        );
+
+        downloader.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn lib_network_node_caps_replies_to_unvalidated_addresses() {
+        let dir = std::env::temp_dir().join(format!("udp2p-amplification-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("file.txt"), b"amplified".repeat(100)).unwrap();
+
+        let exporter = local_node().await;
+        let exporter_addr = exporter.local_addr4().unwrap();
+        let root = exporter.export(&dir).await.unwrap();
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let recv = |packet_type: PacketType| {
+            let sock = &sock;
+            async move {
+                loop {
+                    let (_, packet) = timeout(Duration::from_secs(5), Packet::recv_from(sock))
+                        .await
+                        .unwrap()
+                        .unwrap();
+                    if packet.is(packet_type) {
+                        return packet;
+                    }
+                }
+            }
+        };
+
+        /*An address that didn't say Hello gets no NoDatum, small as it is */
+        let get_datum = PacketBuilder::get_datum_packet([0u8; 32]).unwrap();
+        get_datum.send_to_addr(&sock, &exporter_addr).await.unwrap();
+        let reply = recv(PacketType::ErrorReply).await;
+        assert_eq!(reply.get_id(), get_datum.get_id());
+        assert_eq!(&reply.get_body()[..], HANDSHAKE_FIRST);
+
+        /*A 39 bytes GetDatum doesn't buy a whole datum */
+        let get_datum = PacketBuilder::get_datum_packet(root).unwrap();
+        get_datum.send_to_addr(&sock, &exporter_addr).await.unwrap();
+        let reply = recv(PacketType::ErrorReply).await;
+        assert_eq!(reply.get_id(), get_datum.get_id());
+        assert_eq!(&reply.get_body()[..], HANDSHAKE_FIRST);
+
+        /*A short Hello gets the signed HelloReply and the Hello of the
+        exporter, three times as long as it is */
+        PacketBuilder::hello_packet(Extensions::empty(), b"raw".to_vec())
+            .unwrap()
+            .send_to_addr(&sock, &exporter_addr)
+            .await
+            .unwrap();
+        let (mut hello, mut replied) = (None, false);
+        while hello.is_none() || !replied {
+            let (_, packet) = timeout(Duration::from_secs(5), Packet::recv_from(&sock))
+                .await
+                .unwrap()
+                .unwrap();
+            replied |= packet.is(PacketType::HelloReply);
+            if packet.is(PacketType::Hello) {
+                hello = Some(packet);
+            }
+        }
+        let hello = hello.unwrap();
+
+        /*Replying to the Hello of the exporter validates our address */
+        PacketBuilder::hello_reply_packet(hello.get_id(), Extensions::empty(), b"raw".to_vec())
+            .unwrap()
+            .send_to_addr(&sock, &exporter_addr)
+            .await
+            .unwrap();
+        for _ in 0..50 {
+            if ActivePeers::lock_and_is_validated(
+                exporter.get_active_peers(),
+                sock.local_addr().unwrap(),
+            ) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let get_datum = PacketBuilder::get_datum_packet(root).unwrap();
+        get_datum.send_to_addr(&sock, &exporter_addr).await.unwrap();
+        let reply = recv(PacketType::Datum).await;
+        assert_eq!(reply.get_id(), get_datum.get_id());
+
+        fs::remove_dir_all(&dir).unwrap();
+        exporter.shutdown().await;
+    }
+
+    /*Flag and checks of the peer at sock_addr as seen by node */
+    fn checked_by(
+        node: &Node,
+        sock_addr: SocketAddr,
+        announced: &[Announced],
+    ) -> (Option<PeerError>, bool) {
+        let active_peers = node.get_active_peers();
+        let active_peers = active_peers.lock().unwrap();
+        match active_peers.get(sock_addr) {
+            Some(peer) => (
+                peer.get_flag(),
+                announced.iter().all(|announced| peer.is_checked(announced)),
+            ),
+            None => (None, false),
+        }
+    }
+
+    #[tokio::test]
+    async fn lib_network_node_checks_peers_off_the_process_task() {
+        /*A REST server that never answers */
+        let silent = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url =
+            discovery::parse_url(format!("http://{}/", silent.local_addr().unwrap())).unwrap();
+        let mut config = NodeConfig::new();
+        config.set_rest_server(Some(RestServer::new(url, 1, MismatchPolicy::Flag).unwrap()));
+        let checker = local_node_with(config).await;
+        let peer = local_node().await;
+        let peer_addr = peer.local_addr4().unwrap();
+
+        /*The handshake doesn't wait on the server */
+        let started = std::time::Instant::now();
+        assert_eq!(checker.root_of(peer_addr).await.unwrap(), None);
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        /*Which says nothing about the peer, the root is asked again later */
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert_eq!(
+            checked_by(&checker, peer_addr, &[Announced::Root(None)]),
+            (None, false)
+        );
+        assert_eq!(checker.root_of(peer_addr).await.unwrap(), None);
+        assert_eq!(checker.peers_with_root([0u8; 32]), vec![]);
+
+        checker.shutdown().await;
+        peer.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn lib_network_node_leaves_out_flagged_peers() {
+        let server = RendezvousServer::bind(
+            "127.0.0.1:0".parse().unwrap(),
+            "rendezvous",
+            Duration::from_secs(180),
+        )
+        .await
+        .unwrap();
+        let (server_addr, url) = (
+            server.local_addr(),
+            discovery::parse_url(server.url()).unwrap(),
+        );
+        server.spawn();
+        let dir = std::env::temp_dir().join(format!("udp2p-flagged-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("file.txt"), b"exported after registering").unwrap();
+        let output = dir.with_extension("out");
+
+        let mut config = NodeConfig::new();
+        config.add_rendezvous_server(server_addr);
+        let peer = local_node_with(config).await;
+        peer.register().await.unwrap();
+        /*The server still has no root for the peer */
+        let root = peer.export(&dir).await.unwrap();
+        let peer_addr = peer.local_addr4().unwrap();
+
+        let mut config = NodeConfig::new();
+        config.set_rest_server(Some(RestServer::new(url, 5, MismatchPolicy::Flag).unwrap()));
+        let checker = local_node_with(config).await;
+        /*Answered before the server is asked */
+        let _ = checker.root_of(peer_addr).await;
+        let mut flag = None;
+        for _ in 0..50 {
+            flag = checked_by(&checker, peer_addr, &[]).0;
+            if flag.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(flag, Some(PeerError::RootMismatch));
+
+        assert_eq!(
+            checker.root_of(peer_addr).await,
+            Err(PeerError::RootMismatch)
+        );
+        assert_eq!(checker.peers_with_root(root), vec![]);
+        assert_eq!(
+            checker
+                .download(peer_addr, root, &output)
+                .await
+                .unwrap_err(),
+            PeerError::NoPeers
+        );
+        assert!(!output.exists());
+        assert!(!journal_path(&output).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+        checker.shutdown().await;
+        peer.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn lib_network_node_checks_peers_once() {
+        let server = RendezvousServer::bind(
+            "127.0.0.1:0".parse().unwrap(),
+            "rendezvous",
+            Duration::from_secs(180),
+        )
+        .await
+        .unwrap();
+        let (server_addr, url) = (
+            server.local_addr(),
+            discovery::parse_url(server.url()).unwrap(),
+        );
+        server.spawn();
+
+        let mut config = NodeConfig::new();
+        config.add_rendezvous_server(server_addr);
+        let peer = local_node_with(config).await;
+        peer.register().await.unwrap();
+        let peer_addr = peer.local_addr4().unwrap();
+        let public_key = peer.get_my_data().get_public_key();
+
+        let mut config = NodeConfig::new();
+        config.set_rest_server(Some(RestServer::new(url, 5, MismatchPolicy::Flag).unwrap()));
+        let checker = local_node_with(config).await;
+        checker.root_of(peer_addr).await.unwrap();
+
+        /*What the server confirmed isn't asked again */
+        let announced = [Announced::PublicKey(public_key), Announced::Root(None)];
+        let mut checked = (None, false);
+        for _ in 0..50 {
+            checked = checked_by(&checker, peer_addr, &announced);
+            if checked.1 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(checked, (None, true));
+
+        checker.shutdown().await;
+        peer.shutdown().await;
+    }
+}
@@ -1,6 +1,8 @@
+use bytes::{Bytes, BytesMut};
 use log::debug;
 use prelude::*;
 use std::fmt::Display;
+use thiserror::Error;
 
 // use crate::peer_data::*;
 
@@ -12,12 +14,45 @@ use sha2::{Digest, Sha256};
 use anyhow::Result;
 
 /*Async/net libraries */
-use std::net::{IpAddr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use tokio::net::UdpSocket;
 
+use crate::extensions::Extensions;
+use crate::protocol::*;
+
 pub const HASH_OF_EMPTY_STRING: &str =
     "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
 
+/*Addresses in NatTraversal bodies are the ip (4 or 16 bytes)
+followed by the port in big endian */
+pub fn socket_addr_to_bytes(sock_addr: SocketAddr) -> Vec<u8> {
+    let mut raw_sock_addr = match sock_addr.ip() {
+        IpAddr::V4(ip4_addr) => ip4_addr.octets().to_vec(),
+        IpAddr::V6(ip6_addr) => ip6_addr.octets().to_vec(),
+    };
+    raw_sock_addr.extend_from_slice(&sock_addr.port().to_be_bytes());
+    raw_sock_addr
+}
+
+pub fn socket_addr_from_bytes(raw_sock_addr: &[u8]) -> Option<SocketAddr> {
+    let ip_addr = match raw_sock_addr.len() {
+        6 => {
+            let mut octets = [0u8; 4];
+            octets.copy_from_slice(&raw_sock_addr[0..4]);
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        18 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&raw_sock_addr[0..16]);
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return None,
+    };
+    let port_index = raw_sock_addr.len() - 2;
+    let port = u16::from_be_bytes([raw_sock_addr[port_index], raw_sock_addr[port_index + 1]]);
+    Some(SocketAddr::new(ip_addr, port))
+}
+
 #[derive(Debug)]
 pub enum PacketError {
     NoIdError,
@@ -27,6 +62,9 @@ pub enum PacketError {
     NoSignatureError,
     InvalidIdError,
     InvalidFormatError,
+    BodyTooLongError,
+    DatagramTooLongError,
+    TruncatedError,
     UnknownError,
 }
 
@@ -40,6 +78,11 @@ impl std::fmt::Display for PacketError {
             PacketError::NoSignatureError => write!(f, "Unsigned Packet"),
             PacketError::InvalidIdError => write!(f, "Packet has invalid Id"),
             PacketError::InvalidFormatError => write!(f, "Packet has invalid format"),
+            PacketError::BodyTooLongError => write!(f, "Packet body is too long"),
+            PacketError::DatagramTooLongError => {
+                write!(f, "Datagram is longer than the protocol allows")
+            }
+            PacketError::TruncatedError => write!(f, "Packet is shorter than its length"),
             PacketError::UnknownError => write!(f, "Packet has invalid format"),
         }
     }
@@ -85,6 +128,18 @@ impl Display for PacketType {
 }
 
 impl PacketType {
+    /*Packets that must carry a signature when the sender advertised a public key */
+    pub fn is_signed(&self) -> bool {
+        matches!(
+            self,
+            PacketType::Hello
+                | PacketType::HelloReply
+                | PacketType::Root
+                | PacketType::RootReply
+                | PacketType::Datum
+        )
+    }
+
     fn from_u8(val: u8) -> Result<PacketType, PacketError> {
         match val {
             0 => return Ok(PacketType::NoOp),
@@ -106,12 +161,172 @@ impl PacketType {
     }
 }
 
+/*Why the body of a packet couldn't be decoded, sent back to its sender */
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    #[error("{0} body is too short")]
+    TooShort(PacketType),
+    #[error("Public key must be 0 or 64 bytes, not {0}")]
+    InvalidPublicKeyLength(usize),
+    #[error("Datum doesn't match its hash")]
+    InvalidHash,
+}
+
+/*
+    Body of a packet read according to its type. The byte fields are
+    slices of the buffer the packet was received in, not copies.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    NoOp,
+    Error(Bytes),
+    Hello { extensions: Extensions, name: Bytes },
+    PublicKey(Option<[u8; 64]>),
+    Root(Option<[u8; 32]>),
+    GetDatum([u8; 32]),
+    NatTraversalRequest(Bytes),
+    NatTraversal(Bytes),
+
+    ErrorReply(Bytes),
+    HelloReply { extensions: Extensions, name: Bytes },
+    PublicKeyReply(Option<[u8; 64]>),
+    RootReply(Option<[u8; 32]>),
+    /*node is the mkfs node : its type then its content */
+    Datum {
+        hash: [u8; 32],
+        node: Bytes,
+    },
+    NoDatum,
+}
+
+impl Message {
+    pub fn get_packet_type(&self) -> PacketType {
+        match self {
+            Message::NoOp => PacketType::NoOp,
+            Message::Error(_) => PacketType::Error,
+            Message::Hello { .. } => PacketType::Hello,
+            Message::PublicKey(_) => PacketType::PublicKey,
+            Message::Root(_) => PacketType::Root,
+            Message::GetDatum(_) => PacketType::GetDatum,
+            Message::NatTraversalRequest(_) => PacketType::NatTraversalRequest,
+            Message::NatTraversal(_) => PacketType::NatTraversal,
+            Message::ErrorReply(_) => PacketType::ErrorReply,
+            Message::HelloReply { .. } => PacketType::HelloReply,
+            Message::PublicKeyReply(_) => PacketType::PublicKeyReply,
+            Message::RootReply(_) => PacketType::RootReply,
+            Message::Datum { .. } => PacketType::Datum,
+            Message::NoDatum => PacketType::NoDatum,
+        }
+    }
+
+    pub fn decode(packet_type: PacketType, body: &Bytes) -> Result<Message, DecodeError> {
+        let message = match packet_type {
+            PacketType::NoOp => Message::NoOp,
+            PacketType::Error => Message::Error(body.clone()),
+            PacketType::Hello => {
+                let (extensions, name) = decode_hello(body, packet_type)?;
+                Message::Hello { extensions, name }
+            }
+            PacketType::PublicKey => Message::PublicKey(decode_public_key(body)?),
+            PacketType::Root => Message::Root(decode_root(body, packet_type)?),
+            PacketType::GetDatum => Message::GetDatum(decode_hash(body, packet_type)?),
+            PacketType::NatTraversalRequest => Message::NatTraversalRequest(body.clone()),
+            PacketType::NatTraversal => Message::NatTraversal(body.clone()),
+            PacketType::ErrorReply => Message::ErrorReply(body.clone()),
+            PacketType::HelloReply => {
+                let (extensions, name) = decode_hello(body, packet_type)?;
+                Message::HelloReply { extensions, name }
+            }
+            PacketType::PublicKeyReply => Message::PublicKeyReply(decode_public_key(body)?),
+            PacketType::RootReply => Message::RootReply(decode_root(body, packet_type)?),
+            PacketType::Datum => Message::Datum {
+                hash: decode_hash(body, packet_type)?,
+                node: body.slice(32..),
+            },
+            PacketType::NoDatum => Message::NoDatum,
+        };
+        Ok(message)
+    }
+
+    pub fn encode(&self) -> Bytes {
+        let mut body = BytesMut::new();
+        match self {
+            Message::NoOp | Message::NoDatum => (),
+            Message::Error(bytes)
+            | Message::ErrorReply(bytes)
+            | Message::NatTraversalRequest(bytes)
+            | Message::NatTraversal(bytes) => body.extend_from_slice(bytes),
+            Message::Hello { extensions, name } | Message::HelloReply { extensions, name } => {
+                body.extend_from_slice(&extensions.to_bytes());
+                body.extend_from_slice(name);
+            }
+            Message::PublicKey(public_key) | Message::PublicKeyReply(public_key) => {
+                if let Some(public_key) = public_key {
+                    body.extend_from_slice(public_key);
+                }
+            }
+            Message::Root(root) | Message::RootReply(root) => match root {
+                Some(root) => body.extend_from_slice(root),
+                None => body.extend_from_slice(&hex::decode(HASH_OF_EMPTY_STRING).unwrap()),
+            },
+            Message::GetDatum(hash) => body.extend_from_slice(hash),
+            Message::Datum { hash, node } => {
+                body.extend_from_slice(hash);
+                body.extend_from_slice(node);
+            }
+        }
+        body.freeze()
+    }
+}
+
+/*Extensions then name */
+fn decode_hello(body: &Bytes, packet_type: PacketType) -> Result<(Extensions, Bytes), DecodeError> {
+    if body.len() < 4 {
+        return Err(DecodeError::TooShort(packet_type));
+    }
+    let mut extensions: [u8; 4] = [0; 4];
+    extensions.copy_from_slice(&body[0..4]);
+    Ok((Extensions::from_bytes(extensions), body.slice(4..)))
+}
+
+fn decode_public_key(body: &[u8]) -> Result<Option<[u8; 64]>, DecodeError> {
+    match body.len() {
+        /*Peer doesn't implement signatures */
+        0 => Ok(None),
+        /*Peer implements signatures */
+        PUBLIC_KEY_LENGTH => {
+            let mut public_key: [u8; 64] = [0; 64];
+            public_key.copy_from_slice(body);
+            Ok(Some(public_key))
+        }
+        length => Err(DecodeError::InvalidPublicKeyLength(length)),
+    }
+}
+
+fn decode_hash(body: &[u8], packet_type: PacketType) -> Result<[u8; 32], DecodeError> {
+    if body.len() < HASH_LENGTH {
+        return Err(DecodeError::TooShort(packet_type));
+    }
+    let mut hash: [u8; 32] = [0; 32];
+    hash.copy_from_slice(&body[0..32]);
+    Ok(hash)
+}
+
+/*The hash of the empty string means no root */
+fn decode_root(body: &[u8], packet_type: PacketType) -> Result<Option<[u8; 32]>, DecodeError> {
+    let root = decode_hash(body, packet_type)?;
+    match hex::encode(root) == HASH_OF_EMPTY_STRING {
+        true => Ok(None),
+        false => Ok(Some(root)),
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Packet {
     id: [u8; 4],
     packet_type: PacketType,
     length: usize,
-    body: Vec<u8>,
+    body: Bytes,
     signature: Option<[u8; 64]>,
 }
 
@@ -124,7 +339,7 @@ impl Default for Packet {
             id,
             packet_type: PacketType::NoOp,
             length: 0,
-            body: Vec::new(),
+            body: Bytes::new(),
             signature: None,
         };
     }
@@ -135,7 +350,7 @@ pub struct PacketBuilder {
     id: Option<[u8; 4]>,
     packet_type: Option<PacketType>,
     length: Option<usize>,
-    body: Option<Vec<u8>>,
+    body: Option<Bytes>,
     signature: Option<[u8; 64]>,
 }
 
@@ -154,6 +369,9 @@ impl PacketBuilder {
         let Some(body) = self.body.to_owned() else {
             return Err(PacketError::NoBodyError);
         };
+        if length > MAX_BODY_LENGTH {
+            return Err(PacketError::BodyTooLongError);
+        }
 
         Ok(Packet {
             id: id,
@@ -168,187 +386,106 @@ impl PacketBuilder {
         Self {
             /*nanorand the id */
             packet_type: Some(PacketType::NoOp),
-            body: Some(vec![]),
+            body: Some(Bytes::new()),
             length: Some(0),
             ..PacketBuilder::default()
         }
     }
-    pub fn noop_packet() -> Packet {
-        let hello_packet = PacketBuilder::new()
-            .gen_id()
-            .packet_type(PacketType::NoOp)
-            .build();
-        hello_packet.unwrap()
+    pub fn noop_packet() -> Result<Packet, PacketError> {
+        PacketBuilder::request(Message::NoOp)
     }
-    pub fn hello_packet(extensions: Option<&[u8; 4]>, name: Vec<u8>) -> Packet {
-        let mut body = match extensions {
-            Some(extensions) => extensions.to_vec(),
-            None => vec![0, 0, 0, 0],
-        };
-        let mut name = name.clone();
-        body.append(&mut name);
-        let hello_packet = PacketBuilder::new()
-            .gen_id()
-            .body(body)
-            .packet_type(PacketType::Hello)
-            .build();
-        hello_packet.unwrap()
+    pub fn hello_packet(extensions: Extensions, name: Vec<u8>) -> Result<Packet, PacketError> {
+        PacketBuilder::request(Message::Hello {
+            extensions,
+            name: name.into(),
+        })
     }
 
-    pub fn hello_reply_packet(id: &[u8; 4], extensions: Option<[u8; 4]>, name: Vec<u8>) -> Packet {
-        let mut body = match extensions {
-            Some(extensions) => extensions.to_vec(),
-            None => vec![0, 0, 0, 0],
-        };
-        let mut name = name.clone();
-        body.append(&mut name);
-        let hello_packet = PacketBuilder::new()
-            .set_id(*id)
-            .body(body)
-            .packet_type(PacketType::HelloReply)
-            .build();
-        hello_packet.unwrap()
+    pub fn hello_reply_packet(
+        id: &[u8; 4],
+        extensions: Extensions,
+        name: Vec<u8>,
+    ) -> Result<Packet, PacketError> {
+        PacketBuilder::reply(
+            id,
+            Message::HelloReply {
+                extensions,
+                name: name.into(),
+            },
+        )
     }
-    pub fn error_packet(err_msg: Option<Vec<u8>>) -> Packet {
-        let err_msg = match err_msg {
-            Some(err_msg) => err_msg.to_vec(),
-            None => vec![],
-        };
-
-        let err_packet = PacketBuilder::new()
-            .gen_id()
-            .body(err_msg)
-            .packet_type(PacketType::Error)
-            .build();
-
-        err_packet.unwrap()
+    pub fn error_packet(err_msg: Option<Vec<u8>>) -> Result<Packet, PacketError> {
+        PacketBuilder::request(Message::Error(err_msg.unwrap_or_default().into()))
     }
-    pub fn error_reply_packet(id: &[u8; 4], err_msg: Option<Vec<u8>>) -> Packet {
-        let err_msg = match err_msg {
-            Some(err_msg) => err_msg.to_vec(),
-            None => vec![],
-        };
-
-        let err_reply_packet = PacketBuilder::new()
-            .set_id(*id)
-            .body(err_msg)
-            .packet_type(PacketType::ErrorReply)
-            .build();
-
-        err_reply_packet.unwrap()
+    pub fn error_reply_packet(
+        id: &[u8; 4],
+        err_msg: Option<Vec<u8>>,
+    ) -> Result<Packet, PacketError> {
+        PacketBuilder::reply(id, Message::ErrorReply(err_msg.unwrap_or_default().into()))
     }
-    pub fn public_key_packet(public_key: Option<[u8; 64]>) -> Packet {
-        let public_key = match public_key {
-            Some(public_key) => public_key.to_vec(),
-            None => vec![],
-        };
-
-        let public_key_packet = PacketBuilder::new()
-            .gen_id()
-            .body(public_key)
-            .packet_type(PacketType::PublicKey)
-            .build();
-
-        public_key_packet.unwrap()
+    pub fn public_key_packet(public_key: Option<[u8; 64]>) -> Result<Packet, PacketError> {
+        PacketBuilder::request(Message::PublicKey(public_key))
     }
-    pub fn public_key_reply_packet(public_key: Option<[u8; 64]>, id: [u8; 4]) -> Packet {
-        let public_key = match public_key {
-            Some(public_key) => public_key.to_vec(),
-            None => vec![],
-        };
-        let public_key_packet = PacketBuilder::new()
-            .set_id(id)
-            .body(public_key)
-            .packet_type(PacketType::PublicKeyReply)
-            .build();
-
-        public_key_packet.unwrap()
+    pub fn public_key_reply_packet(
+        public_key: Option<[u8; 64]>,
+        id: [u8; 4],
+    ) -> Result<Packet, PacketError> {
+        PacketBuilder::reply(&id, Message::PublicKeyReply(public_key))
     }
 
-    pub fn root_packet(root: Option<[u8; 32]>) -> Packet {
-        let root = match root {
-            Some(root) => root.to_vec(),
-            None => hex::decode(HASH_OF_EMPTY_STRING).unwrap(),
-        };
-
-        let root_packet = PacketBuilder::new()
-            .gen_id()
-            .body(root)
-            .packet_type(PacketType::Root)
-            .build();
-
-        root_packet.unwrap()
+    pub fn root_packet(root: Option<[u8; 32]>) -> Result<Packet, PacketError> {
+        PacketBuilder::request(Message::Root(root))
     }
 
-    pub fn root_reply_packet(id: &[u8; 4], root: Option<[u8; 32]>) -> Packet {
-        let root = match root {
-            Some(root) => root.to_vec(),
-            None => hex::decode(HASH_OF_EMPTY_STRING).unwrap(),
-        };
-
-        let root_packet = PacketBuilder::new()
-            .set_id(*id)
-            .body(root)
-            .packet_type(PacketType::RootReply)
-            .build();
-
-        root_packet.unwrap()
+    pub fn root_reply_packet(id: &[u8; 4], root: Option<[u8; 32]>) -> Result<Packet, PacketError> {
+        PacketBuilder::reply(id, Message::RootReply(root))
     }
-    pub fn get_datum_packet(hash: [u8; 32]) -> Packet {
-        let get_datum_packet = PacketBuilder::new()
-            .gen_id()
-            .body(hash.to_vec())
-            .packet_type(PacketType::GetDatum)
-            .build();
-
-        get_datum_packet.unwrap()
+    pub fn get_datum_packet(hash: [u8; 32]) -> Result<Packet, PacketError> {
+        PacketBuilder::request(Message::GetDatum(hash))
     }
-    pub fn datum_packet(id: &[u8; 4], hash: [u8; 32], datum: Vec<u8>) -> Packet {
-        let mut body = hash.to_vec();
-        let mut datum = datum.clone();
-        body.append(&mut datum);
-        let datum_packet = PacketBuilder::new()
-            .set_id(*id)
-            .body(body)
-            .packet_type(PacketType::Datum)
-            .build();
-
-        datum_packet.unwrap()
+    pub fn datum_packet(
+        id: &[u8; 4],
+        hash: [u8; 32],
+        datum: Vec<u8>,
+    ) -> Result<Packet, PacketError> {
+        PacketBuilder::reply(
+            id,
+            Message::Datum {
+                hash,
+                node: datum.into(),
+            },
+        )
     }
-    pub fn nodatum_packet(id: &[u8; 4]) -> Packet {
-        let nodatum_packet = PacketBuilder::new()
-            .set_id(*id)
-            .body(vec![])
-            .packet_type(PacketType::NoDatum)
-            .build();
-
-        nodatum_packet.unwrap()
+    pub fn nodatum_packet(id: &[u8; 4]) -> Result<Packet, PacketError> {
+        PacketBuilder::reply(id, Message::NoDatum)
     }
-    pub fn nat_traversal_request_packet(behind_nat_addr: Vec<u8>) -> Packet {
-        let nat_traversal_requet_packet = PacketBuilder::new()
-            .gen_id()
-            .body(behind_nat_addr)
-            .packet_type(PacketType::NatTraversalRequest)
-            .build();
-
-        nat_traversal_requet_packet.unwrap()
+    pub fn nat_traversal_request_packet(behind_nat_addr: Vec<u8>) -> Result<Packet, PacketError> {
+        PacketBuilder::request(Message::NatTraversalRequest(behind_nat_addr.into()))
+    }
+    pub fn nat_traversal_request_from_addr_packet(
+        behind_nat_addr: SocketAddr,
+    ) -> Result<Packet, PacketError> {
+        PacketBuilder::nat_traversal_request_packet(socket_addr_to_bytes(behind_nat_addr))
+    }
+    /*Sent by rendezvous servers to the peer behind a nat, the body
+    is the address of the peer asking for the traversal */
+    pub fn nat_traversal_packet(requester_addr: SocketAddr) -> Result<Packet, PacketError> {
+        PacketBuilder::request(Message::NatTraversal(
+            socket_addr_to_bytes(requester_addr).into(),
+        ))
     }
-    pub fn nat_traversal_request_from_addr_packet(behind_nat_addr: SocketAddr) -> Packet {
-        let raw_socketaddr = {
-            let ip_addr = behind_nat_addr.ip();
-            let port = behind_nat_addr.port();
-
-            let mut parse_sock_addr = match ip_addr {
-                IpAddr::V4(ip4_addr) => ip4_addr.octets().to_vec(),
-                IpAddr::V6(ip6_addr) => ip6_addr.octets().to_vec(),
-            };
 
-            parse_sock_addr.push((port >> 8) as u8);
-            parse_sock_addr.push((port & 0xff) as u8);
-            parse_sock_addr
-        };
+    /*Fails if the body of message is longer than MAX_BODY_LENGTH */
+    fn request(message: Message) -> Result<Packet, PacketError> {
+        PacketBuilder::new().gen_id().message(&message).build()
+    }
+    fn reply(id: &[u8; 4], message: Message) -> Result<Packet, PacketError> {
+        PacketBuilder::new().set_id(*id).message(&message).build()
+    }
 
-        PacketBuilder::nat_traversal_request_packet(raw_socketaddr)
+    pub fn message(&mut self, message: &Message) -> &mut Self {
+        self.packet_type(message.get_packet_type())
+            .body(message.encode())
     }
 
     pub fn packet_type(&mut self, packet_type: PacketType) -> &mut Self {
@@ -372,15 +509,10 @@ impl PacketBuilder {
         self.id = Some(id);
         self
     }
-    pub fn body(&mut self, body: Vec<u8>) -> &mut Self {
-        let body_length = body.len();
-
-        self.length = match body_length {
-            0..=1128 => Some(body_length),
-            1129.. => panic!("Invalid packet"),
-            _ => panic!("Shouldn't happen"),
-        };
-
+    /*A body longer than MAX_BODY_LENGTH makes build fail */
+    pub fn body(&mut self, body: impl Into<Bytes>) -> &mut Self {
+        let body = body.into();
+        self.length = Some(body.len());
         self.body = Some(body);
 
         self
@@ -395,9 +527,12 @@ impl Packet {
     pub fn get_id(&self) -> &[u8; 4] {
         &self.id
     }
-    pub fn get_body(&self) -> &Vec<u8> {
+    pub fn get_body(&self) -> &Bytes {
         &self.body
     }
+    pub fn message(&self) -> Result<Message, DecodeError> {
+        Message::decode(self.packet_type, &self.body)
+    }
     pub fn get_body_length(&self) -> usize {
         self.length
     }
@@ -410,6 +545,9 @@ impl Packet {
             None => Err(PacketError::NoSignatureError),
         }
     }
+    pub fn set_signature(&mut self, signature: Option<[u8; 64]>) {
+        self.signature = signature;
+    }
     pub fn is_response(&self) -> bool {
         let packet_type = self.packet_type as u8;
         match packet_type {
@@ -422,7 +560,8 @@ impl Packet {
         *self.get_packet_type() == other_packet_type
     }
 
-    pub fn as_bytes(&self) -> Vec<u8> {
+    /*Header and body only, this is what gets signed */
+    pub fn as_unsigned_bytes(&self) -> Vec<u8> {
         let mut packet_buf: Vec<u8> = vec![];
         for byte in self.id {
             packet_buf.push(byte);
@@ -433,38 +572,33 @@ impl Packet {
         packet_buf.push(((self.length >> 8) & 0xff).try_into().unwrap());
         packet_buf.push((self.length & 0xff).try_into().unwrap());
 
-        for byte in &self.body {
-            /*byte is copied ? */
-            packet_buf.push(*byte);
-        }
+        packet_buf.extend_from_slice(&self.body);
 
-        match self.signature {
-            Some(sig) => {
-                for byte in sig {
-                    packet_buf.push(byte);
-                }
-            }
-            None => (),
+        packet_buf
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut packet_buf = self.as_unsigned_bytes();
+
+        if let Some(sig) = self.signature {
+            packet_buf.extend_from_slice(&sig);
         }
 
         packet_buf
     }
 
-    /*Using drain to check if empty after,
-    maybe should be verified manually so that
-    an invalid packet doesn't make the program
-        panic */
-    pub fn from_bytes(raw_packet: &mut Vec<u8>) -> Result<Packet, PacketError> {
+    /*The body is a slice of raw_packet, it isn't copied */
+    pub fn from_bytes(raw_packet: Bytes) -> Result<Packet, PacketError> {
         /*Should check len */
         let mut len = raw_packet.len();
         let mut copied = 0;
-        if len < 7 {
-            return Err(PacketError::InvalidFormatError);
+        if len < HEADER_LENGTH {
+            return Err(PacketError::TruncatedError);
         }
-        len -= 7;
+        len -= HEADER_LENGTH;
 
         let mut id: [u8; 4] = [0; 4];
-        id.copy_from_slice(&raw_packet.as_slice()[copied..4]);
+        id.copy_from_slice(&raw_packet[copied..4]);
         copied += 4;
 
         let packet_type = PacketType::from_u8(raw_packet[copied])?;
@@ -472,7 +606,7 @@ impl Packet {
 
         let length = {
             let mut length_as_bytes: [u8; 2] = [0; 2];
-            length_as_bytes.copy_from_slice(&raw_packet.as_slice()[copied..(copied + 2)]);
+            length_as_bytes.copy_from_slice(&raw_packet[copied..(copied + 2)]);
 
             (*length_as_bytes.get(0).unwrap() as usize) * (256)
                 + (*length_as_bytes.get(1).unwrap() as usize)
@@ -480,47 +614,41 @@ impl Packet {
         copied += 2;
 
         if len < length {
-            return Err(PacketError::InvalidFormatError);
+            return Err(PacketError::TruncatedError);
         }
 
-        let body: Vec<u8>;
-        if length == 0 {
-            body = vec![];
-        } else {
-            body = raw_packet.as_slice()[copied..(copied + length)].to_vec();
-        }
+        let body = raw_packet.slice(copied..(copied + length));
         copied += length;
         len -= length;
 
         let signature: Option<[u8; 64]>;
         if len == 0 {
             signature = None;
-        } else if len < 64 {
-            return Err(PacketError::InvalidFormatError);
+        } else if len < SIGNATURE_LENGTH {
+            return Err(PacketError::TruncatedError);
         } else {
             signature = {
-                let mut signature = [0; 64];
-                signature.copy_from_slice(&raw_packet.as_slice()[copied..copied + 64]);
+                let mut signature = [0; SIGNATURE_LENGTH];
+                signature.copy_from_slice(&raw_packet[copied..copied + SIGNATURE_LENGTH]);
                 Some(signature)
             }
         }
 
-        Ok(PacketBuilder::new()
+        PacketBuilder::new()
             .set_id(id)
             .packet_type(packet_type)
             .body(body)
             .signature(signature)
             .build()
-            .unwrap())
     }
 
     pub fn raw_length(&self) -> usize {
         let add_64_if_signed = match self.signature {
-            Some(_) => 64,
+            Some(_) => SIGNATURE_LENGTH,
             None => 0,
         };
 
-        32 + 1 + 2 + self.length + add_64_if_signed
+        HEADER_LENGTH + self.length + add_64_if_signed
     }
 
     pub async fn send_to_addr(
@@ -541,24 +669,22 @@ impl Packet {
     }
 
     pub async fn recv_from(sock: &UdpSocket) -> Result<(SocketAddr, Packet), PacketError> {
-        /*1095=4+1+2+(32+1024)+64 being the maximum packet size*/
-        let mut packet_buf: [u8; 1128] = [0; 1128];
-
-        let (recvd_packet_size, peer_addr) = sock.recv_from(&mut packet_buf).await.unwrap();
-
-        let response = (
-            peer_addr,
-            match Packet::from_bytes(&mut {
-                let mut packet_buf = packet_buf.to_vec();
-                packet_buf.truncate(recvd_packet_size);
-                packet_buf
-            }) {
-                Ok(packet) => packet,
-                Err(e) => return Err(e),
-            },
-        );
+        /*One byte more than the largest datagram, so that a longer one
+        fills it and is known to have been cut instead of read as a
+        shorter packet */
+        let mut packet_buf = BytesMut::with_capacity(MAX_DATAGRAM_LENGTH + 1);
+
+        /*The datagram is written once, the packet then keeps slices of it */
+        let peer_addr = match sock.recv_buf_from(&mut packet_buf).await {
+            Ok((_, peer_addr)) => peer_addr,
+            /*ICMP errors of previous sends land here on some systems */
+            Err(_) => return Err(PacketError::UnknownError),
+        };
+        if packet_buf.len() > MAX_DATAGRAM_LENGTH {
+            return Err(PacketError::DatagramTooLongError);
+        }
 
-        Ok(response)
+        Ok((peer_addr, Packet::from_bytes(packet_buf.freeze())?))
     }
 
     /*Verify the hash of a Packet during p2p export/import */
@@ -566,10 +692,13 @@ impl Packet {
     pub fn valid_hash(&self) -> bool {
         debug!("PACKET HASH CHECKING : {self:?}");
         let body = self.get_body();
-        let given_hash = &body.as_slice()[0..32];
+        if body.len() < HASH_LENGTH {
+            return false;
+        }
+        let given_hash = &body[0..HASH_LENGTH];
 
         let calculated_hash = {
-            let data = &body.as_slice()[32..self.get_body_length()];
+            let data = &body[HASH_LENGTH..];
             let mut hasher = Sha256::new();
             hasher.update(data);
 
@@ -581,3 +710,86 @@ impl Packet {
         calculated_hash == given_hash
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn any_packet() -> impl Strategy<Value = Packet> {
+        (
+            any::<[u8; 4]>(),
+            prop_oneof![0u8..8, 128u8..134],
+            proptest::collection::vec(any::<u8>(), 0..=MAX_BODY_LENGTH),
+            proptest::option::of(proptest::collection::vec(any::<u8>(), 64)),
+        )
+            .prop_map(|(id, packet_type, body, signature)| {
+                PacketBuilder::new()
+                    .set_id(id)
+                    .packet_type(PacketType::from_u8(packet_type).unwrap())
+                    .body(body)
+                    .signature(signature.map(|signature| signature.try_into().unwrap()))
+                    .build()
+                    .unwrap()
+            })
+    }
+
+    #[tokio::test]
+    async fn lib_network_packet_datagram_length() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let receiver_addr = receiver.local_addr().unwrap();
+
+        /*The largest packet the protocol allows */
+        let mut datum =
+            PacketBuilder::datum_packet(&[1; 4], [2; 32], vec![3; MAX_NODE_LENGTH]).unwrap();
+        datum.set_signature(Some([4; SIGNATURE_LENGTH]));
+        assert_eq!(datum.raw_length(), MAX_DATAGRAM_LENGTH);
+        datum.send_to_addr(&sender, &receiver_addr).await.unwrap();
+        let (_, received) = Packet::recv_from(&receiver).await.unwrap();
+        assert_eq!(received, datum);
+        /*A longer one can't even be built */
+        assert!(matches!(
+            PacketBuilder::datum_packet(&[1; 4], [2; 32], vec![3; MAX_NODE_LENGTH + 1]),
+            Err(PacketError::BodyTooLongError)
+        ));
+
+        let mut raw_datum = datum.as_bytes();
+        raw_datum.push(0);
+        sender.send_to(&raw_datum, receiver_addr).await.unwrap();
+        assert!(matches!(
+            Packet::recv_from(&receiver).await,
+            Err(PacketError::DatagramTooLongError)
+        ));
+
+        /*Header announcing a body longer than what follows */
+        sender
+            .send_to(&raw_datum[..100], receiver_addr)
+            .await
+            .unwrap();
+        assert!(matches!(
+            Packet::recv_from(&receiver).await,
+            Err(PacketError::TruncatedError)
+        ));
+
+        let too_long = PacketBuilder::new()
+            .gen_id()
+            .body(vec![0; MAX_BODY_LENGTH + 1])
+            .build();
+        assert!(matches!(too_long, Err(PacketError::BodyTooLongError)));
+    }
+
+    proptest! {
+        #[test]
+        fn lib_network_packet_bytes_round_trip(packet in any_packet()) {
+            let raw_packet = packet.as_bytes();
+            prop_assert_eq!(raw_packet.len(), packet.raw_length());
+            prop_assert_eq!(Packet::from_bytes(raw_packet.into()).unwrap(), packet.clone());
+            /*A decoded message encodes to a body that decodes to it again */
+            if let Ok(message) = packet.message() {
+                let body = message.encode();
+                prop_assert_eq!(Message::decode(*packet.get_packet_type(), &body), Ok(message));
+            }
+        }
+    }
+}
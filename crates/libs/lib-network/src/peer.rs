@@ -1,13 +1,18 @@
 use log::{debug, error};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     net::SocketAddr,
     sync::{Arc, Mutex},
     time::{Duration, Instant},
 };
 use thiserror::Error;
 
-#[derive(Error, Debug)]
+use crate::extensions::Extensions;
+use crate::packet::PacketError;
+use crate::rest_server::{MismatchPolicy, RestServer};
+use crate::signature::KeyPair;
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PeerError {
     #[error("No public key.")]
     NoPublicKey,
@@ -31,12 +36,46 @@ pub enum PeerError {
     PeerTimedOut,
     #[error("No datum")]
     NoDatum,
+    #[error("No peer left to ask")]
+    NoPeers,
     #[error("Invalid name format")]
     InvalidUTF8Name,
     #[error("Name changed")]
     NameChanged,
     #[error("File is directory")]
     FileIsDirectory,
+    #[error("Public key doesn't match the server's record")]
+    PublicKeyMismatch,
+    #[error("Public key changed without a new handshake")]
+    PublicKeyChanged,
+    #[error("Root doesn't match the server's record")]
+    RootMismatch,
+    #[error("REST server unreachable")]
+    ServerUnreachable,
+    #[error("REST server rejected the registration")]
+    RegistrationFailed,
+    #[error("Peer replied with an error")]
+    ErrorReply,
+    #[error("Peer is over its rate limits, retry later")]
+    Throttled,
+    #[error("Peer didn't validate our address yet")]
+    HandshakeFirst,
+    #[error("Parts of the file don't line up")]
+    InvalidLayout,
+    #[error("Failed to write the download")]
+    WriteFailed,
+    #[error("Downloaded file doesn't match its hash")]
+    CorruptedDownload,
+    #[error("Failed to build the packet")]
+    PacketBuild,
+}
+
+/*A packet we build can only be too long */
+impl From<PacketError> for PeerError {
+    fn from(e: PacketError) -> Self {
+        error!("{e}");
+        PeerError::PacketBuild
+    }
 }
 
 #[derive(Default, Debug, Clone)]
@@ -45,8 +84,18 @@ pub struct Peer {
     addresses: Vec<SocketAddr>,
     root: Option<[u8; 32]>,
     public_key: Option<[u8; 64]>,
-    extensions: Option<[u8; 4]>,
+    /*The ones we advertise for our own peer, the ones we negotiated
+    for the others, see Extensions::negotiate */
+    extensions: Extensions,
+    /*Advertised none at all, see Extensions::predates_extensions */
+    predates_extensions: bool,
     timer: Option<Instant>,
+    /*Only set for our own peer */
+    key_pair: Option<KeyPair>,
+    /*Why the REST server disagrees with this peer, if it does */
+    flag: Option<PeerError>,
+    /*What the REST server confirmed, not asked again */
+    checked: Vec<Announced>,
 }
 
 impl Peer {
@@ -63,19 +112,46 @@ impl Peer {
         self.public_key = public_key;
         self
     }
+    /*Also sets the public key and the signatures extension advertised
+    to other peers */
+    pub fn set_key_pair(&mut self, key_pair: Option<KeyPair>) -> &mut Self {
+        self.public_key = key_pair.as_ref().map(|key_pair| key_pair.public_key());
+        self.extensions
+            .set(Extensions::SIGNATURES, key_pair.is_some());
+        self.key_pair = key_pair;
+        self
+    }
     pub fn set_name(&mut self, name: String) -> &mut Self {
         self.name = Some(name);
         self
     }
-    pub fn set_extensions(&mut self, extensions: Option<[u8; 4]>) -> &mut Self {
+    pub fn set_extensions(&mut self, extensions: Extensions) -> &mut Self {
         self.extensions = extensions;
         self
     }
+    /*For the peers that advertised theirs to us */
+    pub fn negotiate_extensions(&mut self, mine: Extensions, theirs: Extensions) -> &mut Self {
+        self.extensions = mine.negotiate(theirs);
+        self.predates_extensions = theirs.predates_extensions();
+        self
+    }
 
     pub fn add_address(&mut self, address: SocketAddr) -> &mut Self {
         self.addresses.push(address);
         self
     }
+    pub fn set_flag(&mut self, flag: Option<PeerError>) -> &mut Self {
+        self.flag = flag;
+        self
+    }
+    /*Replaces what was confirmed of the same kind */
+    pub fn set_checked(&mut self, announced: Announced) -> &mut Self {
+        self.checked.retain(|checked| {
+            std::mem::discriminant(checked) != std::mem::discriminant(&announced)
+        });
+        self.checked.push(announced);
+        self
+    }
     pub fn set_timer(&mut self) -> &mut Self {
         self.timer = Some(Instant::now());
         self
@@ -95,6 +171,10 @@ impl Peer {
         }
     }
 
+    pub fn get_key_pair(&self) -> Option<&KeyPair> {
+        self.key_pair.as_ref()
+    }
+
     pub fn get_addresses(&self) -> Option<&Vec<SocketAddr>> {
         if self.addresses.len() == 0 {
             None
@@ -105,8 +185,18 @@ impl Peer {
     pub fn get_name(&self) -> Option<&String> {
         self.name.as_ref()
     }
-    pub fn get_extensions(&self) -> Option<[u8; 4]> {
-        *&self.extensions
+    pub fn get_flag(&self) -> Option<PeerError> {
+        self.flag
+    }
+    pub fn get_extensions(&self) -> Extensions {
+        self.extensions
+    }
+    /*Whether we sign what we send to the peer, once it said Hello */
+    pub fn gets_signatures(&self) -> bool {
+        self.extensions.contains(Extensions::SIGNATURES) || self.predates_extensions
+    }
+    pub fn is_checked(&self, announced: &Announced) -> bool {
+        self.checked.contains(announced)
     }
     pub fn has_timed_out(&self, time_out: u64) -> Result<(), PeerError> {
         match self.timer {
@@ -124,6 +214,13 @@ impl Peer {
     }
 }
 
+/*Value announced by a peer over UDP, to be checked against the REST server */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Announced {
+    PublicKey(Option<[u8; 64]>),
+    Root(Option<[u8; 32]>),
+}
+
 /*When receiving hello/hello reply packet:
     -Add/create peer into active_peers(in process), 2 cases:
         -peer already exists->reset timer (keep alive)
@@ -141,8 +238,31 @@ When receiving any other packet:
 pub struct ActivePeers {
     pub addr_map: HashMap<SocketAddr, String>,
     pub peer_map: HashMap<String, Peer>,
+    /*Addresses that replied to one of our Hellos, so they aren't spoofed */
+    validated: HashSet<SocketAddr>,
+    /*What was received from and sent to the addresses that talked to
+    us first and aren't validated yet */
+    addr_to_traffic: HashMap<SocketAddr, Traffic>,
 }
 
+#[derive(Default)]
+struct Traffic {
+    received: usize,
+    sent: usize,
+    /*Sent once outside of the cap, see lock_and_try_send_handshake */
+    hello_reply_sent: bool,
+    hello_sent: bool,
+}
+
+/*
+    Until an address is validated we send it at most this many times
+    what we received from it, so that a spoofed source address can't
+    turn us into an amplifier.
+*/
+pub const AMPLIFICATION_FACTOR: usize = 3;
+/*Above this many unvalidated addresses their traffic is forgotten */
+const MAX_UNVALIDATED: usize = 4096;
+
 impl ActivePeers {
     pub fn build_mutex() -> Arc<Mutex<Self>> {
         Arc::new(Mutex::new(ActivePeers::default()))
@@ -168,6 +288,8 @@ impl ActivePeers {
     pub fn pop(&mut self, peer: &Peer) {
         for addr in &peer.addresses {
             self.addr_map.remove(addr);
+            /*The address may be someone else's next time */
+            self.validated.remove(addr);
         }
     }
     pub fn get(&self, sock_addr: SocketAddr) -> Option<&Peer> {
@@ -201,6 +323,93 @@ impl ActivePeers {
         };
         active_peers.addr_map.get(&sock_addr).cloned()
     }
+    pub fn lock_and_get_public_key(
+        active_peers: Arc<Mutex<ActivePeers>>,
+        sock_addr: SocketAddr,
+    ) -> Option<[u8; 64]> {
+        let active_peers = match active_peers.lock() {
+            Ok(active_peers) => active_peers,
+            Err(e) => {
+                error!("[lock_and_get_public_key] Peers mutex is poisoned {e}");
+                panic!("[lock_and_get_public_key] Peers mutex is poisoned {e}")
+            }
+        };
+        active_peers.get(sock_addr)?.get_public_key()
+    }
+    /*The extensions negotiated with the peer at sock_addr, None if it
+    never said Hello */
+    pub fn lock_and_get_extensions(
+        active_peers: Arc<Mutex<ActivePeers>>,
+        sock_addr: SocketAddr,
+    ) -> Option<Extensions> {
+        let active_peers = match active_peers.lock() {
+            Ok(active_peers) => active_peers,
+            Err(e) => {
+                error!("[lock_and_get_extensions] Peers mutex is poisoned {e}");
+                panic!("[lock_and_get_extensions] Peers mutex is poisoned {e}")
+            }
+        };
+        active_peers
+            .get(sock_addr)
+            .map(|peer| peer.get_extensions())
+    }
+    /*Why the REST server disagrees with the peer at sock_addr, if it does */
+    pub fn lock_and_get_flag(
+        active_peers: Arc<Mutex<ActivePeers>>,
+        sock_addr: SocketAddr,
+    ) -> Option<PeerError> {
+        let active_peers = match active_peers.lock() {
+            Ok(active_peers) => active_peers,
+            Err(e) => {
+                error!("[lock_and_get_flag] Peers mutex is poisoned {e}");
+                panic!("[lock_and_get_flag] Peers mutex is poisoned {e}")
+            }
+        };
+        active_peers.get(sock_addr)?.get_flag()
+    }
+    /*Whether we sign what we send to the peer at sock_addr, None if it
+    never said Hello, see Peer::gets_signatures */
+    pub fn lock_and_gets_signatures(
+        active_peers: Arc<Mutex<ActivePeers>>,
+        sock_addr: SocketAddr,
+    ) -> Option<bool> {
+        let active_peers = match active_peers.lock() {
+            Ok(active_peers) => active_peers,
+            Err(e) => {
+                error!("[lock_and_gets_signatures] Peers mutex is poisoned {e}");
+                panic!("[lock_and_gets_signatures] Peers mutex is poisoned {e}")
+            }
+        };
+        active_peers
+            .get(sock_addr)
+            .map(|peer| peer.gets_signatures())
+    }
+    /*Addresses of the peers whose last Root or RootReply was root,
+    but the flagged ones */
+    pub fn lock_and_get_with_root(
+        active_peers: Arc<Mutex<ActivePeers>>,
+        root: [u8; 32],
+    ) -> Vec<SocketAddr> {
+        let active_peers = match active_peers.lock() {
+            Ok(active_peers) => active_peers,
+            Err(e) => {
+                error!("[lock_and_get_with_root] Peers mutex is poisoned {e}");
+                panic!("[lock_and_get_with_root] Peers mutex is poisoned {e}")
+            }
+        };
+        let mut addresses: Vec<SocketAddr> = active_peers
+            .addr_map
+            .keys()
+            .filter(|addr| {
+                active_peers.get(**addr).is_some_and(|peer| {
+                    peer.get_root_hash() == Some(root) && peer.get_flag().is_none()
+                })
+            })
+            .copied()
+            .collect();
+        addresses.sort();
+        addresses
+    }
     pub fn lock_and_push(active_peers: Arc<Mutex<ActivePeers>>, peer: Peer) {
         let mut active_peers = match active_peers.lock() {
             Ok(active_peers) => active_peers,
@@ -222,13 +431,136 @@ impl ActivePeers {
         active_peers.pop(peer);
     }
 
+    /*sock_addr replied to a Hello we sent it, its address is its own */
+    pub fn lock_and_validate(active_peers: Arc<Mutex<ActivePeers>>, sock_addr: SocketAddr) {
+        let mut active_peers = match active_peers.lock() {
+            Ok(active_peers) => active_peers,
+            Err(e) => {
+                error!("[lock_and_validate] Peers mutex is poisoned {e}");
+                panic!("Peers mutex is poisoned {e}")
+            }
+        };
+        active_peers.addr_to_traffic.remove(&sock_addr);
+        active_peers.validated.insert(sock_addr);
+    }
+    pub fn lock_and_is_validated(
+        active_peers: Arc<Mutex<ActivePeers>>,
+        sock_addr: SocketAddr,
+    ) -> bool {
+        let active_peers = match active_peers.lock() {
+            Ok(active_peers) => active_peers,
+            Err(e) => {
+                error!("[lock_and_is_validated] Peers mutex is poisoned {e}");
+                panic!("Peers mutex is poisoned {e}")
+            }
+        };
+        active_peers.validated.contains(&sock_addr)
+    }
+
+    pub fn lock_and_add_received(
+        active_peers: Arc<Mutex<ActivePeers>>,
+        sock_addr: SocketAddr,
+        size: usize,
+    ) {
+        let mut active_peers = match active_peers.lock() {
+            Ok(active_peers) => active_peers,
+            Err(e) => {
+                error!("[lock_and_add_received] Peers mutex is poisoned {e}");
+                panic!("Peers mutex is poisoned {e}")
+            }
+        };
+        if active_peers.validated.contains(&sock_addr) {
+            return;
+        }
+        /*A forgotten address gets no reply until it sends again */
+        if active_peers.addr_to_traffic.len() >= MAX_UNVALIDATED
+            && !active_peers.addr_to_traffic.contains_key(&sock_addr)
+        {
+            active_peers.addr_to_traffic.clear();
+        }
+        active_peers
+            .addr_to_traffic
+            .entry(sock_addr)
+            .or_default()
+            .received += size;
+    }
+
+    /*
+        Whether the HelloReply to the Hello of sock_addr, or our own Hello
+        validating it, can be sent outside of the cap. Once each : even a
+        Hello three times shorter than them gets the handshake going, the
+        ones after are counted as any other packet.
+    */
+    pub fn lock_and_try_send_handshake(
+        active_peers: Arc<Mutex<ActivePeers>>,
+        sock_addr: SocketAddr,
+        is_response: bool,
+    ) -> bool {
+        let mut active_peers = match active_peers.lock() {
+            Ok(active_peers) => active_peers,
+            Err(e) => {
+                error!("[lock_and_try_send_handshake] Peers mutex is poisoned {e}");
+                panic!("Peers mutex is poisoned {e}")
+            }
+        };
+        if active_peers.validated.contains(&sock_addr) {
+            return true;
+        }
+        match active_peers.addr_to_traffic.get_mut(&sock_addr) {
+            Some(traffic) => {
+                let sent = match is_response {
+                    true => &mut traffic.hello_reply_sent,
+                    false => &mut traffic.hello_sent,
+                };
+                !std::mem::replace(sent, true)
+            }
+            None => !is_response,
+        }
+    }
+
+    /*
+        Whether size bytes can be sent to sock_addr, counts them if so.
+        Validated addresses have no limit. So do the addresses we
+        talked to first, but only for our requests : a reply always
+        answers something received.
+    */
+    pub fn lock_and_try_send(
+        active_peers: Arc<Mutex<ActivePeers>>,
+        sock_addr: SocketAddr,
+        size: usize,
+        is_response: bool,
+    ) -> bool {
+        let mut active_peers = match active_peers.lock() {
+            Ok(active_peers) => active_peers,
+            Err(e) => {
+                error!("[lock_and_try_send] Peers mutex is poisoned {e}");
+                panic!("Peers mutex is poisoned {e}")
+            }
+        };
+        if active_peers.validated.contains(&sock_addr) {
+            return true;
+        }
+        match active_peers.addr_to_traffic.get_mut(&sock_addr) {
+            Some(traffic) => {
+                if traffic.sent + size > AMPLIFICATION_FACTOR * traffic.received {
+                    return false;
+                }
+                traffic.sent += size;
+                true
+            }
+            None => !is_response,
+        }
+    }
+
     /*Checks if there is a peer associated to sock_addr. If yes
-    reset timer and return, else create the peer and set its extensions and name.
-    Cannot fail. */
+    reset timer and update its extensions, else create the peer and set its
+    extensions and name. Its extensions are the ones negotiated from
+    ours and theirs. Cannot fail. */
     pub fn set_peer_extensions_and_name(
         active_peers: Arc<Mutex<ActivePeers>>,
         sock_addr: SocketAddr,
-        extensions: Option<[u8; 4]>,
+        mine: Extensions,
+        theirs: Extensions,
         name: Vec<u8>,
     ) -> Result<(), PeerError> {
         /*Peers are identified by name */
@@ -255,7 +587,7 @@ impl ActivePeers {
                 let mut peer = Peer::new();
                 peer.add_address(sock_addr)
                     .set_name(name)
-                    .set_extensions(extensions)
+                    .negotiate_extensions(mine, theirs)
                     .set_timer();
                 // println!("PUSHING PEER {}", String::from_utf8(name).unwrap());
                 active_peers.push(&peer);
@@ -264,12 +596,127 @@ impl ActivePeers {
         };
 
         match active_peers.peer_map.get_mut(&name) {
-            Some(peer) => peer.set_timer(),
+            Some(peer) => peer.negotiate_extensions(mine, theirs).set_timer(),
             None => return Err(PeerError::Unknown),
         };
         return Ok(());
     }
 
+    /*Flags or drops the peer at sock_addr according to the policy of the server */
+    pub fn lock_and_flag(
+        active_peers: Arc<Mutex<ActivePeers>>,
+        sock_addr: SocketAddr,
+        flag: PeerError,
+        policy: MismatchPolicy,
+    ) {
+        let mut active_peers = match active_peers.lock() {
+            Ok(active_peers) => active_peers,
+            Err(e) => {
+                error!("[lock_and_flag] Peers mutex is poisoned {e}");
+                panic!("Peers mutex is poisoned {e}")
+            }
+        };
+        let peer = match active_peers.get_mut(sock_addr) {
+            Some(peer) => peer,
+            None => return,
+        };
+        match policy {
+            MismatchPolicy::Flag => {
+                peer.set_flag(Some(flag));
+            }
+            MismatchPolicy::Drop => {
+                let peer_clone = peer.clone();
+                active_peers.pop(&peer_clone);
+            }
+        }
+    }
+
+    /*
+        Checks a value the peer at sock_addr announced against the
+        records of the REST server, once per value. A mismatch flags or
+        drops the peer as the policy of the server says. A server we
+        can't ask says nothing about the peer, the value stays unchecked
+        and is asked again the next time the peer announces it.
+    */
+    pub async fn check_with_server(
+        active_peers: Arc<Mutex<ActivePeers>>,
+        sock_addr: SocketAddr,
+        rest_server: &RestServer,
+        announced: Announced,
+    ) -> Result<(), PeerError> {
+        let name = match ActivePeers::lock_and_get(Arc::clone(&active_peers), sock_addr) {
+            Some(name) => name,
+            None => return Err(PeerError::UnknownPeer),
+        };
+        if ActivePeers::lock_and_is_checked(Arc::clone(&active_peers), sock_addr, &announced) {
+            return Ok(());
+        }
+
+        let checked = match announced {
+            Announced::PublicKey(public_key) => {
+                rest_server.check_public_key(&name, public_key).await
+            }
+            Announced::Root(root) => rest_server.check_root(&name, root).await,
+        };
+
+        match checked {
+            Ok(()) => {
+                ActivePeers::lock_and_set_checked(active_peers, sock_addr, announced);
+                Ok(())
+            }
+            Err(e @ (PeerError::PublicKeyMismatch | PeerError::RootMismatch)) => {
+                ActivePeers::lock_and_flag(active_peers, sock_addr, e, rest_server.on_mismatch());
+                Err(e)
+            }
+            other => other,
+        }
+    }
+
+    pub fn lock_and_is_checked(
+        active_peers: Arc<Mutex<ActivePeers>>,
+        sock_addr: SocketAddr,
+        announced: &Announced,
+    ) -> bool {
+        let active_peers = match active_peers.lock() {
+            Ok(active_peers) => active_peers,
+            Err(e) => {
+                error!("[lock_and_is_checked] Peers mutex is poisoned {e}");
+                panic!("Peers mutex is poisoned {e}")
+            }
+        };
+        match active_peers.get(sock_addr) {
+            Some(peer) => peer.is_checked(announced),
+            None => false,
+        }
+    }
+
+    /*Records what the server confirmed, which lifts the flag a value
+    of the same kind raised before */
+    pub fn lock_and_set_checked(
+        active_peers: Arc<Mutex<ActivePeers>>,
+        sock_addr: SocketAddr,
+        announced: Announced,
+    ) {
+        let mut active_peers = match active_peers.lock() {
+            Ok(active_peers) => active_peers,
+            Err(e) => {
+                error!("[lock_and_set_checked] Peers mutex is poisoned {e}");
+                panic!("Peers mutex is poisoned {e}")
+            }
+        };
+        if let Some(peer) = active_peers.get_mut(sock_addr) {
+            let lifted = match announced {
+                Announced::PublicKey(_) => PeerError::PublicKeyMismatch,
+                Announced::Root(_) => PeerError::RootMismatch,
+            };
+            peer.set_checked(announced);
+            if peer.get_flag() == Some(lifted) {
+                peer.set_flag(None);
+            }
+        }
+    }
+
+    /*See check_with_server for the REST server */
     pub fn set_peer_root(
         active_peers: Arc<Mutex<ActivePeers>>,
         sock_addr: SocketAddr,
@@ -306,6 +753,13 @@ impl ActivePeers {
             _ => panic!("Shouldn't happen"),
         }
     }
+    /*
+        The first key a peer announces is the one its signed packets are
+        checked against until it times out or is dropped, a fresh
+        Hello/HelloReply then starts a peer without a key. Neither a
+        different key nor no key replaces it before, a spoofed PublicKey
+        can't turn the checks off.
+    */
     pub fn set_peer_public_key(
         active_peers: Arc<Mutex<ActivePeers>>,
         sock_addr: SocketAddr,
@@ -327,6 +781,12 @@ impl ActivePeers {
         match peer.has_timed_out(30000) {
             /*Hasn't timed out */
             Ok(()) => {
+                if peer
+                    .get_public_key()
+                    .is_some_and(|key| Some(key) != public_key)
+                {
+                    return Err(PeerError::PublicKeyChanged);
+                }
                 peer.set_public_key(public_key);
                 return Ok(());
             }
@@ -370,6 +830,7 @@ impl ActivePeers {
             }
             Err(PeerError::PeerTimedOut) => {
                 active_peers.addr_map.remove(&sock_addr);
+                active_peers.validated.remove(&sock_addr);
                 return Err(PeerError::PeerTimedOut);
             }
             Err(PeerError::UnknownPeer) => return Err(PeerError::Unknown),
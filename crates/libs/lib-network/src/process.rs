@@ -1,25 +1,41 @@
 use core::panic;
 
 use std::collections::HashMap;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex, RwLock};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
 
 use crate::action::Action;
+use crate::packet::socket_addr_from_bytes;
 use lib_file::mk_fs::MktFsNode;
 use log::{debug, error};
+use tokio::{sync::watch, task::JoinHandle};
+use tokio_util::sync::CancellationToken;
 
 use crate::peer::*;
+use crate::config::NodeConfig;
+use crate::protocol::{CHUNK_SIZE, MAX_NODE_LENGTH};
+use crate::rate_limit::RateLimiter;
+use crate::rest_server::RestServer;
 
 use crate::congestion_handler::*;
 
+/*Bytes counted against the rate limits for any GetDatum */
+const MIN_REQUEST_COST: usize = 64;
+/*Body of the ErrorReply to a GetDatum over the rate limits */
+pub const THROTTLED: &[u8] = b"Throttled, too many requests, retry later";
+/*Body of the ErrorReply to a GetDatum whose node doesn't fit in a datum */
+pub const TOO_LARGE: &[u8] = b"Datum is too large for a packet";
+
+/*
+    The tree served by the process task. Whoever holds the sender
+    can export a new tree while the node runs.
+*/
+pub type ExportedTree = watch::Receiver<Option<Arc<MktFsNode>>>;
+
 /*Chaque sous task du CLI lit passivement la process queue
 et push des paquets dans l'action queue en conséquence ?*/
 pub fn process_task(
-    action_queue: Arc<Mutex<Queue<Action>>>,
-    action_queue_state: Arc<QueueState>,
-    process_queue: Arc<RwLock<Queue<Action>>>,
-    process_queue_state: Arc<QueueState>,
+    queues: &Queues,
     active_peers: Arc<Mutex<ActivePeers>>,
     mut my_data: Peer,
     // to_export: Arc<HashMap<[u8;32], &MktFsNode>>,
@@ -30,62 +46,48 @@ pub fn process_task(
     //send datum.
     //hash_map:?
     //self_data:?
-    exporting: bool,
-    path: PathBuf,
-) {
+    mut exported: ExportedTree,
+    config: Arc<NodeConfig>,
+    cancel: CancellationToken,
+) -> JoinHandle<()> {
+    let action_queue = Arc::clone(&queues.action_queue);
+    let action_queue_state = Arc::clone(&queues.action_queue_state);
+    let process_queue = Arc::clone(&queues.process_queue);
+    let process_queue_state = Arc::clone(&queues.process_queue_state);
     //Should pop only if too full ? For subtasks to have time to read
+    let mut rate_limiter = RateLimiter::new(config.get_rate_limits());
     tokio::spawn(async move {
-        if exporting == true {
-            let tree = MktFsNode::try_from_path(&path, 1200, 100).unwrap();
-            my_data.set_hash(Some(tree.hash.clone()));
-            debug!("{:?}", tree);
-            let map = tree.to_hashmap();
-            loop {
-                match Queue::write_lock_and_get(Arc::clone(&process_queue)) {
-                    Some(action) => {
-                        /*action queue is not empty get an action and handle it*/
-                        // println!("process: {:?}\n", action);
-                        process_action(
-                            action.clone(),
-                            Arc::clone(&action_queue),
-                            Arc::clone(&action_queue_state),
-                            Arc::clone(&active_peers),
-                            &my_data,
-                            &map,
-                            exporting,
-                        );
-                        debug!("{:?}", action)
-                        /*return the action required */
-                    }
-                    None => {
-                        /*
-                        action queue is empty wait for the activity of
-                        the receive queue
-                        */
-
-                        // println!("process wait");
-                        QueueState::set_empty_queue(Arc::clone(&process_queue_state));
-                        process_queue_state.wait();
-                        continue;
-                    }
+        while !cancel.is_cancelled() {
+            /*Without a tree every GetDatum is answered with NoDatum */
+            let tree = exported.borrow_and_update().clone();
+            let map = match &tree {
+                Some(tree) => {
+                    debug!("{:?}", tree);
+                    tree.to_hashmap()
                 }
-            }
-        } else {
-            let map = HashMap::new();
+                None => HashMap::new(),
+            };
+            my_data.set_hash(tree.as_ref().map(|tree| tree.hash));
+
             loop {
+                /*Rebuild the map when a new tree is exported */
+                if cancel.is_cancelled() || matches!(exported.has_changed(), Ok(true)) {
+                    break;
+                }
                 match Queue::write_lock_and_get(Arc::clone(&process_queue)) {
                     Some(action) => {
                         /*action queue is not empty get an action and handle it*/
                         // println!("process: {:?}\n", action);
-                        process_action(
-                            action.clone(),
-                            Arc::clone(&action_queue),
-                            Arc::clone(&action_queue_state),
-                            Arc::clone(&active_peers),
-                            &my_data,
-                            &map,
-                            exporting,
-                        );
+                        let context = ProcessContext {
+                            action_queue: Arc::clone(&action_queue),
+                            action_queue_state: Arc::clone(&action_queue_state),
+                            active_peers: Arc::clone(&active_peers),
+                            my_data: &my_data,
+                            tree: &map,
+                            config: &config,
+                            rate_limiter: &mut rate_limiter,
+                        };
+                        process_action(action.clone(), context).await;
                         debug!("{:?}", action)
                         /*return the action required */
                     }
@@ -97,32 +99,81 @@ pub fn process_task(
 
                         // println!("process wait");
                         QueueState::set_empty_queue(Arc::clone(&process_queue_state));
-                        process_queue_state.wait();
-                        continue;
+                        tokio::select! {
+                            _ = cancel.cancelled() => break,
+                            _ = process_queue_state.wait() => continue,
+                        }
                     }
                 }
             }
         }
-    });
+    })
+}
+
+/*
+    What the process task lends process_action : where the replies go,
+    the peers we know, our own data, what we serve and how much.
+*/
+pub struct ProcessContext<'a> {
+    pub action_queue: Arc<Mutex<Queue<Action>>>,
+    pub action_queue_state: Arc<QueueState>,
+    pub active_peers: Arc<Mutex<ActivePeers>>,
+    pub my_data: &'a Peer,
+    /*Empty when not exporting */
+    pub tree: &'a HashMap<[u8; 32], &'a MktFsNode>,
+    pub config: &'a NodeConfig,
+    pub rate_limiter: &'a mut RateLimiter,
 }
 
-pub fn process_action(
-    action: Action,
-    action_queue: Arc<Mutex<Queue<Action>>>,
-    action_queue_state: Arc<QueueState>,
+/*
+    Checks what a peer announced against the REST server, if any, off
+    the process task so that the reply doesn't wait on the server. The
+    peer is told with an Error when the server disagrees with it.
+*/
+fn spawn_check(
+    action_queue: &Arc<Mutex<Queue<Action>>>,
+    action_queue_state: &Arc<QueueState>,
     active_peers: Arc<Mutex<ActivePeers>>,
-    my_data: &Peer, //Pour store public key et root ->
-    // to_export: Arc<HashMap<[u8;32], &MktFsNode>>       //hashmap: sockaddr vers peer
-    //peer.set_public_key...
-    //peer.set_root..
-    //active_peers: Arc<ActivePeers>
-    //struct ActivePeers {
-    //    peers: Vec<Peer>,
-    //    map: Hashmap<SocketAddr, Peer>
-    //}
-    tree: &HashMap<[u8; 32], &MktFsNode>,
-    exporting: bool,
+    rest_server: Option<&RestServer>,
+    sock_addr: SocketAddr,
+    announced: Announced,
 ) {
+    let rest_server = match rest_server {
+        Some(rest_server) => rest_server.clone(),
+        None => return,
+    };
+    let (action_queue, action_queue_state) =
+        (Arc::clone(action_queue), Arc::clone(action_queue_state));
+    tokio::spawn(async move {
+        match ActivePeers::check_with_server(active_peers, sock_addr, &rest_server, announced).await
+        {
+            Ok(()) | Err(PeerError::UnknownPeer) => (),
+            Err(e @ (PeerError::PublicKeyMismatch | PeerError::RootMismatch)) => {
+                error!("{sock_addr} : {e}");
+                Queue::lock_and_push(
+                    Arc::clone(&action_queue),
+                    Action::SendError(e.to_string().into_bytes(), sock_addr),
+                );
+                QueueState::set_non_empty_queue(action_queue_state);
+            }
+            Err(e) => error!("Couldn't check {sock_addr} against the REST server : {e}"),
+        }
+    });
+}
+
+pub async fn process_action(action: Action, context: ProcessContext<'_>) {
+    let ProcessContext {
+        action_queue,
+        action_queue_state,
+        active_peers,
+        my_data,
+        tree,
+        config,
+        rate_limiter,
+    } = context;
+    /*Serves what we downloaded too, along an exported tree */
+    let reshared_store = config.get_reshared_store().filter(|_| !tree.is_empty());
+    let rest_server = config.get_rest_server();
     let my_name = my_data.get_name().unwrap().as_bytes().to_vec();
     let my_hash: Option<[u8; 32]> = my_data.get_root_hash();
     match action {
@@ -135,13 +186,22 @@ pub fn process_action(
             /*Send Hello reply then process the hello. Fails when the name is invalid utf8.*/
             Queue::lock_and_push(
                 Arc::clone(&action_queue),
-                Action::SendHelloReply(id, my_data.get_extensions(), my_name, sock_addr),
+                Action::SendHelloReply(id, my_data.get_extensions(), my_name.clone(), sock_addr),
             );
+            /*Our own Hello checks that the address isn't spoofed, the
+            replies we send until then are capped */
+            if !ActivePeers::lock_and_is_validated(Arc::clone(&active_peers), sock_addr) {
+                Queue::lock_and_push(
+                    Arc::clone(&action_queue),
+                    Action::SendHello(my_data.get_extensions(), my_name, sock_addr),
+                );
+            }
             QueueState::set_non_empty_queue(Arc::clone(&action_queue_state));
             /*Add peer and set peer timer (30s) */
             let _ = ActivePeers::set_peer_extensions_and_name(
                 active_peers,
                 sock_addr,
+                my_data.get_extensions(),
                 extensions,
                 name,
             );
@@ -151,15 +211,24 @@ pub fn process_action(
             /*DONE */
             error!(
                 "Received Error with body: {}\n from {}\n",
-                String::from_utf8(error_msg).unwrap(),
+                String::from_utf8_lossy(&error_msg),
                 sock_addr
             );
             return;
         }
         Action::ProcessPublicKey(id, public_key, sock_addr) => {
             /*DONE */
-            match ActivePeers::set_peer_public_key(active_peers, sock_addr, public_key) {
+            match ActivePeers::set_peer_public_key(Arc::clone(&active_peers), sock_addr, public_key)
+            {
                 Ok(()) => {
+                    spawn_check(
+                        &action_queue,
+                        &action_queue_state,
+                        active_peers,
+                        rest_server,
+                        sock_addr,
+                        Announced::PublicKey(public_key),
+                    );
                     Queue::lock_and_push(
                         Arc::clone(&action_queue),
                         Action::SendPublicKeyReply(id, my_data.get_public_key(), sock_addr),
@@ -182,6 +251,7 @@ pub fn process_action(
                     QueueState::set_non_empty_queue(Arc::clone(&action_queue_state));
                 }
                 Err(PeerError::UnknownPeer) => (),
+                Err(e @ PeerError::PublicKeyChanged) => error!("{sock_addr} : {e}"),
                 Err(e) => {
                     error!("{e}");
                     panic!("Unkown error in process_action {}\n", e)
@@ -191,8 +261,16 @@ pub fn process_action(
         }
         Action::ProcessRoot(id, root, sock_addr) => {
             /*DONE */
-            match ActivePeers::set_peer_root(active_peers, sock_addr, root) {
+            match ActivePeers::set_peer_root(Arc::clone(&active_peers), sock_addr, root) {
                 Ok(()) => {
+                    spawn_check(
+                        &action_queue,
+                        &action_queue_state,
+                        active_peers,
+                        rest_server,
+                        sock_addr,
+                        Announced::Root(root),
+                    );
                     Queue::lock_and_push(
                         Arc::clone(&action_queue),
                         Action::SendRootReply(id, my_hash, sock_addr),
@@ -223,29 +301,51 @@ pub fn process_action(
             return;
         }
         Action::ProcessGetDatum(id, hash, sock_addr) => {
-            if exporting {
-                let datum = match tree.get(&hash) {
-                    Some(node) => {
-                        debug!("Found datum");
-                        node.to_bytes(1024)
-                    }
-                    None => {
-                        debug!("NoDatum");
-                        vec![]
+            let datum = match tree.get(&hash) {
+                Some(node) => {
+                    debug!("Found datum");
+                    node.to_bytes(CHUNK_SIZE)
+                }
+                None => {
+                    let node = match reshared_store {
+                        Some(store) => store.spawn_get(hash).await,
+                        None => None,
+                    };
+                    match node {
+                        Some(node) => node.to_vec(),
+                        None => {
+                            debug!("NoDatum");
+                            vec![]
+                        }
                     }
-                };
-                if datum.is_empty() {
-                    Queue::lock_and_push(action_queue.clone(), Action::SendNoDatum(id, sock_addr));
-                    QueueState::set_non_empty_queue(action_queue_state.clone());
-                } else {
-                    Queue::lock_and_push(
-                        action_queue.clone(),
-                        Action::SendDatumWithHash(id, *&hash, datum, sock_addr),
-                    )
                 }
-            } else {
+            };
+            /*Even a NoDatum costs something, so that flooding us with
+            unknown hashes is throttled too */
+            if !rate_limiter.allow(sock_addr, datum.len().max(MIN_REQUEST_COST)) {
+                debug!("Throttled GetDatum from {sock_addr}");
+                Queue::lock_and_push(
+                    action_queue.clone(),
+                    Action::SendErrorReply(id, Some(THROTTLED.to_vec()), sock_addr),
+                );
+                QueueState::set_non_empty_queue(action_queue_state.clone());
+            } else if datum.is_empty() {
                 Queue::lock_and_push(action_queue.clone(), Action::SendNoDatum(id, sock_addr));
                 QueueState::set_non_empty_queue(action_queue_state.clone());
+            } else if datum.len() > MAX_NODE_LENGTH {
+                /*A directory with too many entries, it can't be served */
+                error!("Datum {} is {} bytes long", hex::encode(hash), datum.len());
+                Queue::lock_and_push(
+                    action_queue.clone(),
+                    Action::SendErrorReply(id, Some(TOO_LARGE.to_vec()), sock_addr),
+                );
+                QueueState::set_non_empty_queue(action_queue_state.clone());
+            } else {
+                Queue::lock_and_push(
+                    action_queue.clone(),
+                    Action::SendDatumWithHash(id, hash, datum, sock_addr),
+                );
+                QueueState::set_non_empty_queue(action_queue_state.clone());
             }
             /*to do */
             return;
@@ -256,9 +356,16 @@ pub fn process_action(
         // }
         Action::ProcessHelloReply(extensions, name, sock_addr) => {
             /*DONE */
+            /*Only replies to our ids get here, the peer got our Hello */
+            ActivePeers::lock_and_validate(Arc::clone(&active_peers), sock_addr);
+            debug!(
+                "Extensions with {sock_addr} : {}",
+                my_data.get_extensions().negotiate(extensions)
+            );
             let _ = ActivePeers::set_peer_extensions_and_name(
                 active_peers,
                 sock_addr,
+                my_data.get_extensions(),
                 extensions,
                 name,
             );
@@ -275,8 +382,18 @@ pub fn process_action(
         }
         Action::ProcessPublicKeyReply(public_key, sock_addr) => {
             /*DONE */
-            match ActivePeers::set_peer_public_key(active_peers, sock_addr, public_key) {
-                Ok(()) => {}
+            match ActivePeers::set_peer_public_key(Arc::clone(&active_peers), sock_addr, public_key)
+            {
+                Ok(()) => {
+                    spawn_check(
+                        &action_queue,
+                        &action_queue_state,
+                        active_peers,
+                        rest_server,
+                        sock_addr,
+                        Announced::PublicKey(public_key),
+                    );
+                }
                 Err(PeerError::PeerTimedOut) => {
                     Queue::lock_and_push(
                         Arc::clone(&action_queue),
@@ -290,6 +407,7 @@ pub fn process_action(
                     QueueState::set_non_empty_queue(Arc::clone(&action_queue_state));
                 }
                 Err(PeerError::UnknownPeer) => (),
+                Err(e @ PeerError::PublicKeyChanged) => error!("{sock_addr} : {e}"),
                 Err(e) => {
                     error!("{e}");
                     panic!("Unkown error in process_action {}\n", e)
@@ -299,8 +417,17 @@ pub fn process_action(
         }
         Action::ProcessRootReply(root, sock_addr) => {
             /*DONE */
-            match ActivePeers::set_peer_root(active_peers, sock_addr, root) {
-                Ok(()) => {}
+            match ActivePeers::set_peer_root(Arc::clone(&active_peers), sock_addr, root) {
+                Ok(()) => {
+                    spawn_check(
+                        &action_queue,
+                        &action_queue_state,
+                        active_peers,
+                        rest_server,
+                        sock_addr,
+                        Announced::Root(root),
+                    );
+                }
                 Err(PeerError::PeerTimedOut) => {
                     Queue::lock_and_push(
                         Arc::clone(&action_queue),
@@ -321,7 +448,7 @@ pub fn process_action(
             }
             return;
         }
-        Action::ProcessDatum(_datum, _sock_addr) => {
+        Action::ProcessDatum(_hash, _node, _sock_addr) => {
             /*DONE? */
             return;
         }
@@ -330,29 +457,10 @@ pub fn process_action(
             return;
         }
         Action::ProcessNatTraversal(body, _sock_addr) => {
-            let addr: SocketAddr;
-            if body.len() == 6 {
-                addr = SocketAddr::new(
-                    IpAddr::V4(Ipv4Addr::new(body[0], body[1], body[2], body[3])),
-                    (body[4] as u16) * 256 + (body[5] as u16),
-                );
-            } else if body.len() == 18 {
-                addr = SocketAddr::new(
-                    IpAddr::V6(Ipv6Addr::new(
-                        (body[0] as u16) * 256 + (body[1] as u16),
-                        (body[2] as u16) * 256 + (body[3] as u16),
-                        (body[4] as u16) * 256 + (body[5] as u16),
-                        (body[6] as u16) * 256 + (body[7] as u16),
-                        (body[8] as u16) * 256 + (body[9] as u16),
-                        (body[10] as u16) * 256 + (body[11] as u16),
-                        (body[12] as u16) * 256 + (body[13] as u16),
-                        (body[14] as u16) * 256 + (body[15] as u16),
-                    )),
-                    (body[16] as u16) * 256 + (body[17] as u16),
-                );
-            } else {
-                return;
-            }
+            let addr = match socket_addr_from_bytes(&body) {
+                Some(addr) => addr,
+                None => return,
+            };
 
             Queue::lock_and_push(
                 Arc::clone(&action_queue),
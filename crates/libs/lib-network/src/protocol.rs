@@ -0,0 +1,24 @@
+/*
+    Sizes fixed by the protocol. A datagram is the header, the body
+    and, for signed packets, the signature. The largest body is a
+    Datum : the hash then a node made of its type and at most one
+    chunk, 32 children hashes or 16 directory entries.
+*/
+
+/*Id, type and body length */
+pub const HEADER_LENGTH: usize = 4 + 1 + 2;
+pub const HASH_LENGTH: usize = 32;
+pub const PUBLIC_KEY_LENGTH: usize = 64;
+pub const SIGNATURE_LENGTH: usize = 64;
+
+pub const CHUNK_SIZE: usize = 1024;
+/*Children of a big file, HASH_LENGTH bytes each */
+pub const MAX_CHILDREN: usize = CHUNK_SIZE / HASH_LENGTH;
+/*Names of directory entries are padded with zeros */
+pub const NAME_LENGTH: usize = 32;
+pub const MAX_DIRECTORY_ENTRIES: usize = CHUNK_SIZE / (NAME_LENGTH + HASH_LENGTH);
+
+/*Type of the node then its content */
+pub const MAX_NODE_LENGTH: usize = 1 + CHUNK_SIZE;
+pub const MAX_BODY_LENGTH: usize = HASH_LENGTH + MAX_NODE_LENGTH;
+pub const MAX_DATAGRAM_LENGTH: usize = HEADER_LENGTH + MAX_BODY_LENGTH + SIGNATURE_LENGTH;
@@ -0,0 +1,164 @@
+use std::{collections::HashMap, net::SocketAddr, time::Instant};
+
+use crate::protocol::MAX_BODY_LENGTH;
+
+/*Above this many buckets, the ones that refilled are forgotten */
+const MAX_IDLE_BUCKETS: usize = 4096;
+
+/*
+    Bandwidth an exporter gives to the datums it serves, in bytes per
+    second, to each address and to all of them together. None means
+    no limit.
+*/
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RateLimits {
+    pub per_peer: Option<u64>,
+    pub global: Option<u64>,
+}
+
+impl RateLimits {
+    pub fn new(per_peer: Option<u64>, global: Option<u64>) -> Self {
+        Self { per_peer, global }
+    }
+}
+
+/*
+    Holds up to one second of rate, refilled continuously. A datum is
+    served only if the bucket holds its size, so it holds at least the
+    largest one whatever the rate, or some would never be served.
+*/
+#[derive(Debug, Clone, Copy)]
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64, now: Instant) -> Self {
+        let capacity = rate.max(MAX_BODY_LENGTH as u64) as f64;
+        Self {
+            rate: rate as f64,
+            capacity,
+            tokens: capacity,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last = now;
+    }
+
+    pub fn has(&mut self, amount: u64, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= amount as f64
+    }
+
+    pub fn take(&mut self, amount: u64) {
+        self.tokens -= amount as f64;
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.capacity
+    }
+}
+
+/*
+    Token buckets of the process task, keyed by address since a peer
+    can only be told apart by its address before its Hello.
+*/
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    limits: RateLimits,
+    global: Option<TokenBucket>,
+    addr_to_bucket: HashMap<SocketAddr, TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        let now = Instant::now();
+        Self {
+            limits,
+            global: limits.global.map(|rate| TokenBucket::new(rate, now)),
+            addr_to_bucket: HashMap::new(),
+        }
+    }
+
+    /*Whether sock_addr may be sent size more bytes, takes them if so */
+    pub fn allow(&mut self, sock_addr: SocketAddr, size: usize) -> bool {
+        self.allow_at(sock_addr, size, Instant::now())
+    }
+
+    pub fn allow_at(&mut self, sock_addr: SocketAddr, size: usize, now: Instant) -> bool {
+        let size = size as u64;
+        if let Some(global) = &mut self.global {
+            if !global.has(size, now) {
+                return false;
+            }
+        }
+        if let Some(rate) = self.limits.per_peer {
+            if self.addr_to_bucket.len() > MAX_IDLE_BUCKETS {
+                self.addr_to_bucket.retain(|_, bucket| !bucket.is_full(now));
+            }
+            let bucket = self
+                .addr_to_bucket
+                .entry(sock_addr)
+                .or_insert_with(|| TokenBucket::new(rate, now));
+            if !bucket.has(size, now) {
+                return false;
+            }
+            bucket.take(size);
+        }
+        if let Some(global) = &mut self.global {
+            global.take(size);
+        }
+        true
+    }
+
+    pub fn get_limits(&self) -> RateLimits {
+        self.limits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn lib_network_rate_limit_per_peer_and_global() {
+        let now = Instant::now();
+        let peer1: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        let peer2: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+
+        let mut limiter = RateLimiter::new(RateLimits::new(Some(2000), Some(3000)));
+        assert!(limiter.allow_at(peer1, 1000, now));
+        assert!(limiter.allow_at(peer1, 1000, now));
+        /*peer1 spent its second, peer2 still has its own */
+        assert!(!limiter.allow_at(peer1, 1000, now));
+        assert!(limiter.allow_at(peer2, 1000, now));
+        /*Everyone together spent the global budget */
+        assert!(!limiter.allow_at(peer2, 1000, now));
+
+        /*Half a second later half of each bucket is back */
+        let later = now + Duration::from_millis(500);
+        assert!(limiter.allow_at(peer1, 1000, later));
+        assert!(!limiter.allow_at(peer1, 1000, later));
+
+        /*A rate below a datum still lets one through now and then */
+        let mut slow = RateLimiter::new(RateLimits::new(Some(100), None));
+        assert!(slow.allow_at(peer1, MAX_BODY_LENGTH, now));
+        assert!(!slow.allow_at(peer1, MAX_BODY_LENGTH, later));
+        assert!(slow.allow_at(peer1, MAX_BODY_LENGTH, now + Duration::from_secs(11)));
+
+        let mut unlimited = RateLimiter::new(RateLimits::default());
+        for _ in 0..100 {
+            assert!(unlimited.allow_at(peer1, 1_000_000, now));
+        }
+    }
+}
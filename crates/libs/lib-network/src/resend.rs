@@ -1,59 +1,47 @@
-use crate::{action::Action, peer::Peer};
+use crate::config::NodeConfig;
 use crate::{
-    congestion_handler::{PendingIds, Queue, QueueState},
-    import_export::keep_alive_to_peer,
-    packet::{Packet, PacketBuilder},
-    peer::ActivePeers,
-};
-use std::{
-    net::SocketAddr,
-    sync::{Arc, Mutex},
-    thread::sleep,
-    time::Duration,
+    congestion_handler::{PendingIds, Queue, QueueState, Queues, MAX_RTO},
+    packet::PacketBuilder,
 };
+use log::error;
+use std::{sync::Arc, time::Duration};
+use tokio::{task::JoinHandle, time::sleep};
+use tokio_util::sync::CancellationToken;
 
 /*Remark: Packets/ids are popped only when received. */
 pub fn resend_task(
-    pending_ids: Arc<Mutex<PendingIds>>,
-    _peek_active_peers: Arc<Mutex<ActivePeers>>,
-    action_queue: Arc<Mutex<Queue<Action>>>,
-    action_queue_state: Arc<QueueState>,
-    sending_queue: Arc<Mutex<Queue<(Packet, SocketAddr)>>>,
-    sending_queue_state: Arc<QueueState>,
-    my_data: Arc<Peer>,
-) {
+    queues: &Queues,
+    config: Arc<NodeConfig>,
+    cancel: CancellationToken,
+) -> JoinHandle<()> {
+    let pending_ids = Arc::clone(&queues.pending_ids);
+    let pending_ids_state = Arc::clone(&queues.pending_ids_state);
+    let sending_queue = Arc::clone(&queues.send_queue);
+    let sending_queue_state = Arc::clone(&queues.send_queue_state);
     tokio::spawn(async move {
-        /*Shouldn't wait/resend after replies!!  */
-        let server_socket_addr4: SocketAddr = "81.194.27.155:8443".parse().unwrap();
-        let server_socket_addr6: SocketAddr =
-            "[2001:660:3301:9200::51c2:1b9b]:8443".parse().unwrap();
-
-        keep_alive_to_peer(
-            action_queue.clone(),
-            action_queue_state.clone(),
-            server_socket_addr4.clone(),
-            my_data,
-            30_000,
-        );
+        /*
+            Each pending packet has its own timeout, from the rtt of its
+            peer. Sleep until the first one expires, or until a new
+            packet is sent since it may expire sooner.
+        */
         loop {
-            sleep(Duration::from_secs(2));
-            // pending_ids_state.wait();
+            QueueState::set_empty_queue(Arc::clone(&pending_ids_state));
             let (addr_to_send_nat_trav, packet_to_resend) =
                 PendingIds::packets_to_resend(Arc::clone(&pending_ids));
 
+            let resend = !packet_to_resend.is_empty();
             Queue::lock_and_push_mul(sending_queue.clone(), packet_to_resend);
-            QueueState::set_non_empty_queue(sending_queue_state.clone());
 
             let mut nat_trav_packets = vec![];
             for addr in addr_to_send_nat_trav {
-                nat_trav_packets.push((
-                    PacketBuilder::nat_traversal_request_from_addr_packet(addr),
-                    server_socket_addr4,
-                ));
-                nat_trav_packets.push((
-                    PacketBuilder::nat_traversal_request_from_addr_packet(addr),
-                    server_socket_addr6,
-                ));
+                for server_socket_addr in config.get_rendezvous_servers() {
+                    match PacketBuilder::nat_traversal_request_from_addr_packet(addr) {
+                        Ok(packet) => nat_trav_packets.push((packet, *server_socket_addr)),
+                        Err(e) => error!(
+                            "[resend] Failed to build the NatTraversalRequest for {addr} : {e}"
+                        ),
+                    }
+                }
             }
             nat_trav_packets.sort_by(|a, b| a.1.cmp(&b.1) );
             let mut nat_trav_packets_norepeat = vec![];
@@ -68,7 +56,19 @@ pub fn resend_task(
                 nat_trav_packets_norepeat.push(nat_trav_packets[i].clone());
             }
 
+            let nat_trav = !nat_trav_packets_norepeat.is_empty();
             Queue::lock_and_push_mul(sending_queue.clone(), nat_trav_packets_norepeat);
+            if resend || nat_trav {
+                QueueState::set_non_empty_queue(sending_queue_state.clone());
+            }
+
+            let next_resend_in = PendingIds::lock_and_next_resend_in(Arc::clone(&pending_ids))
+                .unwrap_or(Duration::from_millis(MAX_RTO));
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = sleep(next_resend_in) => (),
+                _ = pending_ids_state.wait() => (),
+            }
         }
-    });
+    })
 }
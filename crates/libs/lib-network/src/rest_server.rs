@@ -0,0 +1,191 @@
+use std::net::SocketAddr;
+
+use lib_web::discovery;
+use log::{error, info, warn};
+use reqwest::{Client, Url};
+
+use crate::peer::{Peer, PeerError};
+
+/*What to do with a peer whose announced key or root doesn't
+match what the REST server knows about its name. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MismatchPolicy {
+    /*Keep the peer but record the mismatch on it : its root isn't
+    returned and nothing is downloaded from it */
+    #[default]
+    Flag,
+    /*Forget the peer, it has to handshake again */
+    Drop,
+}
+
+/*
+    Handle to the central REST server, used to cross-check what
+    peers announce over UDP against the server's records and to
+    publish our own.
+*/
+#[derive(Debug, Clone)]
+pub struct RestServer {
+    client: Client,
+    url: Url,
+    on_mismatch: MismatchPolicy,
+}
+
+impl RestServer {
+    pub fn new(url: Url, timeout: u64, on_mismatch: MismatchPolicy) -> anyhow::Result<Self> {
+        Ok(Self {
+            client: discovery::get_client(timeout)?,
+            url,
+            on_mismatch,
+        })
+    }
+
+    pub fn get_client(&self) -> &Client {
+        &self.client
+    }
+    pub fn get_url(&self) -> &Url {
+        &self.url
+    }
+    pub fn on_mismatch(&self) -> MismatchPolicy {
+        self.on_mismatch
+    }
+
+    pub async fn check_public_key(
+        &self,
+        name: &str,
+        public_key: Option<[u8; 64]>,
+    ) -> Result<(), PeerError> {
+        let recorded = match discovery::get_peer_key(&self.client, &self.url, name).await {
+            Ok(recorded) => recorded,
+            Err(e) => {
+                error!("Failed to fetch the key of {name} : {e}");
+                return Err(PeerError::ServerUnreachable);
+            }
+        };
+        compare_public_key(&recorded, public_key)
+    }
+
+    pub async fn check_root(&self, name: &str, root: Option<[u8; 32]>) -> Result<(), PeerError> {
+        let recorded = match discovery::get_peer_root(&self.client, &self.url, name).await {
+            Ok(recorded) => recorded,
+            Err(e) => {
+                error!("Failed to fetch the root of {name} : {e}");
+                return Err(PeerError::ServerUnreachable);
+            }
+        };
+        compare_root(&recorded, root)
+    }
+
+    /*Publishes our key, root and addresses under our name. The server
+    only knows names that said Hello to it, so this has to follow the
+    Hello handshake. Missing fields are left untouched on the server,
+    addresses are added to the ones it saw our Hellos come from.
+    Each PUT is signed with our key, the only one allowed to change
+    the records of our name once the server has it. */
+    pub async fn publish(&self, my_data: &Peer, addresses: &[SocketAddr]) -> Result<(), PeerError> {
+        let name = match my_data.get_name() {
+            Some(name) => name,
+            None => return Err(PeerError::RegistrationFailed),
+        };
+        let sign = my_data
+            .get_key_pair()
+            .map(|key_pair| |data: &[u8]| key_pair.sign(data).to_vec());
+        let signer = sign.as_ref().map(|sign| sign as discovery::Signer);
+
+        if let Some(public_key) = my_data.get_public_key() {
+            if let Err(e) =
+                discovery::put_peer_key(&self.client, &self.url, name, &public_key, signer).await
+            {
+                error!("Failed to publish the key of {name} : {e}");
+                return Err(PeerError::RegistrationFailed);
+            }
+        }
+        if let Some(root) = my_data.get_root_hash() {
+            if let Err(e) =
+                discovery::put_peer_root(&self.client, &self.url, name, &root, signer).await
+            {
+                error!("Failed to publish the root of {name} : {e}");
+                return Err(PeerError::RegistrationFailed);
+            }
+        }
+        if !addresses.is_empty() {
+            let mut published =
+                match discovery::get_peer_addresses(&self.client, &self.url, name).await {
+                    Ok(peer) => peer.addresses,
+                    Err(e) => {
+                        error!("Failed to fetch the addresses of {name} : {e}");
+                        return Err(PeerError::RegistrationFailed);
+                    }
+                };
+            for address in addresses.iter().map(|a| a.to_string()) {
+                if !published.contains(&address) {
+                    published.push(address);
+                }
+            }
+            if let Err(e) =
+                discovery::put_peer_addresses(&self.client, &self.url, name, &published, signer)
+                    .await
+            {
+                error!("Failed to publish the addresses of {name} : {e}");
+                return Err(PeerError::RegistrationFailed);
+            }
+        }
+
+        info!("Published {name} on {}", self.url);
+        Ok(())
+    }
+}
+
+/*An empty record means the peer didn't register any key */
+pub fn compare_public_key(recorded: &[u8], public_key: Option<[u8; 64]>) -> Result<(), PeerError> {
+    let matches = match public_key {
+        Some(public_key) => recorded == public_key.as_slice(),
+        None => recorded.is_empty(),
+    };
+    if matches {
+        Ok(())
+    } else {
+        warn!("Announced public key doesn't match the server's record");
+        Err(PeerError::PublicKeyMismatch)
+    }
+}
+
+/*An empty record means the peer doesn't export anything */
+pub fn compare_root(recorded: &[u8], root: Option<[u8; 32]>) -> Result<(), PeerError> {
+    let matches = match root {
+        Some(root) => recorded == root.as_slice(),
+        None => recorded.is_empty(),
+    };
+    if matches {
+        Ok(())
+    } else {
+        warn!("Announced root doesn't match the server's record");
+        Err(PeerError::RootMismatch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lib_network_rest_server_compare_records() {
+        assert!(compare_public_key(&[], None).is_ok());
+        assert!(compare_public_key(&[3u8; 64], Some([3u8; 64])).is_ok());
+        assert_eq!(
+            compare_public_key(&[3u8; 64], Some([4u8; 64])),
+            Err(PeerError::PublicKeyMismatch)
+        );
+        /*A peer can't drop the key the server knows about */
+        assert_eq!(
+            compare_public_key(&[3u8; 64], None),
+            Err(PeerError::PublicKeyMismatch)
+        );
+
+        assert!(compare_root(&[], None).is_ok());
+        assert!(compare_root(&[1u8; 32], Some([1u8; 32])).is_ok());
+        assert_eq!(
+            compare_root(&[], Some([1u8; 32])),
+            Err(PeerError::RootMismatch)
+        );
+    }
+}
@@ -2,11 +2,14 @@ use log::{debug, error};
 use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
 
-use tokio::net::UdpSocket;
+use tokio::{net::UdpSocket, task::JoinHandle};
+use tokio_util::sync::CancellationToken;
 
 
-use crate::congestion_handler::{PendingIds, Queue, QueueState};
-use crate::packet::Packet;
+use crate::congestion_handler::{PendingIds, Queue, QueueState, Queues};
+use crate::extensions::Extensions;
+use crate::packet::{Packet, PacketBuilder, PacketType};
+use crate::peer::{ActivePeers, Peer};
 
 /*
     Maybe lock only first and last element ? sender accesses only the first
@@ -14,17 +17,26 @@ use crate::packet::Packet;
 */
 pub fn receiver4(
     sock4: Arc<UdpSocket>,
-    receive_queue: Arc<Mutex<Queue<(Packet, SocketAddr)>>>,
-    receive_queue_state: Arc<QueueState>,
-) {
+    queues: &Queues,
+    active_peers: Arc<Mutex<ActivePeers>>,
+    cancel: CancellationToken,
+) -> JoinHandle<()> {
+    let receive_queue = Arc::clone(&queues.receive_queue);
+    let receive_queue_state = Arc::clone(&queues.receive_queue_state);
     tokio::spawn(async move {
         loop {
             /*Get the first packet in the Queue or None if the queue
             is empty. Also break, if task is cancelled. */
-            let (sock_addr, packet) = match Packet::recv_from(&sock4).await{
-                        Ok(packet_and_addr)=>packet_and_addr,
-                    _=> continue,
-                };
+            let (sock_addr, packet) = tokio::select! {
+                _ = cancel.cancelled() => break,
+                received = Packet::recv_from(&sock4) => match received {
+                    Ok(packet_and_addr) => packet_and_addr,
+                    Err(e) => {
+                        debug!("Dropped datagram : {e}");
+                        continue;
+                    }
+                },
+            };
 
             debug!(
                 "Received {} packet from {}\n",
@@ -32,25 +44,46 @@ pub fn receiver4(
                 sock_addr,
             );
 
-            Queue::lock_and_push(Arc::clone(&receive_queue), (packet, sock_addr));
+            /*What we may send back to an unvalidated address */
+            ActivePeers::lock_and_add_received(
+                Arc::clone(&active_peers),
+                sock_addr,
+                packet.as_bytes().len(),
+            );
+            /*Stops reading the socket while the queue is full */
+            let pushed =
+                Queue::lock_and_push_or_wait(Arc::clone(&receive_queue), (packet, sock_addr));
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = pushed => (),
+            }
             QueueState::set_non_empty_queue(Arc::clone(&receive_queue_state));
         }
-    });
+    })
 }
 
 pub fn receiver6(
     sock6: Arc<UdpSocket>,
-    receive_queue: Arc<Mutex<Queue<(Packet, SocketAddr)>>>,
-    receive_queue_state: Arc<QueueState>,
-) {
+    queues: &Queues,
+    active_peers: Arc<Mutex<ActivePeers>>,
+    cancel: CancellationToken,
+) -> JoinHandle<()> {
+    let receive_queue = Arc::clone(&queues.receive_queue);
+    let receive_queue_state = Arc::clone(&queues.receive_queue_state);
     tokio::spawn(async move {
         loop {
             /*Get the first packet in the Queue or None if the queue
             is empty */
-            let (sock_addr, packet) = match Packet::recv_from(&sock6).await{
-                        Ok(packet_and_addr)=>packet_and_addr,
-                    _=> continue,
-                };
+            let (sock_addr, packet) = tokio::select! {
+                _ = cancel.cancelled() => break,
+                received = Packet::recv_from(&sock6) => match received {
+                    Ok(packet_and_addr) => packet_and_addr,
+                    Err(e) => {
+                        debug!("Dropped datagram : {e}");
+                        continue;
+                    }
+                },
+            };
 
             debug!(
                 "Received {} packet from {} : {:?}\n",
@@ -58,23 +91,41 @@ pub fn receiver6(
                 sock_addr,
                 packet
             );
-            /*Maybe should be async? */
-            Queue::lock_and_push(Arc::clone(&receive_queue), (packet, sock_addr));
-
+            /*What we may send back to an unvalidated address */
+            ActivePeers::lock_and_add_received(
+                Arc::clone(&active_peers),
+                sock_addr,
+                packet.as_bytes().len(),
+            );
+            /*Stops reading the socket while the queue is full */
+            let pushed =
+                Queue::lock_and_push_or_wait(Arc::clone(&receive_queue), (packet, sock_addr));
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = pushed => (),
+            }
             QueueState::set_non_empty_queue(Arc::clone(&receive_queue_state));
         }
-    });
+    })
 }
 
 pub fn sender(
     sock4: Arc<UdpSocket>,
     sock6: Arc<UdpSocket>,
-    send_queue: Arc<Mutex<Queue<(Packet, SocketAddr)>>>,
-    send_queue_state: Arc<QueueState>,
-    pending_ids_to_add: Arc<Mutex<PendingIds>>,
-) {
+    queues: &Queues,
+    active_peers: Arc<Mutex<ActivePeers>>,
+    my_data: Arc<Peer>,
+    cancel: CancellationToken,
+) -> JoinHandle<()> {
+    let send_queue = Arc::clone(&queues.send_queue);
+    let send_queue_state = Arc::clone(&queues.send_queue_state);
+    let pending_ids_to_add = Arc::clone(&queues.pending_ids);
+    let pending_ids_state = Arc::clone(&queues.pending_ids_state);
     tokio::spawn(async move {
         loop {
+            if cancel.is_cancelled() {
+                break;
+            }
             /*Get the first packet in the Queue or None if the queue
             is empty */
             let packet_for_addr = {
@@ -92,17 +143,31 @@ pub fn sender(
 
             /*If the queue is empty, put the thread to sleep until queue
             is not empty*/
-            let (packet, sock_addr) = match packet_for_addr {
+            let (mut packet, sock_addr) = match packet_for_addr {
                 Some(packet_for_addr) => packet_for_addr,
                 None => {
                         QueueState::set_empty_queue(Arc::clone(&send_queue_state));
                         /*Passively wait for non empty queue state */
-                        send_queue_state.wait();
-                        continue
+                        tokio::select! {
+                            _ = cancel.cancelled() => break,
+                            _ = send_queue_state.wait() => continue,
+                        }
                     }
             };
 
+            /*Sign before storing the packet so that resent packets keep their signature */
+            sign(&mut packet, sock_addr, &active_peers, &my_data);
+
+            let packet = match limit_amplification(packet, sock_addr, &active_peers, &my_data) {
+                Some(packet) => packet,
+                None => {
+                    debug!("Dropped packet to unvalidated {sock_addr}");
+                    continue;
+                }
+            };
+
             PendingIds::lock_and_add_id(Arc::clone(&pending_ids_to_add), &packet, &sock_addr);
+            QueueState::set_non_empty_queue(Arc::clone(&pending_ids_state));
             let sent = match sock_addr {
                 SocketAddr::V4(_) => packet.send_to_addr(&sock4, &sock_addr).await,
                 SocketAddr::V6(_) => packet.send_to_addr(&sock6, &sock_addr).await,
@@ -121,5 +186,84 @@ pub fn sender(
                 }
             }
         }
-    });
+    })
+}
+
+/*Peers we didn't negotiate signatures with don't get signed packets
+but the ones that predate extensions, the ones that didn't say Hello
+yet get them if we sign */
+fn sign(
+    packet: &mut Packet,
+    sock_addr: SocketAddr,
+    active_peers: &Arc<Mutex<ActivePeers>>,
+    my_data: &Peer,
+) {
+    if !packet.get_packet_type().is_signed() || packet.get_signature().is_ok() {
+        return;
+    }
+    let gets_signatures =
+        ActivePeers::lock_and_gets_signatures(Arc::clone(active_peers), sock_addr)
+            .unwrap_or(my_data.get_extensions().contains(Extensions::SIGNATURES));
+    if !gets_signatures {
+        return;
+    }
+    if let Some(key_pair) = my_data.get_key_pair() {
+        key_pair.sign_packet(packet);
+    }
+}
+
+/*
+    Replies to an address that never answered our Hello are capped,
+    see ActivePeers::lock_and_try_send, but for the first HelloReply
+    and Hello of the handshake. An address that didn't even say Hello
+    only gets the HelloReply, a reply over the cap or to such an
+    address is replaced by an ErrorReply asking for a handshake, or
+    dropped if even that doesn't fit.
+*/
+fn limit_amplification(
+    packet: Packet,
+    sock_addr: SocketAddr,
+    active_peers: &Arc<Mutex<ActivePeers>>,
+    my_data: &Peer,
+) -> Option<Packet> {
+    let is_response = packet.is_response();
+    if (packet.is(PacketType::Hello) || packet.is(PacketType::HelloReply))
+        && ActivePeers::lock_and_try_send_handshake(
+            Arc::clone(active_peers),
+            sock_addr,
+            is_response,
+        )
+    {
+        return Some(packet);
+    }
+    let is_handshake = packet.is(PacketType::HelloReply) || packet.is(PacketType::ErrorReply);
+    let is_known = ActivePeers::lock_and_get(Arc::clone(active_peers), sock_addr).is_some();
+    if !is_response || is_handshake || is_known {
+        let size = packet.as_bytes().len();
+        if ActivePeers::lock_and_try_send(Arc::clone(active_peers), sock_addr, size, is_response) {
+            return Some(packet);
+        }
+        if !is_response {
+            return None;
+        }
+    }
+    let mut error_reply =
+        match PacketBuilder::error_reply_packet(packet.get_id(), Some(HANDSHAKE_FIRST.to_vec())) {
+            Ok(error_reply) => error_reply,
+            Err(e) => {
+                error!(
+                    "[limit_amplification] Failed to build the ErrorReply for {sock_addr} : {e}"
+                );
+                return None;
+            }
+        };
+    sign(&mut error_reply, sock_addr, active_peers, my_data);
+    let size = error_reply.as_bytes().len();
+    match ActivePeers::lock_and_try_send(Arc::clone(active_peers), sock_addr, size, true) {
+        true => Some(error_reply),
+        false => None,
+    }
 }
+
+/*Body of the ErrorReply to an unvalidated address over its cap or unknown */
+pub const HANDSHAKE_FIRST: &[u8] = b"Unverified address, reply to our Hello first";
@@ -0,0 +1,164 @@
+use std::fmt;
+
+use p256::ecdsa::{
+    signature::{Signer, Verifier},
+    Signature, SigningKey, VerifyingKey,
+};
+use p256::EncodedPoint;
+use rand_core::OsRng;
+use thiserror::Error;
+
+use crate::packet::Packet;
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureError {
+    #[error("Packet is not signed")]
+    Unsigned,
+    #[error("Invalid signature")]
+    InvalidSignature,
+    #[error("Invalid public key")]
+    InvalidPublicKey,
+    #[error("Invalid private key")]
+    InvalidPrivateKey,
+}
+
+/*
+    ECDSA P-256 identity of the local peer.
+    Public keys travel as the 64 bytes x||y of the uncompressed
+    point (without the 0x04 prefix) and signatures as the 64 bytes r||s.
+*/
+#[derive(Clone)]
+pub struct KeyPair {
+    signing_key: SigningKey,
+}
+
+impl fmt::Debug for KeyPair {
+    /*Never print the private key */
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "KeyPair({})", hex::encode(self.public_key()))
+    }
+}
+
+impl KeyPair {
+    pub fn generate() -> Self {
+        Self {
+            signing_key: SigningKey::random(&mut OsRng),
+        }
+    }
+
+    pub fn from_private_key(private_key: &[u8; 32]) -> Result<Self, SignatureError> {
+        match SigningKey::from_slice(private_key) {
+            Ok(signing_key) => Ok(Self { signing_key }),
+            Err(_) => Err(SignatureError::InvalidPrivateKey),
+        }
+    }
+
+    pub fn private_key(&self) -> [u8; 32] {
+        let mut private_key = [0u8; 32];
+        private_key.copy_from_slice(&self.signing_key.to_bytes());
+        private_key
+    }
+
+    pub fn public_key(&self) -> [u8; 64] {
+        let point = self.signing_key.verifying_key().to_encoded_point(false);
+        let mut public_key = [0u8; 64];
+        /*Skip the 0x04 tag of the uncompressed point */
+        public_key.copy_from_slice(&point.as_bytes()[1..65]);
+        public_key
+    }
+
+    pub fn sign(&self, data: &[u8]) -> [u8; 64] {
+        let signature: Signature = self.signing_key.sign(data);
+        let mut raw_signature = [0u8; 64];
+        raw_signature.copy_from_slice(&signature.to_bytes());
+        raw_signature
+    }
+
+    /*Signs the header and the body of the packet, replacing any previous signature */
+    pub fn sign_packet(&self, packet: &mut Packet) {
+        let signature = self.sign(&packet.as_unsigned_bytes());
+        packet.set_signature(Some(signature));
+    }
+}
+
+pub fn verify(
+    public_key: &[u8; 64],
+    data: &[u8],
+    signature: &[u8; 64],
+) -> Result<(), SignatureError> {
+    let mut raw_point = [4u8; 65];
+    raw_point[1..].copy_from_slice(public_key);
+
+    let point = match EncodedPoint::from_bytes(raw_point) {
+        Ok(point) => point,
+        Err(_) => return Err(SignatureError::InvalidPublicKey),
+    };
+    let verifying_key = match VerifyingKey::from_encoded_point(&point) {
+        Ok(key) => key,
+        Err(_) => return Err(SignatureError::InvalidPublicKey),
+    };
+    let signature = match Signature::from_slice(signature) {
+        Ok(signature) => signature,
+        Err(_) => return Err(SignatureError::InvalidSignature),
+    };
+
+    match verifying_key.verify(data, &signature) {
+        Ok(()) => Ok(()),
+        Err(_) => Err(SignatureError::InvalidSignature),
+    }
+}
+
+pub fn verify_packet(public_key: &[u8; 64], packet: &Packet) -> Result<(), SignatureError> {
+    let signature = match packet.get_signature() {
+        Ok(signature) => signature,
+        Err(_) => return Err(SignatureError::Unsigned),
+    };
+
+    verify(public_key, &packet.as_unsigned_bytes(), signature)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{extensions::Extensions, packet::PacketBuilder};
+
+    #[test]
+    fn lib_network_signature_sign_and_verify() {
+        let key_pair = KeyPair::generate();
+        let mut packet = PacketBuilder::root_packet(Some([7u8; 32])).unwrap();
+        key_pair.sign_packet(&mut packet);
+
+        assert!(verify_packet(&key_pair.public_key(), &packet).is_ok());
+
+        /*Signature must survive the wire format */
+        let received = Packet::from_bytes(packet.as_bytes().into()).unwrap();
+        assert!(verify_packet(&key_pair.public_key(), &received).is_ok());
+    }
+
+    #[test]
+    fn lib_network_signature_rejects_tampered_and_unsigned() {
+        let key_pair = KeyPair::generate();
+        let other_key_pair = KeyPair::generate();
+
+        let unsigned = PacketBuilder::hello_packet(Extensions::empty(), b"nist".to_vec()).unwrap();
+        assert_eq!(
+            verify_packet(&key_pair.public_key(), &unsigned),
+            Err(SignatureError::Unsigned)
+        );
+
+        let mut packet =
+            PacketBuilder::hello_packet(Extensions::empty(), b"nist".to_vec()).unwrap();
+        key_pair.sign_packet(&mut packet);
+        assert_eq!(
+            verify_packet(&other_key_pair.public_key(), &packet),
+            Err(SignatureError::InvalidSignature)
+        );
+
+        let mut forged = PacketBuilder::hello_packet(Extensions::empty(), b"jch".to_vec()).unwrap();
+        forged.set_signature(packet.get_signature().ok().copied());
+        assert_eq!(
+            verify_packet(&key_pair.public_key(), &forged),
+            Err(SignatureError::InvalidSignature)
+        );
+    }
+}
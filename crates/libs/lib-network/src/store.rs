@@ -1,64 +1,52 @@
+use bytes::Bytes;
 use log::{error, warn};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 
 
 
 use crate::{action::Action, peer::PeerError};
 use std::sync::{Arc, Mutex};
 
-#[derive(Debug)]
-pub struct SimpleNode {
-    pub name: String,
-    pub hash: [u8; 32],
-    pub children: Option<Vec<SimpleNode>>,
-    pub data: Option<Vec<u8>>,
-}
-
-impl SimpleNode {
-    pub fn flatten(&self) -> Vec<u8> {
-        let mut data = Vec::<u8>::new();
-
-        match &self.data {
-            Some(d) => data.extend_from_slice(d),
-            None => (),
-        }
-
-        match &self.children {
-            Some(children) => {
-                for c in children.into_iter() {
-                    data.extend_from_slice(&c.flatten())
-                }
-            }
-            None => (),
-        }
-
-        return data;
-    }
+/*A datum of a file : a chunk of it, or the hashes of its parts in order */
+#[derive(Debug, PartialEq, Eq)]
+pub enum FileNode {
+    Chunk(Bytes),
+    Big(Vec<[u8; 32]>),
 }
 
-pub fn build_tree_mutex() -> Arc<
+/*
+    What fetch_subtree_from learns of a tree : child to parent, parent
+    to the names and hashes of its children (directories only) and
+    hash to name. Identical files share a hash, so hash to name only
+    keeps one of their names.
+*/
+pub type TreeMaps = Arc<
     Mutex<(
         HashMap<[u8; 32], [u8; 32]>,
-        HashMap<[u8; 32], Vec<[u8; 32]>>,
+        HashMap<[u8; 32], Vec<(String, [u8; 32])>>,
         HashMap<[u8; 32], String>,
     )>,
-> {
+>;
+
+pub fn build_tree_mutex() -> TreeMaps {
     Arc::new(Mutex::new((
         HashMap::<[u8; 32], [u8; 32]>::new(),
-        HashMap::<[u8; 32], Vec<[u8; 32]>>::new(),
+        HashMap::<[u8; 32], Vec<(String, [u8; 32])>>::new(),
         HashMap::<[u8; 32], String>::new(),
     )))
 }
 
+/*Name of a directory entry, padded with zeros up to 32 bytes */
+fn entry_name(name: &[u8]) -> String {
+    let mut name = name.to_vec();
+    name.retain(|&x| x != 0u8);
+    String::from_utf8_lossy(&name).to_string()
+}
+
 pub fn build_tree_maps(
     action: &Action,
-    maps: Arc<
-        Mutex<(
-            HashMap<[u8; 32], [u8; 32]>,
-            HashMap<[u8; 32], Vec<[u8; 32]>>,
-            HashMap<[u8; 32], String>,
-        )>,
-    >,
+    maps: TreeMaps,
 ) -> Result<Option<Vec<[u8; 32]>>, PeerError> {
     let mut guard = match maps.lock() {
         Ok(maps) => maps,
@@ -96,24 +84,23 @@ pub fn get_child_to_parent_hashmap(
     hashmap: &mut HashMap<[u8; 32], [u8; 32]>,
 ) -> Result<(), PeerError> {
     match action {
-        Action::ProcessDatum(data, _address) => {
-            let data_type = match data.get(32) {
+        Action::ProcessDatum(hash, node, _address) => {
+            let hash = *hash;
+            let data_type = match node.first() {
                 Some(d) => d.to_owned(),
                 None => return Err(PeerError::InvalidPacket),
             };
-            let mut hash = [0u8; 32];
-            let temp = match data.get(0..32) {
-                Some(d) => d,
-                None => return Err(PeerError::InvalidPacket),
-            };
-            hash.copy_from_slice(temp);
             match data_type {
                 0 => {}
                 1 => {
-                    let data = match data.get(33..) {
+                    let data = match node.get(1..) {
                         Some(d) => d,
                         None => return Err(PeerError::InvalidPacket),
                     };
+                    /*A truncated hash would panic copy_from_slice */
+                    if data.len() % 32 != 0 {
+                        return Err(PeerError::InvalidPacket);
+                    }
                     let leaves = data.chunks(32).map(|s| s.to_owned());
                     for leaf in leaves {
                         let mut leaf_slice = [0u8; 32];
@@ -122,14 +109,16 @@ pub fn get_child_to_parent_hashmap(
                     }
                 }
                 2 => {
-                    let data = match data.get(33..) {
+                    let data = match node.get(1..) {
                         Some(d) => d,
                         None => return Err(PeerError::InvalidPacket),
                     };
+                    if data.len() % 64 != 0 {
+                        return Err(PeerError::InvalidPacket);
+                    }
                     let leaves = data.chunks(64).map(|s| s.to_owned());
                     for leaf in leaves {
-                        let _name = leaf.get(0..32).unwrap();
-                        let leaf = leaf.get(32..).unwrap();
+                        let leaf = &leaf[32..];
                         let mut leaf_slice = [0u8; 32];
                         leaf_slice.copy_from_slice(&leaf);
                         hashmap.insert(leaf_slice, hash);
@@ -149,100 +138,48 @@ pub fn get_child_to_parent_hashmap(
     return Ok(());
 }
 
-pub fn get_children(
-    action: &Action,
-    maps: Arc<
-        Mutex<(
-            HashMap<[u8; 32], [u8; 32]>,
-            HashMap<[u8; 32], Vec<[u8; 32]>>,
-            HashMap<[u8; 32], String>,
-        )>,
-    >,
-) -> Result<SimpleNode, PeerError> {
+pub fn get_file_node(action: &Action) -> Result<FileNode, PeerError> {
     match action {
-        Action::ProcessDatum(data, _address) => {
-            let data_type = match data.get(32) {
+        Action::ProcessDatum(_hash, node, _address) => {
+            let data_type = match node.first() {
                 Some(d) => d.to_owned(),
                 None => return Err(PeerError::InvalidPacket),
             };
-            let mut hash = [0u8; 32];
-            let temp = match data.get(0..32) {
-                Some(d) => d,
-                None => return Err(PeerError::InvalidPacket),
-            };
-            hash.copy_from_slice(temp);
-            let name = match maps.lock() {
-                Ok(m) => match m.2.get(&hash) {
-                    Some(n) => n.to_string(),
-                    None => "/".to_string(),
-                },
-                Err(e) => {
-                    error!("Could not lock hash to name map because of {e}");
-                    panic!("Failed")
-                }
-            };
+            let data = node.slice(1..);
             match data_type {
-                0 => {
-                    let data = match data.get(33..) {
-                        Some(d) => d,
-                        None => return Err(PeerError::InvalidPacket),
-                    };
-                    let node = SimpleNode {
-                        name: name,
-                        hash: hash,
-                        children: None,
-                        data: Some(data.to_vec()),
-                    };
-                    return Ok(node);
-                }
+                0 => Ok(FileNode::Chunk(data)),
                 1 => {
-                    let data = match data.get(33..) {
-                        Some(d) => d,
-                        None => return Err(PeerError::InvalidPacket),
-                    };
-                    let leaves = data.chunks(32).map(|s| s.to_owned());
-                    let children: Vec<SimpleNode> = leaves
-                        .into_iter()
-                        .map(|l| {
-                            let mut leaf_slice = [0u8; 32];
-                            leaf_slice.copy_from_slice(&l);
-                            SimpleNode {
-                                name: name.clone(),
-                                hash: leaf_slice,
-                                children: None,
-                                data: None,
-                            }
+                    if data.len() % 32 != 0 {
+                        return Err(PeerError::InvalidPacket);
+                    }
+                    let children = data
+                        .chunks_exact(32)
+                        .map(|child| {
+                            let mut child_hash = [0u8; 32];
+                            child_hash.copy_from_slice(child);
+                            child_hash
                         })
                         .collect();
-                    let node = SimpleNode {
-                        name: name,
-                        hash: hash,
-                        children: Some(children),
-                        data: None,
-                    };
-                    return Ok(node);
-                }
-                2 => {
-                    warn!("Found a directory, not a valid file.");
-                    return Err(PeerError::InvalidPacket);
+                    Ok(FileNode::Big(children))
                 }
+                2 => Err(PeerError::FileIsDirectory),
                 _ => {
                     warn!("Not a mkfs node");
-                    return Err(PeerError::InvalidPacket);
+                    Err(PeerError::InvalidPacket)
                 }
             }
         }
         _ => {
             warn!("Not the datum we are looking for");
-            return Err(PeerError::InvalidPacket);
+            Err(PeerError::InvalidPacket)
         }
     }
 }
 
 pub fn get_type(action: &Action) -> Result<u8, PeerError> {
     match action {
-        Action::ProcessDatum(data, _address) => {
-            match data.get(32) {
+        Action::ProcessDatum(_hash, node, _address) => {
+            match node.first() {
                 Some(d) => return Ok(d.to_owned()),
                 None => return Err(PeerError::InvalidPacket),
             };
@@ -256,32 +193,30 @@ pub fn get_type(action: &Action) -> Result<u8, PeerError> {
 
 pub fn get_parent_to_child_hashmap(
     action: &Action,
-    hashmap: &mut HashMap<[u8; 32], Vec<[u8; 32]>>,
+    hashmap: &mut HashMap<[u8; 32], Vec<(String, [u8; 32])>>,
 ) -> Result<Option<Vec<[u8; 32]>>, PeerError> {
     match action {
-        Action::ProcessDatum(data, _address) => {
-            let data_type = match data.get(32) {
+        Action::ProcessDatum(hash, node, _address) => {
+            let hash = *hash;
+            let data_type = match node.first() {
                 Some(d) => d.to_owned(),
                 None => return Err(PeerError::InvalidPacket),
             };
-            let mut hash = [0u8; 32];
-            let temp = match data.get(0..32) {
-                Some(d) => d,
-                None => return Err(PeerError::InvalidPacket),
-            };
-            hash.copy_from_slice(temp);
             match data_type {
                 0 => Ok(None),
                 1 => Ok(None),
                 2 => {
-                    let data = match data.get(33..) {
+                    let data = match node.get(1..) {
                         Some(d) => d,
                         None => return Err(PeerError::InvalidPacket),
                     };
+                    if data.len() % 64 != 0 {
+                        return Err(PeerError::InvalidPacket);
+                    }
                     let leaves = data.chunks(64).map(|s| s.to_owned());
-                    let mut children: Vec<[u8; 32]> = Vec::new();
+                    let mut children: Vec<(String, [u8; 32])> = Vec::new();
                     for leaf in leaves {
-                        let _name = match leaf.get(0..32) {
+                        let name = match leaf.get(0..32) {
                             Some(d) => d,
                             None => return Err(PeerError::InvalidPacket),
                         };
@@ -291,10 +226,11 @@ pub fn get_parent_to_child_hashmap(
                         };
                         let mut leaf_slice = [0u8; 32];
                         leaf_slice.copy_from_slice(&leaf);
-                        children.push(leaf_slice);
+                        children.push((entry_name(name), leaf_slice));
                     }
-                    hashmap.insert(hash, children.clone());
-                    Ok(Some(children))
+                    let hashes = children.iter().map(|(_, hash)| *hash).collect();
+                    hashmap.insert(hash, children);
+                    Ok(Some(hashes))
                 }
                 _ => {
                     warn!("Not a mkfs node");
@@ -315,17 +251,11 @@ pub fn get_hash_to_name_hashmap(
     _c_to_p_hashmap: &HashMap<[u8; 32], [u8; 32]>,
 ) -> Result<(), PeerError> {
     match action {
-        Action::ProcessDatum(data, _address) => {
-            let data_type = match data.get(32) {
+        Action::ProcessDatum(_hash, node, _address) => {
+            let data_type = match node.first() {
                 Some(d) => d.to_owned(),
                 None => return Err(PeerError::InvalidPacket),
             };
-            let mut hash = [0u8; 32];
-            let temp = match data.get(0..32) {
-                Some(d) => d,
-                None => return Err(PeerError::InvalidPacket),
-            };
-            hash.copy_from_slice(temp);
 
             // let parent_name = match c_to_p_hashmap.get(&hash) {
             //     Some(p) => match h_to_n_hashmap.get(p) {
@@ -341,19 +271,20 @@ pub fn get_hash_to_name_hashmap(
                 0 => {}
                 1 => {}
                 2 => {
-                    let data = match data.get(33..) {
+                    let data = match node.get(1..) {
                         Some(d) => d,
                         None => return Err(PeerError::InvalidPacket),
                     };
+                    if data.len() % 64 != 0 {
+                        return Err(PeerError::InvalidPacket);
+                    }
                     let leaves = data.chunks(64).map(|s| s.to_owned());
                     for leaf in leaves {
                         let name = match leaf.get(0..32) {
                             Some(d) => d,
                             None => return Err(PeerError::InvalidPacket),
                         };
-                        let mut name = name.to_vec();
-                        name.retain(|&x| x != 0u8);
-                        let name = String::from_utf8(name).unwrap();
+                        let name = entry_name(name);
                         let leaf = match leaf.get(32..) {
                             Some(d) => d,
                             None => return Err(PeerError::InvalidPacket),
@@ -427,12 +358,104 @@ pub fn get_name_to_hash_hashmap(
     return n_to_h_hashmap;
 }
 
+/*A file or directory of a downloaded tree, path is relative to the output */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalEntry {
+    pub path: PathBuf,
+    pub hash: [u8; 32],
+    pub is_directory: bool,
+}
+
+/*
+    Local paths of everything under the directory with the given hash,
+    from the maps filled by fetch_subtree_from. A directory comes before
+    its content. Only directories are keys of p_to_c_hashmap, each entry
+    is named as its own directory names it.
+*/
+pub fn get_local_entries(
+    hash: &[u8; 32],
+    p_to_c_hashmap: &HashMap<[u8; 32], Vec<(String, [u8; 32])>>,
+) -> Vec<LocalEntry> {
+    let mut entries = Vec::new();
+    let children = match p_to_c_hashmap.get(hash) {
+        Some(children) => children,
+        None => return entries,
+    };
+    let mut taken = HashSet::new();
+    for (name, child) in children {
+        let path = PathBuf::from(deduplicate_name(sanitize_name(name, child), &mut taken));
+        let is_directory = p_to_c_hashmap.contains_key(child);
+        entries.push(LocalEntry {
+            path: path.clone(),
+            hash: *child,
+            is_directory,
+        });
+        if is_directory {
+            entries.extend(
+                get_local_entries(child, p_to_c_hashmap)
+                    .into_iter()
+                    .map(|entry| LocalEntry {
+                        path: path.join(entry.path),
+                        ..entry
+                    }),
+            );
+        }
+    }
+    entries
+}
+
+/*
+    Names come from the peer and some exporters send a whole path. Keeps
+    the last component without the characters a file system could trip
+    on, and never gives an empty name, . or .. : those are named after
+    the hash instead.
+*/
+pub fn sanitize_name(name: &str, hash: &[u8; 32]) -> String {
+    let name = name
+        .rsplit(['/', '\\'])
+        .find(|name| !name.is_empty())
+        .unwrap_or("");
+    let name: String = name
+        .chars()
+        .map(|c| match c.is_control() {
+            true => '_',
+            false => c,
+        })
+        .collect();
+    match name.as_str() {
+        "" | "." | ".." => hex::encode(&hash[..8]),
+        _ => name,
+    }
+}
+
+/*Entries whose names end up the same get " (1)", " (2)"... before the extension */
+fn deduplicate_name(name: String, taken: &mut HashSet<String>) -> String {
+    let mut candidate = name.clone();
+    let mut count = 1;
+    while !taken.insert(candidate.clone()) {
+        candidate = match name.rsplit_once('.') {
+            Some((stem, extension)) if !stem.is_empty() => {
+                format!("{stem} ({count}).{extension}")
+            }
+            _ => format!("{name} ({count})"),
+        };
+        count += 1;
+    }
+    candidate
+}
+
 #[cfg(test)]
 pub mod test {
     use std::net::SocketAddr;
 
     use super::*;
 
+    /*data is the body of the Datum packet */
+    fn datum(mut data: Vec<u8>, address: SocketAddr) -> Action {
+        let node = data.split_off(32);
+        Action::ProcessDatum(data.try_into().unwrap(), node.into(), address)
+    }
+
     #[test]
     fn lib_network_store_get_child_to_parent_hashmap() {
         let address = "127.0.0.1:8080".parse::<SocketAddr>().unwrap();
@@ -447,7 +470,7 @@ pub mod test {
             0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
         ];
 
-        let _action = Action::ProcessDatum(data, address);
+        let _action = datum(data, address);
         // let mut hashmap = Arc::new(Mutex::new(HashMap::<[u8; 32], [u8; 32]>::new()));
         // // get_child_to_parent_hashmap(&action, Arc::clone(&hashmap));
         // let hashmap = hashmap.lock().unwrap();
@@ -468,7 +491,7 @@ pub mod test {
             0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
         ];
 
-        let _action = Action::ProcessDatum(data, address);
+        let _action = datum(data, address);
         // let mut p_to_c_hashmap = Arc::new(Mutex::new(HashMap::<[u8; 32], Vec<[u8; 32]>>::new()));
         // get_parent_to_child_hashmap(&action, Arc::clone(&p_to_c_hashmap));
         // println!("{:?}", p_to_c_hashmap);
@@ -488,7 +511,7 @@ pub mod test {
             0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
         ];
 
-        let _action = Action::ProcessDatum(data, address);
+        let _action = datum(data, address);
         // let mut c_to_p_hashmap = Arc::new(Mutex::new(HashMap::<[u8; 32], [u8; 32]>::new()));
         // get_child_to_parent_hashmap(&action, Arc::clone(&c_to_p_hashmap));
         // let mut h_to_n_hashmap = HashMap::<[u8; 32], String>::new();
@@ -521,38 +544,103 @@ pub mod test {
     // }
 
     #[test]
-    fn lib_network_store_flatten() {
-        let mut children: Vec<SimpleNode> = (1..11)
-            .map(|i| SimpleNode {
-                name: i.to_string(),
-                hash: [i as u8; 32],
-                children: None,
-                data: Some(vec![i as u8; 5]),
-            })
-            .collect();
-
-        let children2: Vec<SimpleNode> = (11..21)
-            .map(|i| SimpleNode {
-                name: i.to_string(),
-                hash: [i as u8; 32],
-                children: None,
-                data: Some(vec![i as u8; 5]),
-            })
-            .collect();
-
-        children[0].children = Some(children2);
+    fn lib_network_store_get_file_node() {
+        let address = "127.0.0.1:8080".parse::<SocketAddr>().unwrap();
 
-        let parent = SimpleNode {
-            name: "root".to_string(),
-            hash: [0u8; 32],
-            children: Some(children),
-            data: None,
-        };
+        let mut data = vec![7u8; 32];
+        data.push(0);
+        data.extend_from_slice(b"chunk");
+        let chunk = get_file_node(&datum(data, address)).unwrap();
+        assert_eq!(chunk, FileNode::Chunk(Bytes::from_static(b"chunk")));
+
+        let mut data = vec![7u8; 32];
+        data.push(1);
+        data.extend_from_slice(&[[1u8; 32], [2u8; 32]].concat());
+        let big = get_file_node(&datum(data.clone(), address)).unwrap();
+        assert_eq!(big, FileNode::Big(vec![[1u8; 32], [2u8; 32]]));
+
+        /*A truncated hash */
+        data.pop();
+        assert_eq!(
+            get_file_node(&datum(data, address)),
+            Err(PeerError::InvalidPacket)
+        );
+        let mut data = vec![7u8; 32];
+        data.push(2);
+        assert_eq!(
+            get_file_node(&datum(data, address)),
+            Err(PeerError::FileIsDirectory)
+        );
+    }
 
-        println!("Node : {parent:?}");
+    #[test]
+    fn lib_network_store_get_local_entries() {
+        let (root, dir, file, same_file) = ([0u8; 32], [1u8; 32], [2u8; 32], [3u8; 32]);
+        let p_to_c_hashmap = HashMap::from([
+            (
+                root,
+                vec![
+                    ("/home/exporter/..".to_string(), dir),
+                    ("../../etc/passwd".to_string(), file),
+                    ("pass\nwd".to_string(), same_file),
+                ],
+            ),
+            (dir, vec![("../../etc/passwd".to_string(), file)]),
+        ]);
+
+        let entries = get_local_entries(&root, &p_to_c_hashmap);
+        let paths: Vec<(&str, bool)> = entries
+            .iter()
+            .map(|entry| (entry.path.to_str().unwrap(), entry.is_directory))
+            .collect();
+        assert_eq!(
+            paths,
+            [
+                ("0101010101010101", true),
+                ("0101010101010101/passwd", false),
+                ("passwd", false),
+                ("pass_wd", false),
+            ]
+        );
+        assert_eq!(entries[1].hash, file);
+        assert_eq!(sanitize_name("a.tar.gz", &file), "a.tar.gz");
+        let mut taken = HashSet::new();
+        assert_eq!(deduplicate_name("a.txt".to_string(), &mut taken), "a.txt");
+        assert_eq!(
+            deduplicate_name("a.txt".to_string(), &mut taken),
+            "a (1).txt"
+        );
+        assert_eq!(deduplicate_name(".a".to_string(), &mut taken), ".a");
+        assert_eq!(deduplicate_name(".a".to_string(), &mut taken), ".a (1)");
+    }
 
-        let data = parent.flatten();
+    #[test]
+    fn lib_network_store_get_local_entries_of_identical_files() {
+        let address = "127.0.0.1:8080".parse::<SocketAddr>().unwrap();
+        let (root, file) = ([0u8; 32], [2u8; 32]);
+        let mut data = root.to_vec();
+        data.push(2);
+        for name in [&b"first.txt"[..], b"copy.txt"] {
+            let mut name = name.to_vec();
+            name.resize(32, 0);
+            data.extend(name);
+            data.extend(file);
+        }
 
-        println!("Data : {data:?}");
+        let maps = build_tree_mutex();
+        assert_eq!(
+            build_tree_maps(&datum(data, address), Arc::clone(&maps)),
+            Ok(Some(vec![file, file]))
+        );
+        /*Both keep their own name, though they share a hash */
+        let maps = maps.lock().unwrap();
+        let paths: Vec<PathBuf> = get_local_entries(&root, &maps.1)
+            .into_iter()
+            .map(|entry| entry.path)
+            .collect();
+        assert_eq!(
+            paths,
+            [PathBuf::from("first.txt"), PathBuf::from("copy.txt")]
+        );
     }
 }
@@ -0,0 +1,263 @@
+use std::{net::SocketAddr, sync::Mutex, time::Duration};
+
+use log::{error, info};
+
+/*Failures in a row after which a peer gets no more requests */
+pub const MAX_FAILURES: u32 = 3;
+
+/*
+    Peers a download can ask for datums. Every datum is checked against
+    its hash, so any of them will do : each request goes to the peer
+    expected to answer it first, from the throughput measured on its
+    replies and the requests it already has in flight. Peers that time
+    out or don't have the datum are tried last, and left out after
+    MAX_FAILURES failures in a row.
+*/
+#[derive(Debug)]
+pub struct Swarm {
+    peers: Mutex<Vec<SwarmPeer>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SwarmPeer {
+    sock_addr: SocketAddr,
+    /*Bytes per second, smoothed over the replies */
+    throughput: Option<f64>,
+    in_flight: usize,
+    failures: u32,
+    received: u64,
+}
+
+impl SwarmPeer {
+    fn new(sock_addr: SocketAddr) -> Self {
+        Self {
+            sock_addr,
+            throughput: None,
+            in_flight: 0,
+            failures: 0,
+            received: 0,
+        }
+    }
+
+    pub fn get_sock_addr(&self) -> SocketAddr {
+        self.sock_addr
+    }
+    pub fn get_throughput(&self) -> Option<f64> {
+        self.throughput
+    }
+    pub fn get_failures(&self) -> u32 {
+        self.failures
+    }
+    /*Bytes of the datums it sent */
+    pub fn get_received(&self) -> u64 {
+        self.received
+    }
+    pub fn is_excluded(&self) -> bool {
+        self.failures >= MAX_FAILURES
+    }
+}
+
+impl Swarm {
+    pub fn new(peers: &[SocketAddr]) -> Self {
+        let mut swarm_peers: Vec<SwarmPeer> = vec![];
+        for sock_addr in peers {
+            if !swarm_peers.iter().any(|peer| peer.sock_addr == *sock_addr) {
+                swarm_peers.push(SwarmPeer::new(*sock_addr));
+            }
+        }
+        Self {
+            peers: Mutex::new(swarm_peers),
+        }
+    }
+
+    fn lock_peers(&self) -> std::sync::MutexGuard<'_, Vec<SwarmPeer>> {
+        match self.peers.lock() {
+            Ok(peers) => peers,
+            Err(e) => {
+                error!("[lock_peers] Swarm mutex is poisoned {e}");
+                panic!("Swarm mutex is poisoned {e}")
+            }
+        }
+    }
+
+    pub fn get_peers(&self) -> Vec<SwarmPeer> {
+        self.lock_peers().clone()
+    }
+
+    /*sock_addr gets no more requests, as if it failed too often */
+    pub fn exclude(&self, sock_addr: SocketAddr) {
+        if let Some(peer) = self
+            .lock_peers()
+            .iter_mut()
+            .find(|peer| peer.sock_addr == sock_addr)
+        {
+            peer.failures = peer.failures.max(MAX_FAILURES);
+        }
+    }
+
+    /*
+        Takes a request for the peer expected to answer first, among the
+        ones not tried for it yet. A peer that failed less recently comes
+        first, then the one whose requests in flight plus this one take
+        the least time at its throughput. Peers not measured yet are
+        assumed as fast as the fastest one, so that they get tried.
+        The request stays in flight until the returned InFlight records
+        how it went, or is dropped with the future waiting for it.
+    */
+    pub fn pick(&self, tried: &[SocketAddr]) -> Option<InFlight<'_>> {
+        let mut peers = self.lock_peers();
+        let fastest = peers
+            .iter()
+            .filter_map(|peer| peer.throughput)
+            .fold(1.0, f64::max);
+        let peer = peers
+            .iter_mut()
+            .filter(|peer| !peer.is_excluded() && !tried.contains(&peer.sock_addr))
+            .min_by(|a, b| {
+                let time = |peer: &SwarmPeer| {
+                    (peer.in_flight + 1) as f64 / peer.throughput.unwrap_or(fastest)
+                };
+                a.failures
+                    .cmp(&b.failures)
+                    .then(time(a).total_cmp(&time(b)))
+            })?;
+        peer.in_flight += 1;
+        Some(InFlight {
+            swarm: self,
+            sock_addr: peer.sock_addr,
+        })
+    }
+
+    /*Ends a request taken with pick, updating its peer */
+    fn end_request(&self, sock_addr: SocketAddr, update: impl FnOnce(&mut SwarmPeer)) {
+        let mut peers = self.lock_peers();
+        if let Some(peer) = peers.iter_mut().find(|peer| peer.sock_addr == sock_addr) {
+            peer.in_flight = peer.in_flight.saturating_sub(1);
+            update(peer);
+        }
+    }
+}
+
+/*A request taken with Swarm::pick, released if nothing is recorded */
+#[derive(Debug)]
+pub struct InFlight<'a> {
+    swarm: &'a Swarm,
+    sock_addr: SocketAddr,
+}
+
+impl InFlight<'_> {
+    pub fn get_sock_addr(&self) -> SocketAddr {
+        self.sock_addr
+    }
+
+    /*The request got bytes back after elapsed */
+    pub fn record_reply(self, bytes: usize, elapsed: Duration) {
+        self.swarm.end_request(self.sock_addr, |peer| {
+            peer.failures = 0;
+            peer.received += bytes as u64;
+            let sample = bytes as f64 / elapsed.as_secs_f64().max(1e-6);
+            peer.throughput = Some(match peer.throughput {
+                Some(throughput) => (throughput * 7.0 + sample) / 8.0,
+                None => sample,
+            });
+        });
+        std::mem::forget(self);
+    }
+
+    /*The request timed out or got no datum */
+    pub fn record_failure(self) {
+        let sock_addr = self.sock_addr;
+        self.swarm.end_request(sock_addr, |peer| {
+            peer.failures += 1;
+            if peer.is_excluded() {
+                info!("Leaving {sock_addr} out of the swarm");
+            }
+        });
+        std::mem::forget(self);
+    }
+}
+
+/*The request failed on our side, or its download was given up */
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.swarm.end_request(self.sock_addr, |_| ());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lib_network_swarm_pick() {
+        let (fast, slow, gone): (SocketAddr, SocketAddr, SocketAddr) = (
+            "127.0.0.1:4001".parse().unwrap(),
+            "127.0.0.1:4002".parse().unwrap(),
+            "127.0.0.1:4003".parse().unwrap(),
+        );
+        let swarm = Swarm::new(&[fast, slow, gone, fast]);
+        assert_eq!(swarm.get_peers().len(), 3);
+
+        /*Each one is tried before any is measured */
+        let mut picked = vec![];
+        for _ in 0..3 {
+            picked.push(swarm.pick(&[]).unwrap());
+        }
+        picked.sort_by_key(|request| request.get_sock_addr());
+        let mut picked = picked.into_iter();
+        let (to_fast, to_slow, to_gone) = (
+            picked.next().unwrap(),
+            picked.next().unwrap(),
+            picked.next().unwrap(),
+        );
+        assert_eq!(
+            [
+                to_fast.get_sock_addr(),
+                to_slow.get_sock_addr(),
+                to_gone.get_sock_addr()
+            ],
+            [fast, slow, gone]
+        );
+        to_fast.record_reply(1024, Duration::from_millis(10));
+        to_slow.record_reply(1024, Duration::from_millis(100));
+
+        /*gone times out, the next requests avoid it */
+        to_gone.record_failure();
+        let mut in_flight = vec![swarm.pick(&[]).unwrap()];
+        assert_ne!(in_flight[0].get_sock_addr(), gone);
+        /*fast has one in flight and still goes first */
+        in_flight.push(swarm.pick(&[]).unwrap());
+        assert_eq!(in_flight[1].get_sock_addr(), fast);
+        /*then slow once fast has enough in flight */
+        let mut to_slow = 0;
+        for _ in 0..20 {
+            let request = swarm.pick(&[]).unwrap();
+            if request.get_sock_addr() == slow {
+                to_slow += 1;
+            }
+            in_flight.push(request);
+        }
+        assert!(to_slow > 0 && to_slow < 10);
+        /*Retrying a datum skips the peers tried for it */
+        assert_eq!(
+            swarm
+                .pick(&[fast, slow])
+                .map(|request| request.get_sock_addr()),
+            Some(gone)
+        );
+
+        /*gone already failed once */
+        for _ in 1..MAX_FAILURES {
+            swarm.pick(&[fast, slow]).unwrap().record_failure();
+        }
+        assert!(swarm.pick(&[fast, slow]).is_none());
+        assert!(swarm
+            .get_peers()
+            .iter()
+            .any(|peer| peer.get_sock_addr() == gone && peer.is_excluded()));
+
+        /*Requests given up are no longer in flight */
+        drop(in_flight);
+        assert!(swarm.get_peers().iter().all(|peer| peer.in_flight == 0));
+    }
+}
@@ -1,139 +1,150 @@
-use tokio::net::UdpSocket;
+use log::{debug, error};
+use tokio::{net::UdpSocket, task::JoinHandle, time::sleep};
+use tokio_util::sync::CancellationToken;
 
-use crate::resend::resend_task;
+use crate::{import_export::keep_alive_to_peer, resend::resend_task};
 
 use {
     crate::{
-        action::Action,
+        config::NodeConfig,
         congestion_handler::*,
         handle_action::handle_action_task,
         handle_packet::handle_packet_task,
-        packet::Packet,
         peer::{ActivePeers, Peer},
-        process::process_task,
+        process::{process_task, ExportedTree},
         sender_receiver::{receiver4, receiver6, sender},
     },
     std::{
-        net::SocketAddr,
-        sync::{Arc, Mutex, RwLock},
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
     },
 };
 
+/*Milliseconds given to the queued packets to go out on shutdown */
+pub const DRAIN_TIMEOUT: u64 = 2_000;
+
+/*
+    The tasks launched for a node and the token that stops them.
+    Dropping it cancels the tasks without waiting for them.
+*/
+pub struct Tasks {
+    queues: Queues,
+    cancel: CancellationToken,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl Tasks {
+    pub fn get_cancel_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+
+    /*Cancels every task, the ones waiting for their queue select
+    on the token and stop right away */
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    /*
+        Waits for the action and send queues to be empty, at most
+        drain_timeout ms, so that the replies already queued go out.
+        Then cancels the tasks and waits for them to end.
+    */
+    pub async fn shutdown(&mut self, drain_timeout: u64) {
+        let deadline = Instant::now() + Duration::from_millis(drain_timeout);
+        while Instant::now() < deadline
+            && !(Queue::lock_and_is_empty(Arc::clone(&self.queues.action_queue))
+                && Queue::lock_and_is_empty(Arc::clone(&self.queues.send_queue)))
+        {
+            sleep(Duration::from_millis(10)).await;
+        }
+
+        self.cancel();
+        for handle in self.handles.drain(..) {
+            if let Err(e) = handle.await {
+                error!("Task failed before shutdown : {e}");
+            }
+        }
+        debug!("Every task stopped");
+    }
+}
+
+impl Drop for Tasks {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
 pub fn task_launcher(
-    queues: (
-        Arc<Mutex<Queue<(Packet, SocketAddr)>>>,
-        Arc<Mutex<Queue<(Packet, SocketAddr)>>>,
-        Arc<Mutex<Queue<Action>>>,
-        Arc<RwLock<Queue<Action>>>,
-        Arc<Mutex<PendingIds>>,
-        Arc<QueueState>,
-        Arc<QueueState>,
-        Arc<QueueState>,
-        Arc<QueueState>,
-        Arc<QueueState>,
-    ),
+    queues: Queues,
     active_peers: Arc<Mutex<ActivePeers>>,
-    my_data: Arc<Peer>,
-    my_data_own: Peer,
+    my_data: Peer,
     sock4: Arc<UdpSocket>,
     sock6: Arc<UdpSocket>,
-    exporting: bool,
-    path: std::path::PathBuf
-) {
-    let (
-        receive_queue,
-        send_queue,
-        action_queue,
-        process_queue,
-        pending_ids,
-        receive_queue_state,
-        action_queue_state,
-        send_queue_state,
-        process_queue_state,
-        process_queue_readers_state,
-    ) = queues;
+    exported: ExportedTree,
+    config: Arc<NodeConfig>,
+) -> Tasks {
+    let cancel = CancellationToken::new();
+    /*The process task keeps its own copy to update the root it replies with */
+    let my_data_own = my_data.clone();
+    let my_data = Arc::new(my_data);
 
-    let (
-        receive_queue,
-        send_queue,
-        action_queue,
-        process_queue,
-        pending_ids,
-        receive_queue_state,
-        action_queue_state,
-        send_queue_state,
-        process_queue_state,
-        process_queue_readers_state,
-    ) = (
-        Arc::clone(&receive_queue),
-        Arc::clone(&send_queue),
-        Arc::clone(&action_queue),
-        Arc::clone(&process_queue),
-        Arc::clone(&pending_ids),
-        Arc::clone(&receive_queue_state),
-        Arc::clone(&action_queue_state),
-        Arc::clone(&send_queue_state),
-        Arc::clone(&process_queue_state),
-        Arc::clone(&process_queue_readers_state),
-    );
-
-    tokio::spawn(async move {
+    let mut handles = vec![
         receiver4(
             Arc::clone(&sock4),
-            Arc::clone(&receive_queue),
-            Arc::clone(&receive_queue_state),
-        );
-
+            &queues,
+            Arc::clone(&active_peers),
+            cancel.clone(),
+        ),
         receiver6(
             Arc::clone(&sock6),
-            Arc::clone(&receive_queue),
-            Arc::clone(&receive_queue_state),
-        );
-
+            &queues,
+            Arc::clone(&active_peers),
+            cancel.clone(),
+        ),
         handle_packet_task(
-            Arc::clone(&pending_ids),
-            Arc::clone(&receive_queue),
-            Arc::clone(&receive_queue_state),
-            Arc::clone(&process_queue),
-            Arc::clone(&process_queue_state),
-            Arc::clone(&process_queue_readers_state),
-        );
-        handle_action_task(
-            Arc::clone(&send_queue),
-            Arc::clone(&send_queue_state),
-            Arc::clone(&action_queue),
-            Arc::clone(&action_queue_state),
-            Arc::clone(&process_queue),
-            Arc::clone(&process_queue_state),
-
-        );
+            &queues,
+            Arc::clone(&active_peers),
+            Arc::clone(&config),
+            cancel.clone(),
+        ),
+        handle_action_task(&queues, cancel.clone()),
         process_task(
-            Arc::clone(&action_queue),
-            Arc::clone(&action_queue_state),
-            Arc::clone(&process_queue),
-            Arc::clone(&process_queue_state),
+            &queues,
             Arc::clone(&active_peers),
             my_data_own,
-            exporting,
-            path
-        );
-
+            exported,
+            Arc::clone(&config),
+            cancel.clone(),
+        ),
         sender(
-            Arc::clone(&sock4),
-            Arc::clone(&sock6),
-            Arc::clone(&send_queue),
-            Arc::clone(&send_queue_state),
-            Arc::clone(&pending_ids),
-        );
-        resend_task(
-            pending_ids.clone(),
-            active_peers.clone(),
-            action_queue.clone(),
-            action_queue_state.clone(),
-            send_queue.clone(),
-            send_queue_state.clone(),
-            my_data.clone()
-        );
+            sock4,
+            sock6,
+            &queues,
+            Arc::clone(&active_peers),
+            Arc::clone(&my_data),
+            cancel.clone(),
+        ),
+        resend_task(&queues, Arc::clone(&config), cancel.clone()),
+    ];
+    /*Keeps us alive with every rendezvous server */
+    for server_sock_addr in config.get_rendezvous_servers() {
+        handles.push(keep_alive_to_peer(
+            Arc::clone(&queues.action_queue),
+            Arc::clone(&queues.action_queue_state),
+            *server_sock_addr,
+            Arc::clone(&my_data),
+            30_000,
+            cancel.clone(),
+        ));
+    }
 
-    });
+    Tasks {
+        queues,
+        cancel,
+        handles,
+    }
 }
@@ -0,0 +1,80 @@
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+};
+
+/*
+    Output file of a download. Chunks are written at their offset as
+    soon as they are verified, in whatever order they arrive, so no
+    more of the file is in memory than the datums in flight.
+*/
+#[derive(Debug)]
+pub struct FileWriter {
+    file: File,
+    path: PathBuf,
+}
+
+impl FileWriter {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(Self {
+            file,
+            path: path.to_path_buf(),
+        })
+    }
+
+    /*Keeps what an interrupted download already wrote */
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        Ok(Self {
+            file,
+            path: path.to_path_buf(),
+        })
+    }
+
+    /*Grows the file to at least len bytes, the holes read as zeros */
+    pub fn preallocate(&self, len: u64) -> io::Result<()> {
+        if self.file.metadata()?.len() < len {
+            self.file.set_len(len)?;
+        }
+        Ok(())
+    }
+
+    pub fn write_chunk(&self, offset: u64, chunk: &[u8]) -> io::Result<()> {
+        self.file.write_all_at(chunk, offset)
+    }
+
+    pub fn read_chunk(&self, offset: u64, chunk: &mut [u8]) -> io::Result<()> {
+        self.file.read_exact_at(chunk, offset)
+    }
+
+    /*Cuts what was preallocated past the end of the file */
+    pub fn finish(self, len: u64) -> io::Result<()> {
+        self.file.set_len(len)?;
+        self.file.sync_all()
+    }
+
+    pub fn get_path(&self) -> &Path {
+        &self.path
+    }
+
+    /*Another handle on the same file, for a blocking thread */
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self {
+            file: self.file.try_clone()?,
+            path: self.path.clone(),
+        })
+    }
+}
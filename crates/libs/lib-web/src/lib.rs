@@ -94,6 +94,48 @@ pub mod discovery {
         }
     }
 
+    /// Header carrying the hex encoded signature of a PUT.
+    pub const SIGNATURE_HEADER: &str = "x-signature";
+
+    /// Signs what a PUT covers, see signed_data.
+    pub type Signer<'a> = &'a (dyn Fn(&[u8]) -> Vec<u8> + Sync);
+
+    /// What the signature of a PUT covers : the path of its url, a newline, then its body.
+    /// The server only lets the key registered for a name change its records.
+    pub fn signed_data(path: &str, data: &[u8]) -> Vec<u8> {
+        let mut signed = format!("{path}\n").into_bytes();
+        signed.extend_from_slice(data);
+        signed
+    }
+
+    /// Attempt to put bytes on a host, succeeds on any 2xx status code.
+    pub async fn put_bytes_data(
+        client: &Client,
+        url: &Url,
+        data: Vec<u8>,
+        signer: Option<Signer<'_>>,
+    ) -> Result<()> {
+        let mut request = client.put(url.clone());
+        if let Some(signer) = signer {
+            let signature = signer(&signed_data(url.path(), &data));
+            request = request.header(SIGNATURE_HEADER, hex::encode(signature));
+        }
+        let request = request.body(data);
+        let response = request
+            .send()
+            .await
+            .context(format!("Failed to connect to host {}.", url.as_str()))?;
+
+        if response.status().is_success() {
+            return Ok(());
+        }
+        bail!(
+            "Failed to put data on host {} with status code {}.",
+            response.url().as_str(),
+            response.status()
+        );
+    }
+
     pub async fn get_peer_addresses(client: &Client, base_url: &Url, peer: &str) -> Result<Peer> {
         // Peer adresses must be located at /peers/<p>/addresses from the base_url
         let url = base_url.join(format!("peers/{peer}/addresses").as_str())?;
@@ -113,6 +155,25 @@ pub mod discovery {
         return Ok(peers);
     }
 
+    /// Peers whose published root is root, with their addresses.
+    pub async fn get_peers_with_root(
+        client: &Client,
+        base_url: &Url,
+        root: &[u8],
+    ) -> Result<Vec<Peer>> {
+        let mut peers = vec![];
+        for name in get_peers_names(client, base_url).await? {
+            match get_peer_root(client, base_url, &name).await {
+                Ok(peer_root) if peer_root == root => (),
+                _ => continue,
+            }
+            if let Ok(peer) = get_peer_addresses(client, base_url, &name).await {
+                peers.push(peer);
+            }
+        }
+        Ok(peers)
+    }
+
     pub async fn get_peer_key(client: &Client, base_url: &Url, peer: &str) -> Result<Bytes> {
         let url = base_url.join(format!("peers/{peer}/key").as_str())?;
         let data = get_bytes_data(&client, &url).await?;
@@ -125,6 +186,44 @@ pub mod discovery {
         return Ok(data);
     }
 
+    pub async fn put_peer_key(
+        client: &Client,
+        base_url: &Url,
+        peer: &str,
+        key: &[u8],
+        signer: Option<Signer<'_>>,
+    ) -> Result<()> {
+        // The key is sent raw, 64 bytes
+        let url = base_url.join(format!("peers/{peer}/key").as_str())?;
+        put_bytes_data(client, &url, key.to_vec(), signer).await
+    }
+
+    pub async fn put_peer_root(
+        client: &Client,
+        base_url: &Url,
+        peer: &str,
+        root: &[u8],
+        signer: Option<Signer<'_>>,
+    ) -> Result<()> {
+        // The root is sent raw, 32 bytes
+        let url = base_url.join(format!("peers/{peer}/root").as_str())?;
+        put_bytes_data(client, &url, root.to_vec(), signer).await
+    }
+
+    pub async fn put_peer_addresses(
+        client: &Client,
+        base_url: &Url,
+        peer: &str,
+        addresses: &[String],
+        signer: Option<Signer<'_>>,
+    ) -> Result<()> {
+        // Addresses are sent newline separated, the same way they are served
+        let url = base_url.join(format!("peers/{peer}/addresses").as_str())?;
+        let mut data = addresses.join("\n");
+        data.push('\n');
+        put_bytes_data(client, &url, data.into_bytes(), signer).await
+    }
+
     pub fn get_client(timeout: u64) -> Result<Client> {
         let delay = Duration::new(timeout, 0);
         let client = Client::builder()
@@ -145,79 +244,63 @@ pub mod discovery {
     mod tests {
 
         use super::*;
+        use rendezvous::RendezvousServer;
         use std::time::Duration;
         use tokio;
 
+        /// A local rendezvous server named rendezvous, returns its url.
+        async fn start_server() -> Url {
+            let server = RendezvousServer::bind(
+                "127.0.0.1:0".parse().unwrap(),
+                "rendezvous",
+                Duration::from_secs(180),
+            )
+            .await
+            .unwrap();
+            let url = parse_url(server.url()).unwrap();
+            server.spawn();
+            url
+        }
+
         #[tokio::test]
         async fn lib_web_get_peers() {
-            let host = Url::parse("https://jch.irif.fr:8443/").unwrap();
-            let five_seconds = Duration::new(5, 0);
-            let client = Client::builder()
-                .timeout(five_seconds)
-                .user_agent("Projet M2 protocoles Internet")
-                .build()
-                .unwrap();
+            let host = start_server().await;
+            let client = get_client(5).unwrap();
             let result = get_peers_names(&client, &host).await.unwrap();
-            println!("Peers = {:?}", result);
+            assert_eq!(result, vec!["rendezvous".to_string()]);
         }
 
         #[tokio::test]
         async fn lib_web_get_peer_addresses() {
-            let host = Url::parse("https://jch.irif.fr:8443/").unwrap();
-            let five_seconds = Duration::new(5, 0);
-            let client = Client::builder()
-                .timeout(five_seconds)
-                .user_agent("Projet M2 protocoles Internet")
-                .build()
-                .unwrap();
-            let result = get_peer_addresses(&client, &host, "jch.irif.fr")
+            let host = start_server().await;
+            let client = get_client(5).unwrap();
+            let result = get_peer_addresses(&client, &host, "rendezvous")
                 .await
                 .unwrap();
-            println!("Peer address = {:?}", result);
+            assert_eq!(result.addresses, vec![host.authority().to_string()]);
+            assert!(get_peer_addresses(&client, &host, "unknown").await.is_err());
         }
 
         #[tokio::test]
         async fn lib_web_get_peer_key() {
-            let host = Url::parse("https://jch.irif.fr:8443/").unwrap();
-            let five_seconds = Duration::new(5, 0);
-            let client = Client::builder()
-                .timeout(five_seconds)
-                .user_agent("Projet M2 protocoles Internet")
-                .build()
-                .unwrap();
-            let result = get_peer_key(&client, &host, "jch.irif.fr").await;
-            match result {
-                Ok(key) => {
-                    if key.len() == 0 {
-                        println!("Peer key doesn't exist.")
-                    } else {
-                        println!("Peer key = {:?}", key);
-                    }
-                }
-                Err(_e) => println!("Failed to retrieve peer key"),
-            }
+            let host = start_server().await;
+            let client = get_client(5).unwrap();
+            // The server has no key
+            let key = get_peer_key(&client, &host, "rendezvous").await.unwrap();
+            assert!(key.is_empty());
+            assert!(get_peer_key(&client, &host, "unknown").await.is_err());
         }
 
         #[tokio::test]
         async fn lib_web_get_peer_root() {
-            let host = Url::parse("https://jch.irif.fr:8443/").unwrap();
-            let five_seconds = Duration::new(5, 0);
-            let client = Client::builder()
-                .timeout(five_seconds)
-                .user_agent("Projet M2 protocoles Internet")
-                .build()
-                .unwrap();
-            let result = get_peer_root(&client, &host, "jch.irif.fr").await;
-            match result {
-                Ok(hash) => {
-                    if hash.len() == 0 {
-                        println!("Peer root doesn't exist.")
-                    } else {
-                        println!("Peer root = {:#?}", hash);
-                    }
-                }
-                Err(_e) => println!("Failed to retrieve peer root"),
-            }
+            let host = start_server().await;
+            let client = get_client(5).unwrap();
+            // Nor exports anything
+            let root = get_peer_root(&client, &host, "rendezvous").await.unwrap();
+            assert!(root.is_empty());
+            assert!(put_peer_root(&client, &host, "unknown", &[7u8; 32], None)
+                .await
+                .is_err());
         }
     }
 }
@@ -1,20 +1,22 @@
 use anyhow::{bail, Result};
 use clap::{Parser, Subcommand};
+use futures::future::join_all;
 use hex;
 use lib_network::{
-    action::*,
-    congestion_handler::*,
-    import_export::{download_from, handshake, keep_alive_to_peer, peek_until_root_reply_from},
+    chunk_store::ChunkStore,
+    config::{NodeConfig, DEFAULT_RENDEZVOUS},
+    keystore::Keystore,
+    node::{Download, Node},
     peer::*,
-    store::*,
-    task_launcher_canceller::*,
+    rate_limit::RateLimits,
+    rest_server::{MismatchPolicy, RestServer},
+    swarm::Swarm,
 };
 use lib_web::discovery;
-use log::{error, info};
+use log::{error, info, warn};
 use owo_colors::OwoColorize;
-use std::{path::PathBuf, thread::sleep};
-use std::{fs::File, io::Write, net::SocketAddr, sync::Arc};
-use tokio::{self, net::UdpSocket};
+use std::path::{Path, PathBuf};
+use std::{net::SocketAddr, sync::Arc};
 
 #[derive(Parser)]
 #[command(name = "UDP2P-cli")]
@@ -29,10 +31,34 @@ use tokio::{self, net::UdpSocket};
  \\____/|_____/|_|   |____|_|\n
 P2P data exchange using UDP and NAT traversal.")]
 struct Cli {
+    /// Directory of the identity keystore
+    /// Default value is $UDP2P_HOME, $XDG_CONFIG_HOME/udp2p or ~/.config/udp2p
+    #[arg(short, long, global = true)]
+    keystore: Option<PathBuf>,
+    /// Rendezvous server as host:port, can be repeated
+    /// Default value is the host of the REST server, or jch.irif.fr:8443
+    #[arg(short, long, global = true)]
+    rendezvous: Vec<String>,
+    /// Keep the downloaded datums in a chunk store in this directory,
+    /// looked into before asking peers
+    /// Default is no store
+    #[arg(long, global = true)]
+    store: Option<PathBuf>,
+    /// Size of the chunk store in MiB, past it the oldest datums are evicted
+    #[arg(long, global = true, default_value = "1024", value_parser = parse_size)]
+    store_size: u64,
     #[command(subcommand)]
     command: Commands,
 }
 
+#[derive(Subcommand)]
+enum IdentityCommands {
+    /// Show the name and public key of this node.
+    Show,
+    /// Replace the key pair of this node, keeping its name.
+    Rotate,
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Fetch the available peers.
@@ -53,17 +79,171 @@ enum Commands {
         /// Default value is ./dump
         #[arg(short, long)]
         output: Option<String>,
+        /// Url of the REST server used to cross-check peers keys and roots
+        #[arg(short = 'u', long)]
+        host: Option<String>,
+        /// Forget peers that don't match the REST server instead of flagging them
+        #[arg(long)]
+        drop_mismatched: bool,
+        /// Also download from the other peers exporting the same root,
+        /// the ones we know and the ones of the REST server
+        #[arg(long)]
+        swarm: bool,
+    },
+    /// Show or rotate the identity of this node
+    Identity {
+        #[command(subcommand)]
+        command: IdentityCommands,
     },
     Export {
         #[arg(short, long)]
         path: String,
+        /// Url of the REST server used to publish our key and root
+        /// and to cross-check peers keys and roots
+        #[arg(short = 'u', long)]
+        host: Option<String>,
+        /// Forget peers that don't match the REST server instead of flagging them
+        #[arg(long)]
+        drop_mismatched: bool,
+        /// Bandwidth given to each peer, in KiB/s
+        /// Default is no limit
+        #[arg(long, value_parser = parse_rate)]
+        peer_rate: Option<u64>,
+        /// Bandwidth given to all peers together, in KiB/s
+        /// Default is no limit
+        #[arg(long, value_parser = parse_rate)]
+        total_rate: Option<u64>,
+        /// Also serve the datums of the chunk store given with --store
+        #[arg(long)]
+        reshare: bool,
     },
 }
 
-#[tokio::main(flavor = "multi_thread", worker_threads = 15)]
+/// Parse a number of units of factor bytes, rejecting what doesn't fit in a u64.
+fn parse_bytes(value: &str, factor: u64, unit: &str) -> Result<u64, String> {
+    let value: u64 = value.parse().map_err(|e| format!("{e}"))?;
+    value
+        .checked_mul(factor)
+        .ok_or_else(|| format!("{value} {unit} is too large"))
+}
+
+/// Parse a bandwidth in KiB/s into bytes per second.
+fn parse_rate(rate: &str) -> Result<u64, String> {
+    parse_bytes(rate, 1024, "KiB/s")
+}
+
+/// Parse a size in MiB into bytes.
+fn parse_size(size: &str) -> Result<u64, String> {
+    parse_bytes(size, 1024 * 1024, "MiB")
+}
+
+/// Build the handle used to check peers against the REST server, if one is given.
+fn build_rest_server(host: Option<&str>, drop_mismatched: bool) -> Result<Option<RestServer>> {
+    let host = match host {
+        Some(host) => host,
+        None => return Ok(None),
+    };
+    let policy = match drop_mismatched {
+        true => MismatchPolicy::Drop,
+        false => MismatchPolicy::Flag,
+    };
+    let url = discovery::parse_url(host)?;
+    Ok(Some(RestServer::new(url, 5, policy)?))
+}
+
+/// Resolve the rendezvous servers, from the command line first, then from the REST server.
+/// Only the ones given on the command line must resolve, a node can still reach peers by
+/// address without the others.
+async fn build_node_config(
+    rendezvous: &[String],
+    rest_server: Option<RestServer>,
+    rate_limits: RateLimits,
+    chunk_store: Option<ChunkStore>,
+    reshare: bool,
+) -> Result<Arc<NodeConfig>> {
+    let mut config = NodeConfig::new();
+    match (rendezvous.is_empty(), &rest_server) {
+        (false, _) => {
+            for host in rendezvous {
+                config.resolve_rendezvous_server(host).await?;
+            }
+        }
+        (true, Some(rest_server)) => {
+            if let Err(e) = config
+                .resolve_rendezvous_server_from_url(rest_server.get_url())
+                .await
+            {
+                warn!("{e}, going on without a rendezvous server");
+            }
+        }
+        (true, None) => {
+            if let Err(e) = config.resolve_rendezvous_server(DEFAULT_RENDEZVOUS).await {
+                warn!("{e}, going on without a rendezvous server");
+            }
+        }
+    }
+    config
+        .set_rest_server(rest_server)
+        .set_rate_limits(rate_limits)
+        .set_chunk_store(chunk_store)
+        .set_reshare(reshare);
+    Ok(Arc::new(config))
+}
+
+/// Peers exporting the root of sock_addr, besides it, handshaken so that they answer us.
+/// The ones listed by the REST server are only kept once they handshake and say it is
+/// their root.
+async fn swarm_peers(
+    node: &Node,
+    sock_addr: SocketAddr,
+    host: Option<&str>,
+) -> Result<Vec<SocketAddr>> {
+    let root = match node.root_of(sock_addr).await {
+        Ok(Some(root)) => root,
+        Ok(None) => bail!("Peer is not exporting any file."),
+        Err(e) => bail!("Couldn't fetch peer root : {e}"),
+    };
+    let mut peers = node.peers_with_root(root);
+    if let Some(host) = host {
+        let client = discovery::get_client(5)?;
+        let url = discovery::parse_url(host)?;
+        let mut listed = vec![];
+        for peer in discovery::get_peers_with_root(&client, &url, &root).await? {
+            for address in peer.addresses {
+                match address.parse::<SocketAddr>() {
+                    Ok(address) if !peers.contains(&address) && !listed.contains(&address) => {
+                        listed.push(address)
+                    }
+                    Ok(_) => (),
+                    Err(_) => error!("Invalid address {address} for {}", peer.name),
+                }
+            }
+        }
+        let roots = join_all(listed.iter().map(|address| node.root_of(*address))).await;
+        for (address, listed_root) in listed.into_iter().zip(roots) {
+            match listed_root {
+                Ok(Some(listed_root)) if listed_root == root => peers.push(address),
+                Ok(_) => warn!("{address} doesn't export {}", hex::encode(root)),
+                Err(e) => warn!("Leaving {address} out of the swarm : {e}"),
+            }
+        }
+    }
+    peers.retain(|peer| *peer != sock_addr);
+    Ok(peers)
+}
+
+#[tokio::main]
 async fn main() -> Result<()> {
     env_logger::init();
     let cli = Cli::parse();
+    let keystore = match &cli.keystore {
+        Some(dir) => Keystore::new(dir),
+        None => Keystore::new(Keystore::default_dir()),
+    };
+    let chunk_store = match &cli.store {
+        Some(dir) => Some(ChunkStore::open(dir, cli.store_size)?),
+        None => None,
+    };
 
     match &cli.command {
         Commands::Peers { host } => {
@@ -100,7 +280,11 @@ async fn main() -> Result<()> {
             peer,
             datum,
             output,
+            host,
+            drop_mismatched,
+            swarm,
         } => {
+            let rest_server = build_rest_server(host.as_deref(), *drop_mismatched)?;
             let peer_hash: Option<[u8; 32]> = match datum {
                 Some(d) => {
                     info!("Fetching content from peer {} for hash {}.", peer, d);
@@ -126,69 +310,30 @@ async fn main() -> Result<()> {
                 }
             };
 
-            let addr4 = UdpSocket::bind("0.0.0.0:40000").await;
-            let sock4: Arc<UdpSocket>;
-            match addr4 {
-                Ok(a) => sock4 = Arc::new(a),
-                Err(e) => {
-                    error!("Failed to bind IPv4 address : {e}");
-                    bail!("Failed to bind IPv4 address : {e}")
-                }
-            }
-            let addr6 = UdpSocket::bind(SocketAddr::new("::1".parse().unwrap(), 0)).await;
-            let sock6: Arc<UdpSocket>;
-            match addr6 {
-                Ok(a) => sock6 = Arc::new(a),
+            let config = build_node_config(
+                &cli.rendezvous,
+                rest_server,
+                RateLimits::default(),
+                chunk_store,
+                false,
+            )
+            .await?;
+            let my_data = keystore.load_or_create()?.to_peer();
+            let mut node = match Node::bind(
+                "0.0.0.0:40000".parse()?,
+                SocketAddr::new("::1".parse()?, 0),
+                my_data,
+                config.clone(),
+            )
+            .await
+            {
+                Ok(node) => node,
                 Err(e) => {
-                    error!("Failed to bind IPv6 address : {e}");
-                    bail!("Failed to bind IPv6 address : {e}")
+                    error!("{e}");
+                    bail!("{e}")
                 }
-            }
-
-            let server_sock_addr4: SocketAddr = "81.194.27.155:8443".parse().unwrap();
-            let maps = build_tree_mutex();
-            let queues = build_queues();
-            let active_peers = ActivePeers::build_mutex();
-
-            let (
-                _receive_queue,
-                _send_queue,
-                action_queue,
-                process_queue,
-                _pending_ids,
-                _receive_queue_state,
-                action_queue_state,
-                _send_queue_state,
-                process_queue_state,
-                process_queue_readers_state,
-            ) = (
-                Arc::clone(&queues.0),
-                Arc::clone(&queues.1),
-                Arc::clone(&queues.2),
-                Arc::clone(&queues.3),
-                Arc::clone(&queues.4),
-                Arc::clone(&queues.5),
-                Arc::clone(&queues.6),
-                Arc::clone(&queues.7),
-                Arc::clone(&queues.8),
-                Arc::clone(&queues.9),
-            );
-
-            let mut my_data = Peer::new();
-            my_data.set_name("nist".to_string());
-            let my_data_own = my_data.clone();
-            let my_data = Arc::new(my_data);
-
-            task_launcher(
-                queues,
-                active_peers.clone(),
-                my_data.clone(),
-                my_data_own,
-                sock4.clone(),
-                sock6.clone(),
-                false,
-                PathBuf::from("/home/splash/files/dls"),
-            );
+            };
+            node.set_timeout(100000);
 
             let sock_addr: SocketAddr;
             match peer.parse() {
@@ -199,40 +344,20 @@ async fn main() -> Result<()> {
                 }
             }
 
-            handshake(
-                process_queue.clone(),
-                process_queue_readers_state.clone(),
-                action_queue.clone(),
-                action_queue_state.clone(),
-                server_sock_addr4,
-                my_data.clone(),
-            );
+            for server_sock_addr in config.get_rendezvous_servers() {
+                node.handshake(*server_sock_addr);
+            }
 
             info!("Contacting address {}", sock_addr.to_string());
 
-            handshake(
-                process_queue.clone(),
-                process_queue_readers_state.clone(),
-                action_queue.clone(),
-                action_queue_state.clone(),
-                sock_addr,
-                my_data.clone(),
-            );
-
             let peer_hash = match peer_hash {
-                Some(hash) => Some(hash),
-                None => match peek_until_root_reply_from(
-                    process_queue.clone(),
-                    process_queue_state.clone(),
-                    process_queue_readers_state.clone(),
-                    action_queue.clone(),
-                    action_queue_state.clone(),
-                    sock_addr,
-                    100000,
-                )
-                .await
-                {
-                    Ok(Action::ProcessRootReply(hash, _)) => hash,
+                Some(hash) => {
+                    node.handshake(sock_addr);
+                    Some(hash)
+                }
+                /*root_of handshakes with the peer */
+                None => match node.root_of(sock_addr).await {
+                    Ok(hash) => hash,
                     Err(PeerError::ResponseTimeout) => bail!("Couldn't fetch peer root"),
                     _ => bail!("Unexpected error"),
                 },
@@ -243,186 +368,153 @@ async fn main() -> Result<()> {
                 None => {
                     println!("{}", "Peer is not exporting any file.".red());
                     error!("{}", "Peer is not exporting any file.".red());
-                    std::process::exit(0);
+                    node.shutdown().await;
+                    return Ok(());
                 }
             };
 
             info!("Selected peer hash is {}", hex::encode(&peer_hash));
 
-            let content = download_from(
-                Arc::clone(&process_queue),
-                Arc::clone(&process_queue_readers_state),
-                Arc::clone(&action_queue),
-                Arc::clone(&action_queue_state),
-                Arc::clone(&maps),
-                peer_hash,
-                sock_addr,
-                100000,
-            )
-            .await;
+            let path = match output {
+                Some(s) => s.to_string(),
+                None => {
+                    info!("No output file provided, defaulting to ./dump");
+                    "./dump".to_string()
+                }
+            };
 
-            match content {
-                Ok(node) => {
-                    let path = match output {
-                        Some(s) => s.to_string(),
-                        None => {
-                            info!("No output file provided, defaulting to ./dump");
-                            "./dump".to_string()
-                        }
-                    };
+            log::info!(
+                "Saving from peer {} for hash {}.",
+                peer,
+                hex::encode(&peer_hash)
+            );
+            let mut peers = vec![sock_addr];
+            if *swarm {
+                peers.extend(swarm_peers(&node, sock_addr, host.as_deref()).await?);
+                println!("Downloading from {} peers.", peers.len());
+            }
+            let swarm = Swarm::new(&peers);
+            let content = node
+                .swarm_download(&swarm, peer_hash, Path::new(&path))
+                .await;
+            if peers.len() > 1 {
+                for peer in swarm.get_peers() {
+                    println!("  {} : {} bytes", peer.get_sock_addr(), peer.get_received());
+                }
+            }
 
-                    log::info!(
-                        "Saving file from peer {} for hash {}.",
-                        peer,
-                        hex::encode(&peer_hash)
-                    );
+            match content {
+                Ok(Download::File(size)) => {
                     println!(
-                        "Saving file from peer {} for hash {}.",
+                        "Saved file from peer {} for hash {}.",
                         peer,
                         hex::encode(&peer_hash)
                     );
-
-                    let mut file = match File::create(&path) {
-                        Ok(f) => f,
-                        Err(e) => {
-                            error!("Could not create or open file {} : {}", &path, e);
-                            std::process::exit(0);
-                        }
-                    };
-
-                    let content = node.flatten();
-                    println!("Size of file {}", &content.len());
-                    match file.write(&content) {
-                        Ok(size) => println!("Download completed. Wrote {} bytes", size),
-                        Err(e) => {
-                            println!("Failed to save file {e}");
-                            error!("Failed to save file {e}");
-                            std::process::exit(0);
-                        }
-                    }
+                    println!("Download completed. Wrote {} bytes to {}", size, &path);
                 }
-                Err(PeerError::FileIsDirectory) => {
-                    match maps.lock() {
-                        Ok(m) => {
-                            println!("\nFile tree :");
-                            let n_to_h_hashmap = get_name_to_hash_hashmap(&m.0, &m.2);
-                            let mut names: Vec<&String> = n_to_h_hashmap.keys().collect();
-                            names.sort();
-                            for n in names.into_iter() {
-                                let mut step = n.chars().filter(|ch| *ch == '/').count();
-                                if step > 0 {
-                                    step -= 1;
-                                }
-                                let carry = str::repeat("   ", step);
-                                println!("{carry}└──\u{1f4c4} {n}");
-                                println!(
-                                    "   {carry} {}",
-                                    hex::encode(
-                                        n_to_h_hashmap
-                                            .get(n)
-                                            .expect("Failed because of unknown error [code 31]")
-                                    )
-                                );
-                            }
-                        }
-                        Err(e) => {
-                            error!("[Code 32] Download failed with error {e}");
-                            std::process::exit(0);
-                            // bail!("Download failed with error {e}")
-                        }
-                    };
+                Ok(Download::Directory(entries)) => {
+                    println!("\nFile tree saved under {} :", &path);
+                    /*Directories come before their content */
+                    for entry in entries.iter() {
+                        let step = entry.path.components().count() - 1;
+                        let carry = str::repeat("   ", step);
+                        let icon = match entry.is_directory {
+                            true => '\u{1f4c1}',
+                            false => '\u{1f4c4}',
+                        };
+                        let name = entry.path.file_name().unwrap_or_default();
+                        println!("{carry}└──{icon} {}", name.to_string_lossy());
+                        println!("   {carry} {}", hex::encode(entry.hash));
+                    }
                 }
                 Err(e) => {
                     error!("[Code 33] Download failed with error {e}");
-                    std::process::exit(0);
+                    bail!("Download failed with error {e}")
                 }
             }
+            node.shutdown().await;
+        }
+        Commands::Identity { command } => {
+            let identity = match command {
+                IdentityCommands::Show => keystore.load_or_create()?,
+                IdentityCommands::Rotate => {
+                    let identity = keystore.rotate()?;
+                    println!("{}", "Rotated key pair.".green());
+                    identity
+                }
+            };
+            println!("\u{1f4c7} {}", identity.get_name().green());
+            println!(
+                "  \u{1f511} Key  : {}",
+                hex::encode(identity.get_key_pair().public_key())
+            );
+            println!(
+                "  \u{1f4c1} File : {}",
+                keystore.identity_path().to_string_lossy()
+            );
         }
-        Commands::Export { path } => {
+        Commands::Export {
+            path,
+            host,
+            drop_mismatched,
+            peer_rate,
+            total_rate,
+            reshare,
+        } => {
+            let rest_server = build_rest_server(host.as_deref(), *drop_mismatched)?;
             let path = PathBuf::from(path);
 
-            let addr4 = UdpSocket::bind("0.0.0.0:0").await;
-            info!("{addr4:?}");
-            let sock4: Arc<UdpSocket>;
-            match addr4 {
-                Ok(a) => sock4 = Arc::new(a),
+            let rate_limits = RateLimits::new(*peer_rate, *total_rate);
+            if *reshare && chunk_store.is_none() {
+                bail!("--reshare needs a chunk store, given with --store");
+            }
+            let config = build_node_config(
+                &cli.rendezvous,
+                rest_server,
+                rate_limits,
+                chunk_store,
+                *reshare,
+            )
+            .await?;
+            let my_data = keystore.load_or_create()?.to_peer();
+            let node = match Node::bind(
+                "0.0.0.0:0".parse()?,
+                SocketAddr::new("::1".parse()?, 0),
+                my_data,
+                config,
+            )
+            .await
+            {
+                Ok(node) => node,
                 Err(e) => {
-                    error!("Failed to bind IPv4 address : {e}");
-                    bail!("Failed to bind IPv4 address : {e}")
+                    error!("{e}");
+                    bail!("{e}")
                 }
-            }
-            let addr6 = UdpSocket::bind(SocketAddr::new("::1".parse().unwrap(), 0)).await;
-            let sock6: Arc<UdpSocket>;
-            match addr6 {
-                Ok(a) => sock6 = Arc::new(a),
+            };
+            info!("{:?}", node.local_addr4());
+
+            /*The tree is built before registering so that the root we
+            publish is the one we serve */
+            match node.export(&path).await {
+                Ok(root) => info!("Exporting root {}", hex::encode(root)),
                 Err(e) => {
-                    error!("Failed to bind IPv6 address : {e}");
-                    bail!("Failed to bind IPv6 address : {e}")
+                    error!("{e}");
+                    bail!("{e}")
                 }
             }
 
-            let server_sock_addr4: SocketAddr = "81.194.27.155:8443".parse().unwrap();
-            let queues = build_queues();
-            let active_peers = ActivePeers::build_mutex();
-
-            let (
-                _receive_queue,
-                _send_queue,
-                action_queue,
-                process_queue,
-                _pending_ids,
-                _receive_queue_state,
-                action_queue_state,
-                _send_queue_state,
-                _process_queue_state,
-                process_queue_readers_state,
-            ) = (
-                Arc::clone(&queues.0),
-                Arc::clone(&queues.1),
-                Arc::clone(&queues.2),
-                Arc::clone(&queues.3),
-                Arc::clone(&queues.4),
-                Arc::clone(&queues.5),
-                Arc::clone(&queues.6),
-                Arc::clone(&queues.7),
-                Arc::clone(&queues.8),
-                Arc::clone(&queues.9),
-            );
-
-            let mut my_data = Peer::new();
-            my_data.set_name("nist".to_string());
-            let my_data_own = my_data.clone();
-            let my_data = Arc::new(my_data);
-
-            handshake(
-                process_queue.clone(),
-                process_queue_readers_state.clone(),
-                action_queue.clone(),
-                action_queue_state.clone(),
-                server_sock_addr4,
-                my_data.clone(),
-            );
-            keep_alive_to_peer(
-                action_queue.clone(),
-                action_queue_state.clone(),
-                server_sock_addr4,
-                my_data.clone(),
-                30_000
-            );
+            match node.register().await {
+                Ok(()) => info!("Registered {}", node.get_my_data().get_name().unwrap()),
+                /*Peers knowing our address can still download from us */
+                Err(e) => error!("Registration failed : {e}"),
+            }
 
-            task_launcher(
-                queues,
-                active_peers.clone(),
-                my_data.clone(),
-                my_data_own,
-                sock4.clone(),
-                sock6.clone(),
-                true,
-                path,
-            );
-            sleep(std::time::Duration::from_secs(9999));
+            /*Serve until interrupted */
+            if let Err(e) = tokio::signal::ctrl_c().await {
+                error!("Failed to listen for ctrl-c : {e}");
+            }
+            node.shutdown().await;
         }
     }
-    std::process::exit(0);
-    // return Ok(());
+    Ok(())
 }
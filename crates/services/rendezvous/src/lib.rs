@@ -0,0 +1,100 @@
+//! Local stand-in for the central server : the REST API used for peer
+//! discovery and the UDP side used to register and traverse nats, both
+//! listening on the same address like jch.irif.fr does.
+
+pub mod registry;
+pub mod rest;
+pub mod udp;
+
+use std::{
+    net::{SocketAddr, TcpListener},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use log::info;
+use tokio::{net::UdpSocket, task::JoinHandle};
+
+use crate::registry::Registry;
+
+pub struct RendezvousServer {
+    name: String,
+    registry: Arc<Mutex<Registry>>,
+    http: TcpListener,
+    udp: Arc<UdpSocket>,
+}
+
+impl RendezvousServer {
+    /// Bind the REST API and the UDP socket on the same address.
+    /// With port 0, both get the same random port.
+    pub async fn bind(addr: SocketAddr, name: &str, timeout: Duration) -> Result<Self> {
+        let (http, udp) = match addr.port() {
+            0 => Self::bind_random_port(addr).await?,
+            _ => (
+                TcpListener::bind(addr).context(format!("Failed to bind {addr} over TCP"))?,
+                UdpSocket::bind(addr)
+                    .await
+                    .context(format!("Failed to bind {addr} over UDP"))?,
+            ),
+        };
+        http.set_nonblocking(true)?;
+
+        let registry = Registry::build_mutex(timeout);
+        Registry::lock(&registry).add_server(name, udp.local_addr()?);
+
+        Ok(Self {
+            name: name.to_string(),
+            registry,
+            http,
+            udp: Arc::new(udp),
+        })
+    }
+
+    /*The UDP port picked for the TCP listener may be taken, try a few ones */
+    async fn bind_random_port(addr: SocketAddr) -> Result<(TcpListener, UdpSocket)> {
+        let mut attempts = 0;
+        loop {
+            let http = TcpListener::bind(addr)?;
+            match UdpSocket::bind(http.local_addr()?).await {
+                Ok(udp) => return Ok((http, udp)),
+                Err(_) if attempts < 10 => attempts += 1,
+                Err(e) => return Err(e).context(format!("Failed to bind {addr} over UDP")),
+            }
+        }
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.http
+            .local_addr()
+            .expect("Bound listener has an address")
+    }
+    /// Base url of the REST API, to give to lib_web::discovery.
+    pub fn url(&self) -> String {
+        format!("http://{}/", self.local_addr())
+    }
+    pub fn get_name(&self) -> &String {
+        &self.name
+    }
+    pub fn get_registry(&self) -> Arc<Mutex<Registry>> {
+        Arc::clone(&self.registry)
+    }
+
+    pub async fn run(self) -> Result<()> {
+        info!(
+            "Rendezvous server {} listening on {}",
+            self.name,
+            self.local_addr()
+        );
+        tokio::spawn(udp::serve(self.udp, Arc::clone(&self.registry), self.name));
+
+        axum::Server::from_tcp(self.http)?
+            .serve(rest::router(self.registry).into_make_service())
+            .await
+            .context("REST server stopped")
+    }
+
+    pub fn spawn(self) -> JoinHandle<Result<()>> {
+        tokio::spawn(self.run())
+    }
+}
@@ -0,0 +1,37 @@
+use std::{net::SocketAddr, time::Duration};
+
+use anyhow::Result;
+use clap::Parser;
+use rendezvous::RendezvousServer;
+
+#[derive(Parser)]
+#[command(name = "UDP2P-rendezvous")]
+#[command(author = "NIST team M2 MIC")]
+#[command(version = "1.0")]
+#[command(about = "Local discovery and rendezvous server for UDP2P peers.")]
+struct Cli {
+    /// Address of both the REST API and the UDP socket
+    #[arg(short, long, default_value = "0.0.0.0:8443")]
+    listen: SocketAddr,
+    /// Name the server answers Hellos with
+    #[arg(short, long, default_value = "udp2p-rendezvous")]
+    name: String,
+    /// Seconds after which a silent peer is forgotten
+    #[arg(short, long, default_value_t = 180)]
+    timeout: u64,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    env_logger::init();
+    let cli = Cli::parse();
+
+    let server =
+        RendezvousServer::bind(cli.listen, &cli.name, Duration::from_secs(cli.timeout)).await?;
+    println!(
+        "Listening on {}, REST API at {}",
+        server.local_addr(),
+        server.url()
+    );
+    server.run().await
+}
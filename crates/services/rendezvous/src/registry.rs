@@ -0,0 +1,271 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    net::SocketAddr,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use log::{debug, error};
+
+/*What the server knows about a peer */
+#[derive(Debug, Clone)]
+pub struct PeerRecord {
+    addresses: Vec<SocketAddr>,
+    public_key: Option<Vec<u8>>,
+    root: Option<Vec<u8>>,
+    /*None for the server itself, which never expires */
+    last_seen: Option<Instant>,
+}
+
+impl PeerRecord {
+    fn new() -> Self {
+        Self {
+            addresses: vec![],
+            public_key: None,
+            root: None,
+            last_seen: Some(Instant::now()),
+        }
+    }
+
+    pub fn get_addresses(&self) -> &Vec<SocketAddr> {
+        &self.addresses
+    }
+    pub fn get_public_key(&self) -> Option<&Vec<u8>> {
+        self.public_key.as_ref()
+    }
+    pub fn get_root(&self) -> Option<&Vec<u8>> {
+        self.root.as_ref()
+    }
+
+    /*A record with a key only changes when signed by that key,
+    the first key is taken on trust */
+    fn is_authorized(&self, is_signed_by: impl Fn(&[u8; 64]) -> bool) -> bool {
+        match &self.public_key {
+            Some(public_key) => <[u8; 64]>::try_from(public_key.as_slice())
+                .is_ok_and(|public_key| is_signed_by(&public_key)),
+            None => true,
+        }
+    }
+
+    fn has_expired(&self, timeout: Duration) -> bool {
+        match self.last_seen {
+            Some(last_seen) => last_seen.elapsed() > timeout,
+            None => false,
+        }
+    }
+}
+
+/*Why the registry refused a change */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refusal {
+    /*No such name, or an address that didn't say Hello */
+    UnknownPeer,
+    /*The name has a key and the change isn't signed by it */
+    NotSigned,
+}
+
+impl Display for Refusal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Refusal::UnknownPeer => write!(f, "unknown peer, send hello"),
+            Refusal::NotSigned => write!(f, "not signed by the key registered for this name"),
+        }
+    }
+}
+
+/*
+    Peers known by the server, by name. A peer is known once it sent
+    a Hello and forgotten when it stays silent for longer than the timeout.
+    Once a name has a key, only that key can add addresses to it or
+    change what the REST API serves for it : is_signed_by checks the
+    Hello or the PUT against the key given.
+*/
+#[derive(Debug)]
+pub struct Registry {
+    peers: HashMap<String, PeerRecord>,
+    timeout: Duration,
+}
+
+impl Registry {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            peers: HashMap::new(),
+            timeout,
+        }
+    }
+
+    pub fn build_mutex(timeout: Duration) -> Arc<Mutex<Self>> {
+        Arc::new(Mutex::new(Self::new(timeout)))
+    }
+
+    pub fn lock(registry: &Mutex<Registry>) -> MutexGuard<'_, Registry> {
+        match registry.lock() {
+            Ok(guard) => guard,
+            Err(poison_error) => {
+                error!("Registry mutex is poisoned {poison_error}");
+                panic!("Registry mutex is poisoned {poison_error}");
+            }
+        }
+    }
+
+    /*The server lists itself like jch does, so that peers
+    can cross-check it as any other peer */
+    pub fn add_server(&mut self, name: &str, sock_addr: SocketAddr) {
+        let mut record = PeerRecord::new();
+        record.addresses.push(sock_addr);
+        record.last_seen = None;
+        self.peers.insert(name.to_string(), record);
+    }
+
+    pub fn hello(
+        &mut self,
+        name: &str,
+        sock_addr: SocketAddr,
+        is_signed_by: impl Fn(&[u8; 64]) -> bool,
+    ) -> Result<(), Refusal> {
+        let timeout = self.timeout;
+        let record = self.peers.entry(name.to_string()).or_insert_with(|| {
+            debug!("New peer {name} at {sock_addr}");
+            PeerRecord::new()
+        });
+        if record.has_expired(timeout) {
+            *record = PeerRecord::new();
+        }
+        if !record.addresses.contains(&sock_addr) {
+            if !record.is_authorized(is_signed_by) {
+                return Err(Refusal::NotSigned);
+            }
+            record.addresses.push(sock_addr);
+        }
+        record.last_seen = Some(Instant::now());
+        Ok(())
+    }
+
+    fn get_mut_by_addr(&mut self, sock_addr: SocketAddr) -> Option<&mut PeerRecord> {
+        let timeout = self.timeout;
+        self.peers
+            .values_mut()
+            .find(|record| !record.has_expired(timeout) && record.addresses.contains(&sock_addr))
+    }
+
+    pub fn name_of(&self, sock_addr: SocketAddr) -> Option<String> {
+        self.peers
+            .iter()
+            .find(|(_, record)| {
+                !record.has_expired(self.timeout) && record.addresses.contains(&sock_addr)
+            })
+            .map(|(name, _)| name.clone())
+    }
+
+    /*Called for every packet, so that active peers don't expire */
+    pub fn touch(&mut self, sock_addr: SocketAddr) {
+        if let Some(record) = self.get_mut_by_addr(sock_addr) {
+            if record.last_seen.is_some() {
+                record.last_seen = Some(Instant::now());
+            }
+        }
+    }
+
+    /*Key announced over UDP, which isn't signed : a registered key
+    can only be announced again */
+    pub fn set_public_key(
+        &mut self,
+        sock_addr: SocketAddr,
+        public_key: Option<Vec<u8>>,
+    ) -> Result<(), Refusal> {
+        let record = self
+            .get_mut_by_addr(sock_addr)
+            .ok_or(Refusal::UnknownPeer)?;
+        if record.public_key.is_some() && record.public_key != public_key {
+            return Err(Refusal::NotSigned);
+        }
+        record.public_key = public_key;
+        Ok(())
+    }
+
+    /*Root announced over UDP by an address that said Hello */
+    pub fn set_root(
+        &mut self,
+        sock_addr: SocketAddr,
+        root: Option<Vec<u8>>,
+    ) -> Result<(), Refusal> {
+        let record = self
+            .get_mut_by_addr(sock_addr)
+            .ok_or(Refusal::UnknownPeer)?;
+        record.root = root;
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&PeerRecord> {
+        match self.peers.get(name) {
+            Some(record) if !record.has_expired(self.timeout) => Some(record),
+            _ => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut PeerRecord> {
+        let timeout = self.timeout;
+        match self.peers.get_mut(name) {
+            Some(record) if !record.has_expired(timeout) => Some(record),
+            _ => None,
+        }
+    }
+
+    /*Record of name to change over REST, if is_signed_by its key */
+    fn get_authorized(
+        &mut self,
+        name: &str,
+        is_signed_by: impl Fn(&[u8; 64]) -> bool,
+    ) -> Result<&mut PeerRecord, Refusal> {
+        let record = self.get_mut(name).ok_or(Refusal::UnknownPeer)?;
+        match record.is_authorized(is_signed_by) {
+            true => Ok(record),
+            false => Err(Refusal::NotSigned),
+        }
+    }
+
+    /*Key published over REST */
+    pub fn put_public_key(
+        &mut self,
+        name: &str,
+        public_key: Option<Vec<u8>>,
+        is_signed_by: impl Fn(&[u8; 64]) -> bool,
+    ) -> Result<(), Refusal> {
+        self.get_authorized(name, is_signed_by)?.public_key = public_key;
+        Ok(())
+    }
+
+    /*Root published over REST */
+    pub fn put_root(
+        &mut self,
+        name: &str,
+        root: Option<Vec<u8>>,
+        is_signed_by: impl Fn(&[u8; 64]) -> bool,
+    ) -> Result<(), Refusal> {
+        self.get_authorized(name, is_signed_by)?.root = root;
+        Ok(())
+    }
+
+    /*Addresses published over REST replace the ones seen over UDP */
+    pub fn put_addresses(
+        &mut self,
+        name: &str,
+        addresses: Vec<SocketAddr>,
+        is_signed_by: impl Fn(&[u8; 64]) -> bool,
+    ) -> Result<(), Refusal> {
+        self.get_authorized(name, is_signed_by)?.addresses = addresses;
+        Ok(())
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .peers
+            .iter()
+            .filter(|(_, record)| !record.has_expired(self.timeout))
+            .map(|(name, _)| name.clone())
+            .collect();
+        names.sort();
+        names
+    }
+}
@@ -0,0 +1,177 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode, Uri},
+    routing::get,
+    Router,
+};
+use lib_network::signature::verify;
+use lib_web::discovery;
+
+use crate::registry::{Refusal, Registry};
+
+type SharedRegistry = Arc<Mutex<Registry>>;
+
+/*
+    REST API consumed by lib_web::discovery. Lists are newline
+    separated, keys and roots are raw bytes. An unknown peer is a 404
+    and a peer without key or root is a 204. Once a name has a key, a
+    PUT on it needs the signature of discovery::signed_data by that key
+    or it is a 403.
+*/
+pub fn router(registry: SharedRegistry) -> Router {
+    Router::new()
+        .route("/peers", get(get_peers))
+        .route(
+            "/peers/:name/addresses",
+            get(get_addresses).put(put_addresses),
+        )
+        .route("/peers/:name/key", get(get_key).put(put_key))
+        .route("/peers/:name/root", get(get_root).put(put_root))
+        .with_state(registry)
+}
+
+fn newline_separated(lines: Vec<String>) -> Vec<u8> {
+    let mut data = lines.join("\n");
+    data.push('\n');
+    data.into_bytes()
+}
+
+fn optional_bytes(data: Option<&Vec<u8>>) -> (StatusCode, Vec<u8>) {
+    match data {
+        Some(data) => (StatusCode::OK, data.clone()),
+        None => (StatusCode::NO_CONTENT, vec![]),
+    }
+}
+
+async fn get_peers(State(registry): State<SharedRegistry>) -> (StatusCode, Vec<u8>) {
+    let names = Registry::lock(&registry).names();
+    (StatusCode::OK, newline_separated(names))
+}
+
+async fn get_addresses(
+    State(registry): State<SharedRegistry>,
+    Path(name): Path<String>,
+) -> (StatusCode, Vec<u8>) {
+    match Registry::lock(&registry).get(&name) {
+        Some(record) => (
+            StatusCode::OK,
+            newline_separated(
+                record
+                    .get_addresses()
+                    .iter()
+                    .map(|a| a.to_string())
+                    .collect(),
+            ),
+        ),
+        None => (StatusCode::NOT_FOUND, vec![]),
+    }
+}
+
+async fn get_key(
+    State(registry): State<SharedRegistry>,
+    Path(name): Path<String>,
+) -> (StatusCode, Vec<u8>) {
+    match Registry::lock(&registry).get(&name) {
+        Some(record) => optional_bytes(record.get_public_key()),
+        None => (StatusCode::NOT_FOUND, vec![]),
+    }
+}
+
+async fn get_root(
+    State(registry): State<SharedRegistry>,
+    Path(name): Path<String>,
+) -> (StatusCode, Vec<u8>) {
+    match Registry::lock(&registry).get(&name) {
+        Some(record) => optional_bytes(record.get_root()),
+        None => (StatusCode::NOT_FOUND, vec![]),
+    }
+}
+
+/*An empty body clears the record */
+fn fixed_length(body: Bytes, length: usize) -> Result<Option<Vec<u8>>, StatusCode> {
+    match body.len() {
+        0 => Ok(None),
+        len if len == length => Ok(Some(body.to_vec())),
+        _ => Err(StatusCode::BAD_REQUEST),
+    }
+}
+
+fn put_status(put: Result<(), Refusal>) -> StatusCode {
+    match put {
+        Ok(()) => StatusCode::NO_CONTENT,
+        Err(Refusal::UnknownPeer) => StatusCode::NOT_FOUND,
+        Err(Refusal::NotSigned) => StatusCode::FORBIDDEN,
+    }
+}
+
+/*Checks the signature of the PUT, if any, against a key */
+fn is_signed_by(uri: &Uri, headers: &HeaderMap, body: &[u8]) -> impl Fn(&[u8; 64]) -> bool {
+    let signature: Option<[u8; 64]> = headers
+        .get(discovery::SIGNATURE_HEADER)
+        .and_then(|signature| hex::decode(signature.as_bytes()).ok())
+        .and_then(|signature| signature.try_into().ok());
+    let signed = discovery::signed_data(uri.path(), body);
+    move |public_key| {
+        signature.is_some_and(|signature| verify(public_key, &signed, &signature).is_ok())
+    }
+}
+
+async fn put_key(
+    State(registry): State<SharedRegistry>,
+    Path(name): Path<String>,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> StatusCode {
+    let is_signed_by = is_signed_by(&uri, &headers, &body);
+    match fixed_length(body, 64) {
+        Ok(public_key) => {
+            put_status(Registry::lock(&registry).put_public_key(&name, public_key, is_signed_by))
+        }
+        Err(status) => status,
+    }
+}
+
+async fn put_root(
+    State(registry): State<SharedRegistry>,
+    Path(name): Path<String>,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> StatusCode {
+    let is_signed_by = is_signed_by(&uri, &headers, &body);
+    match fixed_length(body, 32) {
+        Ok(root) => put_status(Registry::lock(&registry).put_root(&name, root, is_signed_by)),
+        Err(status) => status,
+    }
+}
+
+async fn put_addresses(
+    State(registry): State<SharedRegistry>,
+    Path(name): Path<String>,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> StatusCode {
+    let is_signed_by = is_signed_by(&uri, &headers, &body);
+    let addresses: Result<Vec<SocketAddr>, _> = match std::str::from_utf8(&body) {
+        Ok(body) => body
+            .split('\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| line.parse())
+            .collect(),
+        Err(_) => return StatusCode::BAD_REQUEST,
+    };
+    match addresses {
+        Ok(addresses) => {
+            put_status(Registry::lock(&registry).put_addresses(&name, addresses, is_signed_by))
+        }
+        Err(_) => StatusCode::BAD_REQUEST,
+    }
+}
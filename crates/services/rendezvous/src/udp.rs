@@ -0,0 +1,264 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use lib_network::extensions::Extensions;
+use lib_network::packet::{socket_addr_from_bytes, Message, Packet, PacketBuilder, PacketError};
+use lib_network::signature::verify_packet;
+use log::{debug, error};
+use tokio::net::UdpSocket;
+
+use crate::registry::{Refusal, Registry};
+
+fn error_reply(
+    packet: &Packet,
+    sock_addr: SocketAddr,
+    message: &str,
+) -> Result<Option<(Packet, SocketAddr)>, PacketError> {
+    debug!("{message} ({} from {sock_addr})", packet.get_packet_type());
+    let reply =
+        PacketBuilder::error_reply_packet(packet.get_id(), Some(message.as_bytes().to_vec()))?;
+    Ok(Some((reply, sock_addr)))
+}
+
+/*
+    Answers the UDP side of the protocol. The server exports nothing
+    and has no key, it only records what peers announce and relays
+    NatTraversalRequests to the peer behind the nat. A Hello from a new
+    address of a name with a key has to be signed by it.
+    Returns the packet to send and where, if any.
+*/
+pub fn handle_packet(
+    packet: &Packet,
+    sock_addr: SocketAddr,
+    registry: &Mutex<Registry>,
+    server_name: &str,
+) -> Result<Option<(Packet, SocketAddr)>, PacketError> {
+    let mut registry = Registry::lock(registry);
+    registry.touch(sock_addr);
+
+    let message = match packet.message() {
+        Ok(message) => message,
+        Err(e) => return error_reply(packet, sock_addr, &e.to_string()),
+    };
+    let reply = match message {
+        Message::Hello { name, .. } => {
+            let name = match std::str::from_utf8(&name) {
+                Ok(name) if !name.is_empty() => name,
+                _ => return error_reply(packet, sock_addr, "invalid name"),
+            };
+            if let Err(refusal) =
+                registry.hello(name, sock_addr, |key| verify_packet(key, packet).is_ok())
+            {
+                return error_reply(packet, sock_addr, &refusal.to_string());
+            }
+            PacketBuilder::hello_reply_packet(
+                packet.get_id(),
+                Extensions::empty(),
+                server_name.as_bytes().to_vec(),
+            )?
+        }
+        Message::PublicKey(public_key) => {
+            let public_key = public_key.map(|public_key| public_key.to_vec());
+            if let Err(refusal) = registry.set_public_key(sock_addr, public_key) {
+                return error_reply(packet, sock_addr, &refusal.to_string());
+            }
+            PacketBuilder::public_key_reply_packet(None, *packet.get_id())?
+        }
+        Message::Root(root) => {
+            if let Err(refusal) = registry.set_root(sock_addr, root.map(|root| root.to_vec())) {
+                return error_reply(packet, sock_addr, &refusal.to_string());
+            }
+            PacketBuilder::root_reply_packet(packet.get_id(), None)?
+        }
+        Message::GetDatum(_) => PacketBuilder::nodatum_packet(packet.get_id())?,
+        Message::NatTraversalRequest(behind_nat_addr) => {
+            if registry.name_of(sock_addr).is_none() {
+                return error_reply(packet, sock_addr, &Refusal::UnknownPeer.to_string());
+            }
+            match socket_addr_from_bytes(&behind_nat_addr) {
+                Some(behind_nat_addr) => {
+                    debug!("Relaying nat traversal from {sock_addr} to {behind_nat_addr}");
+                    let relayed = PacketBuilder::nat_traversal_packet(sock_addr)?;
+                    return Ok(Some((relayed, behind_nat_addr)));
+                }
+                None => return error_reply(packet, sock_addr, "invalid address"),
+            }
+        }
+        Message::Error(message) | Message::ErrorReply(message) => {
+            error!(
+                "Received {} from {sock_addr} : {}",
+                packet.get_packet_type(),
+                String::from_utf8_lossy(&message)
+            );
+            return Ok(None);
+        }
+        /*NoOp, NatTraversal and replies */
+        _ => return Ok(None),
+    };
+    Ok(Some((reply, sock_addr)))
+}
+
+pub async fn serve(sock: Arc<UdpSocket>, registry: Arc<Mutex<Registry>>, server_name: String) {
+    loop {
+        let (sock_addr, packet) = match Packet::recv_from(&sock).await {
+            Ok(packet_and_addr) => packet_and_addr,
+            Err(_) => continue,
+        };
+
+        let (reply, reply_addr) = match handle_packet(&packet, sock_addr, &registry, &server_name) {
+            Ok(Some(reply)) => reply,
+            Ok(None) => continue,
+            Err(e) => {
+                error!("Failed to answer {sock_addr} : {e}");
+                continue;
+            }
+        };
+        if let Err(e) = reply.send_to_addr(&sock, &reply_addr).await {
+            error!(
+                "Failed to send {} to {reply_addr} : {e}",
+                reply.get_packet_type()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use lib_network::{packet::PacketType, signature::KeyPair};
+
+    use super::*;
+
+    #[test]
+    fn rendezvous_udp_records_and_relays() {
+        let registry = Registry::build_mutex(Duration::from_secs(180));
+        let peer: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        let behind_nat: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+
+        /*Unknown peers have to say Hello first */
+        let (reply, _) = handle_packet(
+            &PacketBuilder::root_packet(Some([1u8; 32])).unwrap(),
+            peer,
+            &registry,
+            "server",
+        )
+        .unwrap()
+        .unwrap();
+        assert!(reply.is(PacketType::ErrorReply));
+
+        let hello = PacketBuilder::hello_packet(Extensions::empty(), b"nist".to_vec()).unwrap();
+        let (reply, reply_addr) = handle_packet(&hello, peer, &registry, "server")
+            .unwrap()
+            .unwrap();
+        assert!(reply.is(PacketType::HelloReply));
+        assert_eq!(reply.get_id(), hello.get_id());
+        assert_eq!(reply_addr, peer);
+        match reply.message().unwrap() {
+            Message::HelloReply { extensions, .. } => assert_eq!(extensions, Extensions::empty()),
+            _ => unreachable!(),
+        }
+
+        let (reply, _) = handle_packet(
+            &PacketBuilder::root_packet(Some([1u8; 32])).unwrap(),
+            peer,
+            &registry,
+            "server",
+        )
+        .unwrap()
+        .unwrap();
+        assert!(reply.is(PacketType::RootReply));
+        assert_eq!(
+            Registry::lock(&registry).get("nist").unwrap().get_root(),
+            Some(&vec![1u8; 32])
+        );
+
+        let (relayed, relayed_addr) = handle_packet(
+            &PacketBuilder::nat_traversal_request_from_addr_packet(behind_nat).unwrap(),
+            peer,
+            &registry,
+            "server",
+        )
+        .unwrap()
+        .unwrap();
+        assert!(relayed.is(PacketType::NatTraversal));
+        assert_eq!(relayed_addr, behind_nat);
+        assert_eq!(socket_addr_from_bytes(relayed.get_body()), Some(peer));
+    }
+
+    #[test]
+    fn rendezvous_udp_requires_the_registered_key() {
+        let registry = Registry::build_mutex(Duration::from_secs(180));
+        let (peer, other): (SocketAddr, SocketAddr) = (
+            "127.0.0.1:4000".parse().unwrap(),
+            "127.0.0.1:4001".parse().unwrap(),
+        );
+        let key_pair = KeyPair::generate();
+        let hello =
+            || PacketBuilder::hello_packet(Extensions::SIGNATURES, b"nist".to_vec()).unwrap();
+
+        /*The first key is taken on trust */
+        let (reply, _) = handle_packet(&hello(), peer, &registry, "server")
+            .unwrap()
+            .unwrap();
+        assert!(reply.is(PacketType::HelloReply));
+        let (reply, _) = handle_packet(
+            &PacketBuilder::public_key_packet(Some(key_pair.public_key())).unwrap(),
+            peer,
+            &registry,
+            "server",
+        )
+        .unwrap()
+        .unwrap();
+        assert!(reply.is(PacketType::PublicKeyReply));
+
+        /*Then it can't be replaced, nor the name taken from another address */
+        let (reply, _) = handle_packet(
+            &PacketBuilder::public_key_packet(Some(KeyPair::generate().public_key())).unwrap(),
+            peer,
+            &registry,
+            "server",
+        )
+        .unwrap()
+        .unwrap();
+        assert!(reply.is(PacketType::ErrorReply));
+        let (reply, _) = handle_packet(&hello(), other, &registry, "server")
+            .unwrap()
+            .unwrap();
+        assert!(reply.is(PacketType::ErrorReply));
+        let mut forged = hello();
+        KeyPair::generate().sign_packet(&mut forged);
+        let (reply, _) = handle_packet(&forged, other, &registry, "server")
+            .unwrap()
+            .unwrap();
+        assert!(reply.is(PacketType::ErrorReply));
+        assert_eq!(
+            Registry::lock(&registry)
+                .get("nist")
+                .unwrap()
+                .get_addresses(),
+            &vec![peer]
+        );
+
+        /*Unless signed by the key, and known addresses keep saying Hello */
+        let mut signed = hello();
+        key_pair.sign_packet(&mut signed);
+        let (reply, _) = handle_packet(&signed, other, &registry, "server")
+            .unwrap()
+            .unwrap();
+        assert!(reply.is(PacketType::HelloReply));
+        let (reply, _) = handle_packet(&hello(), peer, &registry, "server")
+            .unwrap()
+            .unwrap();
+        assert!(reply.is(PacketType::HelloReply));
+        assert_eq!(
+            Registry::lock(&registry)
+                .get("nist")
+                .unwrap()
+                .get_addresses(),
+            &vec![peer, other]
+        );
+    }
+}
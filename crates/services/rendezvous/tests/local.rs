@@ -0,0 +1,218 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use lib_network::{
+    config::NodeConfig,
+    extensions::Extensions,
+    keystore::Identity,
+    node::Node,
+    packet::{socket_addr_from_bytes, Packet, PacketBuilder, PacketType},
+    rest_server::{MismatchPolicy, RestServer},
+    signature::KeyPair,
+};
+use lib_web::discovery;
+use rendezvous::RendezvousServer;
+use tokio::{net::UdpSocket, time::timeout};
+
+async fn start_server() -> (SocketAddr, String) {
+    let server = RendezvousServer::bind(
+        "127.0.0.1:0".parse().unwrap(),
+        "rendezvous",
+        Duration::from_secs(180),
+    )
+    .await
+    .unwrap();
+    let (addr, url) = (server.local_addr(), server.url());
+    server.spawn();
+    (addr, url)
+}
+
+async fn request(sock: &UdpSocket, server_addr: SocketAddr, packet: Packet) -> Packet {
+    packet.send_to_addr(sock, &server_addr).await.unwrap();
+    let (from, reply) = timeout(Duration::from_secs(5), Packet::recv_from(sock))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(from, server_addr);
+    assert_eq!(reply.get_id(), packet.get_id());
+    reply
+}
+
+#[tokio::test]
+async fn rendezvous_register_and_discover() {
+    let (server_addr, url) = start_server().await;
+    let url = discovery::parse_url(url).unwrap();
+    let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+    let identity = Identity::generate();
+    let public_key = identity.get_key_pair().public_key();
+    let name = identity.get_name().as_bytes().to_vec();
+
+    let reply = request(
+        &sock,
+        server_addr,
+        PacketBuilder::hello_packet(Extensions::empty(), name).unwrap(),
+    )
+    .await;
+    assert!(reply.is(PacketType::HelloReply));
+    let reply = request(
+        &sock,
+        server_addr,
+        PacketBuilder::public_key_packet(Some(public_key)).unwrap(),
+    )
+    .await;
+    assert!(reply.is(PacketType::PublicKeyReply));
+    let reply = request(
+        &sock,
+        server_addr,
+        PacketBuilder::root_packet(Some([7u8; 32])).unwrap(),
+    )
+    .await;
+    assert!(reply.is(PacketType::RootReply));
+
+    let client = discovery::get_client(5).unwrap();
+    let names = discovery::get_peers_names(&client, &url).await.unwrap();
+    assert!(names.contains(identity.get_name()));
+    assert!(names.contains(&"rendezvous".to_string()));
+
+    let key = discovery::get_peer_key(&client, &url, identity.get_name())
+        .await
+        .unwrap();
+    assert_eq!(key.as_ref(), public_key.as_slice());
+    let root = discovery::get_peer_root(&client, &url, identity.get_name())
+        .await
+        .unwrap();
+    assert_eq!(root.as_ref(), [7u8; 32].as_slice());
+    let addresses = discovery::get_peer_addresses(&client, &url, identity.get_name())
+        .await
+        .unwrap();
+    assert_eq!(
+        addresses.addresses,
+        vec![sock.local_addr().unwrap().to_string()]
+    );
+
+    /*Publishing over REST overrides what was announced over UDP */
+    let mut my_data = identity.to_peer();
+    my_data.set_hash(Some([9u8; 32]));
+    RestServer::new(url.clone(), 5, MismatchPolicy::Flag)
+        .unwrap()
+        .publish(&my_data, &[])
+        .await
+        .unwrap();
+    let root = discovery::get_peer_root(&client, &url, identity.get_name())
+        .await
+        .unwrap();
+    assert_eq!(root.as_ref(), [9u8; 32].as_slice());
+
+    /*But only with the registered key */
+    assert!(
+        discovery::put_peer_root(&client, &url, identity.get_name(), &[1u8; 32], None)
+            .await
+            .is_err()
+    );
+    let other = KeyPair::generate();
+    let sign = |data: &[u8]| other.sign(data).to_vec();
+    assert!(
+        discovery::put_peer_root(&client, &url, identity.get_name(), &[1u8; 32], Some(&sign))
+            .await
+            .is_err()
+    );
+    let root = discovery::get_peer_root(&client, &url, identity.get_name())
+        .await
+        .unwrap();
+    assert_eq!(root.as_ref(), [9u8; 32].as_slice());
+
+    /*The server has no key and exports nothing */
+    let key = discovery::get_peer_key(&client, &url, "rendezvous")
+        .await
+        .unwrap();
+    assert!(key.is_empty());
+    assert!(discovery::get_peer_key(&client, &url, "unknown")
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn rendezvous_relays_nat_traversal() {
+    let (server_addr, _) = start_server().await;
+    let requester = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let behind_nat = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+    PacketBuilder::hello_packet(Extensions::empty(), b"requester".to_vec())
+        .unwrap()
+        .send_to_addr(&requester, &server_addr)
+        .await
+        .unwrap();
+    let (_, reply) = timeout(Duration::from_secs(5), Packet::recv_from(&requester))
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(reply.is(PacketType::HelloReply));
+
+    PacketBuilder::nat_traversal_request_from_addr_packet(behind_nat.local_addr().unwrap())
+        .unwrap()
+        .send_to_addr(&requester, &server_addr)
+        .await
+        .unwrap();
+    let (from, relayed) = timeout(Duration::from_secs(5), Packet::recv_from(&behind_nat))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(from, server_addr);
+    assert!(relayed.is(PacketType::NatTraversal));
+    assert_eq!(
+        socket_addr_from_bytes(relayed.get_body()),
+        Some(requester.local_addr().unwrap())
+    );
+}
+
+/*The node and the server share the single thread of the test runtime */
+#[tokio::test]
+async fn rendezvous_node_registers() {
+    let (server_addr, url) = start_server().await;
+    let url = discovery::parse_url(url).unwrap();
+    let identity = Identity::generate();
+
+    let mut config = NodeConfig::new();
+    config
+        .add_rendezvous_server(server_addr)
+        .set_rest_server(Some(
+            RestServer::new(url.clone(), 5, MismatchPolicy::Flag).unwrap(),
+        ));
+    let sock4 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+    /*The server only sees the Hellos sent over ipv4 */
+    let sock6 = Arc::new(UdpSocket::bind("[::1]:0").await.unwrap());
+    let node = Node::from_sockets(
+        Arc::clone(&sock4),
+        Arc::clone(&sock6),
+        identity.to_peer(),
+        Arc::new(config),
+    );
+
+    timeout(Duration::from_secs(10), node.register())
+        .await
+        .unwrap()
+        .unwrap();
+
+    let client = discovery::get_client(5).unwrap();
+    let key = discovery::get_peer_key(&client, &url, identity.get_name())
+        .await
+        .unwrap();
+    assert_eq!(
+        key.as_ref(),
+        identity.get_key_pair().public_key().as_slice()
+    );
+    /*Our ipv6 address was published next to the one it saw */
+    let addresses = discovery::get_peer_addresses(&client, &url, identity.get_name())
+        .await
+        .unwrap();
+    assert_eq!(
+        addresses.addresses,
+        vec![
+            sock4.local_addr().unwrap().to_string(),
+            sock6.local_addr().unwrap().to_string()
+        ]
+    );
+    timeout(Duration::from_secs(5), node.shutdown())
+        .await
+        .unwrap();
+}
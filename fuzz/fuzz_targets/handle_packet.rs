@@ -0,0 +1,24 @@
+#![no_main]
+
+use std::net::SocketAddr;
+
+use lib_network::config::NodeConfig;
+use lib_network::congestion_handler::PendingIds;
+use lib_network::handle_packet::handle_packet;
+use lib_network::packet::Packet;
+use lib_network::peer::ActivePeers;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(packet) = Packet::from_bytes(data.to_vec().into()) else {
+        return;
+    };
+    let sock_addr: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+    let _ = handle_packet(
+        packet,
+        sock_addr,
+        PendingIds::build_mutex(),
+        ActivePeers::build_mutex(),
+        &NodeConfig::new(),
+    );
+});
@@ -0,0 +1,15 @@
+#![no_main]
+
+use lib_network::packet::Packet;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    /*Bytes after the signature are ignored, so only the parsed packet
+    has to survive a round trip */
+    if let Ok(packet) = Packet::from_bytes(data.to_vec().into()) {
+        assert_eq!(
+            Packet::from_bytes(packet.as_bytes().into()).unwrap(),
+            packet
+        );
+    }
+});
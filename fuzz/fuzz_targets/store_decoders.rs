@@ -0,0 +1,22 @@
+#![no_main]
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use lib_network::action::Action;
+use lib_network::store::{get_file_node, get_hash_to_name_hashmap, get_parent_to_child_hashmap};
+use libfuzzer_sys::fuzz_target;
+
+/*The body of a Datum : its hash then the mkfs node */
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 32 {
+        return;
+    }
+    let sock_addr: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+    let hash = data[..32].try_into().unwrap();
+    let datum = Action::ProcessDatum(hash, data[32..].to_vec().into(), sock_addr);
+
+    let _ = get_file_node(&datum);
+    let _ = get_parent_to_child_hashmap(&datum, &mut HashMap::new());
+    let _ = get_hash_to_name_hashmap(&datum, &mut HashMap::new(), &HashMap::new());
+});